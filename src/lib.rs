@@ -1,4 +1,4 @@
-#![feature(custom_derive, plugin, const_fn)]
+#![feature(custom_derive, plugin, const_fn, try_from, integer_atomics)]
 #![plugin(serde_macros)]
 #![deny(missing_debug_implementations, missing_copy_implementations,
         trivial_casts, trivial_numeric_casts,
@@ -16,19 +16,36 @@ extern crate bincode;
 extern crate byteorder;
 extern crate either;
 extern crate env_logger;
+extern crate flate2;
 extern crate hlua;
+extern crate mio;
 extern crate serde;
+extern crate sha1;
 
 pub mod net;
+pub mod player;
 pub mod script;
 pub mod test_util;
 
-use std::collections::HashMap;
+use std::cell::{Ref, RefCell};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::convert::TryInto;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::error::Error;
 use std::fmt::{Display, Error as FmtError, Formatter};
-use std::io;
-use std::net::SocketAddr;
+use std::io::{self, BufRead};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+use hlua::any::AnyLuaValue;
+use sha1::Sha1;
 
 /// The current version of buildengine. Fallows Semantic Versioning.
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
@@ -36,52 +53,1371 @@ pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 /// If the game is allowed to crash in the event of a semi-handleable error, such as a bad network packet or a peer crashing.
 ///
 /// Programming mistakes however, will still panic.
-#[allow(unused)]
 static SHOULD_CRASH: AtomicBool = AtomicBool::new(true);    // Basically Erlang's too_big_to_fail process_flag.
 
+/// How many bytes of a `NetworkPacket::ScriptBody` are left for the name and the framing, apart
+/// from the piece of source it carries.
+const SCRIPT_BODY_OVERHEAD: usize = 64;
+
+/// The most bytes of a single script a client accepts from a server, so a server can't make it
+/// hold on to pieces of a script without end.
+pub const MAX_SCRIPT_SIZE: usize = 4 * 1024 * 1024;
+
+/// The ticks per second of an `EngineConfig` by default.
+pub const DEFAULT_TICK_RATE: u32 = 20;
+
+/// Configuration for an `Engine`, made with `EngineConfig::server` or `EngineConfig::client`.
+///
+/// Checked when the engine is created with `Engine::with_config`.
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    /// If the engine is a server or a client, and the address it listens on or connects to.
+    pub role: EngineRole,
+    /// The game scripts a server runs and offers to clients, which must include "init" unless
+    /// there are none at all.
+    ///
+    /// A client only gets a script engine if it is given scripts, which it runs but does not offer
+    /// to anyone. Defaults to no scripts.
+    pub scripts: HashMap<String, String>,
+    /// A directory to load scripts from with `script::load_scripts_from_dir` when the engine is
+    /// created, which are added to `scripts`, replacing any with the same name.
+    ///
+    /// Defaults to None.
+    pub script_dir: Option<PathBuf>,
+    /// How much of the lua standard library the scripts get.
+    ///
+    /// Defaults to `SandboxLevel::Full` on a server, and `SandboxLevel::Untrusted` on a client.
+    pub sandbox: script::SandboxLevel,
+    /// How many ticks `Engine::run` aims to run every second. Must be above 0.
+    ///
+    /// Defaults to DEFAULT_TICK_RATE.
+    pub tick_rate: u32,
+    /// The config of the engine's controller, see `Engine::net`.
+    ///
+    /// Defaults to `net::ControllerConfig::default()`.
+    pub net: net::ControllerConfig,
+    /// Copied to `Engine::should_crash`. Defaults to None.
+    pub should_crash: Option<bool>,
+}
+
+/// If an engine is a server or a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EngineRole {
+    /// A server listening on the address.
+    Server(SocketAddr),
+    /// A client connecting to the server at the address.
+    Client(SocketAddr),
+}
+
+impl EngineConfig {
+    /// Starts the config of a server listening on the address, with every other setting at it's
+    /// default.
+    pub fn server(address: SocketAddr) -> EngineConfigBuilder {
+        EngineConfigBuilder { config: EngineConfig::new(EngineRole::Server(address)) }
+    }
+
+    /// Starts the config of a client connecting to the server at the address, with every other
+    /// setting at it's default.
+    pub fn client(server_address: SocketAddr) -> EngineConfigBuilder {
+        EngineConfigBuilder { config: EngineConfig::new(EngineRole::Client(server_address)) }
+    }
+
+    fn new(role: EngineRole) -> EngineConfig {
+        let sandbox = match role {
+            EngineRole::Server(_) => script::SandboxLevel::Full,
+            EngineRole::Client(_) => script::SandboxLevel::Untrusted,
+        };
+        EngineConfig {
+            role: role,
+            scripts: HashMap::new(),
+            script_dir: None,
+            sandbox: sandbox,
+            tick_rate: DEFAULT_TICK_RATE,
+            net: net::ControllerConfig::default(),
+            should_crash: None,
+        }
+    }
+
+    /// Checks the config can be used to create an engine.
+    ///
+    /// Any scripts in `script_dir` are only checked once they are loaded by `Engine::with_config`.
+    ///
+    /// # Errors
+    /// * `InitError::ZeroTickRate` if the tick rate is 0.
+    /// * `InitError::MissingInitScript` if there are scripts, but none of them is "init".
+    pub fn validate(&self) -> Result<(), InitError> {
+        if self.tick_rate == 0 {
+            return Err(InitError::ZeroTickRate);
+        }
+        if !self.scripts.is_empty() && !self.scripts.contains_key("init") {
+            return Err(InitError::MissingInitScript);
+        }
+        Ok(())
+    }
+
+    /// How long each tick lasts at the tick rate.
+    ///
+    /// # Panics
+    /// * The tick rate is 0.
+    pub fn tick_length(&self) -> Duration {
+        assert!(self.tick_rate != 0, "an EngineConfig has a tick_rate of 0");
+        let nanos = 1000000000 / self.tick_rate as u64;
+        Duration::new(nanos / 1000000000, (nanos % 1000000000) as u32)
+    }
+}
+
+/// Builds an `EngineConfig`, started with `EngineConfig::server` or `EngineConfig::client`.
+#[derive(Clone, Debug)]
+pub struct EngineConfigBuilder {
+    config: EngineConfig,
+}
+
+impl EngineConfigBuilder {
+    /// Sets `EngineConfig::scripts`.
+    pub fn scripts(mut self, scripts: HashMap<String, String>) -> Self {
+        self.config.scripts = scripts;
+        self
+    }
+
+    /// Sets `EngineConfig::script_dir`.
+    pub fn script_dir(mut self, script_dir: &Path) -> Self {
+        self.config.script_dir = Some(script_dir.to_owned());
+        self
+    }
+
+    /// Sets `EngineConfig::sandbox`.
+    pub fn sandbox(mut self, sandbox: script::SandboxLevel) -> Self {
+        self.config.sandbox = sandbox;
+        self
+    }
+
+    /// Sets `EngineConfig::tick_rate`.
+    pub fn tick_rate(mut self, tick_rate: u32) -> Self {
+        self.config.tick_rate = tick_rate;
+        self
+    }
+
+    /// Sets `EngineConfig::net`, replacing anything set on it before, such as by `max_clients`.
+    pub fn net(mut self, net: net::ControllerConfig) -> Self {
+        self.config.net = net;
+        self
+    }
+
+    /// Sets `ControllerConfig::max_clients` on `EngineConfig::net`.
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.config.net.max_clients = max_clients;
+        self
+    }
+
+    /// Sets `ControllerConfig::password` on `EngineConfig::net`, which clients must know to
+    /// connect to a server.
+    pub fn password(mut self, password: &str) -> Self {
+        self.config.net.password = Some(password.to_owned());
+        self
+    }
+
+    /// Sets `ControllerConfig::display_name` on `EngineConfig::net`, the name a client asks the
+    /// server to give it's player.
+    pub fn display_name(mut self, display_name: &str) -> Self {
+        self.config.net.display_name = display_name.to_owned();
+        self
+    }
+
+    /// Sets `ControllerConfig::rcon_password` on `EngineConfig::net`, turning on the remote
+    /// console of a server.
+    pub fn rcon_password(mut self, rcon_password: &str) -> Self {
+        self.config.net.rcon_password = Some(rcon_password.to_owned());
+        self
+    }
+
+    /// Sets `EngineConfig::should_crash`, along with `ControllerConfig::should_crash` on
+    /// `EngineConfig::net`.
+    pub fn should_crash(mut self, should_crash: bool) -> Self {
+        self.config.should_crash = Some(should_crash);
+        self.config.net.should_crash = Some(should_crash);
+        self
+    }
+
+    /// Finishes the config. It is checked once it is given to `Engine::with_config`.
+    pub fn build(self) -> EngineConfig {
+        self.config
+    }
+}
+
+/// Stops `Engine::run` from any thread, made with `Engine::stop_handle`.
+#[derive(Clone, Debug)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    /// Makes `Engine::run` return after the tick it is running, see `Engine::request_stop`.
+    pub fn request_stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Pauses and resumes `Engine::run` from any thread, made with `Engine::pause_handle`.
+#[derive(Clone, Debug)]
+pub struct PauseHandle(Arc<AtomicBool>);
+
+impl PauseHandle {
+    /// Pauses the engine from the start of it's next tick, see `Engine::pause`.
+    pub fn pause(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes the engine from the start of it's next tick, see `Engine::resume`.
+    pub fn resume(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    /// If the engine is paused, or will be from the start of it's next tick.
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Forwards the connections removed from the controller of a server to it's engine, which can't be
+/// sent to the controller thread.
+#[derive(Debug)]
+struct DisconnectForwarder {
+    tx: Sender<(net::ConnectionId, net::DisconnectReason)>,
+}
+
+impl net::ControllerHandler for DisconnectForwarder {
+    fn on_disconnect(&mut self, id: net::ConnectionId, reason: net::DisconnectReason) {
+        // The engine only stops receiving once it is dropped.
+        let _ = self.tx.send((id, reason));
+    }
+}
+
 /// Main game struct. Contains all state nescary to work.
 ///
 /// While you may never need the fields exposed, they are exposed if you ever want to inspect the game state.
 /// You probably, however don't want to mutate the state directly. That can mess up client-server syncronization.
 #[derive(Debug)]
 pub struct Engine<'be> {
-    // The clientside or serverside networking state.
-    //
-    // Currently a Some if it is a client, or None if server.
-    // pub net_state: Option<Client>,
-    //
-    // The networking event loop. Mostly used in other functions for sending, adding, and killing connections.
-    //
-    // Also contains all state relating to networking.
-    // pub event_loop: Box<EventLoop>,
+    /// The connection to the server on a client, or None on a server.
+    pub net_state: Option<net::client::Client>,
+    /// The networking controller. Mostly used in other functions for sending, adding, and killing
+    /// connections.
+    ///
+    /// Also contains all state relating to networking. On a server it listens on the address it
+    /// was created with, and on a client it is the controller of `net_state`.
+    pub net: net::Controller,
     /// The scripting backend for the engine.
     ///
-    /// Not present on a client, for security reasons.
+    /// Always present on a server. A client only has one if it was given scripts of it's own,
+    /// which are sandboxed with `EngineConfig::sandbox`.
     pub script_engine: Option<script::Engine<'be>>,
+    /// On a server, the scripts it was created with, which are offered to clients.
+    ///
+    /// On a client, the scripts received from the server. They are never run, but are kept across
+    /// connections so unchanged scripts are not sent again. The client's own scripts are not kept
+    /// here.
+    pub scripts: HashMap<String, String>,
+    /// The controller packets are answered through instead of `net`, such as the scripts asked for
+    /// by a client.
+    ///
+    /// None by default. `buildengine.net` always goes through `net`.
+    pub controller: Option<net::Controller>,
+    /// On a client, the hash of every script in the latest `NetworkPacket::ScriptManifest` that
+    /// has yet to arrive, with what has arrived of it so far.
+    ///
+    /// None untill a manifest arrives.
+    pub pending_scripts: Option<HashMap<String, (u64, String)>>,
+    /// If an error sent by the server should crash the client, overriding `should_crash()`.
+    ///
+    /// None by default, so the global value is read whenever it is needed. Set it so several
+    /// engines in one process, such as in integration tests, don't share the flag.
+    pub should_crash: Option<bool>,
+    /// The configuration the engine was created with.
+    pub config: EngineConfig,
+    /// When the last tick started, None before the first tick.
+    last_tick: Option<Instant>,
+    /// Set to stop `run`, shared with every `StopHandle`.
+    stop: Arc<AtomicBool>,
+    /// Set to pause the engine, shared with every `PauseHandle` and the "pause" and "resume"
+    /// commands.
+    paused: Arc<AtomicBool>,
+    /// If the last tick was run paused, to tell when "on_pause" and "on_resume" are due.
+    ticked_paused: bool,
+    /// On a client, the packets received from the server, see `net::client::Client::recv`.
+    incoming: Option<Receiver<net::NetworkPacket>>,
+    /// On a server, the players of the connections that finished their handshake, which
+    /// "on_player_connect" has been executed for. Shared with the script engine for
+    /// `buildengine.players`.
+    players: Rc<RefCell<player::PlayerRegistry>>,
+    /// On a server, the connections removed from `net`, sent by it's handler.
+    disconnects: Option<Receiver<(net::ConnectionId, net::DisconnectReason)>>,
+    /// If `shutdown` has already run, so dropping the engine does not run it again.
+    shut_down: bool,
+    /// Sends lines to the console, cloned by the thread `spawn_console` starts.
+    console_tx: Sender<String>,
+    /// The lines sent to the console, run once per tick. None once the engine is shut down, so
+    /// the thread of the console stops at it's next line.
+    console: Option<Receiver<String>>,
+    /// If `spawn_console` has started it's thread.
+    console_spawned: bool,
 }
 
 impl<'be> Engine<'be> {
-    /// Creates a new client game.
-    pub fn new_client(_server_address: SocketAddr) -> Result<Self, InitError> {
+    /// Creates a new engine from the config.
+    ///
+    /// A server listens on it's address, and handles packets received when `handle_incoming` is
+    /// called. It sets the handler of `net` to learn of disconnected players, so replacing it
+    /// stops "on_player_disconnect" from being executed.
+    ///
+    /// A client connects to the server at it's address, blocking untill the handshake is done,
+    /// see `net::client::Client::spawn_with_config`.
+    ///
+    /// # Errors
+    /// * `InitError::LoadError` if loading the scripts in `EngineConfig::script_dir` failed.
+    /// * Any error from `EngineConfig::validate`, once they are loaded.
+    /// * `InitError::ScriptEngineError` if the prelude or sandbox of the scripts failed.
+    /// * `InitError::ScriptError` if the scripts failed to load, or
+    ///   `InitError::ScriptDependencyCycle` or `InitError::MissingScriptDependency` if their
+    ///   dependencies can't be satisfied.
+    /// * `InitError::IoError` if a server failed binding it's address, such as when it is already
+    ///   in use, or a client failed connecting to the server.
+    /// * `InitError::NetError` if the server refused the connection of a client.
+    pub fn with_config(mut config: EngineConfig) -> Result<Self, InitError> {
+        if let Some(ref script_dir) = config.script_dir {
+            let loaded = try!(script::load_scripts_from_dir(script_dir));
+            config.scripts.extend(loaded);
+        }
+        try!(config.validate());
+        let (console_tx, console) = channel();
+        let mut disconnects = None;
+        let players = Rc::new(RefCell::new(player::PlayerRegistry::new()));
+        let paused = Arc::new(AtomicBool::new(false));
+        let (net, net_state, script_engine, scripts, incoming) = match config.role {
+            EngineRole::Server(address) => {
+                let mut controller = net::Controller::new_with_config(config.net.clone());
+                let mut script_engine = try!(new_script_engine(&config, true, controller.clone()));
+                script_engine.set_players(Some(players.clone()));
+                register_pause_commands(&mut script_engine, &paused);
+                let listener = try!(TcpListener::bind(address));
+                try!(controller.add_listener(listener));
+                let (tx, rx) = channel();
+                controller.set_handler(Box::new(DisconnectForwarder { tx: tx }));
+                disconnects = Some(rx);
+                (controller, None, Some(script_engine), config.scripts.clone(), None)
+            }
+            EngineRole::Client(server_address) => {
+                let client = try!(net::client::Client::spawn_with_config(server_address,
+                                                                         config.net.clone()));
+                let script_engine = if config.scripts.is_empty() {
+                    None
+                } else {
+                    Some(try!(new_script_engine(&config, false, client.controller.clone())))
+                };
+                let incoming = client.recv();
+                (client.controller.clone(),
+                 Some(client),
+                 script_engine,
+                 HashMap::new(),
+                 Some(incoming))
+            }
+        };
         Ok(Engine {
-            // event_loop: Box::new(event_loop),
-            // net_state: Some(client),
-            script_engine: None,
+            net: net,
+            net_state: net_state,
+            script_engine: script_engine,
+            scripts: scripts,
+            controller: None,
+            pending_scripts: None,
+            should_crash: config.should_crash,
+            config: config,
+            last_tick: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            paused: paused,
+            ticked_paused: false,
+            incoming: incoming,
+            players: players,
+            disconnects: disconnects,
+            shut_down: false,
+            console_tx: console_tx,
+            console: Some(console),
+            console_spawned: false,
         })
     }
 
-    /// Creates a new server.
-    pub fn new_server(_server_address: &SocketAddr,
+    /// Creates a new client game, connected to the server at the address.
+    ///
+    /// The same as `with_config` with `EngineConfig::client(server_address).build()`.
+    pub fn new_client(server_address: SocketAddr) -> Result<Self, InitError> {
+        Engine::with_config(EngineConfig::client(server_address).build())
+    }
+
+    /// Creates a new client game like `new_client`, which runs the scripts in a script engine
+    /// sandboxed with `SandboxLevel::Untrusted`.
+    ///
+    /// The same as `with_config` with `EngineConfig::client(server_address)` given the scripts.
+    pub fn new_client_with_scripts(server_address: SocketAddr,
+                                   scripts: HashMap<String, String>)
+                                   -> Result<Self, InitError> {
+        Engine::with_config(EngineConfig::client(server_address).scripts(scripts).build())
+    }
+
+    /// Creates a new server listening on the address, running the scripts.
+    ///
+    /// The same as `with_config` with `EngineConfig::server(*server_address)` given the scripts.
+    pub fn new_server(server_address: &SocketAddr,
                       game_scripts: HashMap<String, String>)
                       -> Result<Self, InitError> {
-        Ok(Engine {
-            // event_loop: Box::new(event_loop),
-            // net_state: None,
-            script_engine: Some(try!(script::Engine::new(game_scripts))),
+        Engine::with_config(EngineConfig::server(*server_address).scripts(game_scripts).build())
+    }
+
+    /// Runs ticks untill `request_stop` is called, aiming for `EngineConfig::tick_rate` ticks a
+    /// second.
+    ///
+    /// The remainder of each tick is slept. A tick that takes longer than a tick should is logged,
+    /// and the next one starts right away. A stop requested before calling this makes it return
+    /// right away, and the request is cleared once it returns, so it can be called again.
+    ///
+    /// Ticks keep being run while the engine is paused, so packets and the console are still
+    /// handled, see `pause`.
+    ///
+    /// # Errors
+    /// The same as `tick`, in which case no more ticks are run.
+    pub fn run(&mut self) -> Result<(), script::ExecEventError> {
+        let tick_length = self.config.tick_length();
+        while !self.stop.swap(false, Ordering::SeqCst) {
+            let started = Instant::now();
+            let tick = self.ticks();
+            try!(self.tick());
+            let elapsed = started.elapsed();
+            if elapsed > tick_length {
+                warn!("Tick {} took {:?}, overrunning it's length of {:?}.",
+                      tick,
+                      elapsed,
+                      tick_length);
+            } else {
+                thread::sleep(tick_length - elapsed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a single tick.
+    ///
+    /// Every packet received is handled with `handle_incoming`, and every line sent to the console
+    /// is run with `handle_console`, then on a server the "on_tick" event is executed with the
+    /// number of the tick, see `ticks`, and the seconds since the last tick started. The first
+    /// tick is given the length of a tick instead.
+    ///
+    /// Once the event has run, the tick is counted and the game time scripts read with
+    /// `buildengine.time` and keep their timers against is advanced by the same time, see
+    /// `script::Engine::advance_tick`, up to `script::QUEUED_EVENTS_PER_TICK` events queued with
+    /// `buildengine.queue_event` are executed, and the events queued by scripts with
+    /// `buildengine.send_to` are sent.
+    /// Packets are queued on their connections as they are sent, and written out by the
+    /// controller's threads, so nothing is left to send once it returns.
+    ///
+    /// While the engine is paused, see `pause`, the packets and console lines are still handled,
+    /// and the events scripts queue with `buildengine.send_to` are still sent, but nothing else is
+    /// done, so the tick isn't counted, and neither the game time nor the timers move. The first
+    /// tick paused executes the "on_pause" event before anything else, and the first tick once
+    /// resumed executes the "on_resume" event, then runs as usual.
+    ///
+    /// # Errors
+    /// * Any error from executing the "on_tick", "on_pause" or "on_resume" event, apart from
+    ///   `ExecEventError::EngineStdNotImported`, as then nothing can subscribe to it.
+    /// * Otherwise any error from the callback of a timer, or from a queued event.
+    pub fn tick(&mut self) -> Result<(), script::ExecEventError> {
+        let now = Instant::now();
+        let delta = match self.last_tick {
+            Some(last_tick) => now - last_tick,
+            None => self.config.tick_length(),
+        };
+        self.last_tick = Some(now);
+        let paused = self.paused.load(Ordering::SeqCst);
+        let mut result = Ok(());
+        if paused != self.ticked_paused {
+            self.ticked_paused = paused;
+            if let Some(ref mut script_engine) = self.script_engine {
+                let event = if paused { "on_pause" } else { "on_resume" };
+                match script_engine.exec_event(event, Vec::new()) {
+                    Ok(_) | Err(script::ExecEventError::EngineStdNotImported) => {}
+                    Err(err) => result = Err(err),
+                }
+            }
+        }
+        self.handle_incoming();
+        self.handle_console();
+        if paused {
+            self.flush_outgoing();
+            return result;
+        }
+        if let Some(ref mut script_engine) = self.script_engine {
+            let delta_secs = delta.as_secs() as f64 + delta.subsec_nanos() as f64 / 1e9;
+            let args = vec![AnyLuaValue::LuaNumber(script_engine.tick() as f64),
+                            AnyLuaValue::LuaNumber(delta_secs)];
+            match script_engine.exec_event("on_tick", args) {
+                Ok(_) | Err(script::ExecEventError::EngineStdNotImported) => {}
+                Err(err) => {
+                    if result.is_ok() {
+                        result = Err(err);
+                    } else {
+                        warn!("The \"on_tick\" event failed: {}", err);
+                    }
+                }
+            }
+            if let Err(err) = script_engine.advance_tick(delta) {
+                if result.is_ok() {
+                    result = Err(err);
+                } else {
+                    warn!("A timer callback failed: {}", err);
+                }
+            }
+            if let Err(err) = script_engine.process_queued_events(script::QUEUED_EVENTS_PER_TICK) {
+                if result.is_ok() {
+                    result = Err(err);
+                } else {
+                    warn!("A queued event failed: {}", err);
+                }
+            }
+        }
+        self.flush_outgoing();
+        result
+    }
+
+    /// Tears the engine down, closing every connection before the script engine is dropped.
+    ///
+    /// Every connection is sent a `NetworkPacket::Disconnect` with `net::SHUTDOWN_REASON`, and
+    /// every listener is stopped, so it's address can be bound again once this returns, see
+    /// `net::Controller::shutdown`. The threads of the connections exit once their sockets close.
+    /// Then the "on_shutdown" event is executed, and the script engine is dropped.
+    ///
+    /// Dropping an engine does the same, logging any error instead.
+    ///
+    /// # Errors
+    /// * Any error from executing the "on_shutdown" event, apart from
+    ///   `ExecEventError::EngineStdNotImported`. Everything is still torn down.
+    pub fn shutdown(mut self) -> Result<(), script::ExecEventError> {
+        self.shutdown_once()
+    }
+
+    /// Runs `shutdown` the first time it is called, and does nothing after that.
+    fn shutdown_once(&mut self) -> Result<(), script::ExecEventError> {
+        if self.shut_down {
+            return Ok(());
+        }
+        self.shut_down = true;
+        self.console = None;
+        self.net.shutdown();
+        let mut result = Ok(());
+        if let Some(mut script_engine) = self.script_engine.take() {
+            match script_engine.exec_event("on_shutdown", Vec::new()) {
+                Ok(_) | Err(script::ExecEventError::EngineStdNotImported) => {}
+                Err(err) => result = Err(err),
+            }
+        }
+        result
+    }
+
+    /// Makes `run` return after the tick it is running.
+    ///
+    /// To stop it from another thread, use a `StopHandle` made with `stop_handle`.
+    pub fn request_stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    /// A handle that stops `run` like `request_stop`, which can be sent to another thread.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(self.stop.clone())
+    }
+
+    /// Pauses the engine from the start of it's next tick, freezing the game while still handling
+    /// packets and the console, see `tick`. Does nothing if it is already paused.
+    ///
+    /// Servers also have a "pause" command doing the same. To pause from another thread, use a
+    /// `PauseHandle` made with `pause_handle`.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes the engine from the start of it's next tick, after `pause`. Does nothing if it
+    /// isn't paused.
+    ///
+    /// Servers also have a "resume" command doing the same.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// If the engine is paused, or will be from the start of it's next tick.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// How many ticks have been run, not counting those run while paused, which is the tick
+    /// scripts read with `buildengine.time.tick` and the next "on_tick" event is given, carried
+    /// over snapshots. Always 0 on a client without scripts, as only the script engine counts them.
+    pub fn ticks(&self) -> u64 {
+        self.script_engine.as_ref().map_or(0, |script_engine| script_engine.tick())
+    }
+
+    /// A handle that pauses and resumes the engine like `pause` and `resume`, which can be sent to
+    /// another thread.
+    pub fn pause_handle(&self) -> PauseHandle {
+        PauseHandle(self.paused.clone())
+    }
+
+    /// Starts a thread reading lines from stdin and sending them to the console, where each is
+    /// run as a command with `script::Engine::run_command` once per tick, on this thread, and it's
+    /// output printed. Doing nothing if it was already started.
+    ///
+    /// The thread stops quietly once stdin closes, such as on Ctrl-D or when the server runs as a
+    /// daemon. It is never joined, so `shutdown` doesn't wait on a read of stdin, and stops at the
+    /// next line it reads once the engine is shut down.
+    ///
+    /// Commands that run lua code still need `script::Engine::set_commands_trusted`.
+    ///
+    /// # Errors
+    /// If the thread failed to start.
+    pub fn spawn_console(&mut self) -> io::Result<()> {
+        if self.console_spawned {
+            return Ok(());
+        }
+        let tx = self.console_tx.clone();
+        try!(thread::Builder::new().name("console".to_owned()).spawn(move || {
+            let stdin = io::stdin();
+            let mut line = String::new();
+            loop {
+                line.clear();
+                match stdin.lock().read_line(&mut line) {
+                    Ok(0) => {
+                        debug!("Stdin closed, stopping the console.");
+                        return;
+                    }
+                    Ok(_) => {
+                        let line = line.trim_right_matches(|c: char| c == '\n' || c == '\r');
+                        if tx.send(line.to_owned()).is_err() {
+                            // The engine was shut down.
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        warn!("Error reading stdin, stopping the console: {}", err);
+                        return;
+                    }
+                }
+            }
+        }));
+        self.console_spawned = true;
+        Ok(())
+    }
+
+    /// Sends a line to the console as if it was read from stdin, to be run by the next tick, see
+    /// `spawn_console`.
+    pub fn inject_console_line(&self, line: &str) {
+        // Only fails once the engine is shut down, when no more lines are run.
+        let _ = self.console_tx.send(line.to_owned());
+    }
+
+    /// Runs every line sent to the console so far as a command, printing it's output or error,
+    /// returning how many were run. Blank lines are skipped.
+    ///
+    /// Without a script engine to run them, the lines are dropped with a warning.
+    pub fn handle_console(&mut self) -> usize {
+        let mut lines = Vec::new();
+        if let Some(ref console) = self.console {
+            while let Ok(line) = console.try_recv() {
+                if !line.trim().is_empty() {
+                    lines.push(line);
+                }
+            }
+        }
+        let script_engine = match self.script_engine {
+            Some(ref mut script_engine) => script_engine,
+            None => {
+                if !lines.is_empty() {
+                    warn!("Dropped {} console lines, as there is no script engine to run them.",
+                          lines.len());
+                }
+                return 0;
+            }
+        };
+        for line in &lines {
+            match script_engine.run_command(line) {
+                Ok(ref output) if output.is_empty() => {}
+                Ok(output) => println!("{}", output),
+                Err(err) => println!("{}", err),
+            }
+        }
+        lines.len()
+    }
+
+    /// Handles every packet received so far with `handle_packet`, returning how many were
+    /// handled.
+    ///
+    /// On a server the packets are taken from `net`, and on a client the packets from the server
+    /// are taken from `net_state`. An error handling a packet is logged, and the rest are still
+    /// handled.
+    ///
+    /// Once the packets are handled, a server removes every player whose connection has since
+    /// been removed from `net`, after executing "on_player_disconnect" with the id of the player
+    /// as a number and a description of why it was removed, so the handlers can still read the
+    /// player with `buildengine.players`. Then the events queued by scripts with
+    /// `buildengine.send_to` are sent.
+    pub fn handle_incoming(&mut self) -> usize {
+        let mut packets = Vec::new();
+        while let Some(received) = self.net.try_recv_packet() {
+            packets.push(received);
+        }
+        if let (Some(client), Some(incoming)) = (self.net_state.as_ref(), self.incoming.as_ref()) {
+            while let Ok(packet) = incoming.try_recv() {
+                packets.push((client.id, packet));
+            }
+        }
+        let handled = packets.len();
+        for (id, packet) in packets {
+            if let Err(err) = self.handle_packet(id, packet) {
+                warn!("Error handling a packet from connection {}: {}", id.0, err);
+            }
+        }
+        let mut disconnects = Vec::new();
+        if let Some(ref rx) = self.disconnects {
+            while let Ok(disconnect) = rx.try_recv() {
+                disconnects.push(disconnect);
+            }
+        }
+        for (id, reason) in disconnects {
+            // Connections that never finished their handshake were never players.
+            let found = self.players.borrow().by_connection(id).cloned();
+            let player = match found {
+                Some(player) => player,
+                None => continue,
+            };
+            // The player is only removed once the event has run, so it's handlers can still look
+            // the player up.
+            let reason = reason.to_string();
+            if let Err(err) = self.exec_player_event("on_player_disconnect", player.id, reason) {
+                warn!("Error executing on_player_disconnect for player {}: {}",
+                      player.name,
+                      err);
+            }
+            self.players.borrow_mut().remove_connection(id);
+        }
+        self.flush_outgoing();
+        handled
+    }
+
+    /// Executes an event about a player with it's id as a number, and the detail.
+    ///
+    /// `ExecEventError::EngineStdNotImported` is ignored, as then nothing can subscribe to it.
+    fn exec_player_event(&mut self,
+                         event_name: &str,
+                         id: player::PlayerId,
+                         detail: String)
+                         -> Result<(), script::ExecEventError> {
+        if let Some(ref mut script_engine) = self.script_engine {
+            let args = vec![AnyLuaValue::LuaNumber(id.0 as f64), AnyLuaValue::LuaString(detail)];
+            match script_engine.exec_event(event_name, args) {
+                Ok(_) | Err(script::ExecEventError::EngineStdNotImported) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the events queued by scripts with `buildengine.send_to` through the answering
+    /// controller.
+    ///
+    /// Events that can't be sent, such as to a connection that closed, are logged and dropped.
+    fn flush_outgoing(&mut self) {
+        let outgoing = match self.script_engine {
+            Some(ref mut script_engine) => script_engine.take_outgoing(),
+            None => return,
+        };
+        for event in outgoing {
+            let id = net::ConnectionId(event.to);
+            let packet = net::NetworkPacket::Event {
+                name: event.name,
+                args: event.args,
+            };
+            if let Err(err) = self.answering_controller().send_to(id, packet) {
+                warn!("Dropping an event a script sent to connection {}: {}", id.0, err);
+            }
+        }
+    }
+
+    /// Reacts to a packet received from the connection with the given id.
+    ///
+    /// A `NetworkPacket::Event` is executed on the script engine, with the id of the connection
+    /// prepended to it's arguments as a number. A client without a script engine ignores them.
+    ///
+    /// The first `NetworkPacket::Init` of a connection to a server adds a player for it, named
+    /// after the display_name of the Init, see `player::PlayerRegistry::add`. A name that is taken
+    /// or invalid is answered with `NetworkError::NameTaken` or `NetworkError::InvalidName`, and
+    /// the connection is kicked. Otherwise the server answers with the manifest of it's scripts,
+    /// and sends them through the controller as the client asks for them. The new player then
+    /// executes "on_player_connect", with the id of the player as a number and the address of
+    /// the peer as a string, which is empty if the connection already closed. The player is
+    /// removed once it's connection is, executing "on_player_disconnect", see `handle_incoming`.
+    ///
+    /// A server answers a `NetworkPacket::RconAuth` with a `NetworkPacket::RconResponse` saying
+    /// if the password was right, see `net::Controller::authenticate_rcon`. A
+    /// `NetworkPacket::RconCommand` from a connection that authenticated is run with
+    /// `script::Engine::run_command`, and answered with it's output or error. Otherwise it is
+    /// refused and counted as a failure, see `net::Controller::record_rcon_failure`.
+    ///
+    /// A client asks for the scripts in the manifest that it does not have or that changed, see
+    /// `synced_scripts`.
+    ///
+    /// A `NetworkPacket::Error` on a client crashes it if
+    /// it should crash, and is returned otherwise. A `NetworkPacket::Disconnect` on a client is
+    /// returned with the reason the server gave, such as for being kicked. Other packets are
+    /// ignored.
+    ///
+    /// `NetworkError::ShouldCrashBothTrue` is only logged, since the connection is closed right
+    /// after it.
+    ///
+    /// # Errors
+    /// * `HandlePacketError::Peer` if the server sent an error, and the client should not crash.
+    /// * `HandlePacketError::Disconnected` if the server closed the connection.
+    /// * `HandlePacketError::Send` if an answer could not be sent through the controller.
+    /// * `HandlePacketError::Script` with `ExecEventError::BadArgument` if an argument can't exist
+    ///   in lua.
+    /// * `HandlePacketError::Script` with any other error from executing the event.
+    ///
+    /// # Panics
+    /// * The server sent an error, and the client should crash.
+    pub fn handle_packet(&mut self,
+                         id: net::ConnectionId,
+                         packet: net::NetworkPacket)
+                         -> Result<(), HandlePacketError> {
+        if let EngineRole::Client(_) = self.config.role {
+            return self.handle_client_packet(id, packet);
+        }
+        match packet {
+            net::NetworkPacket::Init { display_name, .. } => {
+                let joined = if self.players.borrow().by_connection(id).is_some() {
+                    None
+                } else {
+                    let added = self.players.borrow_mut().add(&display_name, id);
+                    match added {
+                        Ok(player) => Some(player),
+                        Err(err) => {
+                            info!("Refusing connection {} the name {:?}: {}",
+                                  id.0,
+                                  display_name,
+                                  err);
+                            let reason = err.to_string();
+                            try!(self.send_to(id, net::NetworkPacket::Error(err)));
+                            try!(self.answering_controller().kick(id, &reason));
+                            return Ok(());
+                        }
+                    }
+                };
+                let manifest = self.script_manifest();
+                try!(self.send_to(id, manifest));
+                if let Some(player) = joined {
+                    let addr = match self.answering_controller().peer_addr(id) {
+                        Some(addr) => addr.to_string(),
+                        None => String::new(),
+                    };
+                    try!(self.exec_player_event("on_player_connect", player, addr));
+                }
+            }
+            net::NetworkPacket::ScriptRequest { names } => try!(self.send_scripts(id, names)),
+            net::NetworkPacket::Event { name, args } => {
+                try!(self.exec_packet_event(id, name, args))
+            }
+            net::NetworkPacket::RconAuth { password } => {
+                let output = if self.answering_controller().authenticate_rcon(id, &password) {
+                    info!("Connection {} authenticated for the remote console.", id.0);
+                    "Authenticated."
+                } else {
+                    warn!("Connection {} sent a wrong remote console password.", id.0);
+                    "Wrong password."
+                };
+                try!(self.send_rcon_response(id, output.to_owned()));
+            }
+            net::NetworkPacket::RconCommand { line } => {
+                if !self.answering_controller().is_admin(id) {
+                    warn!("Connection {} sent a remote console command without authenticating.",
+                          id.0);
+                    self.answering_controller().record_rcon_failure(id);
+                    return self.send_rcon_response(id, "Not authenticated.".to_owned());
+                }
+                info!("Connection {} ran the remote console command: {}", id.0, line);
+                let output = match self.script_engine {
+                    Some(ref mut script_engine) => {
+                        match script_engine.run_command(&line) {
+                            Ok(output) => output,
+                            Err(err) => err.to_string(),
+                        }
+                    }
+                    None => "There is no script engine to run commands.".to_owned(),
+                };
+                try!(self.send_rcon_response(id, output));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Answers a remote console packet. The connection may already be gone, such as when it was
+    /// kicked for failing too many times, which is only logged.
+    fn send_rcon_response(&self,
+                          id: net::ConnectionId,
+                          output: String)
+                          -> Result<(), HandlePacketError> {
+        let packet = net::NetworkPacket::RconResponse { output: output };
+        match self.answering_controller().send_to(id, packet) {
+            Err(net::SendError::UnknownConnection(_)) |
+            Err(net::SendError::ConnectionClosed(_)) => {
+                debug!("Connection {} closed before it's remote console response was sent.",
+                       id.0);
+                Ok(())
+            }
+            result => {
+                try!(result);
+                Ok(())
+            }
+        }
+    }
+
+    /// Executes an event received from the connection, with it's id prepended to the arguments.
+    fn exec_packet_event(&mut self,
+                         id: net::ConnectionId,
+                         name: String,
+                         args: Vec<script::LuaValueRepr>)
+                         -> Result<(), HandlePacketError> {
+        let mut lua_args = vec![AnyLuaValue::LuaNumber(id.0 as f64)];
+        for arg in args {
+            lua_args.push(try!(arg.try_into()));
+        }
+        if let Some(ref mut script_engine) = self.script_engine {
+            try!(script_engine.exec_event(&name, lua_args));
+        }
+        Ok(())
+    }
+
+    /// The manifest of the scripts the engine offers to clients, sorted by name.
+    pub fn script_manifest(&self) -> net::NetworkPacket {
+        let mut entries: Vec<(String, u64)> = self.scripts
+                                                  .iter()
+                                                  .map(|(name, source)| {
+                                                      (name.clone(), script_hash(source))
+                                                  })
+                                                  .collect();
+        entries.sort();
+        net::NetworkPacket::ScriptManifest { entries: entries }
+    }
+
+    /// The scripts received from the server, once every script in it's latest manifest has
+    /// arrived.
+    pub fn synced_scripts(&self) -> Option<&HashMap<String, String>> {
+        match self.pending_scripts {
+            Some(ref pending) if pending.is_empty() => Some(&self.scripts),
+            _ => None,
+        }
+    }
+
+    /// The controller packets are answered through, which is `net` unless `controller` is set.
+    fn answering_controller(&self) -> &net::Controller {
+        self.controller.as_ref().unwrap_or(&self.net)
+    }
+
+    /// Sends the packet through the answering controller.
+    fn send_to(&self,
+               id: net::ConnectionId,
+               packet: net::NetworkPacket)
+               -> Result<(), HandlePacketError> {
+        try!(self.answering_controller().send_to(id, packet));
+        Ok(())
+    }
+
+    /// Sends the sources of the named scripts, split to fit the controller's max_packet_size.
+    fn send_scripts(&self,
+                    id: net::ConnectionId,
+                    names: Vec<String>)
+                    -> Result<(), HandlePacketError> {
+        let max_packet_size = self.answering_controller()
+                                  .raw
+                                  .config
+                                  .read()
+                                  .unwrap()
+                                  .max_packet_size as usize;
+        for name in names {
+            let source = match self.scripts.get(&name) {
+                Some(source) => source,
+                None => {
+                    warn!("Connection {} asked for script {}, which does not exist.",
+                          id.0,
+                          name);
+                    continue;
+                }
+            };
+            let max_len = max_packet_size.saturating_sub(name.len() + SCRIPT_BODY_OVERHEAD);
+            let pieces = split_source(source, max_len);
+            let count = pieces.len();
+            for (i, piece) in pieces.into_iter().enumerate() {
+                let packet = net::NetworkPacket::ScriptBody {
+                    name: name.clone(),
+                    source: piece.to_owned(),
+                    last: i + 1 == count,
+                };
+                try!(self.send_to(id, packet));
+            }
+        }
+        Ok(())
+    }
+
+    /// On a server, the players of the connections that finished their handshake, to look them up
+    /// by id, name or connection.
+    pub fn players(&self) -> Ref<player::PlayerRegistry> {
+        self.players.borrow()
+    }
+
+    /// If the engine should crash, taking the override on the engine over the global value.
+    pub fn should_crash(&self) -> bool {
+        self.should_crash.unwrap_or_else(should_crash)
+    }
+
+    fn handle_client_packet(&mut self,
+                            id: net::ConnectionId,
+                            packet: net::NetworkPacket)
+                            -> Result<(), HandlePacketError> {
+        match packet {
+            net::NetworkPacket::Error(net::NetworkError::ShouldCrashBothTrue) => {
+                warn!("The server refused the connection, since neither it nor the client should \
+                       crash.");
+                Ok(())
+            }
+            net::NetworkPacket::Error(err) => {
+                if self.should_crash() {
+                    panic!("the server on connection {} sent an error: {}", id.0, err);
+                }
+                Err(HandlePacketError::Peer(id, err))
+            }
+            net::NetworkPacket::Disconnect { reason } => {
+                info!("The server on connection {} closed it: {}", id.0, reason);
+                Err(HandlePacketError::Disconnected(id, reason))
+            }
+            net::NetworkPacket::ScriptManifest { entries } => {
+                let mut pending = HashMap::new();
+                let mut offered = HashSet::new();
+                for (name, hash) in entries {
+                    let unchanged = match self.scripts.get(&name) {
+                        Some(source) => script_hash(source) == hash,
+                        None => false,
+                    };
+                    if !unchanged {
+                        pending.insert(name.clone(), (hash, String::new()));
+                    }
+                    offered.insert(name);
+                }
+                let stale: Vec<String> = self.scripts
+                                             .keys()
+                                             .filter(|name| !offered.contains(*name))
+                                             .cloned()
+                                             .collect();
+                for name in stale {
+                    self.scripts.remove(&name);
+                }
+                let names: Vec<String> = pending.keys().cloned().collect();
+                self.pending_scripts = Some(pending);
+                if names.is_empty() {
+                    return Ok(());
+                }
+                self.send_to(id, net::NetworkPacket::ScriptRequest { names: names })
+            }
+            net::NetworkPacket::ScriptBody { name, source, last } => {
+                let done = match self.pending_scripts
+                                     .as_mut()
+                                     .and_then(|pending| pending.get_mut(&name)) {
+                    Some(&mut (hash, ref mut received)) => {
+                        if received.len() + source.len() > MAX_SCRIPT_SIZE {
+                            received.clear();
+                            return Err(HandlePacketError::ScriptTooLarge(id, name.clone()));
+                        }
+                        received.push_str(&source);
+                        // Hashed once the last piece has arrived, rather than for every piece.
+                        if last && script_hash(received) != hash {
+                            warn!("Script {} from the server on connection {} does not match \
+                                   it's hash in the manifest, dropping it.",
+                                  name,
+                                  id.0);
+                            received.clear();
+                            return Ok(());
+                        }
+                        last
+                    }
+                    None => {
+                        warn!("The server on connection {} sent script {}, which was not asked \
+                               for.",
+                              id.0,
+                              name);
+                        return Ok(());
+                    }
+                };
+                if done {
+                    let pending = self.pending_scripts.as_mut().unwrap();
+                    let (_hash, source) = pending.remove(&name).unwrap();
+                    self.scripts.insert(name, source);
+                }
+                Ok(())
+            }
+            net::NetworkPacket::Event { name, args } => self.exec_packet_event(id, name, args),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'be> Drop for Engine<'be> {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown_once() {
+            warn!("Error executing on_shutdown while dropping the engine: {}", err);
+        }
+    }
+}
+
+/// The network of `buildengine.net`, sending events through a controller as
+/// `net::NetworkPacket::Event`s.
+struct ControllerNetwork {
+    controller: net::Controller,
+    /// The counters of the connections scripts read the stats of, so reading them again doesn't
+    /// lock the controller's connections. Dropped once their connection is removed.
+    counters: RefCell<HashMap<net::ConnectionId, Arc<net::ConnectionCounters>>>,
+}
+
+impl ControllerNetwork {
+    fn new(controller: net::Controller) -> ControllerNetwork {
+        ControllerNetwork {
+            controller: controller,
+            counters: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl script::ScriptNetwork for ControllerNetwork {
+    fn send(&self,
+            to: u64,
+            event_name: String,
+            args: Vec<script::LuaValueRepr>)
+            -> Result<(), String> {
+        let packet = net::NetworkPacket::Event {
+            name: event_name,
+            args: args,
+        };
+        self.controller.send_to(net::ConnectionId(to), packet).map_err(|err| err.to_string())
+    }
+
+    fn broadcast(&self, event_name: String, args: Vec<script::LuaValueRepr>) -> usize {
+        let packet = net::NetworkPacket::Event {
+            name: event_name,
+            args: args,
+        };
+        self.controller.broadcast(packet)
+    }
+
+    fn kick(&self, to: u64, reason: &str) -> Result<(), String> {
+        self.controller.kick(net::ConnectionId(to), reason).map_err(|err| err.to_string())
+    }
+
+    fn peer_count(&self) -> usize {
+        self.controller.connection_count()
+    }
+
+    fn peer_addr(&self, to: u64) -> Option<String> {
+        self.controller.peer_addr(net::ConnectionId(to)).map(|addr| addr.to_string())
+    }
+
+    fn stats(&self, to: u64) -> Option<script::PeerStats> {
+        let id = net::ConnectionId(to);
+        let mut counters = self.counters.borrow_mut();
+        let removed = counters.get(&id)
+                              .map_or(true, |counters| counters.removed.load(Ordering::Relaxed));
+        if removed {
+            counters.remove(&id);
+            match self.controller.counters(id) {
+                Some(found) => counters.insert(id, found),
+                None => return None,
+            };
+        }
+        let counters = &counters[&id];
+        let connected_for = counters.connected_for();
+        Some(script::PeerStats {
+            rtt_ms: counters.rtt().map(|rtt| {
+                rtt.as_secs() as f64 * 1000.0 + rtt.subsec_nanos() as f64 / 1e6
+            }),
+            bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: counters.bytes_received.load(Ordering::Relaxed),
+            connected_seconds: connected_for.as_secs() as f64 +
+                               connected_for.subsec_nanos() as f64 / 1e9,
         })
     }
 }
 
+/// Constructs the script engine of an `Engine` with the config, like `script::Engine::new_ordered`,
+/// telling the scripts in `buildengine.info` if they are run by a server and the tick rate, and
+/// giving them the controller as the network of `buildengine.net`.
+fn new_script_engine<'lua>(config: &EngineConfig,
+                           is_server: bool,
+                           controller: net::Controller)
+                           -> Result<script::Engine<'lua>, InitError> {
+    let mut script_engine = try!(script::Engine::new_empty(config.sandbox)
+                                     .map_err(InitError::ScriptEngineError));
+    script_engine.set_info(script::EngineInfo {
+        is_server: is_server,
+        is_client: !is_server,
+        tick_rate: Some(config.tick_rate),
+        ..Default::default()
+    });
+    script_engine.set_network(Some(Box::new(ControllerNetwork::new(controller))));
+    if config.scripts.is_empty() {
+        // A server may run without scripts, which `EngineConfig::validate` checks for otherwise.
+        script_engine.set_init_policy(script::InitPolicy::AllowMissing);
+    }
+    try!(script_engine.run_init_ordered(script::scripts_by_name(config.scripts.clone())));
+    Ok(script_engine)
+}
+
+/// Registers the "pause" and "resume" commands of a server, which set the flag `Engine::pause`
+/// and `Engine::resume` do.
+fn register_pause_commands(script_engine: &mut script::Engine, paused: &Arc<AtomicBool>) {
+    let pause = paused.clone();
+    script_engine.register_command("pause",
+                                   "Pauses the game from the next tick.",
+                                   move |_| {
+                                       if pause.swap(true, Ordering::SeqCst) {
+                                           Ok("The game is already paused.".to_owned())
+                                       } else {
+                                           Ok("Paused.".to_owned())
+                                       }
+                                   });
+    let resume = paused.clone();
+    script_engine.register_command("resume",
+                                   "Resumes the game from the next tick.",
+                                   move |_| {
+                                       if resume.swap(false, Ordering::SeqCst) {
+                                           Ok("Resumed.".to_owned())
+                                       } else {
+                                           Ok("The game isn't paused.".to_owned())
+                                       }
+                                   });
+}
+
+/// The hash of the source of a script, as sent in `NetworkPacket::ScriptManifest`.
+///
+/// It is the first 8 bytes of the SHA1 of the source, so it is the same on every platform and
+/// version of the game.
+pub fn script_hash(source: &str) -> u64 {
+    let mut hasher = Sha1::new();
+    hasher.update(source.as_bytes());
+    LittleEndian::read_u64(&hasher.digest().bytes()[..8])
+}
+
+/// Splits the source into pieces of at most max_len bytes, without splitting any character.
+///
+/// An empty source is a single empty piece.
+fn split_source(source: &str, max_len: usize) -> Vec<&str> {
+    // Every character fits in 4 bytes, so every piece holds at least one.
+    let max_len = cmp::max(max_len, 4);
+    let mut pieces = Vec::new();
+    let mut rest = source;
+    while rest.len() > max_len {
+        let mut end = max_len;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        pieces.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    pieces.push(rest);
+    pieces
+}
+
+/// An error that can occour handling a packet with `Engine::handle_packet`.
+#[derive(Debug)]
+pub enum HandlePacketError {
+    /// The server sent an error over the connection with the given id.
+    ///
+    /// Only returned if the client should not crash, as it can carry on, such as by reconnecting.
+    Peer(net::ConnectionId, net::NetworkError),
+    /// The server closed the connection with the given id, giving the reason, such as when kicking.
+    ///
+    /// The reason is meant to be shown to the player.
+    Disconnected(net::ConnectionId, String),
+    /// An answer to the packet could not be sent through the engine's controller.
+    Send(net::SendError),
+    /// An error occoured executing an event on the script engine.
+    Script(script::ExecEventError),
+    /// The server on the connection with the given id sent more than MAX_SCRIPT_SIZE bytes of the
+    /// script with the name, so what had arrived of it was dropped.
+    ScriptTooLarge(net::ConnectionId, String),
+}
+
+impl Display for HandlePacketError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), FmtError> {
+        match *self {
+            HandlePacketError::Peer(id, ref err) => {
+                write!(fmt, "Peer: The server on connection {} sent an error: {}", id.0, err)
+            }
+            HandlePacketError::Disconnected(id, ref reason) => {
+                write!(fmt, "Disconnected: The server on connection {} closed it: {}", id.0, reason)
+            }
+            HandlePacketError::Send(ref err) => write!(fmt, "Send: {}", err),
+            HandlePacketError::Script(ref err) => write!(fmt, "Script: {}", err),
+            HandlePacketError::ScriptTooLarge(id, ref name) => {
+                write!(fmt,
+                       "ScriptTooLarge: The server on connection {} sent more than {} bytes of \
+                        script {}",
+                       id.0,
+                       MAX_SCRIPT_SIZE,
+                       name)
+            }
+        }
+    }
+}
+
+impl Error for HandlePacketError {
+    fn description(&self) -> &str {
+        match *self {
+            HandlePacketError::Peer(_, ref err) => err.description(),
+            HandlePacketError::Disconnected(..) => {
+                "Disconnected: The server closed the connection."
+            }
+            HandlePacketError::Send(ref err) => err.description(),
+            HandlePacketError::Script(ref err) => err.description(),
+            HandlePacketError::ScriptTooLarge(..) => {
+                "ScriptTooLarge: The server sent a script larger than MAX_SCRIPT_SIZE."
+            }
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            HandlePacketError::Peer(_, ref err) => Some(err),
+            HandlePacketError::Disconnected(..) => None,
+            HandlePacketError::Send(ref err) => Some(err),
+            HandlePacketError::Script(ref err) => Some(err),
+            HandlePacketError::ScriptTooLarge(..) => None,
+        }
+    }
+}
+
+impl From<script::ExecEventError> for HandlePacketError {
+    fn from(err: script::ExecEventError) -> Self {
+        HandlePacketError::Script(err)
+    }
+}
+
+impl From<net::SendError> for HandlePacketError {
+    fn from(err: net::SendError) -> Self {
+        HandlePacketError::Send(err)
+    }
+}
+
+impl From<script::LuaReprError> for HandlePacketError {
+    fn from(err: script::LuaReprError) -> Self {
+        HandlePacketError::Script(script::ExecEventError::BadArgument(err))
+    }
+}
+
 /// An error hapened while initing the game.
 #[derive(Debug)]
 pub enum InitError {
@@ -91,6 +1427,23 @@ pub enum InitError {
     IoError(io::Error),
     /// An error occoured from an error in lua code passed to the script engine.
     ScriptError(hlua::LuaError),
+    /// The script engine couldn't be constructed, as it's prelude or sandbox failed, or it's
+    /// init script was missing or empty.
+    ScriptEngineError(script::InitError),
+    /// The server refused the connection of a client with the error, such as when it is full.
+    NetError(net::NetworkError),
+    /// Loading the scripts in `EngineConfig::script_dir` failed.
+    LoadError(script::ScriptLoadError),
+    /// The `EngineConfig` has a tick rate of 0.
+    ZeroTickRate,
+    /// The `EngineConfig` of a server has scripts, but none of them is "init", so none of them
+    /// would ever run.
+    MissingInitScript,
+    /// The scripts depend on each other in a cycle, with the names of the scripts in it, starting
+    /// and ending with the same one.
+    ScriptDependencyCycle(Vec<String>),
+    /// The first script depends on the second, which doesn't exist.
+    MissingScriptDependency(String, String),
 }
 
 impl Display for InitError {
@@ -99,6 +1452,24 @@ impl Display for InitError {
             // InitError::ClientInitError(ref err) => write!(fmt, "ClientInitError: {}", err),
             InitError::IoError(ref err) => write!(fmt, "IoError: {}", err),
             InitError::ScriptError(ref err) => write!(fmt, "ScriptError: {:?}", err),
+            InitError::ScriptEngineError(ref err) => write!(fmt, "ScriptEngineError: {}", err),
+            InitError::NetError(ref err) => write!(fmt, "NetError: {}", err),
+            InitError::LoadError(ref err) => write!(fmt, "LoadError: {}", err),
+            InitError::ZeroTickRate => write!(fmt, "ZeroTickRate: {}", self.description()),
+            InitError::MissingInitScript => {
+                write!(fmt, "MissingInitScript: {}", self.description())
+            }
+            InitError::ScriptDependencyCycle(ref cycle) => {
+                write!(fmt,
+                       "ScriptDependencyCycle: The scripts depend on each other: {}",
+                       cycle.join(" -> "))
+            }
+            InitError::MissingScriptDependency(ref script, ref dependency) => {
+                write!(fmt,
+                       "MissingScriptDependency: {} depends on {}, which doesn't exist.",
+                       script,
+                       dependency)
+            }
         }
     }
 }
@@ -109,6 +1480,13 @@ impl Error for InitError {
             // InitError::ClientInitError(ref err) => err.description(),
             InitError::IoError(ref err) => err.description(),
             InitError::ScriptError(ref _err) => "an unknown lua error occoured",
+            InitError::ScriptEngineError(ref err) => err.description(),
+            InitError::NetError(ref err) => err.description(),
+            InitError::LoadError(ref err) => err.description(),
+            InitError::ZeroTickRate => "the tick rate is 0",
+            InitError::MissingInitScript => "there are scripts, but none of them is init",
+            InitError::ScriptDependencyCycle(_) => "scripts depend on each other in a cycle",
+            InitError::MissingScriptDependency(..) => "a script depends on one that doesn't exist",
         }
     }
 
@@ -117,6 +1495,13 @@ impl Error for InitError {
             // InitError::ClientInitError(ref err) => Some(err),
             InitError::IoError(ref err) => Some(err),
             InitError::ScriptError(ref _err) => None,
+            InitError::ScriptEngineError(ref err) => Some(err),
+            InitError::NetError(ref err) => Some(err),
+            InitError::LoadError(ref err) => Some(err),
+            InitError::ZeroTickRate |
+            InitError::MissingInitScript |
+            InitError::ScriptDependencyCycle(_) |
+            InitError::MissingScriptDependency(..) => None,
         }
     }
 }
@@ -125,12 +1510,24 @@ impl Error for InitError {
 // InitError::ClientInitError(err)
 // }
 // }
-//
-// impl From<io::Error> for InitError {
-// fn from(err: io::Error) -> Self {
-// InitError::IoError(err)
-// }
-// }
+
+impl From<io::Error> for InitError {
+    fn from(err: io::Error) -> Self {
+        InitError::IoError(err)
+    }
+}
+
+impl From<net::NetworkError> for InitError {
+    fn from(err: net::NetworkError) -> Self {
+        InitError::NetError(err)
+    }
+}
+
+impl From<script::ScriptLoadError> for InitError {
+    fn from(err: script::ScriptLoadError) -> Self {
+        InitError::LoadError(err)
+    }
+}
 
 impl From<hlua::LuaError> for InitError {
     fn from(err: hlua::LuaError) -> Self {
@@ -153,7 +1550,26 @@ pub fn print_hello_world() {
     println!("Hello World!");
 }
 
-#[allow(unused)]
-fn check_should_crash() -> bool {
+/// Sets if the game is allowed to crash in the event of a semi-handleable error.
+///
+/// Call it before creating an `Engine` or `net::Controller`. Dedicated servers generally want
+/// false, so a misbehaving client can't take them down, while development clients want true.
+///
+/// The value is sent to peers in `NetworkPacket::Init`. A peer that should not crash forwards
+/// errors it hits to the other side as a `NetworkPacket::Error`, and the side that should crash
+/// crashes instead. Two peers that both should not crash could forward an error back and forth
+/// forever, so they refuse to connect with `NetworkError::ShouldCrashBothTrue`.
+///
+/// Controllers read it at the start of each handshake, and engines whenever they need it, unless
+/// `net::ControllerConfig::should_crash` or `Engine::should_crash` is set. It is also read when a
+/// connection receives something that isn't a packet, or a listener errors. The default is true.
+pub fn set_should_crash(should_crash: bool) {
+    SHOULD_CRASH.store(should_crash, Ordering::Relaxed);
+}
+
+/// If the game is allowed to crash in the event of a semi-handleable error.
+///
+/// See `set_should_crash`.
+pub fn should_crash() -> bool {
     SHOULD_CRASH.load(Ordering::Relaxed)
 }
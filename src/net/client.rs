@@ -0,0 +1,191 @@
+//! Contains a client connected to a single server, built on a `Controller`.
+
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use InitError;
+use super::{ConnectionId, ConnectionState, Controller, ControllerConfig, NetworkPacket, SendError};
+
+/// How long to wait for the packets the server sent before refusing a connection to be forwarded,
+/// in milliseconds.
+const REFUSAL_WAIT_MILLIS: u64 = 50;
+
+/// A connection to a server, along with the controller it belongs to.
+///
+/// Packets from the server are handed to every Receiver made with `recv`. The controller's own
+/// queue of incoming packets is taken over for that, so `Controller::try_recv_packet` never
+/// returns anything on it.
+#[derive(Debug)]
+pub struct Client {
+    /// The controller the connection to the server belongs to.
+    pub controller: Controller,
+    /// The id of the connection to the server.
+    pub id: ConnectionId,
+    /// Shared with the thread forwarding packets from the controller.
+    inbox: Arc<Mutex<Inbox>>,
+}
+
+/// Where the packets from the server go.
+#[derive(Debug)]
+struct Inbox {
+    receivers: Vec<Sender<NetworkPacket>>,
+    /// Packets that arrived while nothing was receiving them, handed to the next Receiver made.
+    backlog: Vec<NetworkPacket>,
+}
+
+impl Client {
+    /// Connects to the server with the default config, blocking untill the handshake is done.
+    ///
+    /// # Errors
+    /// The same as `spawn_with_config`.
+    pub fn spawn(server_addr: SocketAddr) -> Result<Client, InitError> {
+        Client::spawn_with_config(server_addr, ControllerConfig::default())
+    }
+
+    /// Connects to the server with a new controller using the config, blocking untill the
+    /// handshake is done.
+    ///
+    /// # Errors
+    /// * `InitError::NetError` if the server sent an error before closing the connection, such as
+    ///   `NetworkError::ServerFull`.
+    /// * `InitError::IoError` if connecting failed, or the server closed the connection without an
+    ///   error or did not finish the handshake within `ControllerConfig::handshake_timeout_millis`.
+    ///
+    /// Whatever the server sent before closing the connection is still handed to `recv`.
+    pub fn spawn_with_config(server_addr: SocketAddr,
+                             config: ControllerConfig)
+                             -> Result<Client, InitError> {
+        let timeout = Duration::from_millis(config.handshake_timeout_millis);
+        let mut controller = Controller::new_with_config(config);
+        let incoming = {
+            let (_tx, rx) = channel();
+            mem::replace(&mut *controller.raw.incoming_rx.lock().unwrap(), rx)
+        };
+        let inbox = Arc::new(Mutex::new(Inbox {
+            receivers: Vec::new(),
+            backlog: Vec::new(),
+        }));
+        let thread_inbox = inbox.clone();
+        thread::spawn(move || forward_packets(incoming, thread_inbox));
+        let id = try!(controller.connect(server_addr));
+        let client = Client {
+            controller: controller,
+            id: id,
+            inbox: inbox,
+        };
+        try!(client.wait_for_handshake(timeout));
+        Ok(client)
+    }
+
+    /// Blocks untill the connection is ready, or fails once it closes or the timeout passes.
+    fn wait_for_handshake(&self, timeout: Duration) -> Result<(), InitError> {
+        let started = Instant::now();
+        while started.elapsed() < timeout {
+            let state = self.controller
+                            .raw
+                            .connections
+                            .read()
+                            .unwrap()
+                            .get(&self.id)
+                            .map(|connection| *connection.state.lock().unwrap());
+            match state {
+                Some(ConnectionState::Ready) => return Ok(()),
+                Some(ConnectionState::Handshaking) => {}
+                Some(ConnectionState::Closing) | None => return Err(self.refusal()),
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let err = io::Error::new(io::ErrorKind::TimedOut,
+                                 "the server did not finish the handshake in time");
+        Err(InitError::IoError(err))
+    }
+
+    /// The error the server closed the connection during the handshake with.
+    ///
+    /// The packets the server sent before closing it are forwarded by another thread, so it is
+    /// given up to REFUSAL_WAIT_MILLIS to forward an Error.
+    fn refusal(&self) -> InitError {
+        let started = Instant::now();
+        loop {
+            {
+                let inbox = self.inbox.lock().unwrap();
+                for packet in &inbox.backlog {
+                    if let NetworkPacket::Error(ref err) = *packet {
+                        return InitError::NetError(err.clone());
+                    }
+                }
+            }
+            if started.elapsed() >= Duration::from_millis(REFUSAL_WAIT_MILLIS) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let err = io::Error::new(io::ErrorKind::ConnectionAborted,
+                                 "the server closed the connection during the handshake");
+        InitError::IoError(err)
+    }
+
+    /// Queues a packet to be sent to the server.
+    ///
+    /// # Errors
+    /// The same as `Controller::send_to`.
+    pub fn send(&self, packet: NetworkPacket) -> Result<(), SendError> {
+        self.controller.send_to(self.id, packet)
+    }
+
+    /// Returns a Receiver for every packet the server sends from now on.
+    ///
+    /// Any number of Receivers may be made, in which case each gets every packet. Packets that
+    /// arrived while there was no Receiver, such as the server's Init, go to the first one made
+    /// after them.
+    pub fn recv(&self) -> Receiver<NetworkPacket> {
+        let (tx, rx) = channel();
+        let mut inbox = self.inbox.lock().unwrap();
+        for packet in inbox.backlog.drain(..) {
+            // The Receiver is still held right here.
+            tx.send(packet).unwrap();
+        }
+        inbox.receivers.push(tx);
+        rx
+    }
+
+    /// The smoothed round trip time to the server, or None if the connection has closed or none
+    /// of it's Pings have been answered yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.controller.rtt(self.id)
+    }
+
+    /// Disconnects from the server, see `Controller::shutdown`.
+    ///
+    /// Receivers made with `recv` still get anything that was already on it's way, and disconnect
+    /// once the client has been dropped as well.
+    pub fn shutdown(&mut self) {
+        self.controller.shutdown();
+    }
+}
+
+/// Hands every packet from incoming to the receivers in the inbox, untill every connection that
+/// could send to it has closed.
+///
+/// Receivers that have been dropped are removed. Packets nothing received are kept in the backlog.
+fn forward_packets(incoming: Receiver<(ConnectionId, NetworkPacket)>, inbox: Arc<Mutex<Inbox>>) {
+    for (_id, packet) in incoming.iter() {
+        let mut inbox = inbox.lock().unwrap();
+        let mut delivered = false;
+        inbox.receivers.retain(|tx| {
+            let sent = tx.send(packet.clone()).is_ok();
+            delivered |= sent;
+            sent
+        });
+        if !delivered {
+            inbox.backlog.push(packet);
+        }
+    }
+    debug!("Every connection of the client closed, shutting down net::client::forward_packets.");
+    inbox.lock().unwrap().receivers.clear();
+}
@@ -1,334 +1,4456 @@
 //! Contains code relating to networking.
 
+pub mod client;
+mod poll;
 #[cfg(test)]
 mod test;
+pub mod transport;
 
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt;
-use std::fmt::{Display, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 use std::io;
 use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream,
+               ToSocketAddrs};
 use std::thread;
+use std::thread::JoinHandle;
 use std::sync::{Arc, Mutex, RwLock, Weak};
-use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TryRecvError, TrySendError, channel,
+                      sync_channel};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use bincode::serde::{DeserializeError, deserialize, serialize};
+use bincode::serde::{DeserializeError, deserialize_from, serialize};
 use bincode::SizeLimit;
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use flate2;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use mio;
+use sha1::Sha1;
+
+use script::LuaValueRepr;
+use self::poll::IoLoop;
+use self::transport::{Transport, loopback_pair};
 
 #[cfg(test)]
 use test_util::Tattle;
 
-/// Standard number to ensure network connections are syncronized and the same protocol is being used.
+/// Standard number to ensure network connections are syncronized and the same protocol is being used.
+///
+/// It never changes, so the Init of a peer speaking an older or newer protocol still arrives, and
+/// the peer is refused cleanly by it's PROTOCOL_VERSION.
+///
+/// Reexported incase it is of use for something not-networking.
+pub const NET_MAGIC_NUMBER: u32 = 0xCB011043; //0xcafebade + 0x25565, because programming references.
+
+/// The version of the wire protocol, sent in `NetworkPacket::Init`.
+///
+/// Peers only connect if their protocol versions are equal, whatever versions of the game they run.
+/// Bump it whenever a change to the packets or their framing would break an older peer.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// The length of the header before every packet: NET_MAGIC_NUMBER, the length of the body, and the
+/// CRC32 of the body.
+pub const HEADER_LEN: usize = 12;
+
+/// Set in the length of the body in a header if the body is compressed with zlib.
+///
+/// Lengths can never reach it, since `ControllerConfig::max_packet_size` is kept below it.
+pub const COMPRESSED_FLAG: u32 = 1 << 31;
+
+/// The default size in bytes above which the body of a packet is compressed, if the peer supports
+/// it.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// How many packets with a bad checksum a connection may receive before it is closed.
+pub const MAX_CORRUPT_PACKETS: usize = 3;
+
+/// The default maximum size of the body of a single packet, in bytes.
+///
+/// Use `ControllerConfig::max_packet_size` to change the limit for a Controller.
+pub const MAX_PACKET_SIZE: u32 = 16 * 1024 * 1024;
+
+/// The default time a peer has to send a valid `NetworkPacket::Init` before the connection is
+/// dropped.
+///
+/// Use `ControllerConfig::handshake_timeout_millis` to change the limit for a Controller.
+pub const HANDSHAKE_TIMEOUT_MILLIS: u64 = 10000;
+
+/// The reason given to peers in `NetworkPacket::Disconnect` by `Controller::shutdown`.
+pub const SHUTDOWN_REASON: &'static str = "The peer is shutting down.";
+
+/// How long a listener's thread sleeps when there is no socket to accept, before checking again.
+const LISTENER_POLL_MILLIS: u64 = 50;
+
+/// How long a listener's thread waits after an error before accepting again, if it does not crash.
+const LISTENER_RETRY_MILLIS: u64 = 1000;
+
+/// How long a peer has to close it's side of the connection after being sent the reason it is
+/// being closed.
+const CLOSE_DRAIN_MILLIS: u64 = 1000;
+
+/// How long an accepted socket is waited on for it's first packet before it is registered.
+///
+/// Lets a `NetworkPacket::InfoRequest` be answered without the socket ever becoming a connection.
+/// Peers that send nothing in time are registered anyway, and left to their handshake timeout.
+const PROBE_MILLIS: u64 = 100;
+
+/// The default maximum number of clients allowed to be connected at one time.
+///
+/// Use `ControllerConfig::max_clients` to change the limit for a Controller.
+pub const MAX_CONNECTED_CLIENTS: usize = 30;
+
+/// The default maximum number of accepted connections from a single ip address at one time.
+///
+/// Use `ControllerConfig::max_connections_per_address` to change the limit for a Controller.
+pub const MAX_CONNECTIONS_PER_ADDRESS: usize = 3;
+
+/// The default time without sending anything after which a connection sends a
+/// `NetworkPacket::Ping`.
+pub const KEEPALIVE_MILLIS: u64 = 5000;
+
+/// The default time without receiving anything after which a connection is dropped.
+pub const IDLE_TIMEOUT_MILLIS: u64 = 30000;
+
+/// The default number of packets per second a connection may receive on average.
+pub const RATE_LIMIT_PACKETS_PER_SECOND: u32 = 200;
+
+/// The default number of packets a connection may receive at once above it's rate limit.
+pub const RATE_LIMIT_BURST: u32 = 400;
+
+/// How many pings the round trip time of a connection is smoothed over.
+///
+/// Every Pong moves the estimate 1/RTT_SMOOTHING_PINGS of the way towards the round trip it
+/// measured.
+pub const RTT_SMOOTHING_PINGS: u32 = 8;
+
+/// The default number of bytes a connection's send thread buffers before writing them to the socket.
+pub const FLUSH_SIZE: usize = 64 * 1024;
+
+/// The default size in bytes above which the body of a packet is sent in fragments.
+pub const FRAGMENT_THRESHOLD: usize = 64 * 1024;
+
+/// The default number of fragmented packets a connection may be receiving at once.
+pub const MAX_FRAGMENTED_TRANSFERS: usize = 4;
+
+/// The default number of bytes a connection may have announced for the fragmented packets it is
+/// receiving at once.
+pub const MAX_FRAGMENT_BYTES: usize = 64 * 1024 * 1024;
+
+/// The number of `Priority` lanes a connection sends packets on.
+pub const LANES: usize = 3;
+
+/// How many times in a row a lane with something queued may be passed over for a higher one,
+/// before it is sent from regardless.
+pub const MAX_LANE_DEFICIT: usize = 8;
+
+/// The default number of messages that may be queued for a connection's send thread.
+pub const SEND_QUEUE_DEPTH: usize = 1024;
+
+/// The default number of milliseconds a connection's queue may stay full before it is lagging.
+pub const LAG_THRESHOLD_MILLIS: u64 = 5000;
+
+/// The kick reason of connections dropped for lagging, see `ControllerConfig::drop_lagging`.
+pub const LAGGING_REASON: &'static str = "The connection fell too far behind.";
+
+/// How many times a connection may fail to authenticate for the remote console, with a wrong
+/// `NetworkPacket::RconAuth` or a `NetworkPacket::RconCommand` before authenticating, before it is
+/// kicked with RCON_FAILURES_REASON.
+pub const MAX_RCON_FAILURES: usize = 3;
+
+/// The kick reason of connections that failed to authenticate for the remote console too many
+/// times, see MAX_RCON_FAILURES.
+pub const RCON_FAILURES_REASON: &'static str = "Too many failed remote console attempts.";
+
+/// Settings for a Controller.
+///
+/// `ControllerConfig::default()` gives the settings used by `Controller::new_empty`.
+#[derive(Clone, Debug)]
+pub struct ControllerConfig {
+    /// The maximum number of connections, checked before a new socket is registered.
+    ///
+    /// Defaults to MAX_CONNECTED_CLIENTS.
+    pub max_clients: usize,
+    /// The maximum number of connections sharing the ip address of a newly accepted socket,
+    /// checked before it is registered.
+    ///
+    /// IPv4 addresses mapped to IPv6 count as the IPv4 address. Sockets opened with
+    /// `Controller::connect` are not checked. Defaults to MAX_CONNECTIONS_PER_ADDRESS.
+    pub max_connections_per_address: usize,
+    /// How long a connection may go without sending anything before it sends a Ping.
+    ///
+    /// Should be well below the peer's idle_timeout_millis. Defaults to KEEPALIVE_MILLIS.
+    pub keepalive_millis: u64,
+    /// How long a connection may go without receiving anything after it's handshake before it is
+    /// dropped.
+    ///
+    /// Defaults to IDLE_TIMEOUT_MILLIS.
+    pub idle_timeout_millis: u64,
+    /// How long a new connection has to receive a valid Init, counted from when it's socket was
+    /// accepted or connected.
+    ///
+    /// Anything else the peer sends in the meantime does not extend it. Defaults to
+    /// HANDSHAKE_TIMEOUT_MILLIS.
+    pub handshake_timeout_millis: u64,
+    /// The largest body a packet sent or received by the controller may have, in bytes.
+    ///
+    /// Packets above it are not sent, and connections announcing a packet above it are dropped
+    /// before anything is allocated for it. Packets sent in fragments are only limited by
+    /// max_fragment_bytes, since each fragment is a packet of it's own. Defaults to
+    /// MAX_PACKET_SIZE.
+    ///
+    /// Must be below COMPRESSED_FLAG, which a header sets in the length of a compressed body, so
+    /// `Controller::new_with_config` lowers it to just below the flag if it isn't.
+    pub max_packet_size: u32,
+    /// How fast a connection may send packets to the controller, or None if there is no limit.
+    ///
+    /// Defaults to RATE_LIMIT_PACKETS_PER_SECOND and RATE_LIMIT_BURST, throttling peers that go
+    /// over it.
+    pub rate_limit: Option<RateLimit>,
+    /// How many bytes of queued packets a send thread joins into a single write.
+    ///
+    /// Everything already queued when a send thread wakes up is written at once, up to this many
+    /// bytes. A single packet above it is still written on it's own. Defaults to FLUSH_SIZE.
+    pub flush_size: usize,
+    /// The compression offered to peers in the handshake.
+    ///
+    /// Bodies are only compressed if both peers offer the same compression, but compressed bodies
+    /// are always accepted. Defaults to `Compression::Zlib`.
+    pub compression: Compression,
+    /// The size in bytes above which the body of a packet is compressed.
+    ///
+    /// Bodies at or below it, or that compression would not shrink, are sent as they are. Defaults
+    /// to COMPRESSION_THRESHOLD.
+    pub compression_threshold: usize,
+    /// The should_crash sent to peers in the handshake, see `should_crash`.
+    ///
+    /// Peers refuse to connect if neither should crash, with `NetworkError::ShouldCrashBothTrue`.
+    /// None by default, so the global value of `::should_crash()` is read each time a handshake
+    /// starts, rather than once when the config was made.
+    pub should_crash: Option<bool>,
+    /// The password peers must know to finish their handshake on an accepted socket, or None if
+    /// anyone may connect.
+    ///
+    /// Peers are sent a `NetworkPacket::Challenge`, and must answer with an Init proving they know
+    /// the password without sending it. Wrong or missing passwords are sent
+    /// `NetworkError::BadCredentials`. Loopback pairs prove it to themselves. Defaults to None.
+    pub password: Option<String>,
+    /// The name sent to peers in the handshake, which a server gives to the player of the
+    /// connection, see `::player::PlayerRegistry::add`. Defaults to an empty string, so the
+    /// server makes one up.
+    pub display_name: String,
+    /// The size in bytes above which the body of a packet is sent as fragments of at most this
+    /// many bytes, see `NetworkPacket::FragmentStart`.
+    ///
+    /// The peer reassembles the body before handling the packet. Packets on higher lanes are sent
+    /// in between the fragments, so a large packet only holds up the ones behind it on it's own
+    /// lane, see `Priority`. Should be well below the peer's max_packet_size. Defaults to
+    /// FRAGMENT_THRESHOLD.
+    pub fragment_threshold: usize,
+    /// How many fragmented packets a connection may be receiving at once.
+    ///
+    /// Peers starting another are sent `NetworkError::FragmentLimit`. Defaults to
+    /// MAX_FRAGMENTED_TRANSFERS.
+    pub max_fragmented_transfers: usize,
+    /// How many bytes a connection may have announced for the fragmented packets it is receiving
+    /// at once.
+    ///
+    /// Counted from the total_len of every FragmentStart, so nothing is buffered for a packet that
+    /// would go over it. Peers going over it are sent `NetworkError::FragmentLimit`. Defaults to
+    /// MAX_FRAGMENT_BYTES.
+    pub max_fragment_bytes: usize,
+    /// How many messages may be queued for a connection's send thread before `Controller::send_to`
+    /// fails with `SendError::QueueFull`.
+    ///
+    /// The send thread takes up to this many more onto it's lanes while it writes, so at most
+    /// twice this many packets are held for a connection. Must be above 0. Defaults to
+    /// SEND_QUEUE_DEPTH.
+    pub send_queue_depth: usize,
+    /// How long a connection's queue may stay full before it is flagged as lagging in it's
+    /// `ConnectionStats`.
+    ///
+    /// Only noticed when queueing a packet for the connection fails. Defaults to
+    /// LAG_THRESHOLD_MILLIS.
+    pub lag_threshold_millis: u64,
+    /// If connections are dropped once they are flagged as lagging.
+    ///
+    /// Their stream is shut down without sending anything else, and the handler is told with
+    /// `DisconnectReason::Kicked` holding LAGGING_REASON. Defaults to false.
+    pub drop_lagging: bool,
+    /// How the streams of new connections are driven.
+    ///
+    /// Connections already registered keep the model they were added with. Defaults to
+    /// `IoModel::Threaded`.
+    pub io_model: IoModel,
+    /// The password connections must send in a `NetworkPacket::RconAuth` before their
+    /// `NetworkPacket::RconCommand`s are run, or None if the remote console is off.
+    ///
+    /// Unlike `password`, it is sent as it is, so only use it over a network you trust. Defaults
+    /// to None.
+    pub rcon_password: Option<String>,
+}
+
+/// How a controller drives the streams of it's connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoModel {
+    /// Every connection gets a send, recv and keepalive thread of it's own.
+    Threaded,
+    /// Every TCP connection is driven by a single IO thread shared by the whole controller, which
+    /// polls non-blocking sockets with mio.
+    ///
+    /// Each connection keeps read and write buffers of it's own, and messages queued for it wake
+    /// the IO thread. Loopback pairs still get their own threads.
+    Polled,
+}
+
+/// A way of compressing the bodies of packets, offered by a peer in it's `NetworkPacket::Init`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    /// Compressed with zlib, at the default level.
+    Zlib,
+}
+
+/// A limit on the packets received on a single connection, enforced as a token bucket.
+///
+/// A connection starts with burst tokens, and gains packets_per_second tokens every second up to
+/// burst. Every packet received takes a token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub packets_per_second: u32,
+    /// The most tokens a connection can have saved up.
+    pub burst: u32,
+    pub mode: RateLimitMode,
+}
+
+/// What happens to a connection that receives a packet without a token left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitMode {
+    /// Stop reading from the socket untill a token is gained, so the peer's writes back up.
+    Throttle,
+    /// Throttle the connection, but close it with `NetworkError::RateLimited` if it is still out
+    /// of tokens after the given number of milliseconds.
+    Kill { grace_millis: u64 },
+}
+
+impl Default for ControllerConfig {
+    fn default() -> ControllerConfig {
+        ControllerConfig {
+            max_clients: MAX_CONNECTED_CLIENTS,
+            max_connections_per_address: MAX_CONNECTIONS_PER_ADDRESS,
+            keepalive_millis: KEEPALIVE_MILLIS,
+            idle_timeout_millis: IDLE_TIMEOUT_MILLIS,
+            handshake_timeout_millis: HANDSHAKE_TIMEOUT_MILLIS,
+            max_packet_size: MAX_PACKET_SIZE,
+            rate_limit: Some(RateLimit {
+                packets_per_second: RATE_LIMIT_PACKETS_PER_SECOND,
+                burst: RATE_LIMIT_BURST,
+                mode: RateLimitMode::Throttle,
+            }),
+            flush_size: FLUSH_SIZE,
+            compression: Compression::Zlib,
+            compression_threshold: COMPRESSION_THRESHOLD,
+            should_crash: None,
+            password: None,
+            display_name: String::new(),
+            fragment_threshold: FRAGMENT_THRESHOLD,
+            max_fragmented_transfers: MAX_FRAGMENTED_TRANSFERS,
+            max_fragment_bytes: MAX_FRAGMENT_BYTES,
+            send_queue_depth: SEND_QUEUE_DEPTH,
+            lag_threshold_millis: LAG_THRESHOLD_MILLIS,
+            drop_lagging: false,
+            io_model: IoModel::Threaded,
+            rcon_password: None,
+        }
+    }
+}
+
+impl ControllerConfig {
+    /// The should_crash sent to peers in the handshake, taking the override in the config over
+    /// the global value.
+    pub fn should_crash(&self) -> bool {
+        self.should_crash.unwrap_or_else(::should_crash)
+    }
+}
+
+/// What a server tells anyone asking with `NetworkPacket::InfoRequest`, such as a server browser.
+///
+/// Set with `Controller::set_server_info`, and fetched with `query_server`. Nothing in it is
+/// checked or kept up to date by the controller.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub name: String,
+    /// How many players are on the server.
+    pub players: u32,
+    /// How many players the server lets on at once.
+    pub max_players: u32,
+    /// The version of the game the server runs, formatted like the version in an Init.
+    pub version: String,
+    /// The message of the day.
+    pub motd: String,
+}
+
+impl ServerInfo {
+    /// The InfoResponse answering an InfoRequest with this info.
+    fn to_packet(&self) -> NetworkPacket {
+        NetworkPacket::InfoResponse {
+            name: self.name.clone(),
+            players: self.players,
+            max_players: self.max_players,
+            version: self.version.clone(),
+            motd: self.motd.clone(),
+        }
+    }
+}
+
+impl Default for ServerInfo {
+    /// A server without a name or motd, running the local version, with no players out of
+    /// MAX_CONNECTED_CLIENTS.
+    fn default() -> ServerInfo {
+        ServerInfo {
+            name: String::new(),
+            players: 0,
+            max_players: MAX_CONNECTED_CLIENTS as u32,
+            version: ::VERSION.to_owned(),
+            motd: String::new(),
+        }
+    }
+}
+
+/// Holds all state for networking.
+///
+/// Has no notion of client or server. A client can listen, if that would ever be useful.
+#[derive(Debug, Clone)]
+pub struct Controller {
+    pub raw: Arc<ControllerRaw>,
+}
+
+impl Controller {
+    /// Contructs a new Controller with the default config, without any connection or listeners.
+    ///
+    /// This spawns a new thread to check multithreading channels.
+    pub fn new_empty() -> Controller {
+        Controller::new_with_config(ControllerConfig::default())
+    }
+
+    /// Contructs a new Controller with the given config, without any connection or listeners.
+    ///
+    /// This spawns a new thread to check multithreading channels. A max_packet_size reaching
+    /// COMPRESSED_FLAG is lowered to just below it.
+    pub fn new_with_config(mut config: ControllerConfig) -> Controller {
+        if config.max_packet_size >= COMPRESSED_FLAG {
+            warn!("The max packet size {} would reach the compressed flag of headers, using {}.",
+                  config.max_packet_size,
+                  COMPRESSED_FLAG - 1);
+            config.max_packet_size = COMPRESSED_FLAG - 1;
+        }
+        let (tx, rx) = channel::<ControllerMessage>();
+        let self_raw = Arc::from(ControllerRaw::new(tx, config));
+        let self_raw_clone = Arc::downgrade(&self_raw);
+        thread::spawn(move || {
+            check_controller_channel(rx, self_raw_clone);
+        });
+        Controller { raw: self_raw }
+    }
+
+    /// Adds a new listener and spins up a new thread to check it.
+    ///
+    /// The listener is switched to non-blocking mode, so it's thread can notice when it is removed.
+    /// Returns the id to remove it with.
+    ///
+    /// # Errors
+    /// * A call to `listener.set_nonblocking()` or `listener.local_addr()` failed for some reason.
+    pub fn add_listener(&mut self, listener: TcpListener) -> Result<ListenerId, io::Error> {
+        try!(listener.set_nonblocking(true));
+        let addr = try!(listener.local_addr());
+        let id = ListenerId(self.raw.next_listener_id.fetch_add(1, Ordering::SeqCst) as u64);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown_clone = shutdown.clone();
+        let tx_clone = self.raw.tx.lock().unwrap().clone();
+        let thread = thread::spawn(move || {
+            check_listener(id, listener, tx_clone, shutdown_clone);
+        });
+        let listener = Listener {
+            id: id,
+            addr: addr,
+            shutdown: shutdown,
+            thread: thread,
+        };
+        self.raw.listeners.lock().unwrap().insert(id, listener);
+        Ok(id)
+    }
+
+    /// Listens on the port for both IPv4 and IPv6 connections, returning the ids of the listeners
+    /// added.
+    ///
+    /// Binds `[::]:port` and then `0.0.0.0:port`. On platforms where the IPv6 socket already
+    /// accepts IPv4 connections the second bind fails as the address is in use, and only the IPv6
+    /// listener is added. If IPv6 is not available, only the IPv4 listener is added. Giving port 0
+    /// binds both to the same free port.
+    ///
+    /// # Errors
+    /// * Binding the IPv4 address failed, other than for being in use by the IPv6 listener.
+    /// * Binding the IPv6 address failed when the IPv4 one was in use anyway.
+    /// * `add_listener` failed for either listener.
+    pub fn listen_dual_stack(&mut self, port: u16) -> Result<Vec<ListenerId>, io::Error> {
+        let v6_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), port);
+        let v6 = TcpListener::bind(v6_addr);
+        let v4_port = match v6 {
+            Ok(ref listener) => try!(listener.local_addr()).port(),
+            Err(_) => port,
+        };
+        let v4_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), v4_port);
+        let v4 = match (TcpListener::bind(v4_addr), &v6) {
+            (Ok(listener), _) => Some(listener),
+            (Err(ref err), &Ok(_)) if err.kind() == io::ErrorKind::AddrInUse => None,
+            (Err(err), _) => return Err(err),
+        };
+        let mut ids = Vec::new();
+        if let Ok(listener) = v6 {
+            ids.push(try!(self.add_listener(listener)));
+        }
+        if let Some(listener) = v4 {
+            ids.push(try!(self.add_listener(listener)));
+        }
+        Ok(ids)
+    }
+
+    /// Takes the errors that stopped listeners since the last call, along with the listener's id.
+    ///
+    /// Listeners only stop on their own if `::should_crash()` is true. Otherwise they keep
+    /// retrying after errors.
+    pub fn take_listener_errors(&self) -> Vec<(ListenerId, io::Error)> {
+        let mut errors = self.raw.listener_errors.lock().unwrap();
+        let taken = errors.drain(..).collect();
+        taken
+    }
+
+    /// How many connections were dropped for announcing a packet above
+    /// `ControllerConfig::max_packet_size`.
+    pub fn oversized_packets(&self) -> usize {
+        self.raw.oversized_packets.load(Ordering::SeqCst)
+    }
+
+    /// A snapshot of the traffic on a connection, or None if no connection has the given id.
+    ///
+    /// Reading the stats never blocks the connection's threads.
+    pub fn stats(&self, id: ConnectionId) -> Option<ConnectionStats> {
+        self.raw.connections.read().unwrap().get(&id).map(Connection::stats)
+    }
+
+    /// A snapshot of the traffic on every connection.
+    pub fn stats_all(&self) -> Vec<(ConnectionId, ConnectionStats)> {
+        self.raw
+            .connections
+            .read()
+            .unwrap()
+            .values()
+            .map(|connection| (connection.id, connection.stats()))
+            .collect()
+    }
+
+    /// The number of connections registered with the controller, including ones still
+    /// handshaking.
+    pub fn connection_count(&self) -> usize {
+        self.raw.connections.read().unwrap().len()
+    }
+
+    /// The live counters of a connection, or None if no connection has the given id.
+    ///
+    /// Unlike `stats`, reading the counters from then on never locks the controller, so they can
+    /// be read often, such as every tick. `ConnectionCounters::removed` is set once the connection
+    /// is removed.
+    pub fn counters(&self, id: ConnectionId) -> Option<Arc<ConnectionCounters>> {
+        self.raw.connections.read().unwrap().get(&id).map(|connection| connection.stats.clone())
+    }
+
+    /// The smoothed round trip time of a connection, or None if no connection has the given id or
+    /// none of it's Pings have been answered yet.
+    pub fn rtt(&self, id: ConnectionId) -> Option<Duration> {
+        self.raw.connections.read().unwrap().get(&id).and_then(|connection| connection.stats.rtt())
+    }
+
+    /// The address of the peer on the other end of a connection, or None if no connection has the
+    /// given id.
+    pub fn peer_addr(&self, id: ConnectionId) -> Option<SocketAddr> {
+        self.raw.connections.read().unwrap().get(&id).map(|connection| connection.peer_addr)
+    }
+
+    /// The id and local address of every listener, such as to find the port given to a listener
+    /// bound to port 0.
+    pub fn listener_addrs(&self) -> Vec<(ListenerId, SocketAddr)> {
+        self.raw
+            .listeners
+            .lock()
+            .unwrap()
+            .values()
+            .map(|listener| (listener.id, listener.addr))
+            .collect()
+    }
+
+    /// Stops a listener that was added with `add_listener`, closing it's socket.
+    ///
+    /// Blocks untill the listener's thread exits, so the address can be bound again once this
+    /// returns. Returns false if no listener has the given id.
+    pub fn remove_listener(&mut self, id: ListenerId) -> bool {
+        let listener = self.raw.listeners.lock().unwrap().remove(&id);
+        match listener {
+            Some(listener) => {
+                listener.stop();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops every listener and closes every connection.
+    ///
+    /// Listeners are stopped before this returns, like with `remove_listener`. Connections are
+    /// sent a `NetworkPacket::Disconnect` and removed from the controller immediately, but their
+    /// sockets are closed by their send threads shortly after.
+    pub fn shutdown(&mut self) {
+        let listeners: Vec<Listener> = {
+            let mut listeners = self.raw.listeners.lock().unwrap();
+            let drained = listeners.drain().map(|(_id, listener)| listener).collect();
+            drained
+        };
+        for listener in listeners {
+            listener.stop();
+        }
+        let mut connections = self.raw.connections.write().unwrap();
+        for (_id, connection) in connections.drain() {
+            connection.disconnect(SHUTDOWN_REASON);
+        }
+    }
+
+    /// Sends the connection a `NetworkPacket::Disconnect` with the given reason, then closes it.
+    ///
+    /// The connection is removed from the controller immediately, but it's socket is closed by it's
+    /// send thread shortly after, once the Disconnect has been flushed and the peer has had
+    /// CLOSE_DRAIN_MILLIS to read it. The handler is told with `DisconnectReason::Kicked` holding
+    /// the reason.
+    ///
+    /// The peer receives the reason as a `DisconnectReason::Disconnected`, and the packet is passed
+    /// on like any other, so a client can show it.
+    ///
+    /// # Errors
+    /// * `SendError::UnknownConnection` if no connection has the given id.
+    /// * `SendError::ConnectionClosed` if the connection is already closing.
+    pub fn kick(&self, id: ConnectionId, reason: &str) -> Result<(), SendError> {
+        let mut connections = self.raw.connections.write().unwrap();
+        match connections.get(&id).map(|connection| *connection.state.lock().unwrap()) {
+            Some(ConnectionState::Closing) => return Err(SendError::ConnectionClosed(id)),
+            Some(_) => {}
+            None => return Err(SendError::UnknownConnection(id)),
+        }
+        if let Some(connection) = connections.remove(&id) {
+            connection.disconnect(reason);
+        }
+        Ok(())
+    }
+
+    /// Opens a connection to the given address and registers it like any accepted socket.
+    ///
+    /// The `NetworkPacket::Init` handshake is sent before the connection is registered,
+    /// so it is always the first packet the peer receives.
+    ///
+    /// # Errors
+    /// * Connecting to the address failed.
+    /// * Writing the handshake to the new stream failed.
+    /// * The controller thread could not spin up the threads for the connection.
+    pub fn connect(&mut self, addr: SocketAddr) -> Result<ConnectionId, io::Error> {
+        self.connect_as(addr, None)
+    }
+
+    /// Opens a connection like `connect`, proving the password if the peer sends a
+    /// `NetworkPacket::Challenge`.
+    ///
+    /// The password itself is never sent. A wrong password gets `NetworkError::BadCredentials`
+    /// from the peer, which then closes the connection.
+    ///
+    /// # Errors
+    /// * Any error from `connect`.
+    pub fn connect_with_password(&mut self,
+                                 addr: SocketAddr,
+                                 password: &str)
+                                 -> Result<ConnectionId, io::Error> {
+        self.connect_as(addr, Some(password.to_owned()))
+    }
+
+    /// Opens a connection for `connect` or `connect_with_password`.
+    fn connect_as(&mut self,
+                  addr: SocketAddr,
+                  password: Option<String>)
+                  -> Result<ConnectionId, io::Error> {
+        let mut stream = try!(TcpStream::connect(addr));
+        let config = self.raw.config.read().unwrap().clone();
+        let init = local_init(config.compression,
+                              config.should_crash(),
+                              &config.display_name,
+                              None);
+        // An Init is always small enough, since it only holds the version.
+        let bytes = seralize_packet(&init, config.max_packet_size, None).unwrap();
+        try!(stream.write_all(&bytes));
+        try!(stream.flush());
+        let (tx_id, rx_id) = channel();
+        let message = ControllerMessage::AddSocket(stream, addr, Some(tx_id), password);
+        if let Err(_err) = self.raw.tx.lock().unwrap().send(message) {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "the controller thread is no longer running"));
+        }
+        let id = try!(rx_id.recv().map_err(|_err| {
+            io::Error::new(io::ErrorKind::Other,
+                           "the controller thread failed to register the connection")
+        }));
+        // The Init was written before the connection had any stats to count it in.
+        if let Some(connection) = self.raw.connections.read().unwrap().get(&id) {
+            connection.stats.record_sent(bytes.len(), Priority::Control);
+        }
+        Ok(id)
+    }
+
+    /// Resolves the address with `parse_addr_with`, then opens a connection to it like `connect`.
+    ///
+    /// localhost is the loopback address of the preferred family.
+    ///
+    /// # Errors
+    /// * `ErrorKind::InvalidInput` if the address could not be resolved to a single address.
+    /// * Any error from `connect`.
+    pub fn connect_to(&mut self,
+                      addr: &str,
+                      preference: AddrPreference)
+                      -> Result<ConnectionId, io::Error> {
+        let addr = match parse_addr_with(addr, LocalhostPolicy::Loopback, preference) {
+            Ok(addr) => addr,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidInput, err)),
+        };
+        self.connect(addr)
+    }
+
+    /// Registers two connections that are connected to each other in memory, without any sockets.
+    ///
+    /// Everything sent to one is received from the other, exactly as if they were connected over
+    /// TCP. They are not counted against `ControllerConfig::max_clients`, nor checked by the accept
+    /// hook. Blocks untill both have finished their handshake, so they are ready to be sent
+    /// anything.
+    ///
+    /// Meant for tests, which can exercise connections without real sockets.
+    ///
+    /// # Errors
+    /// * The controller thread is not running.
+    /// * The handshake failed or did not finish within
+    ///   `ControllerConfig::handshake_timeout_millis`, such as when the game should not crash, so
+    ///   both ends are sent `NetworkError::ShouldCrashBothTrue`.
+    pub fn add_loopback_pair(&self) -> Result<(ConnectionId, ConnectionId), io::Error> {
+        let (tx_ids, rx_ids) = channel();
+        let message = ControllerMessage::AddLoopbackPair(tx_ids);
+        if let Err(_err) = self.raw.tx.lock().unwrap().send(message) {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "the controller thread is no longer running"));
+        }
+        let (first, second) = try!(rx_ids.recv().map_err(|_err| {
+            io::Error::new(io::ErrorKind::Other,
+                           "the controller thread failed to register the loopback pair")
+        }));
+        let started = Instant::now();
+        let timeout_millis = self.raw.config.read().unwrap().handshake_timeout_millis;
+        let timeout = Duration::from_millis(timeout_millis);
+        while started.elapsed() < timeout {
+            let ready = {
+                let connections = self.raw.connections.read().unwrap();
+                let is_ready = |id| {
+                    connections.get(&id)
+                               .map(|connection| *connection.state.lock().unwrap())
+                };
+                (is_ready(first), is_ready(second))
+            };
+            match ready {
+                (Some(ConnectionState::Ready), Some(ConnectionState::Ready)) => {
+                    return Ok((first, second))
+                }
+                (Some(ConnectionState::Handshaking), _) |
+                (_, Some(ConnectionState::Handshaking)) => {}
+                _ => break,
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        Err(io::Error::new(io::ErrorKind::Other,
+                           "the loopback pair failed to finish it's handshake"))
+    }
+
+    /// Checks the password a connection sent in a `NetworkPacket::RconAuth` against
+    /// `ControllerConfig::rcon_password`, marking the connection as an admin if it matches.
+    ///
+    /// A wrong password, or any password while the remote console is off, is counted as a
+    /// failure, see `record_rcon_failure`. Returns false if it didn't match, or no connection has
+    /// the given id.
+    pub fn authenticate_rcon(&self, id: ConnectionId, password: &str) -> bool {
+        let matches = match self.raw.config.read().unwrap().rcon_password {
+            Some(ref rcon_password) => {
+                constant_time_eq(rcon_password.as_bytes(), password.as_bytes())
+            }
+            None => false,
+        };
+        if !matches {
+            self.record_rcon_failure(id);
+            return false;
+        }
+        match self.raw.connections.read().unwrap().get(&id) {
+            Some(connection) => {
+                connection.is_admin.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// If the connection authenticated for the remote console with `authenticate_rcon`. False if
+    /// no connection has the given id.
+    pub fn is_admin(&self, id: ConnectionId) -> bool {
+        self.raw
+            .connections
+            .read()
+            .unwrap()
+            .get(&id)
+            .map_or(false, |connection| connection.is_admin.load(Ordering::SeqCst))
+    }
+
+    /// Counts a failed attempt of the connection to use the remote console, kicking it with
+    /// RCON_FAILURES_REASON once it has failed MAX_RCON_FAILURES times. Returns how many times it
+    /// has failed, or 0 if no connection has the given id.
+    pub fn record_rcon_failure(&self, id: ConnectionId) -> usize {
+        let failures = match self.raw.connections.read().unwrap().get(&id) {
+            Some(connection) => connection.rcon_failures.fetch_add(1, Ordering::SeqCst) + 1,
+            None => return 0,
+        };
+        if failures >= MAX_RCON_FAILURES {
+            info!("Kicking connection {}, since it failed to use the remote console {} times.",
+                  id.0,
+                  failures);
+            if let Err(err) = self.kick(id, RCON_FAILURES_REASON) {
+                debug!("Failed to kick connection {}: {}", id.0, err);
+            }
+        }
+        failures
+    }
+
+    /// Changes the maximum number of connections.
+    ///
+    /// Lowering it below the current number of connections does not close any of them,
+    /// it only stops new ones from being accepted untill enough have disconnected.
+    pub fn set_max_clients(&self, max_clients: usize) {
+        self.raw.config.write().unwrap().max_clients = max_clients;
+    }
+
+    /// Sets a function deciding if a new socket from the given address should be registered.
+    ///
+    /// The hook is called from the controller thread, without any locks on the connections held,
+    /// so it may call back into the Controller. It must not call `set_accept_hook` however.
+    /// Returning false sends the peer `NetworkError::ConnectionDenied` and closes the socket.
+    pub fn set_accept_hook(&self, hook: Box<Fn(&SocketAddr) -> bool + Send>) {
+        *self.raw.accept_hook.lock().unwrap() = Some(hook);
+    }
+
+    /// Sets the info given to peers that send a `NetworkPacket::InfoRequest`.
+    ///
+    /// Peers asking are answered before they are registered, so they never count towards
+    /// `ControllerConfig::max_clients` or reach the handler. Banned peers and those refused by the
+    /// accept hook are not answered.
+    pub fn set_server_info(&self, info: ServerInfo) {
+        *self.raw.server_info.write().unwrap() = info;
+    }
+
+    /// The info given to peers that send a `NetworkPacket::InfoRequest`.
+    pub fn server_info(&self) -> ServerInfo {
+        self.raw.server_info.read().unwrap().clone()
+    }
+
+    /// Sets the handler notified as connections are added, removed and error.
+    ///
+    /// Replaces any handler set before. It is called from the controller thread, without any locks
+    /// on the connections held, so it may call back into the Controller. It must not call
+    /// `set_handler` however.
+    pub fn set_handler(&self, handler: Box<ControllerHandler>) {
+        *self.raw.handler.lock().unwrap() = Some(handler);
+    }
+
+    /// Refuses new sockets from the address, for the given duration or for as long as the
+    /// controller exists.
+    ///
+    /// Banned peers are sent `NetworkError::Banned` and closed before the accept hook is called.
+    /// Connections that are already registered are left alone, see `kick_and_ban`. Banning an
+    /// address again replaces it's previous ban.
+    pub fn ban(&self, addr: IpAddr, duration: Option<Duration>) {
+        let expires = duration.map(|duration| Instant::now() + duration);
+        self.raw.bans.lock().unwrap().insert(normalize_ip(addr), expires);
+    }
+
+    /// Lifts a ban on the address. Returns false if it was not banned.
+    pub fn unban(&self, addr: IpAddr) -> bool {
+        match self.raw.bans.lock().unwrap().remove(&normalize_ip(addr)) {
+            Some(expires) => !ban_expired(expires),
+            None => false,
+        }
+    }
+
+    /// Kicks a connection like `kick`, then bans it's address for as long as the controller exists.
+    ///
+    /// # Errors
+    /// * `SendError::UnknownConnection` if no connection has the given id.
+    pub fn kick_and_ban(&self, id: ConnectionId, reason: &str) -> Result<(), SendError> {
+        let addr = match self.peer_addr(id) {
+            Some(addr) => addr,
+            None => return Err(SendError::UnknownConnection(id)),
+        };
+        self.ban(addr.ip(), None);
+        self.kick(id, reason)
+    }
+
+    /// Queues a packet to be sent to a single connection, on the lane given by
+    /// `NetworkPacket::priority`.
+    ///
+    /// # Errors
+    /// * `SendError::UnknownConnection` if no connection has the given id.
+    /// * `SendError::ConnectionClosed` if the connection's send thread has shut down, or it is
+    ///   closing.
+    /// * `SendError::NotReady` if the packet is not a control packet and the connection is still
+    ///   handshaking.
+    /// * `SendError::QueueFull` if the connection already has `ControllerConfig::send_queue_depth`
+    ///   messages queued. See `send_to_blocking` to wait for room instead.
+    pub fn send_to(&self, id: ConnectionId, packet: NetworkPacket) -> Result<(), SendError> {
+        let priority = packet.priority();
+        self.send_to_with_priority(id, packet, priority)
+    }
+
+    /// Queues a packet to be sent to a single connection, on the given lane.
+    ///
+    /// Packets on higher lanes are sent before ones queued earlier on lower lanes, see
+    /// `Priority`.
+    ///
+    /// # Errors
+    /// The same as `send_to`.
+    pub fn send_to_with_priority(&self,
+                                 id: ConnectionId,
+                                 packet: NetworkPacket,
+                                 priority: Priority)
+                                 -> Result<(), SendError> {
+        let message = ConnectionMessage::SendWithPriority(packet, priority);
+        self.queue_message(id, message).map_err(|(err, _message)| err)
+    }
+
+    /// Queues a packet to be sent to a single connection like `send_to`, waiting up to timeout
+    /// for room if it's queue is full.
+    ///
+    /// # Errors
+    /// The same as `send_to`, with `SendError::QueueFull` only once the timeout has passed.
+    pub fn send_to_blocking(&self,
+                            id: ConnectionId,
+                            packet: NetworkPacket,
+                            timeout: Duration)
+                            -> Result<(), SendError> {
+        let started = Instant::now();
+        let mut message = ConnectionMessage::SendPacket(packet);
+        loop {
+            // The connections are not kept locked while waiting, so they can still change.
+            message = match self.queue_message(id, message) {
+                Err((SendError::QueueFull(id), returned)) => {
+                    if started.elapsed() >= timeout {
+                        return Err(SendError::QueueFull(id));
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                    returned
+                }
+                result => return result.map_err(|(err, _message)| err),
+            };
+        }
+    }
+
+    /// Queues a message sending a packet to the connection, handing it back with the error if
+    /// that fails.
+    fn queue_message(&self,
+                     id: ConnectionId,
+                     message: ConnectionMessage)
+                     -> Result<(), (SendError, ConnectionMessage)> {
+        let config = self.raw.config.read().unwrap().clone();
+        let connections = self.raw.connections.read().unwrap();
+        let connection = match connections.get(&id) {
+            Some(connection) => connection,
+            None => return Err((SendError::UnknownConnection(id), message)),
+        };
+        let control = match message {
+            ConnectionMessage::SendPacket(ref packet) |
+            ConnectionMessage::SendWithPriority(ref packet, _) => packet.is_control(),
+            _ => true,
+        };
+        let state = *connection.state.lock().unwrap();
+        match state {
+            ConnectionState::Handshaking if !control => {
+                return Err((SendError::NotReady(id), message));
+            }
+            ConnectionState::Closing => return Err((SendError::ConnectionClosed(id), message)),
+            _ => {}
+        }
+        connection.queue(message, &config).map_err(|err| {
+            match err {
+                TrySendError::Full(message) => (SendError::QueueFull(id), message),
+                TrySendError::Disconnected(message) => (SendError::ConnectionClosed(id), message),
+            }
+        })
+    }
+
+    /// Queues an application defined message to be sent to a connection on the given channel.
+    ///
+    /// The peer hands the payload to whatever subscribed to the channel with `subscribe`.
+    ///
+    /// # Errors
+    /// The same as `send_to`.
+    pub fn send_message(&self,
+                        id: ConnectionId,
+                        channel: &str,
+                        payload: Vec<u8>)
+                        -> Result<(), SendError> {
+        self.send_to(id,
+                     NetworkPacket::Message {
+                         channel: channel.to_owned(),
+                         payload: payload,
+                     })
+    }
+
+    /// Returns a Receiver for the payload of every message received on the channel with the name.
+    ///
+    /// Any number of subscribers may share a channel, in which case each gets every message.
+    /// Messages on channels without subscribers are logged and dropped. Dropping the Receiver
+    /// unsubscribes it.
+    pub fn subscribe(&self, name: &str) -> Receiver<(ConnectionId, Vec<u8>)> {
+        let (tx, rx) = channel();
+        let mut subscribers = self.raw.subscribers.lock().unwrap();
+        subscribers.entry(name.to_owned()).or_insert_with(Vec::new).push(tx);
+        rx
+    }
+
+    /// Takes the oldest packet received on any connection, if there is one.
+    ///
+    /// Packets are tagged with the id of the connection they came from, so they can be replied to
+    /// with `send_to`. Keepalive packets are handled internally and never show up here.
+    ///
+    /// The queue of received packets is unbounded, so it should be polled regularly. Packets that
+    /// are never taken out stay in memory untill the controller is dropped.
+    ///
+    /// `NetworkPacket::Message`s go to their channel's subscribers instead.
+    pub fn try_recv_packet(&self) -> Option<(ConnectionId, NetworkPacket)> {
+        self.raw.incoming_rx.lock().unwrap().try_recv().ok()
+    }
+
+    /// Queues a packet to be sent to every connection.
+    ///
+    /// Connections whose send thread has shut down are skipped and removed from the controller.
+    /// Closing connections are skipped, as are ones still handshaking unless the packet is a
+    /// control packet, and ones whose queue is full. Returns the number of connections the packet
+    /// was queued to.
+    pub fn broadcast(&self, packet: NetworkPacket) -> usize {
+        // Only collect the ids while holding the lock, so check_controller_channel isn't
+        // blocked while the packet is cloned for every connection.
+        let ids: Vec<ConnectionId> = {
+            let connections = self.raw.connections.read().unwrap();
+            connections.values()
+                       .filter(|connection| {
+                           match *connection.state.lock().unwrap() {
+                               ConnectionState::Handshaking => packet.is_control(),
+                               ConnectionState::Ready => true,
+                               ConnectionState::Closing => false,
+                           }
+                       })
+                       .map(|connection| connection.id)
+                       .collect()
+        };
+        let mut queued = 0;
+        let mut dead: Vec<ConnectionId> = Vec::new();
+        for id in ids {
+            match self.queue_message(id, ConnectionMessage::SendPacket(packet.clone())) {
+                Ok(()) => queued += 1,
+                Err((SendError::QueueFull(_), _message)) => {
+                    debug!("Not broadcasting to connection {}, since it's queue is full.", id.0);
+                }
+                // Unless it started closing since the ids were collected, which it's recv thread
+                // cleans up after, the send thread has shut down.
+                Err((SendError::ConnectionClosed(_), _message)) => {
+                    let closing = self.raw
+                                      .connections
+                                      .read()
+                                      .unwrap()
+                                      .get(&id)
+                                      .map_or(true, |connection| {
+                                          *connection.state.lock().unwrap() ==
+                                          ConnectionState::Closing
+                                      });
+                    if !closing {
+                        dead.push(id);
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+        if !dead.is_empty() {
+            let mut connections = self.raw.connections.write().unwrap();
+            for id in dead {
+                debug!("Removing connection {} after it's send thread shut down.", id.0);
+                connections.remove(&id);
+            }
+        }
+        queued
+    }
+}
+
+/// Raw representation of a controller. Used internally for things.
+pub struct ControllerRaw {
+    pub connections: RwLock<HashMap<ConnectionId, Connection>>,
+    pub tx: Mutex<Sender<ControllerMessage>>,
+    /// Cloned into every recv thread, which forward the packets they decode through it.
+    pub incoming_tx: Mutex<Sender<(ConnectionId, NetworkPacket)>>,
+    /// Every packet received on any connection, tagged with the connection it came from.
+    ///
+    /// Read with `Controller::try_recv_packet`. Once this is dropped along with the controller,
+    /// recv threads stop forwarding and close their connections.
+    pub incoming_rx: Mutex<Receiver<(ConnectionId, NetworkPacket)>>,
+    pub config: RwLock<ControllerConfig>,
+    /// Set with `Controller::set_accept_hook`.
+    pub accept_hook: Mutex<Option<Box<Fn(&SocketAddr) -> bool + Send>>>,
+    /// Set with `Controller::set_handler`.
+    pub handler: Mutex<Option<Box<ControllerHandler>>>,
+    /// Set with `Controller::set_server_info`.
+    pub server_info: RwLock<ServerInfo>,
+    /// Banned addresses, and when each ban expires if it does. Set with `Controller::ban`.
+    pub bans: Mutex<HashMap<IpAddr, Option<Instant>>>,
+    pub listeners: Mutex<HashMap<ListenerId, Listener>>,
+    /// The id given to the next listener added.
+    pub next_listener_id: AtomicUsize,
+    /// Errors that stopped listeners, taken with `Controller::take_listener_errors`.
+    pub listener_errors: Mutex<Vec<(ListenerId, io::Error)>>,
+    /// How many connections were dropped for announcing a packet above
+    /// `ControllerConfig::max_packet_size`.
+    pub oversized_packets: Arc<AtomicUsize>,
+    /// Receivers of messages for each channel, added with `Controller::subscribe`.
+    ///
+    /// Shared with every recv thread.
+    pub subscribers: Arc<Mutex<HashMap<String, Vec<Sender<(ConnectionId, Vec<u8>)>>>>>,
+    /// When the controller was made. Pings hold the nanoseconds since then.
+    pub started: Instant,
+    /// The IO thread driving connections added under `IoModel::Polled`, started with the first
+    /// one.
+    pub io_loop: Mutex<Option<IoLoop>>,
+    /// How many accepted sockets from each address are waiting on their first packet, see
+    /// `probe_socket`.
+    ///
+    /// Shared with every probe thread, which takes it's socket off once it is done.
+    pub probing: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ControllerRaw {
+    /// Constructs a ControllerRaw without any connections, which sends messages through tx.
+    fn new(tx: Sender<ControllerMessage>, config: ControllerConfig) -> ControllerRaw {
+        let (incoming_tx, incoming_rx) = channel::<(ConnectionId, NetworkPacket)>();
+        ControllerRaw {
+            connections: RwLock::new(HashMap::new()),
+            tx: Mutex::new(tx),
+            incoming_tx: Mutex::new(incoming_tx),
+            incoming_rx: Mutex::new(incoming_rx),
+            config: RwLock::new(config),
+            accept_hook: Mutex::new(None),
+            handler: Mutex::new(None),
+            server_info: RwLock::new(ServerInfo::default()),
+            bans: Mutex::new(HashMap::new()),
+            listeners: Mutex::new(HashMap::new()),
+            next_listener_id: AtomicUsize::new(0),
+            listener_errors: Mutex::new(Vec::new()),
+            oversized_packets: Arc::new(AtomicUsize::new(0)),
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            started: Instant::now(),
+            io_loop: Mutex::new(None),
+            probing: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// If the address is banned, removing it's ban if it has expired.
+    fn is_banned(&self, addr: IpAddr) -> bool {
+        let addr = normalize_ip(addr);
+        let mut bans = self.bans.lock().unwrap();
+        let expired = match bans.get(&addr) {
+            Some(&expires) => ban_expired(expires),
+            None => return false,
+        };
+        if expired {
+            bans.remove(&addr);
+        }
+        !expired
+    }
+
+    /// How many connections have a peer with the ip address.
+    fn connections_from(&self, addr: IpAddr) -> usize {
+        let addr = normalize_ip(addr);
+        self.connections
+            .read()
+            .unwrap()
+            .values()
+            .filter(|connection| normalize_ip(connection.peer_addr.ip()) == addr)
+            .count()
+    }
+
+    /// Runs the closure with the handler, if one is set.
+    ///
+    /// Must not be called with a lock on the connections held.
+    fn with_handler<F: FnOnce(&mut ControllerHandler)>(&self, closure: F) {
+        if let Some(ref mut handler) = *self.handler.lock().unwrap() {
+            closure(&mut **handler);
+        }
+    }
+}
+
+/// Notified by the controller thread as connections are added, removed and error.
+///
+/// Every method does nothing by default, so only the events of interest need implementing.
+pub trait ControllerHandler: Send {
+    /// A connection was registered, either accepted by a listener or opened by this controller.
+    ///
+    /// The handshake may not have completed yet.
+    fn on_connect(&mut self, _id: ConnectionId, _addr: SocketAddr) {}
+
+    /// A connection closed and was removed from the controller.
+    ///
+    /// Called exactly once for every connection given to `on_connect`.
+    fn on_disconnect(&mut self, _id: ConnectionId, _reason: DisconnectReason) {}
+
+    /// The peer sent a `NetworkPacket::Error`, or one was sent to it before closing the connection.
+    fn on_error(&mut self, _id: ConnectionId, _err: NetworkError) {}
+}
+
+impl Debug for ControllerRaw {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("ControllerRaw")
+           .field("connections", &self.connections)
+           .field("tx", &self.tx)
+           .field("incoming_tx", &self.incoming_tx)
+           .field("incoming_rx", &self.incoming_rx)
+           .field("config", &self.config)
+           .field("bans", &self.bans)
+           .field("listeners", &self.listeners)
+           .field("next_listener_id", &self.next_listener_id)
+           .field("listener_errors", &self.listener_errors)
+           .field("oversized_packets", &self.oversized_packets)
+           .field("subscribers", &self.subscribers)
+           .field("started", &self.started)
+           .field("io_loop", &self.io_loop)
+           .field("probing", &self.probing)
+           .finish()
+    }
+}
+
+/// Identifies a single listener for the lifetime of the Controller it was added to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ListenerId(pub u64);
+
+/// A listener added with `Controller::add_listener`, and the thread accepting sockets from it.
+pub struct Listener {
+    pub id: ListenerId,
+    /// The address the listener is bound to.
+    pub addr: SocketAddr,
+    /// Checked by the listener's thread between accepts. It exits once this is set.
+    pub shutdown: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Listener {
+    /// Tells the listener's thread to exit, and waits for it to do so.
+    fn stop(self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        if let Err(_err) = self.thread.join() {
+            warn!("The thread for listener {} panicked.", self.id.0);
+        }
+    }
+}
+
+impl Debug for Listener {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("Listener")
+           .field("id", &self.id)
+           .field("addr", &self.addr)
+           .field("shutdown", &self.shutdown)
+           .finish()
+    }
+}
+
+/// Identifies a single connection for the lifetime of the Controller that accepted it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(pub u64);
+
+#[derive(Debug)]
+/// Represents a single connection without any notion of client or server.
+///
+/// It's own struct to allow for hooks and the like.
+pub struct Connection {
+    pub id: ConnectionId,
+    /// The address of the peer, as reported when the socket was accepted or connected.
+    pub peer_addr: SocketAddr,
+    /// Holds at most `ControllerConfig::send_queue_depth` messages, see `Connection::queue`.
+    pub channel: Mutex<ConnectionSender>,
+    /// Shared with the connection's recv thread, which moves it along as the handshake completes
+    /// and the connection closes.
+    pub state: Arc<Mutex<ConnectionState>>,
+    /// Shared with the connection's send and recv threads, which count everything they move.
+    pub stats: Arc<ConnectionCounters>,
+    /// Shared with the connection's recv thread, and set to the reason once it is kicked.
+    pub kick_reason: Arc<Mutex<Option<String>>>,
+    /// Shuts down the connection's stream, for when it can't be told to close through channel.
+    ///
+    /// Shared with the connection's recv thread.
+    pub closer: Arc<StreamCloser>,
+    /// If the peer authenticated for the remote console, see `Controller::authenticate_rcon`.
+    pub is_admin: AtomicBool,
+    /// How many times the peer failed to authenticate for the remote console.
+    pub rcon_failures: AtomicUsize,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Connections are only dropped once they are removed from the controller.
+        self.stats.removed.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Connection {
+    /// A snapshot of the traffic on the connection.
+    fn stats(&self) -> ConnectionStats {
+        self.stats.snapshot(self.peer_addr)
+    }
+
+    /// Moves the connection to Closing and has it's send thread say goodbye to the peer.
+    ///
+    /// If the queue is full, the stream is shut down at once instead, without saying goodbye.
+    /// Does nothing if the send thread has already shut down.
+    fn disconnect(&self, reason: &str) {
+        *self.kick_reason.lock().unwrap() = Some(reason.to_owned());
+        *self.state.lock().unwrap() = ConnectionState::Closing;
+        let channel = self.channel.lock().unwrap();
+        let packet = NetworkPacket::Disconnect { reason: reason.to_owned() };
+        let queued = channel.try_send(ConnectionMessage::SendPacket(packet))
+                            .and_then(|()| channel.try_send(ConnectionMessage::Close));
+        if let Err(TrySendError::Full(_)) = queued {
+            self.closer.close();
+        }
+    }
+
+    /// Queues the message for the send thread without waiting.
+    ///
+    /// While the queue is full the connection is tracked in it's stats, and once it has been full
+    /// for config.lag_threshold_millis it is flagged as lagging, and dropped if
+    /// config.drop_lagging is set.
+    fn queue(&self,
+             message: ConnectionMessage,
+             config: &ControllerConfig)
+             -> Result<(), TrySendError<ConnectionMessage>> {
+        let result = self.channel.lock().unwrap().try_send(message);
+        match result {
+            Ok(()) => self.stats.record_queued(),
+            Err(TrySendError::Full(_)) => {
+                let lagging = self.stats.record_queue_full(config.lag_threshold_millis);
+                if lagging && config.drop_lagging &&
+                   *self.state.lock().unwrap() != ConnectionState::Closing {
+                    info!("Dropping connection {} with address {}, since it is lagging.",
+                          self.id.0,
+                          self.peer_addr);
+                    *self.kick_reason.lock().unwrap() = Some(LAGGING_REASON.to_owned());
+                    *self.state.lock().unwrap() = ConnectionState::Closing;
+                    self.closer.close();
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+        result
+    }
+}
+
+/// The sending half of a connection's queue of messages.
+pub enum ConnectionSender {
+    /// Read by the connection's send thread.
+    Threaded(SyncSender<ConnectionMessage>),
+    /// Read by the IO thread, which is woken up by every message queued, see `IoModel::Polled`.
+    Polled(mio::channel::SyncSender<ConnectionMessage>),
+}
+
+impl ConnectionSender {
+    /// Queues the message without waiting, like `SyncSender::try_send`.
+    pub fn try_send(&self,
+                    message: ConnectionMessage)
+                    -> Result<(), TrySendError<ConnectionMessage>> {
+        let tx = match *self {
+            ConnectionSender::Threaded(ref tx) => return tx.try_send(message),
+            ConnectionSender::Polled(ref tx) => tx,
+        };
+        match tx.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(mio::channel::TrySendError::Full(message)) => Err(TrySendError::Full(message)),
+            Err(mio::channel::TrySendError::Disconnected(message)) => {
+                Err(TrySendError::Disconnected(message))
+            }
+            Err(mio::channel::TrySendError::Io(err)) => {
+                // The message is queued by then, only waking the IO thread failed.
+                warn!("Failed to wake the IO thread after queueing a message: {}", err);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Clone for ConnectionSender {
+    fn clone(&self) -> ConnectionSender {
+        match *self {
+            ConnectionSender::Threaded(ref tx) => ConnectionSender::Threaded(tx.clone()),
+            ConnectionSender::Polled(ref tx) => ConnectionSender::Polled(tx.clone()),
+        }
+    }
+}
+
+impl Debug for ConnectionSender {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConnectionSender::Threaded(ref tx) => {
+                write!(fmt, "ConnectionSender::Threaded({:?})", tx)
+            }
+            ConnectionSender::Polled(_) => write!(fmt, "ConnectionSender::Polled"),
+        }
+    }
+}
+
+/// Shuts down a connection's stream from outside of it's send and recv threads.
+pub struct StreamCloser(Option<Box<Fn() + Send + Sync>>);
+
+impl StreamCloser {
+    /// Makes a closer shutting down both halves of the stream, or any other handle to it.
+    pub fn new<T: Transport>(stream: T) -> StreamCloser {
+        let stream = Mutex::new(stream);
+        StreamCloser(Some(Box::new(move || {
+            let _ = stream.lock().unwrap().shutdown(Shutdown::Both);
+        })))
+    }
+
+    /// Makes a closer that does nothing, for a connection without a stream of it's own.
+    pub fn none() -> StreamCloser {
+        StreamCloser(None)
+    }
+
+    pub fn close(&self) {
+        if let Some(ref close) = self.0 {
+            close();
+        }
+    }
+}
+
+impl Debug for StreamCloser {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "StreamCloser")
+    }
+}
+
+/// The live traffic counters of a connection.
+///
+/// Every counter is an atomic, so the send and recv threads never wait on anyone reading them.
+/// Read them with `snapshot`.
+#[derive(Debug)]
+pub struct ConnectionCounters {
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub packets_sent: AtomicU64,
+    pub packets_received: AtomicU64,
+    /// Milliseconds since the unix epoch when the connection was registered.
+    pub connected_at: u64,
+    /// Milliseconds since the unix epoch when a packet was last sent or received.
+    pub last_activity: AtomicU64,
+    /// How many times the connection ran out of tokens.
+    pub throttle_events: AtomicU64,
+    /// The packets sent from each lane, indexed by `Priority::index`.
+    pub lane_packets_sent: [AtomicU64; LANES],
+    /// The bytes sent from each lane, indexed by `Priority::index`.
+    pub lane_bytes_sent: [AtomicU64; LANES],
+    /// How many messages were refused for the connection since it's queue was full.
+    pub queue_full_events: AtomicU64,
+    /// Milliseconds since the unix epoch when the queue was found full, or 0 if the last message
+    /// was queued.
+    pub queue_full_since: AtomicU64,
+    /// Set once the queue has been full for the lag threshold, and cleared by the next message
+    /// queued.
+    pub lagging: AtomicBool,
+    /// When the controller was made, which the timestamps in Pings count from.
+    pub epoch: Instant,
+    /// The smoothed round trip time in nanoseconds, or 0 if no Ping has been answered yet.
+    pub rtt_nanos: AtomicU64,
+    /// The timestamp of the newest Ping answered, so older or repeated Pongs can be ignored.
+    pub last_pong: AtomicU64,
+    /// Set once the connection is removed from the controller, so anything holding the counters,
+    /// such as from `Controller::counters`, can tell they will no longer change.
+    pub removed: AtomicBool,
+}
+
+impl ConnectionCounters {
+    /// Constructs counters with nothing counted, for a connection made just now by a controller
+    /// made at epoch.
+    pub fn new(epoch: Instant) -> ConnectionCounters {
+        let now = timestamp_millis();
+        ConnectionCounters {
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            packets_sent: AtomicU64::new(0),
+            packets_received: AtomicU64::new(0),
+            connected_at: now,
+            last_activity: AtomicU64::new(now),
+            throttle_events: AtomicU64::new(0),
+            lane_packets_sent: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+            lane_bytes_sent: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+            queue_full_events: AtomicU64::new(0),
+            queue_full_since: AtomicU64::new(0),
+            lagging: AtomicBool::new(false),
+            epoch: epoch,
+            rtt_nanos: AtomicU64::new(0),
+            last_pong: AtomicU64::new(0),
+            removed: AtomicBool::new(false),
+        }
+    }
+
+    /// The current time as held in a Ping, in nanoseconds since the epoch.
+    fn ping_timestamp(&self) -> u64 {
+        duration_nanos(self.epoch.elapsed())
+    }
+
+    /// Folds the round trip of a Pong holding the given timestamp into the smoothed round trip
+    /// time, returning the new estimate.
+    ///
+    /// Pongs for a Ping older than one already answered, or from the future, are ignored and None
+    /// is returned. Only called from the recv thread, so no other thread updates the estimate.
+    fn record_pong(&self, sent: u64) -> Option<Duration> {
+        let now = self.ping_timestamp();
+        if sent <= self.last_pong.load(Ordering::Relaxed) || sent > now {
+            return None;
+        }
+        self.last_pong.store(sent, Ordering::Relaxed);
+        // A round trip of 0 is stored as 1, so it is not mistaken for no estimate.
+        let sample = cmp::max(now - sent, 1);
+        let rtt = match self.rtt_nanos.load(Ordering::Relaxed) {
+            0 => sample,
+            old if sample >= old => old + (sample - old) / RTT_SMOOTHING_PINGS as u64,
+            old => old - (old - sample) / RTT_SMOOTHING_PINGS as u64,
+        };
+        self.rtt_nanos.store(rtt, Ordering::Relaxed);
+        Some(nanos_duration(rtt))
+    }
+
+    /// The smoothed round trip time, or None if no Ping has been answered yet.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt_nanos.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(nanos_duration(nanos)),
+        }
+    }
+
+    /// How long it has been since the connection was registered.
+    pub fn connected_for(&self) -> Duration {
+        Duration::from_millis(timestamp_millis().saturating_sub(self.connected_at))
+    }
+
+    /// Counts a packet of the given length, including it's header, being written to the peer from
+    /// the given lane.
+    fn record_sent(&self, len: usize, priority: Priority) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.lane_bytes_sent[priority.index()].fetch_add(len as u64, Ordering::Relaxed);
+        self.lane_packets_sent[priority.index()].fetch_add(1, Ordering::Relaxed);
+        self.last_activity.store(timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Notes a message being queued for the send thread, so the queue is not full.
+    fn record_queued(&self) {
+        self.queue_full_since.store(0, Ordering::Relaxed);
+        self.lagging.store(false, Ordering::Relaxed);
+    }
+
+    /// Counts a message refused since the queue is full, returning if it has been full for at
+    /// least the threshold.
+    fn record_queue_full(&self, threshold_millis: u64) -> bool {
+        self.queue_full_events.fetch_add(1, Ordering::Relaxed);
+        let now = timestamp_millis();
+        let since = match self.queue_full_since.compare_and_swap(0, now, Ordering::Relaxed) {
+            0 => now,
+            since => since,
+        };
+        let lagging = now.saturating_sub(since) >= threshold_millis;
+        if lagging {
+            self.lagging.store(true, Ordering::Relaxed);
+        }
+        lagging
+    }
+
+    /// Counts a packet being decoded from the peer. It's bytes are counted as they are read.
+    fn record_received(&self) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.last_activity.store(timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Copies the current value of every counter, for the connection with the given peer.
+    pub fn snapshot(&self, peer_addr: SocketAddr) -> ConnectionStats {
+        ConnectionStats {
+            peer_addr: peer_addr,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            connected_at: self.connected_at,
+            last_activity: self.last_activity.load(Ordering::Relaxed),
+            throttle_events: self.throttle_events.load(Ordering::Relaxed),
+            rtt: self.rtt(),
+            queue_full_events: self.queue_full_events.load(Ordering::Relaxed),
+            lagging: self.lagging.load(Ordering::Relaxed),
+            lanes: [self.lane_snapshot(Priority::Control),
+                    self.lane_snapshot(Priority::Gameplay),
+                    self.lane_snapshot(Priority::Bulk)],
+        }
+    }
+
+    fn lane_snapshot(&self, priority: Priority) -> LaneStats {
+        LaneStats {
+            packets_sent: self.lane_packets_sent[priority.index()].load(Ordering::Relaxed),
+            bytes_sent: self.lane_bytes_sent[priority.index()].load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of the traffic on a connection, from `Controller::stats`.
+///
+/// Byte counts include the headers of packets, and everything read while resynchronizing.
+/// Packets that could not be decoded are only counted in bytes_received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The address of the peer on the other end of the connection.
+    pub peer_addr: SocketAddr,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Milliseconds since the unix epoch when the connection was registered.
+    pub connected_at: u64,
+    /// Milliseconds since the unix epoch when a packet was last sent or received.
+    pub last_activity: u64,
+    /// How many times the connection ran out of tokens under `ControllerConfig::rate_limit`.
+    pub throttle_events: u64,
+    /// The smoothed round trip time, or None if no Ping has been answered yet.
+    pub rtt: Option<Duration>,
+    /// How many packets could not be queued for the connection since it's queue was full.
+    pub queue_full_events: u64,
+    /// If the connection's queue has been full for `ControllerConfig::lag_threshold_millis`,
+    /// without anything being queued since.
+    pub lagging: bool,
+    /// The traffic sent from each lane, indexed by `Priority::index`. See `ConnectionStats::lane`.
+    pub lanes: [LaneStats; LANES],
+}
+
+impl ConnectionStats {
+    /// The traffic sent from the given lane.
+    pub fn lane(&self, priority: Priority) -> LaneStats {
+        self.lanes[priority.index()]
+    }
+}
+
+/// The traffic sent from a single `Priority` lane of a connection, as part of `ConnectionStats`.
+///
+/// Every fragment of a fragmented packet is counted as a packet of it's own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LaneStats {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+}
+
+/// Which of a connection's outgoing lanes a packet is queued on, see
+/// `Controller::send_to_with_priority`.
+///
+/// The send thread frames packets from the highest lane with anything queued, but a lane passed
+/// over MAX_LANE_DEFICIT times in a row is framed from next, so Bulk still makes progress.
+/// Packets on the same lane are sent in the order they were queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Packets managing the connection, the default for control packets.
+    Control,
+    /// Time sensitive game data, the default for anything not on another lane.
+    Gameplay,
+    /// Large transfers that can wait, the default for the packets syncing scripts.
+    Bulk,
+}
+
+impl Priority {
+    /// The position of the lane, from 0 for Control to LANES - 1 for Bulk.
+    pub fn index(self) -> usize {
+        match self {
+            Priority::Control => 0,
+            Priority::Gameplay => 1,
+            Priority::Bulk => 2,
+        }
+    }
+
+    /// The lane at the given position, as returned by `index`.
+    ///
+    /// # Panics
+    /// If index is not below LANES.
+    pub fn from_index(index: usize) -> Priority {
+        match index {
+            0 => Priority::Control,
+            1 => Priority::Gameplay,
+            2 => Priority::Bulk,
+            _ => panic!("There is no lane at index {}.", index),
+        }
+    }
+}
+
+/// Where a connection is in it's lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Waiting on a valid `NetworkPacket::Init` from the peer.
+    ///
+    /// Only control packets, such as Init and Error, may be sent or received.
+    Handshaking,
+    /// The handshake completed, so any packet may be sent.
+    Ready,
+    /// The peer closed the connection or it errored, and it is about to be removed.
+    Closing,
+}
+
+/// Why a connection was removed from the controller.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// It was closed locally by `Controller::kick` or `Controller::shutdown`, with the reason.
+    Kicked(String),
+    /// The peer sent a `NetworkPacket::Disconnect` with the given reason.
+    Disconnected(String),
+    /// The stream closed or errored without the peer saying why.
+    Closed,
+    /// Nothing was received from the peer within the handshake or idle timeout.
+    TimedOut,
+    /// The given error was sent to the peer, then the connection was closed.
+    Error(NetworkError),
+    /// The peer announced a packet of the given length, above `ControllerConfig::max_packet_size`.
+    PacketTooLarge(u32),
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            DisconnectReason::Kicked(ref reason) => write!(fmt, "Kicked: {}", reason),
+            DisconnectReason::Disconnected(ref reason) => write!(fmt, "Disconnected: {}", reason),
+            DisconnectReason::Closed => {
+                write!(fmt, "Closed: The stream closed without the peer saying why.")
+            }
+            DisconnectReason::TimedOut => {
+                write!(fmt, "TimedOut: Nothing was received from the peer in time.")
+            }
+            DisconnectReason::Error(ref err) => write!(fmt, "Error: {}", err),
+            DisconnectReason::PacketTooLarge(len) => {
+                write!(fmt,
+                       "PacketTooLarge: The peer announced a packet of {} bytes, which is too \
+                        large.",
+                       len)
+            }
+        }
+    }
+}
+
+/// An error that can occour queueing a packet for a connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SendError {
+    /// No connection with the given id is registered with the controller.
+    UnknownConnection(ConnectionId),
+    /// The connection exists, but the thread writing to it's stream has shut down.
+    ConnectionClosed(ConnectionId),
+    /// The connection has not finished it's handshake, so only control packets may be sent.
+    NotReady(ConnectionId),
+    /// The connection already has `ControllerConfig::send_queue_depth` messages queued.
+    QueueFull(ConnectionId),
+}
+
+impl Display for SendError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SendError::UnknownConnection(id) => {
+                write!(fmt, "UnknownConnection: No connection with id {} exists.", id.0)
+            }
+            SendError::ConnectionClosed(id) => {
+                write!(fmt,
+                       "ConnectionClosed: The connection with id {} is no longer sending.",
+                       id.0)
+            }
+            SendError::NotReady(id) => {
+                write!(fmt,
+                       "NotReady: The connection with id {} has not finished it's handshake.",
+                       id.0)
+            }
+            SendError::QueueFull(id) => {
+                write!(fmt,
+                       "QueueFull: The connection with id {} has too many packets queued.",
+                       id.0)
+            }
+        }
+    }
+}
+
+impl Error for SendError {
+    fn description(&self) -> &str {
+        match *self {
+            SendError::UnknownConnection(_) => {
+                "UnknownConnection: No connection with the id exists."
+            }
+            SendError::ConnectionClosed(_) => {
+                "ConnectionClosed: The connection is no longer sending."
+            }
+            SendError::NotReady(_) => {
+                "NotReady: The connection has not finished it's handshake."
+            }
+            SendError::QueueFull(_) => {
+                "QueueFull: The connection has too many packets queued."
+            }
+        }
+    }
+}
+
+/// An error that can occour asking a server for it's info with `query_server`.
+#[derive(Debug)]
+pub enum QueryError {
+    /// Connecting to the server or sending the request failed.
+    Io(io::Error),
+    /// The server did not answer within the timeout.
+    TimedOut,
+    /// The server closed the connection without answering.
+    Closed,
+    /// The server refused to answer with the given error, such as `NetworkError::Banned`.
+    Refused(NetworkError),
+    /// The server answered with something other than an InfoResponse.
+    BadResponse,
+}
+
+impl Display for QueryError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            QueryError::Io(ref err) => write!(fmt, "Io: {}", err),
+            QueryError::TimedOut => write!(fmt, "TimedOut: The server did not answer in time."),
+            QueryError::Closed => {
+                write!(fmt, "Closed: The server closed the connection without answering.")
+            }
+            QueryError::Refused(ref err) => {
+                write!(fmt, "Refused: The server sent an error: {}", err)
+            }
+            QueryError::BadResponse => {
+                write!(fmt, "BadResponse: The server did not answer with an InfoResponse.")
+            }
+        }
+    }
+}
+
+impl Error for QueryError {
+    fn description(&self) -> &str {
+        match *self {
+            QueryError::Io(ref err) => err.description(),
+            QueryError::TimedOut => "TimedOut: The server did not answer in time.",
+            QueryError::Closed => "Closed: The server closed the connection without answering.",
+            QueryError::Refused(_) => "Refused: The server sent an error.",
+            QueryError::BadResponse => {
+                "BadResponse: The server did not answer with an InfoResponse."
+            }
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            QueryError::Io(ref err) => Some(err),
+            QueryError::Refused(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for QueryError {
+    fn from(err: io::Error) -> Self {
+        QueryError::Io(err)
+    }
+}
+
+/// An error that can occour framing a packet to be sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SerializeError {
+    /// The body of the packet is larger than the maximum packet size.
+    ///
+    /// Holds the size of the body and the maximum, in bytes.
+    PacketTooLarge(usize, u32),
+}
+
+impl Display for SerializeError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SerializeError::PacketTooLarge(size, max) => {
+                write!(fmt,
+                       "PacketTooLarge: The packet is {} bytes, above the maximum of {} bytes.",
+                       size,
+                       max)
+            }
+        }
+    }
+}
+
+impl Error for SerializeError {
+    fn description(&self) -> &str {
+        match *self {
+            SerializeError::PacketTooLarge(_, _) => {
+                "PacketTooLarge: The packet is above the maximum packet size."
+            }
+        }
+    }
+}
+
+/// Sent in the case of an error that should be sent to the peer.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NetworkError {
+    /// If the protocol versions of the peers differ, so they can not understand each other.
+    ///
+    /// Holds the local and remote protocol versions, then the local and remote game versions.
+    VersionMismatch(u32, u32, String, String),
+    /// If both peers have should_crash == false, then this error should be sent.
+    ///
+    /// Do note that this error should not be rewrapped into a reerror, since it would cause a loop.
+    /// Instead, it should be logged and ignored, as the connection will be killed shortly after.
+    ShouldCrashBothTrue,
+    /// The server already has the maximum number of clients connected.
+    ServerFull,
+    /// The server's accept hook refused the connection.
+    ConnectionDenied,
+    /// Too many packets with a bad checksum were received, so the stream can't be trusted.
+    CorruptStream,
+    /// The peer sent packets faster than the rate limit for longer than the grace period.
+    RateLimited,
+    /// The address of the peer is banned from the server.
+    Banned,
+    /// The server already has the maximum number of connections from the address of the peer.
+    TooManyConnectionsFromAddress,
+    /// The server has a password, and the peer did not prove it knows it.
+    BadCredentials,
+    /// The peer sent fragments that do not add up to a packet, such as a FragmentStart reusing the
+    /// id of a packet still being received, or a FragmentEnd with a bad checksum.
+    BadFragment,
+    /// The peer started more fragmented packets at once than max_fragmented_transfers, or
+    /// announced more bytes for them than max_fragment_bytes.
+    FragmentLimit,
+    /// Another player on the server already has the display_name the peer asked for.
+    NameTaken,
+    /// The display_name the peer asked for isn't a valid name, see `::player::is_valid_name`.
+    InvalidName,
+}
+
+impl Display for NetworkError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            NetworkError::VersionMismatch(protocol1, protocol2, ref ver1, ref ver2) => {
+                write!(fmt,
+                       "VersionMismatch: The protocol versions of the client and server \
+                        attempting to connect mismatch. protocol1: {}, protocol2: {}, ver1: {}, \
+                        ver2: {}",
+                       protocol1,
+                       protocol2,
+                       ver1,
+                       ver2)
+            }
+            NetworkError::ShouldCrashBothTrue => {
+                write!(fmt,
+                       "ShouldCrashBothTrue: Both peers have should_crash == false.")
+            }
+            NetworkError::ServerFull => {
+                write!(fmt,
+                       "ServerFull: The server has the maximum number of clients connected.")
+            }
+            NetworkError::ConnectionDenied => {
+                write!(fmt, "ConnectionDenied: The server refused the connection.")
+            }
+            NetworkError::CorruptStream => {
+                write!(fmt,
+                       "CorruptStream: Too many packets with a bad checksum were received.")
+            }
+            NetworkError::RateLimited => {
+                write!(fmt, "RateLimited: Packets were sent faster than the rate limit.")
+            }
+            NetworkError::Banned => {
+                write!(fmt, "Banned: The address is banned from the server.")
+            }
+            NetworkError::TooManyConnectionsFromAddress => {
+                write!(fmt,
+                       "TooManyConnectionsFromAddress: The server has the maximum number of \
+                        connections from the address.")
+            }
+            NetworkError::BadCredentials => {
+                write!(fmt, "BadCredentials: The password given to the server is wrong.")
+            }
+            NetworkError::BadFragment => {
+                write!(fmt,
+                       "BadFragment: The fragments received do not add up to a packet.")
+            }
+            NetworkError::FragmentLimit => {
+                write!(fmt,
+                       "FragmentLimit: Too many fragmented packets were being received at once.")
+            }
+            NetworkError::NameTaken => {
+                write!(fmt, "NameTaken: Another player on the server already has the name.")
+            }
+            NetworkError::InvalidName => {
+                write!(fmt, "InvalidName: The name isn't a valid player name.")
+            }
+        }
+    }
+}
+
+impl Error for NetworkError {
+    fn description(&self) -> &str {
+        match *self {
+            NetworkError::VersionMismatch(_, _, _, _) => {
+                "VersionMismatch: The protocol versions of the client and server attempting to \
+                 connect mismatch."
+            }
+            NetworkError::ShouldCrashBothTrue => {
+                "ShouldCrashBothTrue: Both peers have should_crash == false."
+            }
+            NetworkError::ServerFull => {
+                "ServerFull: The server has the maximum number of clients connected."
+            }
+            NetworkError::ConnectionDenied => {
+                "ConnectionDenied: The server refused the connection."
+            }
+            NetworkError::CorruptStream => {
+                "CorruptStream: Too many packets with a bad checksum were received."
+            }
+            NetworkError::RateLimited => {
+                "RateLimited: Packets were sent faster than the rate limit."
+            }
+            NetworkError::Banned => {
+                "Banned: The address is banned from the server."
+            }
+            NetworkError::TooManyConnectionsFromAddress => {
+                "TooManyConnectionsFromAddress: The server has the maximum number of connections \
+                 from the address."
+            }
+            NetworkError::BadCredentials => {
+                "BadCredentials: The password given to the server is wrong."
+            }
+            NetworkError::BadFragment => {
+                "BadFragment: The fragments received do not add up to a packet."
+            }
+            NetworkError::FragmentLimit => {
+                "FragmentLimit: Too many fragmented packets were being received at once."
+            }
+            NetworkError::NameTaken => {
+                "NameTaken: Another player on the server already has the name."
+            }
+            NetworkError::InvalidName => "InvalidName: The name isn't a valid player name.",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            NetworkError::VersionMismatch(_, _, _, _) => None,
+            NetworkError::ShouldCrashBothTrue => None,
+            NetworkError::ServerFull => None,
+            NetworkError::ConnectionDenied => None,
+            NetworkError::CorruptStream => None,
+            NetworkError::RateLimited => None,
+            NetworkError::Banned => None,
+            NetworkError::TooManyConnectionsFromAddress => None,
+            NetworkError::BadCredentials => None,
+            NetworkError::BadFragment => None,
+            NetworkError::FragmentLimit => None,
+            NetworkError::NameTaken => None,
+            NetworkError::InvalidName => None,
+        }
+    }
+}
+
+/// Messages that can be sent between peers to facilitate vairous actions.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NetworkPacket {
+    /// Sent on connection to verify everything is in sync.
+    Init {
+        /// The PROTOCOL_VERSION of the local game, which must match the peer's.
+        protocol: u32,
+        /// The curent version of the local game.
+        ///
+        /// Should be formatted according to Scematic Versioning. It is only informative, since
+        /// compatibility is decided by the protocol version.
+        version: String,
+        /// If the local game should crash when an error occours.
+        ///
+        /// Two peers should not have this as false, because should_crash will send the error to the
+        /// remote peer and make it crash instead.
+        /// This would cause a infinite loop if both were to do it.
+        should_crash: bool,
+        /// The compression the local game would like bodies sent to it in.
+        ///
+        /// Peers that both offer the same compression use it for bodies above their
+        /// `ControllerConfig::compression_threshold`.
+        compression: Compression,
+        /// Proof the peer knows the password, answering the nonce of a `NetworkPacket::Challenge`.
+        ///
+        /// None in the first Init a peer sends, since it has not been challenged yet. Ignored by
+        /// peers without a password.
+        password: Option<Vec<u8>>,
+        /// The name the peer would like to be known by, see `ControllerConfig::display_name`.
+        display_name: String,
+    },
+    /// Sent instead of an Init by a peer with a password, in answer to the first Init received.
+    ///
+    /// Holds a nonce unique to the connection. The peer must answer with another Init, holding
+    /// `password_proof` of the password and the nonce.
+    Challenge(u64),
+    /// Asks a server for it's `ServerInfo`, without connecting to it.
+    ///
+    /// Only answered if it is the first packet on a socket. The server answers with an
+    /// InfoResponse, then closes the socket.
+    InfoRequest,
+    /// The answer to an InfoRequest, holding the fields of the server's `ServerInfo`.
+    InfoResponse {
+        name: String,
+        players: u32,
+        max_players: u32,
+        version: String,
+        motd: String,
+    },
+    /// An error that should crash the game and show an error to the user, but only on a client.
+    Error(NetworkError),
+    /// Sent right before a peer closes the connection on purpose.
+    ///
+    /// The receiving side treats it as a clean close.
+    Disconnect {
+        /// Why the connection is being closed, suitable for showing to the user.
+        reason: String,
+    },
+    /// Sent after a while without sending anything, so the peer knows the connection is alive.
+    ///
+    /// Holds the time it was sent, in nanoseconds since the sending controller was made.
+    ///
+    /// Answered with a Pong, which the sender measures the round trip time with.
+    Ping(u64),
+    /// The answer to a Ping, holding the same time as it.
+    Pong(u64),
+    /// A message defined by the game, rather than the engine.
+    ///
+    /// Handed to the subscribers of the channel, as added with `Controller::subscribe`.
+    Message {
+        /// Which subscribers the message is for.
+        channel: String,
+        /// The contents of the message, in whatever format the game uses for the channel.
+        payload: Vec<u8>,
+    },
+    /// Activates a script event on the peer, as done by `script::Engine::exec_event`.
+    ///
+    /// The server prepends the id of the connection it came from to the arguments, so scripts know
+    /// who sent it. See `::Engine::handle_packet`.
+    Event {
+        name: String,
+        args: Vec<LuaValueRepr>,
+    },
+    /// Sent by a server once a client's handshake is done, listing the name and `::script_hash` of
+    /// every script it offers, as made by `::Engine::script_manifest`.
+    ///
+    /// The client answers with a ScriptRequest for the scripts it does not already have.
+    ScriptManifest { entries: Vec<(String, u64)> },
+    /// Asks the server for the sources of the scripts with the given names, after a
+    /// ScriptManifest.
+    ScriptRequest { names: Vec<String> },
+    /// A piece of the source of a script, answering a ScriptRequest.
+    ///
+    /// Sources that would not fit in a single packet are split over several ScriptBodies, which
+    /// the client joins in order, checking them against the hash in the manifest once the last
+    /// one arrives.
+    ScriptBody {
+        name: String,
+        source: String,
+        /// If this is the last piece of the script.
+        last: bool,
+    },
+    /// Starts a packet sent in fragments, since it's body is above the sender's
+    /// fragment_threshold.
+    ///
+    /// Followed by Fragments holding the body in order, then a FragmentEnd. The receiver handles
+    /// the reassembled packet as if it was sent whole. Ids may be reused once a FragmentEnd is
+    /// sent.
+    FragmentStart {
+        id: u32,
+        /// The length of the whole body in bytes.
+        total_len: u32,
+        /// The channel of the packet if it is a Message, or an empty string.
+        channel: String,
+    },
+    /// A piece of the body of a fragmented packet, starting offset bytes into it.
+    Fragment { id: u32, offset: u32, data: Vec<u8> },
+    /// Ends a fragmented packet, with the CRC32 of the whole body.
+    FragmentEnd { id: u32, checksum: u32 },
+    /// Authenticates the connection for the remote console with
+    /// `ControllerConfig::rcon_password`, which is sent as it is.
+    ///
+    /// Answered with an RconResponse saying if it was accepted. See
+    /// `Controller::authenticate_rcon`.
+    RconAuth { password: String },
+    /// A line for a server to run as a command, with `script::Engine::run_command`, once the
+    /// connection has authenticated with an RconAuth.
+    ///
+    /// Answered with an RconResponse holding the output of the command, or the error.
+    RconCommand { line: String },
+    /// The answer to an RconAuth or RconCommand.
+    RconResponse { output: String },
+}
+
+impl NetworkPacket {
+    /// If the packet manages the connection itself, rather than carrying game data.
+    ///
+    /// Only control packets are sent or accepted while a connection is handshaking.
+    pub fn is_control(&self) -> bool {
+        match *self {
+            NetworkPacket::Init { .. } => true,
+            NetworkPacket::Challenge(_) => true,
+            NetworkPacket::InfoRequest => true,
+            NetworkPacket::InfoResponse { .. } => true,
+            NetworkPacket::Error(_) => true,
+            NetworkPacket::Disconnect { .. } => true,
+            NetworkPacket::Ping(_) => true,
+            NetworkPacket::Pong(_) => true,
+            NetworkPacket::Message { .. } => false,
+            NetworkPacket::Event { .. } => false,
+            NetworkPacket::ScriptManifest { .. } => false,
+            NetworkPacket::ScriptRequest { .. } => false,
+            NetworkPacket::ScriptBody { .. } => false,
+            NetworkPacket::FragmentStart { .. } => false,
+            NetworkPacket::Fragment { .. } => false,
+            NetworkPacket::FragmentEnd { .. } => false,
+            NetworkPacket::RconAuth { .. } => false,
+            NetworkPacket::RconCommand { .. } => false,
+            NetworkPacket::RconResponse { .. } => false,
+        }
+    }
+
+    /// The lane the packet is sent on by `Controller::send_to`.
+    ///
+    /// Control packets are sent on `Priority::Control`, and the packets syncing scripts on
+    /// `Priority::Bulk`. Everything else is sent on `Priority::Gameplay`.
+    pub fn priority(&self) -> Priority {
+        match *self {
+            NetworkPacket::ScriptManifest { .. } |
+            NetworkPacket::ScriptRequest { .. } |
+            NetworkPacket::ScriptBody { .. } => Priority::Bulk,
+            ref packet if packet.is_control() => Priority::Control,
+            _ => Priority::Gameplay,
+        }
+    }
+}
+
+/// What was learned waiting on the first packet of an accepted socket, before registering it.
+#[derive(Debug)]
+pub struct Probe {
+    /// When the socket was accepted, which it's handshake timeout is counted from.
+    pub started: Instant,
+    /// The first packet the peer sent, if it sent one in time.
+    ///
+    /// It is handled by the connection's recv thread before anything else is read.
+    pub first_packet: Option<NetworkPacket>,
+}
+
+impl Probe {
+    /// A probe of a socket that was not waited on, starting now.
+    fn none() -> Probe {
+        Probe {
+            started: Instant::now(),
+            first_packet: None,
+        }
+    }
+}
+
+/// Message sent to a connection to do vairous actions.
+#[derive(Debug, Clone)]
+pub enum ConnectionMessage {
+    DoNothing,
+    /// Frame the packet and write it to the connection's stream, on the lane given by
+    /// `NetworkPacket::priority`.
+    SendPacket(NetworkPacket),
+    /// Frame the packet and write it to the connection's stream, on the given lane.
+    SendWithPriority(NetworkPacket, Priority),
+    /// Shut down the connection's stream, which also stops it's recv thread.
+    ///
+    /// Writing is shut down first, and the peer is given CLOSE_DRAIN_MILLIS to read everything
+    /// sent before it and close it's side.
+    Close,
+    /// Send a Ping if nothing has been sent for the keepalive interval.
+    ///
+    /// Sent periodically by the connection's keepalive thread.
+    Keepalive,
+}
+
+/// Channel message sent to controller to dictate certain actions.
+#[derive(Debug)]
+pub enum ControllerMessage {
+    /// Add a socket, spinning up its send and recv threads in the process.
+    ///
+    /// If a Sender is given, the socket was opened by `Controller::connect`,
+    /// and the id assigned to the connection is sent through it. Otherwise it was accepted, and
+    /// it's first packet is waited on before it is registered, so info requests can be answered.
+    ///
+    /// The password is the one given to `Controller::connect_with_password`, if any.
+    AddSocket(TcpStream, SocketAddr, Option<Sender<ConnectionId>>, Option<String>),
+    /// Register an accepted socket once it's first packet has been waited on.
+    ///
+    /// Sent by the thread probing the socket, see `ControllerMessage::AddSocket`.
+    AddProbedSocket(TcpStream, SocketAddr, Probe),
+    /// Add two connections over a loopback pair, which are connected to each other.
+    ///
+    /// The ids assigned to them are sent back, the first being the one that sends the first Init.
+    AddLoopbackPair(Sender<(ConnectionId, ConnectionId)>),
+    /// Remove a connection, which also shuts down it's send thread by dropping it's channel.
+    ///
+    /// Sent by the recv thread of a connection once the peer closes it or it errors.
+    RemoveSocket(ConnectionId, DisconnectReason),
+    /// Pass an error on to `ControllerHandler::on_error`.
+    ///
+    /// Sent by the recv thread of a connection once it sends or receives a `NetworkPacket::Error`.
+    ConnectionError(ConnectionId, NetworkError),
+    /// A listener hit an error it could not recover from, and it's thread exited.
+    ///
+    /// The listener is removed, and the error is kept for `Controller::take_listener_errors`.
+    ListenerDied(ListenerId, io::Error),
+    /// Used for unit testing.
+    #[cfg(test)]
+    Test(Tattle),
+}
+
+/// An error that can occour parsing an address with `parse_addr`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AddrParseError {
+    /// The address is not a valid ip address with a port, nor a host name that resolves to one.
+    Unresolvable,
+    /// The host name resolved to more than one address, which are all given so one can be picked.
+    Ambiguous(Vec<SocketAddr>),
+    /// The host is localhost, which `LocalhostPolicy::Forbid` does not allow.
+    LocalhostForbidden,
+}
+
+impl Display for AddrParseError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            AddrParseError::Unresolvable => {
+                write!(fmt, "Unresolvable: The address could not be resolved.")
+            }
+            AddrParseError::Ambiguous(ref addrs) => {
+                write!(fmt,
+                       "Ambiguous: The address resolved to {} addresses: {:?}",
+                       addrs.len(),
+                       addrs)
+            }
+            AddrParseError::LocalhostForbidden => {
+                write!(fmt,
+                       "LocalhostForbidden: localhost may resolve to both 127.0.0.1 and ::1, so \
+                        it may not be used.")
+            }
+        }
+    }
+}
+
+impl Error for AddrParseError {
+    fn description(&self) -> &str {
+        match *self {
+            AddrParseError::Unresolvable => "Unresolvable: The address could not be resolved.",
+            AddrParseError::Ambiguous(_) => {
+                "Ambiguous: The address resolved to more than one address."
+            }
+            AddrParseError::LocalhostForbidden => {
+                "LocalhostForbidden: localhost may not be used."
+            }
+        }
+    }
+}
+
+/// What `parse_addr_with` does with an address whose host is localhost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalhostPolicy {
+    /// localhost means the loopback address, whatever it would resolve to.
+    ///
+    /// That is ::1 under `AddrPreference::PreferV6`, and 127.0.0.1 otherwise.
+    Loopback,
+    /// Refuse localhost with `AddrParseError::LocalhostForbidden`, since it can resolve to both
+    /// 127.0.0.1 and ::1.
+    Forbid,
+}
+
+/// Which address family `parse_addr_with` picks when a host name resolves to both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddrPreference {
+    /// Only consider the IPv4 addresses, if there are any.
+    PreferV4,
+    /// Only consider the IPv6 addresses, if there are any.
+    PreferV6,
+    /// Consider every address, so one of each family is `AddrParseError::Ambiguous`.
+    Any,
+}
+
+/// Parses a str to a SocketAddr, resolving it if it's host is a name.
+///
+/// localhost is taken to mean 127.0.0.1, see `parse_addr_with` to refuse it or prefer IPv6
+/// instead.
+///
+/// # Errors
+/// * `AddrParseError::Unresolvable` if the str does not resolve to any address.
+/// * `AddrParseError::Ambiguous` if the str resolves to more than one address.
+pub fn parse_addr(addr: &str) -> Result<SocketAddr, AddrParseError> {
+    parse_addr_with(addr, LocalhostPolicy::Loopback, AddrPreference::Any)
+}
+
+/// Parses a str to a SocketAddr like `parse_addr`, handling localhost according to the policy.
+///
+/// If the str resolves to addresses of both families, only those of the preferred family are
+/// considered.
+pub fn parse_addr_with(addr: &str,
+                       policy: LocalhostPolicy,
+                       preference: AddrPreference)
+                       -> Result<SocketAddr, AddrParseError> {
+    let (host, port) = match addr.rfind(':') {
+        Some(split) => (&addr[..split], &addr[split + 1..]),
+        None => return Err(AddrParseError::Unresolvable),
+    };
+    if host.eq_ignore_ascii_case("localhost") {
+        if policy == LocalhostPolicy::Forbid {
+            return Err(AddrParseError::LocalhostForbidden);
+        }
+        let port = try!(port.parse::<u16>().map_err(|_err| AddrParseError::Unresolvable));
+        let loopback = match preference {
+            AddrPreference::PreferV6 => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+            AddrPreference::PreferV4 | AddrPreference::Any => {
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+            }
+        };
+        return Ok(SocketAddr::new(loopback, port));
+    }
+    match addr.to_socket_addrs() {
+        Ok(addrs) => single_addr(addrs.collect(), preference),
+        Err(_err) => Err(AddrParseError::Unresolvable),
+    }
+}
+
+/// Picks the only address an address resolved to, out of those of the preferred family.
+fn single_addr(mut addrs: Vec<SocketAddr>,
+               preference: AddrPreference)
+               -> Result<SocketAddr, AddrParseError> {
+    let preferred = |addr: &SocketAddr| {
+        match (preference, *addr) {
+            (AddrPreference::PreferV4, SocketAddr::V4(_)) |
+            (AddrPreference::PreferV6, SocketAddr::V6(_)) => true,
+            _ => false,
+        }
+    };
+    if addrs.iter().any(&preferred) {
+        addrs.retain(&preferred);
+    }
+    addrs.dedup();
+    match addrs.len() {
+        0 => Err(AddrParseError::Unresolvable),
+        1 => Ok(addrs[0]),
+        _ => Err(AddrParseError::Ambiguous(addrs)),
+    }
+}
+
+/// Parses a str to a SocketAddr.
+///
+/// This is a function because while str implements ToSocketAddrs, it requires a good bit of boilerplate to use.
+///
+/// #Panics
+/// * Calling with a localhost ip address: Use 127.0.0.1 instead.
+/// * Calling with an ip address that resolves to more than 1 ip address, or none at all.
+///
+/// Use `parse_addr` to handle these instead.
+pub fn ip(ip_addr: &str) -> SocketAddr {
+    match parse_addr_with(ip_addr, LocalhostPolicy::Forbid, AddrPreference::Any) {
+        Ok(addr) => addr,
+        Err(AddrParseError::LocalhostForbidden) => {
+            panic!("because localhost can resolve to both 127.0.0.1, and the various IPV6 \
+                    versions of 127.0.0.1, it may not be used. please instead use 127.0.0.1")
+        }
+        Err(AddrParseError::Ambiguous(_)) => {
+            panic!("the given ip to net::ip() resolved to more than 1 SocketAddr")
+        }
+        Err(err) => panic!("the given ip to net::ip() could not be parsed: {}", err),
+    }
+}
+
+/// Asks the server at the address for it's `ServerInfo`, without connecting to it as a peer.
+///
+/// The timeout applies to sending the `NetworkPacket::InfoRequest` and to waiting on the answer.
+/// Opening the socket is not bounded by it.
+///
+/// # Errors
+/// * `QueryError::Io` if connecting or sending the request failed.
+/// * `QueryError::TimedOut` if the server did not answer within the timeout.
+/// * `QueryError::Closed` if the server closed the socket without answering.
+/// * `QueryError::Refused` if the server sent an error instead, such as when the address is
+///   banned.
+/// * `QueryError::BadResponse` if the server sent anything else.
+pub fn query_server(addr: SocketAddr, timeout: Duration) -> Result<ServerInfo, QueryError> {
+    let mut stream = try!(TcpStream::connect(addr));
+    try!(stream.set_read_timeout(Some(timeout)));
+    try!(stream.set_write_timeout(Some(timeout)));
+    // An InfoRequest is far below any packet size limit.
+    let request = seralize_packet(&NetworkPacket::InfoRequest, MAX_PACKET_SIZE, None).unwrap();
+    try!(stream.write_all(&request));
+    match read_packet(&mut stream, &addr, MAX_PACKET_SIZE) {
+        ReadResult::Packet(NetworkPacket::InfoResponse { name,
+                                                         players,
+                                                         max_players,
+                                                         version,
+                                                         motd }) => {
+            Ok(ServerInfo {
+                name: name,
+                players: players,
+                max_players: max_players,
+                version: version,
+                motd: motd,
+            })
+        }
+        ReadResult::Packet(NetworkPacket::Error(err)) => Err(QueryError::Refused(err)),
+        ReadResult::TimedOut => Err(QueryError::TimedOut),
+        ReadResult::Closed => Err(QueryError::Closed),
+        _ => Err(QueryError::BadResponse),
+    }
+}
+
+fn check_controller_channel(rx: Receiver<ControllerMessage>, controller: Weak<ControllerRaw>) {
+    let mut next_id: u64 = 0;
+    loop {
+        let message = match rx.recv() {
+            Ok(message) => message,
+            Err(_err) => {
+                debug!("Channel connected to controller disconnected");
+                break;
+            }
+        };
+        let controller_arc = match controller.upgrade() {
+            Some(val) => val,
+            None => {
+                debug!("All Arc pointers to Controller dropped, shutting down \
+                        net::check_controller_channel.");
+                break;
+            }
+        };
+        match message {
+            ControllerMessage::AddSocket(stream, addr, tx_id, password) => {
+                if let Some(stream) = screen_socket(&controller_arc, stream, addr) {
+                    match tx_id {
+                        Some(tx_id) => {
+                            let id = ConnectionId(next_id);
+                            next_id += 1;
+                            add_socket(&controller_arc,
+                                       id,
+                                       stream,
+                                       addr,
+                                       Some(tx_id),
+                                       Probe::none(),
+                                       password);
+                        }
+                        None => probe_socket(&controller_arc, stream, addr),
+                    }
+                }
+            }
+            ControllerMessage::AddProbedSocket(stream, addr, probe) => {
+                let id = ConnectionId(next_id);
+                next_id += 1;
+                add_socket(&controller_arc, id, stream, addr, None, probe, None);
+            }
+            ControllerMessage::AddLoopbackPair(tx_ids) => {
+                let ids = (ConnectionId(next_id), ConnectionId(next_id + 1));
+                next_id += 2;
+                add_loopback_pair(&controller_arc, ids, tx_ids);
+            }
+            ControllerMessage::RemoveSocket(id, reason) => {
+                // Kicked connections were already removed, but are still reported to the handler.
+                if controller_arc.connections.write().unwrap().remove(&id).is_some() {
+                    debug!("Removed connection {}.", id.0);
+                }
+                controller_arc.with_handler(|handler| handler.on_disconnect(id, reason));
+            }
+            ControllerMessage::ConnectionError(id, err) => {
+                controller_arc.with_handler(|handler| handler.on_error(id, err));
+            }
+            ControllerMessage::ListenerDied(id, err) => {
+                error!("Listener {} stopped accepting connections: {}", id.0, err);
+                controller_arc.listeners.lock().unwrap().remove(&id);
+                controller_arc.listener_errors.lock().unwrap().push((id, err));
+            }
+            #[cfg(test)]
+            ControllerMessage::Test(tattle) => {
+                tattle.call();
+            }
+        }
+    }
+}
+
+/// Turns an IPv4 address mapped to IPv6 back into the IPv4 address, so both count as one address.
+fn normalize_ip(addr: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = addr {
+        let segments = v6.segments();
+        if segments[..6] == [0, 0, 0, 0, 0, 0xffff] {
+            return IpAddr::V4(Ipv4Addr::new((segments[6] >> 8) as u8,
+                                            segments[6] as u8,
+                                            (segments[7] >> 8) as u8,
+                                            segments[7] as u8));
+        }
+    }
+    addr
+}
+
+/// If a ban with the given expiry time has run out.
+fn ban_expired(expires: Option<Instant>) -> bool {
+    match expires {
+        Some(expires) => Instant::now() >= expires,
+        None => false,
+    }
+}
+
+/// Gives back a new socket if it may go on to be registered.
+///
+/// If the address of the socket is banned, the peer is sent `NetworkError::Banned`. If the accept
+/// hook refuses the socket, the peer is instead sent `NetworkError::ConnectionDenied`. The socket
+/// is closed in either case.
+fn screen_socket(controller: &ControllerRaw,
+                 stream: TcpStream,
+                 addr: SocketAddr)
+                 -> Option<TcpStream> {
+    if controller.is_banned(addr.ip()) {
+        info!("Rejecting connection from {}, the address is banned.", addr);
+        reject_stream(stream, NetworkError::Banned);
+        return None;
+    }
+    let allowed = match *controller.accept_hook.lock().unwrap() {
+        Some(ref hook) => hook(&addr),
+        None => true,
+    };
+    if !allowed {
+        info!("Rejecting connection from {}, refused by the accept hook.", addr);
+        reject_stream(stream, NetworkError::ConnectionDenied);
+        return None;
+    }
+    Some(stream)
+}
+
+/// Waits on the first packet of an accepted socket on it's own thread, see `probe_stream`.
+///
+/// At most `ControllerConfig::max_clients` sockets are probed at once, and at most
+/// `ControllerConfig::max_connections_per_address` from one address, so a flood of sockets that
+/// never send anything can't start a thread for each. Sockets over either limit are closed
+/// straight away, without being sent an error, as that would take a thread as well. Registered
+/// connections aren't counted, so a full server still answers info requests.
+fn probe_socket(controller: &ControllerRaw, stream: TcpStream, addr: SocketAddr) {
+    let config = controller.config.read().unwrap().clone();
+    let ip = normalize_ip(addr.ip());
+    {
+        let mut probing = controller.probing.lock().unwrap();
+        let total = probing.values().fold(0, |total, &count| total + count);
+        let from_address = probing.get(&ip).cloned().unwrap_or(0);
+        if total >= config.max_clients || from_address >= config.max_connections_per_address {
+            info!("Dropping the socket from {}, too many sockets are waiting on their first \
+                   packet.",
+                  addr);
+            return;
+        }
+        *probing.entry(ip).or_insert(0) += 1;
+    }
+    let info = controller.server_info.read().unwrap().clone();
+    let controller_tx = controller.tx.lock().unwrap().clone();
+    let guard = ProbeGuard {
+        probing: controller.probing.clone(),
+        ip: ip,
+    };
+    thread::spawn(move || {
+        let _guard = guard;
+        probe_stream(stream, addr, config, info, controller_tx)
+    });
+}
+
+/// Takes a socket off `ControllerRaw::probing` once it's probe thread is done, even if it panics.
+struct ProbeGuard {
+    probing: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for ProbeGuard {
+    fn drop(&mut self) {
+        let mut probing = match self.probing.lock() {
+            Ok(probing) => probing,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let done = match probing.get_mut(&self.ip) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if done {
+            probing.remove(&self.ip);
+        }
+    }
+}
+
+/// Waits up to PROBE_MILLIS for the first packet of an accepted socket.
+///
+/// A `NetworkPacket::InfoRequest` is answered with the info, and the socket is closed without
+/// ever being registered. Otherwise the socket is sent back to the controller with
+/// `ControllerMessage::AddProbedSocket`, along with the packet if one arrived. Sockets that close
+/// or fail before then are dropped.
+fn probe_stream(mut stream: TcpStream,
+                addr: SocketAddr,
+                config: ControllerConfig,
+                info: ServerInfo,
+                controller_tx: Sender<ControllerMessage>) {
+    let started = Instant::now();
+    let handshake_timeout = Duration::from_millis(config.handshake_timeout_millis);
+    let wait = cmp::min(Duration::from_millis(PROBE_MILLIS), handshake_timeout);
+    let mut first_byte: [u8; 1] = [0; 1];
+    let read = stream.set_read_timeout(Some(wait)).and_then(|()| stream.read(&mut first_byte));
+    let first_packet = match read {
+        Ok(0) => {
+            debug!("Socket from {} closed before sending anything.", addr);
+            return;
+        }
+        Ok(_) => {
+            // Once the peer has started on a packet, it has the rest of it's handshake timeout to
+            // finish it.
+            let elapsed = started.elapsed();
+            if elapsed >= handshake_timeout {
+                return;
+            }
+            if let Err(err) = stream.set_read_timeout(Some(handshake_timeout - elapsed)) {
+                warn!("Failed to set the handshake timeout on socket with address {}: {}",
+                      addr,
+                      err);
+                return;
+            }
+            let result = {
+                let mut reader = (&first_byte[..]).chain(&mut stream);
+                read_packet(&mut reader, &addr, config.max_packet_size)
+            };
+            match result {
+                ReadResult::Packet(NetworkPacket::InfoRequest) => {
+                    debug!("Answering an info request from {}.", addr);
+                    close_with(stream, info.to_packet());
+                    return;
+                }
+                ReadResult::Packet(packet) => Some(packet),
+                ReadResult::Malformed | ReadResult::Corrupt => None,
+                ReadResult::TooLarge(len) => {
+                    warn!("Socket from {} announced a first packet of {} bytes, above the maximum \
+                           of {} bytes. Dropping it.",
+                          addr,
+                          len,
+                          config.max_packet_size);
+                    return;
+                }
+                ReadResult::Closed | ReadResult::TimedOut => return,
+            }
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                        err.kind() == io::ErrorKind::TimedOut => None,
+        Err(err) => {
+            info!("Failed to read from socket with address {}: {}", addr, err);
+            return;
+        }
+    };
+    let probe = Probe {
+        started: started,
+        first_packet: first_packet,
+    };
+    // The controller may already be gone, in which case the socket is just dropped.
+    let _ = controller_tx.send(ControllerMessage::AddProbedSocket(stream, addr, probe));
+}
+
+/// Spins up the threads for a socket, or hands it to the IO thread under `IoModel::Polled`, and
+/// registers it as a connection with the given id.
+///
+/// The socket must already have been through `screen_socket`. If the controller already has
+/// `ControllerConfig::max_clients` connections, the peer is sent `NetworkError::ServerFull`.
+/// Accepted sockets from an address with `ControllerConfig::max_connections_per_address`
+/// connections are sent `NetworkError::TooManyConnectionsFromAddress`. The socket is closed in
+/// either case.
+///
+/// Accepted sockets must prove `ControllerConfig::password`, while sockets opened with
+/// `Controller::connect` prove the given password if they are challenged.
+fn add_socket(controller: &ControllerRaw,
+              id: ConnectionId,
+              stream: TcpStream,
+              addr: SocketAddr,
+              tx_id: Option<Sender<ConnectionId>>,
+              probe: Probe,
+              password: Option<String>) {
+    let config = controller.config.read().unwrap().clone();
+    if controller.connections.read().unwrap().len() >= config.max_clients {
+        info!("Rejecting connection from {}, the server is full.", addr);
+        reject_stream(stream, NetworkError::ServerFull);
+        return;
+    }
+    let accepted = tx_id.is_none();
+    if accepted && controller.connections_from(addr.ip()) >= config.max_connections_per_address {
+        info!("Rejecting connection from {}, it has too many connections already.", addr);
+        reject_stream(stream, NetworkError::TooManyConnectionsFromAddress);
+        return;
+    }
+    // Packets are already joined together by the send thread, so Nagle's algorithm would only add
+    // latency.
+    if let Err(err) = stream.set_nodelay(true) {
+        warn!("Failed to set TCP_NODELAY on a newly added socket: {}", err);
+        return;
+    }
+    let password = if accepted {
+        config.password.clone()
+    } else {
+        password
+    };
+    let result = match config.io_model {
+        IoModel::Threaded => {
+            spawn_stream_threads(controller, stream, addr, id, accepted, config, probe, password)
+        }
+        IoModel::Polled => {
+            register_polled(controller, stream, addr, id, accepted, config, probe, password)
+        }
+    };
+    let connection = match result {
+        Ok(connection) => connection,
+        Err(err) => {
+            warn!("Failed to set up a newly added socket: {}", err);
+            return;
+        }
+    };
+    controller.connections.write().unwrap().insert(id, connection);
+    if let Some(tx_id) = tx_id {
+        let _ = tx_id.send(id);
+    }
+    controller.with_handler(|handler| handler.on_connect(id, addr));
+}
+
+/// Spins up the threads for both ends of a new loopback pair, registering them with the given ids.
+///
+/// The first end sends it's Init straight away, like a socket opened with `Controller::connect`.
+/// Both ends use `ControllerConfig::password`, so the first can answer the second's challenge.
+fn add_loopback_pair(controller: &ControllerRaw,
+                     ids: (ConnectionId, ConnectionId),
+                     tx_ids: Sender<(ConnectionId, ConnectionId)>) {
+    let config = controller.config.read().unwrap().clone();
+    let (first_stream, second_stream) = loopback_pair();
+    let addr = ip("127.0.0.1:0");
+    let first = spawn_stream_threads(controller,
+                                     first_stream,
+                                     addr,
+                                     ids.0,
+                                     false,
+                                     config.clone(),
+                                     Probe::none(),
+                                     config.password.clone());
+    let second = spawn_stream_threads(controller,
+                                      second_stream,
+                                      addr,
+                                      ids.1,
+                                      true,
+                                      config.clone(),
+                                      Probe::none(),
+                                      config.password.clone());
+    let (first, second) = match (first, second) {
+        (Ok(first), Ok(second)) => (first, second),
+        (Err(err), _) | (_, Err(err)) => {
+            warn!("Failed to set up a loopback pair: {}", err);
+            return;
+        }
+    };
+    let init = local_init(config.compression,
+                          config.should_crash(),
+                          &config.display_name,
+                          None);
+    let _ = first.channel.lock().unwrap().try_send(ConnectionMessage::SendPacket(init));
+    {
+        let mut connections = controller.connections.write().unwrap();
+        connections.insert(ids.0, first);
+        connections.insert(ids.1, second);
+    }
+    let _ = tx_ids.send(ids);
+    controller.with_handler(|handler| {
+        handler.on_connect(ids.0, addr);
+        handler.on_connect(ids.1, addr);
+    });
+}
+
+/// Accepts sockets from the listener and sends them to the controller, untill shutdown is set.
+///
+/// Transient errors are logged and ignored. Other errors stop the listener and are reported with
+/// `ControllerMessage::ListenerDied` if `::should_crash()` is true, or are retried after a
+/// delay otherwise.
+fn check_listener(id: ListenerId,
+                  listener: TcpListener,
+                  controller_tx: Sender<ControllerMessage>,
+                  shutdown: Arc<AtomicBool>) {
+    while !shutdown.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                // Some platforms have accepted sockets inherit non-blocking mode from the listener.
+                if let Err(err) = stream.set_nonblocking(false) {
+                    warn!("Failed to make the socket from {} blocking: {}", addr, err);
+                    continue;
+                }
+                let message = ControllerMessage::AddSocket(stream, addr, None, None);
+                match controller_tx.send(message) {
+                    Ok(()) => {}
+                    Err(_err) => {
+                        debug!("listener {:?} stopped accepting because of channel close",
+                               listener);
+                        break;
+                    }
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(LISTENER_POLL_MILLIS));
+            }
+            Err(ref err) if is_transient_accept_error(err.kind()) => {
+                debug!("Listener {} failed to accept a socket: {}", id.0, err);
+            }
+            Err(err) => {
+                if ::should_crash() {
+                    let _ = controller_tx.send(ControllerMessage::ListenerDied(id, err));
+                    break;
+                }
+                warn!("Listener {} failed to accept a socket, retrying: {}", id.0, err);
+                thread::sleep(Duration::from_millis(LISTENER_RETRY_MILLIS));
+            }
+        }
+    }
+}
+
+/// If an error from accepting a socket only affected that socket, so the listener can carry on.
+fn is_transient_accept_error(kind: io::ErrorKind) -> bool {
+    match kind {
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::Interrupted |
+        io::ErrorKind::WouldBlock => true,
+        _ => false,
+    }
+}
+
+/// Writes the packets sent through rx to the stream, untill it is told to close.
+///
+/// Once a message arrives, everything else already queued is taken onto it's lane as well, up to
+/// config.send_queue_depth packets across the lanes, and
+/// packets are framed from the lanes as picked by `Lanes::next_lane`. They are written together
+/// once the lanes are empty or config.flush_size bytes have built up, before looking for more
+/// messages.
+///
+/// Bodies are compressed once compress is set by the recv thread, after both peers offered it.
+fn check_stream_send<T: Transport>(rx: Receiver<ConnectionMessage>,
+                                   mut stream: T,
+                                   config: ControllerConfig,
+                                   stats: Arc<ConnectionCounters>,
+                                   compress: Arc<AtomicBool>) {
+    let keepalive_interval = Duration::from_millis(config.keepalive_millis);
+    let peer_addr = stream.peer_addr();
+    let mut last_sent = Instant::now();
+    let mut buffer = WriteBuffer::new();
+    let mut lanes = Lanes::new();
+    loop {
+        // Packets still queued on a lane mean not waiting for anything new.
+        let received = if lanes.is_empty() {
+            rx.recv().ok()
+        } else {
+            match rx.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => Some(ConnectionMessage::DoNothing),
+                Err(TryRecvError::Disconnected) => None,
+            }
+        };
+        let mut message = match received {
+            Some(message) => message,
+            None => {
+                debug!("Channel connected to connection disconnected, shutting down \
+                        net::check_stream_send.");
+                break;
+            }
+        };
+        let compress_above = if compress.load(Ordering::SeqCst) {
+            Some(config.compression_threshold)
+        } else {
+            None
+        };
+        let mut close = false;
+        loop {
+            match message {
+                ConnectionMessage::DoNothing => {}
+                ConnectionMessage::Close => {
+                    close = true;
+                    break;
+                }
+                ConnectionMessage::Keepalive => {
+                    // Anything else being sent keeps the connection alive just as well.
+                    if lanes.is_empty() && last_sent.elapsed() >= keepalive_interval {
+                        let ping = NetworkPacket::Ping(stats.ping_timestamp());
+                        lanes.push(&ping, Priority::Control);
+                    }
+                }
+                ConnectionMessage::SendPacket(packet) => {
+                    let priority = packet.priority();
+                    lanes.push(&packet, priority);
+                }
+                ConnectionMessage::SendWithPriority(packet, priority) => {
+                    lanes.push(&packet, priority);
+                }
+            }
+            // Leaving the rest in the channel keeps it full, so senders see the backpressure.
+            if lanes.len() >= config.send_queue_depth {
+                break;
+            }
+            // A disconnected channel is noticed by the blocking recv once this is written.
+            message = match rx.try_recv() {
+                Ok(message) => message,
+                Err(_err) => break,
+            };
+        }
+        // Once closing, everything still queued is written before the stream is shut down.
+        while close || buffer.len() < config.flush_size {
+            if !lanes.frame_next(&mut buffer, &config, compress_above, &peer_addr) {
+                break;
+            }
+        }
+        if !buffer.is_empty() {
+            if !buffer.flush(&mut stream, &stats) {
+                break;
+            }
+            last_sent = Instant::now();
+        }
+        if close {
+            let _ = stream.shutdown(Shutdown::Write);
+            thread::sleep(Duration::from_millis(CLOSE_DRAIN_MILLIS));
+            let _ = stream.shutdown(Shutdown::Both);
+            break;
+        }
+    }
+}
+
+/// The packets a send thread has taken from it's channel but not yet framed, with a queue for
+/// every `Priority`.
+struct Lanes {
+    queues: [VecDeque<Outgoing>; LANES],
+    /// How many times in a row each lane was passed over for a higher one while it had something
+    /// queued.
+    deficits: [usize; LANES],
+    next_transfer_id: u32,
+}
+
+/// A packet queued on a lane, serialized but not yet framed.
+struct Outgoing {
+    body: Vec<u8>,
+    /// The channel of the packet if it is a Message, or an empty string.
+    channel: String,
+    /// The id of the packet's transfer once it's FragmentStart is framed, if it is sent in
+    /// fragments.
+    transfer: Option<u32>,
+    /// How many bytes of the body have been framed in Fragments.
+    sent: usize,
+}
+
+impl Lanes {
+    fn new() -> Lanes {
+        Lanes {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            deficits: [0; LANES],
+            next_transfer_id: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    /// The number of packets queued on every lane.
+    fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    /// Queues the packet on the end of the lane.
+    fn push(&mut self, packet: &NetworkPacket, priority: Priority) {
+        let channel = match *packet {
+            NetworkPacket::Message { ref channel, .. } => channel.clone(),
+            _ => String::new(),
+        };
+        self.queues[priority.index()].push_back(Outgoing {
+            // Since the size limit is infinite and it's not encoding to a stream, there is no
+            // error.
+            body: serialize(packet, SizeLimit::Infinite).unwrap(),
+            channel: channel,
+            transfer: None,
+            sent: 0,
+        });
+    }
+
+    /// Picks the lane to frame from next, or None if every lane is empty.
+    ///
+    /// That is the highest lane with anything queued, unless a lower one has been passed over
+    /// MAX_LANE_DEFICIT times in a row, so every lane keeps making progress.
+    fn next_lane(&mut self) -> Option<usize> {
+        let highest = match (0..LANES).find(|&lane| !self.queues[lane].is_empty()) {
+            Some(lane) => lane,
+            None => return None,
+        };
+        let starved = (highest + 1..LANES).rev().find(|&lane| {
+            !self.queues[lane].is_empty() && self.deficits[lane] >= MAX_LANE_DEFICIT
+        });
+        let lane = starved.unwrap_or(highest);
+        for other in lane + 1..LANES {
+            if !self.queues[other].is_empty() {
+                self.deficits[other] += 1;
+            }
+        }
+        self.deficits[lane] = 0;
+        Some(lane)
+    }
+
+    /// Frames the next packet, or piece of a fragmented packet, from the lane picked by
+    /// `next_lane` onto the buffer.
+    ///
+    /// Bodies above config.fragment_threshold are framed as a FragmentStart, then a Fragment at a
+    /// time, then a FragmentEnd, each taking a turn of their own. Returns false if every lane is
+    /// empty.
+    fn frame_next(&mut self,
+                  buffer: &mut WriteBuffer,
+                  config: &ControllerConfig,
+                  compress_above: Option<usize>,
+                  peer: &Debug)
+                  -> bool {
+        let lane = match self.next_lane() {
+            Some(lane) => lane,
+            None => return false,
+        };
+        let priority = Priority::from_index(lane);
+        let max_packet_size = config.max_packet_size;
+        let done = {
+            let outgoing = self.queues[lane].front_mut().unwrap();
+            match outgoing.transfer {
+                None if outgoing.body.len() <= config.fragment_threshold => {
+                    let body = mem::replace(&mut outgoing.body, Vec::new());
+                    buffer.push_body(body, max_packet_size, compress_above, priority, peer);
+                    true
+                }
+                None => {
+                    let id = self.next_transfer_id;
+                    self.next_transfer_id = id.wrapping_add(1);
+                    let start = NetworkPacket::FragmentStart {
+                        id: id,
+                        total_len: outgoing.body.len() as u32,
+                        channel: outgoing.channel.clone(),
+                    };
+                    buffer.push(&start, max_packet_size, compress_above, priority, peer);
+                    outgoing.transfer = Some(id);
+                    false
+                }
+                Some(id) if outgoing.sent == outgoing.body.len() => {
+                    let fragment_end = NetworkPacket::FragmentEnd {
+                        id: id,
+                        checksum: crc32(&outgoing.body),
+                    };
+                    buffer.push(&fragment_end, max_packet_size, compress_above, priority, peer);
+                    true
+                }
+                Some(id) => {
+                    let end = cmp::min(outgoing.sent + config.fragment_threshold,
+                                       outgoing.body.len());
+                    let fragment = NetworkPacket::Fragment {
+                        id: id,
+                        offset: outgoing.sent as u32,
+                        data: outgoing.body[outgoing.sent..end].to_vec(),
+                    };
+                    buffer.push(&fragment, max_packet_size, compress_above, priority, peer);
+                    outgoing.sent = end;
+                    false
+                }
+            }
+        };
+        if done {
+            self.queues[lane].pop_front();
+        }
+        true
+    }
+}
+
+/// Framed packets waiting to be written to a stream together.
+struct WriteBuffer {
+    bytes: Vec<u8>,
+    /// The length of each packet in bytes and the lane it was framed from, so they can be
+    /// counted once they are written.
+    packet_lens: Vec<(usize, Priority)>,
+    /// How many bytes of the first packet in packet_lens have already been written, if it was cut
+    /// short by `write_nonblocking`.
+    written: usize,
+}
+
+impl WriteBuffer {
+    fn new() -> WriteBuffer {
+        WriteBuffer {
+            bytes: Vec::new(),
+            packet_lens: Vec::new(),
+            written: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Frames the packet onto the end of the buffer, like `seralize_packet`.
+    ///
+    /// Packets larger than max_packet_size are logged and dropped, along with the peer they were
+    /// meant for.
+    fn push(&mut self,
+            packet: &NetworkPacket,
+            max_packet_size: u32,
+            compress_above: Option<usize>,
+            priority: Priority,
+            peer: &Debug) {
+        // Since the size limit is infinite and it's not encoding to a stream, there is no error.
+        let body = serialize(packet, SizeLimit::Infinite).unwrap();
+        self.push_body(body, max_packet_size, compress_above, priority, peer);
+    }
+
+    /// Frames an already serialized body onto the end of the buffer, like `frame_body`.
+    fn push_body(&mut self,
+                 body: Vec<u8>,
+                 max_packet_size: u32,
+                 compress_above: Option<usize>,
+                 priority: Priority,
+                 peer: &Debug) {
+        match frame_body(body, max_packet_size, compress_above) {
+            Ok(bytes) => {
+                self.bytes.extend_from_slice(&bytes);
+                self.packet_lens.push((bytes.len(), priority));
+            }
+            Err(err) => {
+                warn!("Not sending a packet to socket with address {:?}: {}", peer, err);
+            }
+        }
+    }
+
+    /// Writes everything in the buffer to the stream with a single write, shutting the stream down
+    /// if that fails.
+    ///
+    /// The packets written are counted in stats, and the buffer is emptied. Returns false if
+    /// writing failed.
+    fn flush<T: Transport>(&mut self, stream: &mut T, stats: &ConnectionCounters) -> bool {
+        let result = stream.write_frame(&self.bytes);
+        self.bytes.clear();
+        if let Err(err) = result {
+            info!("Failed to write to socket with address {:?}, shutting it down. display: {}",
+                  stream.peer_addr(),
+                  err);
+            let _ = stream.shutdown(Shutdown::Both);
+            self.packet_lens.clear();
+            return false;
+        }
+        for (len, priority) in self.packet_lens.drain(..) {
+            stats.record_sent(len, priority);
+        }
+        true
+    }
+
+    /// Writes as much of the buffer as a non-blocking stream takes, without waiting for it.
+    ///
+    /// Whatever was written is taken off the front of the buffer, and packets are counted in stats
+    /// once they have been written whole. Returns true once the buffer is empty.
+    fn write_nonblocking<W: Write>(&mut self,
+                                   stream: &mut W,
+                                   stats: &ConnectionCounters)
+                                   -> Result<bool, io::Error> {
+        let mut written = 0;
+        while written < self.bytes.len() {
+            match stream.write(&self.bytes[written..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "the stream stopped taking bytes"))
+                }
+                Ok(len) => written += len,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.bytes.drain(..written);
+        self.written += written;
+        let mut done = 0;
+        for &(len, priority) in &self.packet_lens {
+            if len > self.written {
+                break;
+            }
+            self.written -= len;
+            stats.record_sent(len, priority);
+            done += 1;
+        }
+        self.packet_lens.drain(..done);
+        Ok(self.bytes.is_empty())
+    }
+}
+
+/// Converts a duration to whole nanoseconds.
+fn duration_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1000000000 + duration.subsec_nanos() as u64
+}
+
+/// Converts whole nanoseconds to a duration.
+fn nanos_duration(nanos: u64) -> Duration {
+    Duration::new(nanos / 1000000000, (nanos % 1000000000) as u32)
+}
+
+/// The current time in milliseconds since the unix epoch, as kept in `ConnectionStats`.
+fn timestamp_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() * 1000 + (since.subsec_nanos() / 1000000) as u64,
+        Err(_err) => 0,
+    }
+}
+
+/// Periodically tells the send thread of a connection to check if it should send a Ping.
+///
+/// Exits once the connection is closing. A full queue is skipped over, since it will be sending
+/// anyway.
+fn check_keepalive(tx: SyncSender<ConnectionMessage>,
+                   state: Arc<Mutex<ConnectionState>>,
+                   interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        if *state.lock().unwrap() == ConnectionState::Closing {
+            break;
+        }
+        if let Err(TrySendError::Disconnected(_)) = tx.try_send(ConnectionMessage::Keepalive) {
+            break;
+        }
+    }
+}
+
+/// Sends the error to a peer that has not been registered as a connection, then closes it.
+///
+/// This is done on it's own thread with `close_with`.
+fn reject_stream(stream: TcpStream, err: NetworkError) {
+    thread::spawn(move || close_with(stream, NetworkPacket::Error(err)));
+}
+
+/// Sends the packet to a peer that has not been registered as a connection, then closes it.
+///
+/// Anything the peer already sent is read and discarded before closing. Closing with unread data
+/// resets the connection, which can make the peer lose the packet. Any errors are ignored, since
+/// the stream is being closed anyway.
+fn close_with(mut stream: TcpStream, packet: NetworkPacket) {
+    if let Ok(bytes) = seralize_packet(&packet, MAX_PACKET_SIZE, None) {
+        let _ = stream.write_all(&bytes).and_then(|()| stream.flush());
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(CLOSE_DRAIN_MILLIS)));
+    let mut buf: [u8; 256] = [0; 256];
+    while let Ok(read) = stream.read(&mut buf) {
+        if read == 0 {
+            break;
+        }
+    }
+}
+
+/// Spins up the send and recv threads for a stream.
+///
+/// Returns the connection, starting out handshaking, with the channel used to send messages to the
+/// send thread.
+fn spawn_stream_threads<T: Transport>(controller: &ControllerRaw,
+                                      stream: T,
+                                      addr: SocketAddr,
+                                      id: ConnectionId,
+                                      accepted: bool,
+                                      config: ControllerConfig,
+                                      probe: Probe,
+                                      password: Option<String>)
+                                      -> Result<Connection, io::Error> {
+    let stream_clone = try!(stream.try_clone());
+    let closer = Arc::new(StreamCloser::new(try!(stream.try_clone())));
+    let (tx, rx) = sync_channel(config.send_queue_depth);
+    let state = recv_state(controller,
+                           addr,
+                           id,
+                           accepted,
+                           &config,
+                           probe,
+                           password,
+                           ConnectionSender::Threaded(tx.clone()),
+                           closer);
+    let connection = state.connection();
+    let send_stats = state.stats.clone();
+    let send_compress = state.compress.clone();
+    let keepalive_interval = Duration::from_millis(config.keepalive_millis);
+    let keepalive_state = state.state.clone();
+    thread::spawn(move || check_stream_send(rx, stream, config, send_stats, send_compress));
+    thread::spawn(move || check_stream_recv(stream_clone, state));
+    thread::spawn(move || check_keepalive(tx, keepalive_state, keepalive_interval));
+    Ok(connection)
+}
+
+/// Hands a socket to the controller's IO thread, starting it if this is the first, see
+/// `IoModel::Polled`.
+///
+/// Returns the connection, starting out handshaking, with the channel used to send messages to the
+/// IO thread.
+fn register_polled(controller: &ControllerRaw,
+                   stream: TcpStream,
+                   addr: SocketAddr,
+                   id: ConnectionId,
+                   accepted: bool,
+                   config: ControllerConfig,
+                   probe: Probe,
+                   password: Option<String>)
+                   -> Result<Connection, io::Error> {
+    let closer = Arc::new(StreamCloser::new(try!(stream.try_clone())));
+    let (tx, rx) = mio::channel::sync_channel(config.send_queue_depth);
+    let state = recv_state(controller,
+                           addr,
+                           id,
+                           accepted,
+                           &config,
+                           probe,
+                           password,
+                           ConnectionSender::Polled(tx),
+                           closer);
+    let connection = state.connection();
+    let stream = try!(mio::tcp::TcpStream::from_stream(stream));
+    let mut io_loop = controller.io_loop.lock().unwrap();
+    if io_loop.is_none() {
+        *io_loop = Some(try!(IoLoop::start()));
+    }
+    try!(io_loop.as_ref().unwrap().add(stream, rx, state, config));
+    Ok(connection)
+}
+
+/// The recv side of a new connection, starting out handshaking, which replies to the peer through
+/// connection_tx.
+fn recv_state(controller: &ControllerRaw,
+              addr: SocketAddr,
+              id: ConnectionId,
+              accepted: bool,
+              config: &ControllerConfig,
+              probe: Probe,
+              password: Option<String>,
+              connection_tx: ConnectionSender,
+              closer: Arc<StreamCloser>)
+              -> RecvState {
+    RecvState {
+        addr: addr,
+        id: id,
+        accepted: accepted,
+        incoming_tx: controller.incoming_tx.lock().unwrap().clone(),
+        controller_tx: controller.tx.lock().unwrap().clone(),
+        connection_tx: connection_tx,
+        closer: closer,
+        state: Arc::new(Mutex::new(ConnectionState::Handshaking)),
+        kick_reason: Arc::new(Mutex::new(None)),
+        idle_timeout: Duration::from_millis(config.idle_timeout_millis),
+        handshake_timeout: Duration::from_millis(config.handshake_timeout_millis),
+        started: probe.started,
+        first_packet: probe.first_packet,
+        max_packet_size: config.max_packet_size,
+        oversized_packets: controller.oversized_packets.clone(),
+        subscribers: controller.subscribers.clone(),
+        stats: Arc::new(ConnectionCounters::new(controller.started)),
+        rate_limit: config.rate_limit,
+        compression: config.compression,
+        should_crash: config.should_crash(),
+        display_name: config.display_name.clone(),
+        password: password,
+        compress: Arc::new(AtomicBool::new(false)),
+        max_fragmented_transfers: config.max_fragmented_transfers,
+        max_fragment_bytes: config.max_fragment_bytes,
+    }
+}
+
+/// Everything the recv thread of a connection needs to talk to the rest of the controller.
+struct RecvState {
+    addr: SocketAddr,
+    id: ConnectionId,
+    /// If the socket was accepted by a listener, as opposed to opened with `Controller::connect`.
+    ///
+    /// The accepting side answers a valid Init with it's own, so both peers validate each other.
+    accepted: bool,
+    incoming_tx: Sender<(ConnectionId, NetworkPacket)>,
+    controller_tx: Sender<ControllerMessage>,
+    /// Used to send replies to the peer, such as the Init or errors for a failed handshake.
+    ///
+    /// Nothing is sent through it if the queue is full, since the peer is not reading anyway.
+    connection_tx: ConnectionSender,
+    /// Shared with the connection, and used to close the stream if Close can't be queued.
+    closer: Arc<StreamCloser>,
+    /// The same state as the connection registered with the controller.
+    state: Arc<Mutex<ConnectionState>>,
+    /// The reason the connection was kicked with, shared with the connection.
+    kick_reason: Arc<Mutex<Option<String>>>,
+    /// Used as the read timeout once the handshake is done.
+    idle_timeout: Duration,
+    /// How long after started a valid Init must be received.
+    handshake_timeout: Duration,
+    /// When the socket was accepted or connected.
+    started: Instant,
+    /// A packet read before the recv thread started, which it handles before reading any more.
+    first_packet: Option<NetworkPacket>,
+    max_packet_size: u32,
+    /// Shared with the controller, and incremented when the peer announces a packet above
+    /// max_packet_size.
+    oversized_packets: Arc<AtomicUsize>,
+    subscribers: Arc<Mutex<HashMap<String, Vec<Sender<(ConnectionId, Vec<u8>)>>>>>,
+    /// The same stats as the connection registered with the controller.
+    stats: Arc<ConnectionCounters>,
+    rate_limit: Option<RateLimit>,
+    /// The compression offered in the local Init.
+    compression: Compression,
+    /// The should_crash sent in the local Init.
+    should_crash: bool,
+    /// The display_name sent in the local Init.
+    display_name: String,
+    /// If the socket was accepted, the password the peer must prove. Otherwise the password proven
+    /// to the peer when it sends a Challenge.
+    password: Option<String>,
+    /// Shared with the send thread, and set once both peers offered the same compression.
+    compress: Arc<AtomicBool>,
+    max_fragmented_transfers: usize,
+    max_fragment_bytes: usize,
+}
+
+impl RecvState {
+    /// The connection to register with the controller, sharing everything it shares with the
+    /// recv side.
+    fn connection(&self) -> Connection {
+        Connection {
+            id: self.id,
+            peer_addr: self.addr,
+            channel: Mutex::new(self.connection_tx.clone()),
+            state: self.state.clone(),
+            stats: self.stats.clone(),
+            kick_reason: self.kick_reason.clone(),
+            closer: self.closer.clone(),
+            is_admin: AtomicBool::new(false),
+            rcon_failures: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// The tokens a connection has left under it's RateLimit.
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+    /// When the connection last found the bucket empty, if it has not found a token waiting since.
+    throttled_since: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// Constructs a full bucket.
+    fn new(limit: RateLimit) -> TokenBucket {
+        TokenBucket {
+            limit: limit,
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+            throttled_since: None,
+        }
+    }
+
+    /// Takes a token if there is one, or returns how long untill the next one is gained.
+    fn take(&mut self) -> Option<Duration> {
+        let elapsed = self.last_refill.elapsed();
+        self.last_refill = Instant::now();
+        let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed_secs * self.limit.packets_per_second as f64)
+                          .min(self.limit.burst as f64);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        if self.limit.packets_per_second == 0 {
+            // No tokens are ever gained, so check back at a leisurely pace.
+            return Some(Duration::from_millis(LISTENER_RETRY_MILLIS));
+        }
+        let wait_secs = (1.0 - self.tokens) / self.limit.packets_per_second as f64;
+        Some(Duration::new(wait_secs as u64, (wait_secs.fract() * 1e9) as u32))
+    }
+}
+
+/// Counts every byte read from the stream it wraps in bytes_received.
+struct CountingReader<'a, T: Transport> {
+    stream: T,
+    stats: &'a ConnectionCounters,
+}
+
+impl<'a, T: Transport> Read for CountingReader<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let read = try!(self.stream.read_frame(buf));
+        self.stats.bytes_received.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+/// Reads packets from the stream and forwards them to incoming_tx untill the connection closes.
 ///
-/// Reexported incase it is of use for something not-networking.
-pub const NET_MAGIC_NUMBER: u32 = 0xCB011043; //0xcafebade + 0x25565, because programming references.
+/// The first packet from the peer must be a `NetworkPacket::Init` compatible with the local game,
+/// and it must arrive within HANDSHAKE_TIMEOUT_MILLIS. Otherwise the connection is dropped.
+/// Any other packets that aren't control packets are discarded untill then.
+///
+/// A `NetworkPacket::Disconnect` is forwarded like any other packet, so the reason reaches the
+/// embedder, then the connection is closed cleanly.
+///
+/// Once it closes, the connection is moved to `ConnectionState::Closing`, it's send thread is told
+/// to close the stream after anything already queued, and it is removed from the controller with
+/// `ControllerMessage::RemoveSocket`.
+fn check_stream_recv<T: Transport>(stream: T, mut state: RecvState) {
+    let first_packet = state.first_packet.take();
+    let reason = recv_packets(stream, &state, first_packet);
+    finish_recv(&state, reason);
+}
 
-/// The maximum number of clients allowed to be connected at one time.
+/// Moves the connection to Closing once it's recv side is done, and tells the controller why.
 ///
-/// Eventually this should be removed and replaced with something configurable.
-pub const MAX_CONNECTED_CLIENTS: usize = 30;
+/// See `check_stream_recv`.
+fn finish_recv(state: &RecvState, mut reason: DisconnectReason) {
+    {
+        let mut connection_state = state.state.lock().unwrap();
+        // The stream was closed from under the recv thread by the connection being kicked.
+        if *connection_state == ConnectionState::Closing {
+            let kick_reason = state.kick_reason.lock().unwrap().clone();
+            reason = DisconnectReason::Kicked(kick_reason.unwrap_or_default());
+        }
+        *connection_state = ConnectionState::Closing;
+    }
+    if let Err(TrySendError::Full(_)) = state.connection_tx.try_send(ConnectionMessage::Close) {
+        state.closer.close();
+    }
+    // The controller may already be gone, in which case there is nothing to remove from.
+    let _ = state.controller_tx.send(ControllerMessage::RemoveSocket(state.id, reason));
+}
 
-/// Holds all state for networking.
+/// Reads packets untill the connection closes, returning why it did.
 ///
-/// Has no notion of client or server. A client can listen, if that would ever be useful.
-#[derive(Debug, Clone)]
-pub struct Controller {
-    pub raw: Arc<ControllerRaw>,
+/// The first packet is handled before anything is read, if one is given.
+fn recv_packets<T: Transport>(stream: T,
+                              state: &RecvState,
+                              mut first_packet: Option<NetworkPacket>)
+                              -> DisconnectReason {
+    let handshake_deadline = state.started + state.handshake_timeout;
+    let mut reader = CountingReader {
+        stream: stream,
+        stats: &state.stats,
+    };
+    let mut bucket = state.rate_limit.map(TokenBucket::new);
+    let mut session = RecvSession::new(state);
+    loop {
+        if !session.handshake_done {
+            // Only waits for what is left of the deadline, so packets other than an Init do not
+            // extend it.
+            let now = Instant::now();
+            if now >= handshake_deadline {
+                info!("Peer with ip {} did not finish it's handshake in time, dropping the \
+                       connection.",
+                      state.addr);
+                return DisconnectReason::TimedOut;
+            }
+            if let Err(err) = reader.stream.set_read_timeout(Some(handshake_deadline - now)) {
+                warn!("Failed to set the handshake timeout on socket with address {}: {}",
+                      state.addr,
+                      err);
+                return DisconnectReason::Closed;
+            }
+        }
+        let result = match first_packet.take() {
+            Some(packet) => ReadResult::Packet(packet),
+            None => read_packet(&mut reader, &state.addr, state.max_packet_size),
+        };
+        if let ReadResult::Packet(_) = result {
+            state.stats.record_received();
+            if let Some(ref mut bucket) = bucket {
+                if !wait_for_token(bucket, state) {
+                    return send_error(state, NetworkError::RateLimited);
+                }
+            }
+        }
+        let handshaking = !session.handshake_done;
+        if let Err(reason) = session.handle(state, result) {
+            return reason;
+        }
+        if handshaking && session.handshake_done {
+            if let Err(err) = reader.stream.set_read_timeout(Some(state.idle_timeout)) {
+                warn!("Failed to set the idle timeout on socket with address {}: {}",
+                      state.addr,
+                      err);
+                return DisconnectReason::Closed;
+            }
+        }
+    }
 }
 
-impl Controller {
-    /// Contructs a new Controller, without any connection or listeners.
+/// Everything the recv side of a connection keeps between packets.
+struct RecvSession {
+    handshake_done: bool,
+    /// The nonce the peer was challenged with, once it's first Init has arrived.
+    challenge: Option<u64>,
+    corrupt_packets: usize,
+    reassembly: Reassembly,
+}
+
+impl RecvSession {
+    fn new(state: &RecvState) -> RecvSession {
+        RecvSession {
+            handshake_done: false,
+            challenge: None,
+            corrupt_packets: 0,
+            reassembly: Reassembly::new(state.max_fragmented_transfers, state.max_fragment_bytes),
+        }
+    }
+
+    /// Handles the result of reading a packet, returning why the connection closed if it did.
     ///
-    /// This spawns a new thread to check multithreading channels.
-    pub fn new_empty() -> Controller {
-        let (tx, rx) = channel::<ControllerMessage>();
-        let self_raw = Arc::from(ControllerRaw {
-            connections: RwLock::new(Vec::new()),
-            tx: Mutex::new(tx),
-        });
-        let self_raw_clone = Arc::downgrade(&self_raw);
-        thread::spawn(move || {
-            check_controller_channel(rx, self_raw_clone);
-        });
-        Controller { raw: self_raw }
+    /// The first packet from the peer must be a `NetworkPacket::Init` compatible with the local
+    /// game. Any other packets that aren't control packets are discarded untill then. Received
+    /// packets must already have been counted and let through the connection's RateLimit.
+    fn handle(&mut self, state: &RecvState, result: ReadResult) -> Result<(), DisconnectReason> {
+        let packet = match result {
+            ReadResult::Packet(packet) => packet,
+            ReadResult::Malformed => return Ok(()),
+            ReadResult::TooLarge(len) => {
+                warn!("Peer with ip {} announced a packet of {} bytes, above the maximum of {} \
+                       bytes. Dropping the connection.",
+                      state.addr,
+                      len,
+                      state.max_packet_size);
+                state.oversized_packets.fetch_add(1, Ordering::SeqCst);
+                return Err(DisconnectReason::PacketTooLarge(len));
+            }
+            ReadResult::Corrupt => {
+                self.corrupt_packets += 1;
+                if self.corrupt_packets >= MAX_CORRUPT_PACKETS {
+                    info!("Closing connection with ip {} after {} packets with a bad checksum.",
+                          state.addr,
+                          self.corrupt_packets);
+                    return Err(send_error(state, NetworkError::CorruptStream));
+                }
+                return Ok(());
+            }
+            ReadResult::Closed => return Err(DisconnectReason::Closed),
+            ReadResult::TimedOut => {
+                if !self.handshake_done {
+                    info!("Peer with ip {} did not finish it's handshake in time, dropping the \
+                           connection.",
+                          state.addr);
+                }
+                return Err(DisconnectReason::TimedOut);
+            }
+        };
+        if !self.handshake_done {
+            if let NetworkPacket::Init { protocol,
+                                         ref version,
+                                         should_crash,
+                                         compression,
+                                         ref password,
+                                         .. } = packet {
+                let local = (PROTOCOL_VERSION, ::VERSION);
+                if let Err(err) = validate_init(local,
+                                                state.should_crash,
+                                                (protocol, version),
+                                                should_crash) {
+                    info!("Handshake with ip {} failed: {}", state.addr, err);
+                    return Err(send_error(state, err));
+                }
+                match (state.accepted, &state.password, self.challenge) {
+                    (true, &Some(_), None) => {
+                        let nonce = new_nonce();
+                        self.challenge = Some(nonce);
+                        let packet = NetworkPacket::Challenge(nonce);
+                        let _ = state.connection_tx.try_send(ConnectionMessage::SendPacket(packet));
+                        return Ok(());
+                    }
+                    (true, &Some(ref expected), Some(nonce)) => {
+                        let proven = match *password {
+                            Some(ref proof) => {
+                                constant_time_eq(proof, &password_proof(expected, nonce))
+                            }
+                            None => false,
+                        };
+                        if !proven {
+                            info!("Peer with ip {} did not prove it knows the password.",
+                                  state.addr);
+                            return Err(send_error(state, NetworkError::BadCredentials));
+                        }
+                    }
+                    _ => {}
+                }
+                if version != ::VERSION {
+                    info!("Peer with ip {} runs version {}, while the local game runs {}.",
+                          state.addr,
+                          version,
+                          ::VERSION);
+                }
+                if state.accepted {
+                    let init = local_init(state.compression,
+                                          state.should_crash,
+                                          &state.display_name,
+                                          None);
+                    let _ = state.connection_tx.try_send(ConnectionMessage::SendPacket(init));
+                }
+                if compression != Compression::None && compression == state.compression {
+                    state.compress.store(true, Ordering::SeqCst);
+                }
+                self.handshake_done = true;
+                *state.state.lock().unwrap() = ConnectionState::Ready;
+            } else if !packet.is_control() {
+                info!("Discarding a packet from ip {} that arrived before it's Init.",
+                      state.addr);
+                return Ok(());
+            }
+        }
+        if let NetworkPacket::FragmentStart { id, total_len, ref channel } = packet {
+            debug!("Peer with ip {} started fragmented packet {} of {} bytes on channel {:?}.",
+                   state.addr,
+                   id,
+                   total_len,
+                   channel);
+        }
+        let packet = match self.reassembly.handle(packet) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                info!("Closing connection with ip {} after it's fragments: {}",
+                      state.addr,
+                      err);
+                return Err(send_error(state, err));
+            }
+        };
+        let disconnect = match packet {
+            NetworkPacket::Disconnect { ref reason } => {
+                info!("Peer with ip {} disconnected: {}", state.addr, reason);
+                Some(DisconnectReason::Disconnected(reason.clone()))
+            }
+            NetworkPacket::Error(ref err) => {
+                // Errors are never answered with another error, since the peer could answer that
+                // in turn. ShouldCrashBothTrue in particular means the peer is closing anyway.
+                if *err == NetworkError::ShouldCrashBothTrue {
+                    warn!("Peer with ip {} refused the connection, since neither peer should \
+                           crash.",
+                          state.addr);
+                } else {
+                    info!("Peer with ip {} sent an error: {}", state.addr, err);
+                }
+                let message = ControllerMessage::ConnectionError(state.id, err.clone());
+                let _ = state.controller_tx.send(message);
+                None
+            }
+            NetworkPacket::Challenge(nonce) => {
+                // Only the accepting side challenges, and only before the handshake is done.
+                if !state.accepted && !self.handshake_done {
+                    let proof = state.password
+                                     .as_ref()
+                                     .map(|password| password_proof(password, nonce));
+                    let init = local_init(state.compression,
+                                          state.should_crash,
+                                          &state.display_name,
+                                          proof);
+                    let _ = state.connection_tx.try_send(ConnectionMessage::SendPacket(init));
+                }
+                return Ok(());
+            }
+            NetworkPacket::Ping(time) => {
+                let _ = state.connection_tx
+                             .try_send(ConnectionMessage::SendPacket(NetworkPacket::Pong(time)));
+                return Ok(());
+            }
+            NetworkPacket::Pong(time) => {
+                state.stats.record_pong(time);
+                return Ok(());
+            }
+            NetworkPacket::Message { channel, payload } => {
+                route_message(state, channel, payload);
+                return Ok(());
+            }
+            _ => None,
+        };
+        if let Err(_err) = state.incoming_tx.send((state.id, packet)) {
+            debug!("Channel for incoming packets disconnected, shutting down \
+                    net::check_stream_recv.");
+            return Err(DisconnectReason::Closed);
+        }
+        match disconnect {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
     }
+}
 
-    /// Adds a new listener and spins up a new thread to check it.
+/// The fragmented packets a connection is receiving, see `NetworkPacket::FragmentStart`.
+struct Reassembly {
+    transfers: HashMap<u32, IncomingTransfer>,
+    /// The total_len of every transfer in transfers added up.
+    announced: usize,
+    max_transfers: usize,
+    max_bytes: usize,
+}
+
+/// A fragmented packet being reassembled.
+struct IncomingTransfer {
+    total_len: usize,
+    /// The fragments received so far, joined in order.
+    body: Vec<u8>,
+}
+
+impl Reassembly {
+    fn new(max_transfers: usize, max_bytes: usize) -> Reassembly {
+        Reassembly {
+            transfers: HashMap::new(),
+            announced: 0,
+            max_transfers: max_transfers,
+            max_bytes: max_bytes,
+        }
+    }
+
+    /// Adds a fragment to the packet it belongs to, returning the packet once a valid FragmentEnd
+    /// completes it.
     ///
-    /// The added listener can never be removed, due to the fact that the TcpListener lacks a shutdown method.
+    /// Packets other than fragments are returned as they are, and fragments are returned as None.
     ///
     /// # Errors
-    /// * A call to `listener.try_clone()` failed for some reason.
-    pub fn add_listener(&mut self, listener: TcpListener) -> Result<(), io::Error> {
-        let listener_clone = try!(listener.try_clone());
-        let tx_clone = self.raw.tx.lock().unwrap().clone();
-        thread::spawn(|| {
-            check_listener(listener_clone, tx_clone);
-        });
-        Ok(())
+    /// * `NetworkError::BadFragment` if a FragmentStart reuses the id of a packet still being
+    ///   received, or a fragment does not continue one, or the body does not match it's checksum
+    ///   or does not deserialize into a packet that is not itself a fragment.
+    /// * `NetworkError::FragmentLimit` if a FragmentStart goes over max_transfers or max_bytes.
+    fn handle(&mut self, packet: NetworkPacket) -> Result<Option<NetworkPacket>, NetworkError> {
+        match packet {
+            NetworkPacket::FragmentStart { id, total_len, .. } => {
+                let total_len = total_len as usize;
+                if self.transfers.contains_key(&id) {
+                    return Err(NetworkError::BadFragment);
+                }
+                if self.transfers.len() >= self.max_transfers ||
+                   self.announced + total_len > self.max_bytes {
+                    return Err(NetworkError::FragmentLimit);
+                }
+                self.announced += total_len;
+                self.transfers.insert(id,
+                                      IncomingTransfer {
+                                          total_len: total_len,
+                                          body: Vec::new(),
+                                      });
+                Ok(None)
+            }
+            NetworkPacket::Fragment { id, offset, data } => {
+                let transfer = match self.transfers.get_mut(&id) {
+                    Some(transfer) => transfer,
+                    None => return Err(NetworkError::BadFragment),
+                };
+                if offset as usize != transfer.body.len() ||
+                   transfer.body.len() + data.len() > transfer.total_len {
+                    return Err(NetworkError::BadFragment);
+                }
+                transfer.body.extend_from_slice(&data);
+                Ok(None)
+            }
+            NetworkPacket::FragmentEnd { id, checksum } => {
+                let transfer = match self.transfers.remove(&id) {
+                    Some(transfer) => transfer,
+                    None => return Err(NetworkError::BadFragment),
+                };
+                self.announced -= transfer.total_len;
+                if transfer.body.len() != transfer.total_len || crc32(&transfer.body) != checksum {
+                    return Err(NetworkError::BadFragment);
+                }
+                match deserialize_packet(&transfer.body, transfer.total_len as u32) {
+                    Ok(NetworkPacket::FragmentStart { .. }) |
+                    Ok(NetworkPacket::Fragment { .. }) |
+                    Ok(NetworkPacket::FragmentEnd { .. }) |
+                    Err(_) => Err(NetworkError::BadFragment),
+                    Ok(packet) => Ok(Some(packet)),
+                }
+            }
+            packet => Ok(Some(packet)),
+        }
     }
 }
 
-/// Raw representation of a controller. Used internally for things.
-#[derive(Debug)]
-pub struct ControllerRaw {
-    pub connections: RwLock<Vec<Connection>>,
-    pub tx: Mutex<Sender<ControllerMessage>>,
+/// Sends the error to the peer and reports it to the controller, before closing the connection.
+fn send_error(state: &RecvState, err: NetworkError) -> DisconnectReason {
+    let packet = NetworkPacket::Error(err.clone());
+    let _ = state.connection_tx.try_send(ConnectionMessage::SendPacket(packet));
+    let _ = state.controller_tx.send(ControllerMessage::ConnectionError(state.id, err.clone()));
+    DisconnectReason::Error(err)
 }
 
-#[derive(Debug)]
-/// Represents a single connection without any notion of client or server.
+/// Takes a token from the bucket, blocking the recv thread untill one is gained if it is empty.
 ///
-/// It's own struct to allow for hooks and the like.
-pub struct Connection {
-    pub channel: Mutex<Sender<ConnectionMessage>>,
+/// Not reading from the socket while blocked makes TCP push back on the peer. Returns false if the
+/// connection should instead be killed under `RateLimitMode::Kill`.
+fn wait_for_token(bucket: &mut TokenBucket, state: &RecvState) -> bool {
+    let mut first_try = true;
+    loop {
+        match take_token(bucket, state, first_try) {
+            Throttle::Go => return true,
+            Throttle::Wait(wait) => thread::sleep(wait),
+            Throttle::Kill => return false,
+        }
+        first_try = false;
+    }
 }
 
-/// Sent in the case of an error that should be sent to the peer.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum NetworkError {
-    /// If the versions mismatch sufficently to become incompatible with each other.
-    VersionMismatch(String, String),
-    /// If both peers have should_crash == false, then this error should be sent.
-    ///
-    /// Do note that this error should not be rewrapped into a reerror, since it would cause a loop.
-    /// Instead, it should be logged and ignored, as the connection will be killed shortly after.
-    ShouldCrashBothTrue,
+/// What to do with a packet under a connection's RateLimit.
+enum Throttle {
+    /// A token was taken, so the packet can be handled.
+    Go,
+    /// The bucket is empty, try again after waiting this long.
+    Wait(Duration),
+    /// The connection went over it's grace under `RateLimitMode::Kill`, and should be killed.
+    Kill,
 }
 
-impl Display for NetworkError {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        match *self {
-            NetworkError::VersionMismatch(ref ver1, ref ver2) => {
-                write!(fmt,
-                       "VersionMismatch: The versions of the client and server attempting to \
-                        connect mismatch. ver1: {}, ver2: {}",
-                       ver1,
-                       ver2)
-            }
-            NetworkError::ShouldCrashBothTrue => {
-                write!(fmt,
-                       "ShouldCrashBothTrue: Both peers have should_crash == false.")
+/// Takes a token from the bucket for a packet without waiting.
+///
+/// first_try is false when the packet already had to wait, so it is only counted as throttled
+/// once.
+fn take_token(bucket: &mut TokenBucket, state: &RecvState, first_try: bool) -> Throttle {
+    let wait = match bucket.take() {
+        Some(wait) => wait,
+        None => {
+            if first_try {
+                bucket.throttled_since = None;
             }
+            return Throttle::Go;
+        }
+    };
+    if first_try {
+        state.stats.throttle_events.fetch_add(1, Ordering::Relaxed);
+    }
+    let throttled_since = match bucket.throttled_since {
+        Some(since) => since,
+        None => {
+            debug!("Throttling connection with ip {}, it ran out of tokens.", state.addr);
+            let now = Instant::now();
+            bucket.throttled_since = Some(now);
+            now
+        }
+    };
+    if let RateLimitMode::Kill { grace_millis } = bucket.limit.mode {
+        let grace = Duration::from_millis(grace_millis);
+        if throttled_since.elapsed() + wait > grace {
+            info!("Closing connection with ip {}, it went over the rate limit for more than {} \
+                   milliseconds.",
+                  state.addr,
+                  grace_millis);
+            return Throttle::Kill;
         }
     }
+    Throttle::Wait(wait)
 }
 
-impl Error for NetworkError {
-    fn description(&self) -> &str {
-        match *self {
-            NetworkError::VersionMismatch(_, _) => {
-                "VersionMismatch: The versions of the client and server attempting to connect \
-                 mismatch."
-            }
-            NetworkError::ShouldCrashBothTrue => {
-                "ShouldCrashBothTrue: Both peers have should_crash == false."
-            }
+/// Hands the payload of a message to every subscriber of it's channel.
+///
+/// Subscribers whose Receiver has been dropped are removed.
+fn route_message(state: &RecvState, channel: String, payload: Vec<u8>) {
+    let mut subscribers = state.subscribers.lock().unwrap();
+    let remove = match subscribers.get_mut(&channel) {
+        Some(senders) => {
+            senders.retain(|sender| sender.send((state.id, payload.clone())).is_ok());
+            senders.is_empty()
+        }
+        None => {
+            info!("Dropping a message from ip {} on channel {}, which has no subscribers.",
+                  state.addr,
+                  channel);
+            false
         }
+    };
+    if remove {
+        subscribers.remove(&channel);
     }
+}
 
-    fn cause(&self) -> Option<&Error> {
-        match *self {
-            NetworkError::VersionMismatch(_, _) => None,
-            NetworkError::ShouldCrashBothTrue => None,
-        }
+/// The Init describing the local game, offering the given compression.
+fn local_init(compression: Compression,
+              should_crash: bool,
+              display_name: &str,
+              password: Option<Vec<u8>>)
+              -> NetworkPacket {
+    NetworkPacket::Init {
+        protocol: PROTOCOL_VERSION,
+        version: ::VERSION.to_owned(),
+        should_crash: should_crash,
+        compression: compression,
+        password: password,
+        display_name: display_name.to_owned(),
     }
 }
 
-/// Messages that can be sent between peers to facilitate vairous actions.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum NetworkPacket {
-    /// Sent on connection to verify everything is in sync.
-    Init {
-        /// The curent version of the local game.
-        ///
-        /// Should be formatted according to Scematic Versioning.
-        version: String,
-        /// If the local game should crash when an error occours.
-        ///
-        /// Two peers should not have this as false, because should_crash will send the error to the
-        /// remote peer and make it crash instead.
-        /// This would cause a infinite loop if both were to do it.
-        should_crash: bool,
-    },
-    /// An error that should crash the game and show an error to the user, but only on a client.
-    Error(NetworkError),
+/// Proof of knowing the password, answering a `NetworkPacket::Challenge` with the nonce.
+///
+/// It is the HMAC-SHA1 of the nonce's little endian bytes, keyed with the password. Since every
+/// connection is challenged with a new nonce, a proof seen on the wire can not be replayed.
+pub fn password_proof(password: &str, nonce: u64) -> Vec<u8> {
+    let mut message: [u8; 8] = [0; 8];
+    LittleEndian::write_u64(&mut message, nonce);
+    hmac_sha1(password.as_bytes(), &message).to_vec()
 }
 
-/// Message sent to a connection to do vairous actions.
-#[derive(Debug, Clone, Copy)]
-pub enum ConnectionMessage {
-    DoNothing,
+/// The HMAC of the message with the key, using SHA1, as in RFC 2104.
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    const BLOCK_LEN: usize = 64;
+    let mut block: [u8; BLOCK_LEN] = [0; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..20].copy_from_slice(&sha1_bytes(&[key]));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner_key: [u8; BLOCK_LEN] = [0x36; BLOCK_LEN];
+    let mut outer_key: [u8; BLOCK_LEN] = [0x5c; BLOCK_LEN];
+    for i in 0..BLOCK_LEN {
+        inner_key[i] ^= block[i];
+        outer_key[i] ^= block[i];
+    }
+    let inner = sha1_bytes(&[&inner_key, message]);
+    sha1_bytes(&[&outer_key, &inner])
 }
 
-/// Channel message sent to controller to dictate certain actions.
-#[derive(Debug, Clone)]
-pub enum ControllerMessage {
-    /// Add a socket, spinning up a new thread in the process.
-    AddSocket(Sender<ConnectionMessage>, String),
-    /// Used for unit testing.
-    #[cfg(test)]
-    Test(Tattle),
+/// The SHA1 of the parts joined together.
+fn sha1_bytes(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.digest().bytes()
 }
 
-/// Parses a str to a SocketAddr.
+/// If the bytes are equal, taking as long to find out wherever they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// A nonce for a `NetworkPacket::Challenge`, different for every call.
 ///
-/// This is a function because while str implements ToSocketAddrs, it requires a good bit of boilerplate to use.
+/// The nonce only has to never repeat, so it hashes the time and a count of nonces so far rather
+/// than needing a source of randomness.
+fn new_nonce() -> u64 {
+    static NONCES: AtomicUsize = ATOMIC_USIZE_INIT;
+    let count = NONCES.fetch_add(1, Ordering::SeqCst) as u64;
+    let mut bytes: [u8; 16] = [0; 16];
+    LittleEndian::write_u64(&mut bytes[..8], count);
+    let since_epoch = UNIX_EPOCH.elapsed().unwrap_or(Duration::from_secs(0));
+    LittleEndian::write_u64(&mut bytes[8..], duration_nanos(since_epoch));
+    LittleEndian::read_u64(&sha1_bytes(&[&bytes])[..8])
+}
+
+/// Checks the contents of a peer's Init against the local game.
 ///
-/// #Panics
-/// * Calling with a localhost ip address: Use 127.0.0.1 instead.
-/// * Calling with an ip address that resolves to more than 1 ip address.
-pub fn ip(ip_addr: &str) -> SocketAddr {
-    if ip_addr.starts_with("localhost") {
-        panic!("because localhost can resolve to both 127.0.0.1, and the various IPV6 versions \
-                of 127.0.0.1, it may not be used. please instead use 127.0.0.1");
+/// `local` and `remote` are the protocol version and game version of each peer. Only the protocol
+/// versions have to be equal, the game versions may differ in any way.
+///
+/// # Errors
+/// * `NetworkError::VersionMismatch` if the protocol versions differ.
+/// * `NetworkError::ShouldCrashBothTrue` if neither peer should crash.
+fn validate_init(local: (u32, &str),
+                 local_should_crash: bool,
+                 remote: (u32, &str),
+                 remote_should_crash: bool)
+                 -> Result<(), NetworkError> {
+    if local.0 != remote.0 {
+        return Err(NetworkError::VersionMismatch(local.0,
+                                                 remote.0,
+                                                 local.1.to_owned(),
+                                                 remote.1.to_owned()));
     }
-    let mut iter = ip_addr.to_socket_addrs().unwrap();
-    let ip = iter.next().unwrap();
-    if iter.next() != None {
-        panic!("the given ip to net::ip() resolved to more than 1 SocketAddr");
+    if !local_should_crash && !remote_should_crash {
+        return Err(NetworkError::ShouldCrashBothTrue);
     }
-    ip
+    Ok(())
 }
 
-fn check_controller_channel(rx: Receiver<ControllerMessage>, controller: Weak<ControllerRaw>) {
-    loop {
-        match rx.recv() {
-            Ok(ControllerMessage::AddSocket(tx_connection, _addr)) => {
-                // TODO: Add a hook allowing intersepting the addr and denying the connection.
-                let controller_arc = match controller.upgrade() {
-                    Some(val) => val,
-                    None => {
-                        debug!("All Arc pointers to Controller dropped, shutting down \
-                                net::check_controller_channel.");
-                        break;
-                    }
-                };
-                controller_arc.connections
-                              .write()
-                              .unwrap()
-                              .push(Connection { channel: Mutex::new(tx_connection) });
+/// The outcome of reading a single packet from a stream.
+enum ReadResult {
+    Packet(NetworkPacket),
+    /// A packet was read, but it could not be deserialized.
+    Malformed,
+    /// A packet was read, but it's body did not match the checksum in it's header.
+    Corrupt,
+    /// The header announced a body of the given length, which is above the maximum packet size.
+    ///
+    /// Nothing past the header is read, so the stream can not be read from any further.
+    TooLarge(u32),
+    /// The connection closed, or must be killed. The reason has already been logged.
+    Closed,
+    /// Nothing arrived within the read timeout of the stream.
+    TimedOut,
+}
+
+/// Reads the next framed packet from the stream.
+///
+/// If the header does not start with NET_MAGIC_NUMBER, the connection is killed if the game should
+/// crash, and otherwise resynchronized with `resync_stream`.
+fn read_packet<T: Read>(stream: &mut T, addr: &SocketAddr, max_packet_size: u32) -> ReadResult {
+    let mut header: [u8; HEADER_LEN] = [0; HEADER_LEN];
+    if let Err(result) = fill_from_stream(stream, &mut header, addr) {
+        return result;
+    }
+    let header = match parse_packet_header(header) {
+        Some(header) => header,
+        None => {
+            if ::should_crash() {
+                warn!("Packet from ip {} did not start with NET_MAGIC_NUMBER, killing the \
+                       connection.",
+                      addr);
+                return ReadResult::Closed;
             }
-            #[cfg(test)]
-            Ok(ControllerMessage::Test(tattle)) => {
-                tattle.call();
+            warn!("Packet from ip {} did not start with NET_MAGIC_NUMBER, attempting to \
+                   resynchronize.",
+                  addr);
+            match resync_stream(stream, header) {
+                Ok(header) => header,
+                Err(err) => {
+                    info!("Connection with ip {} closed while resynchronizing: {}",
+                          addr,
+                          err);
+                    return ReadResult::Closed;
+                }
             }
-            Err(_err) => {
-                debug!("Channel connected to controller disconnected");
-                break;
+        }
+    };
+    if header.len > max_packet_size {
+        return ReadResult::TooLarge(header.len);
+    }
+    let mut bytes: Vec<u8> = vec![0; header.len as usize];
+    if let Err(result) = fill_from_stream(stream, &mut bytes, addr) {
+        return result;
+    }
+    if crc32(&bytes) != header.crc {
+        warn!("Packet from ip {} did not match it's checksum.", addr);
+        return ReadResult::Corrupt;
+    }
+    if header.compressed {
+        bytes = match decompress(&bytes, max_packet_size) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                warn!("Failed to decompress packet from ip {}: {}", addr, err);
+                return ReadResult::Malformed;
             }
+        };
+    }
+    match deserialize_packet(&bytes, max_packet_size) {
+        Ok(packet) => ReadResult::Packet(packet),
+        Err(err) => {
+            warn!("Failed to deserialize packet from ip {}: {:?}", addr, err);
+            ReadResult::Malformed
         }
     }
 }
 
-fn check_listener(listener: TcpListener, controller_tx: Sender<ControllerMessage>) {
-    loop {
-        match listener.accept() {
-            Ok((stream, addr)) => {
-                let (tx, rx) = channel();
-                match controller_tx.send(ControllerMessage::AddSocket(tx, addr.to_string())) {
-                    Ok(()) => {}
-                    Err(_err) => {
-                        debug!("listener {:?} stopped accepting because of channel close",
-                               listener);
-                        break;
-                    }
+/// Reads from the stream until the buffer is full, since a single read may come up short.
+///
+/// Returns `ReadResult::TimedOut` or `ReadResult::Closed` if the connection timed out, or closed or
+/// errored, after logging why.
+fn fill_from_stream<T: Read>(stream: &mut T,
+                             buf: &mut [u8],
+                             addr: &SocketAddr)
+                             -> Result<(), ReadResult> {
+    // read_exact already loops over short reads and retries on ErrorKind::Interrupted.
+    match stream.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            match err.kind() {
+                io::ErrorKind::ConnectionReset |
+                io::ErrorKind::ConnectionAborted |
+                io::ErrorKind::UnexpectedEof => {
+                    info!("Connection with ip {} reset or aborted.", addr);
+                }
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                    info!("Connection with ip {} timed out.", addr);
+                    return Err(ReadResult::TimedOut);
+                }
+                _ => {
+                    warn!("Error not accounted for occoured on socket with address {}. display: \
+                           {}",
+                          addr,
+                          err);
                 }
-                let stream_clone = stream.try_clone().unwrap();
-                thread::spawn(|| check_stream_send(rx, stream));
-                thread::spawn(move || check_stream_recv(stream_clone, addr));
             }
-            Err(err) => panic!("{}", err),  // TODO: Better handle errors.
+            Err(ReadResult::Closed)
         }
     }
 }
 
-fn check_stream_send<T: Write>(rx: Receiver<ConnectionMessage>, _stream: T) {
-    loop {
-        match rx.recv().unwrap() {
-            ConnectionMessage::DoNothing => {}
-        }
+/// Inflates a zlib compressed body, refusing to inflate it past max_size bytes.
+fn decompress(compressed: &[u8], max_size: u32) -> Result<Vec<u8>, io::Error> {
+    let mut decompressed = Vec::new();
+    try!(ZlibDecoder::new(compressed).take(max_size as u64 + 1).read_to_end(&mut decompressed));
+    if decompressed.len() > max_size as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "the body decompresses to more than the maximum packet size"));
     }
+    Ok(decompressed)
 }
 
-fn check_stream_recv<T: Read>(mut stream: T, addr: SocketAddr) {
-    'thread: loop {
-        let mut header: [u8; 6] = [0; 6];
-        'read_header: loop {
-            let maybe_err = (&mut stream).take(6).read_exact(&mut header);
-            if let Err(err) = maybe_err {
-                match err.kind() {
-                    io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => {
-                        info!("Connection with ip {} reset or aborted.", addr);
-                        break 'thread;
-                    }
-                    io::ErrorKind::Interrupted => continue 'read_header,
-                    _ => {
-                        panic!("error not accounted for occoured on socket with address {}. \
-                                display: {}",
-                               addr,
-                               err)
-                    }
-                }
-            }
-            break;
+/// Discards bytes one at a time until a valid header is found, then returns it.
+///
+/// `header` is the last HEADER_LEN bytes read, which did not start with NET_MAGIC_NUMBER.
+fn resync_stream<T: Read>(stream: &mut T,
+                          mut header: [u8; HEADER_LEN])
+                          -> Result<PacketHeader, io::Error> {
+    loop {
+        let mut next: [u8; 1] = [0];
+        try!(stream.read_exact(&mut next));
+        for i in 0..HEADER_LEN - 1 {
+            header[i] = header[i + 1];
         }
-        let len = get_packet_length(header).unwrap();
-        let mut bytes: Vec<u8> = Vec::new();
-        'read_data: loop {
-            let maybe_error = (&mut stream).take(len as u64).read_to_end(&mut bytes);
-            if let Err(err) = maybe_error {
-                match err.kind() {
-                    io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => {
-                        info!("Connection with ip {} reset or aborted.", addr);
-                        break 'thread;
-                    }
-                    io::ErrorKind::Interrupted => continue 'read_data,
-                    _ => {
-                        panic!("error not accounted for occoured on socket with address {}. \
-                                display: {}",
-                               addr,
-                               err)
-                    }
-                }
-            }
-            break;
+        header[HEADER_LEN - 1] = next[0];
+        if let Some(header) = parse_packet_header(header) {
+            return Ok(header);
         }
-        let message = deserialize_packet(&bytes).unwrap();
-        handle_message(message);
     }
 }
 
-fn deserialize_packet(to_de: &[u8]) -> Result<NetworkPacket, DeserializeError> {
-    deserialize(to_de)
+/// Deserializes the body of a packet, refusing to allocate more than max_size bytes for it.
+fn deserialize_packet(to_de: &[u8], max_size: u32) -> Result<NetworkPacket, DeserializeError> {
+    let mut reader = to_de;
+    deserialize_from(&mut reader, SizeLimit::Bounded(max_size as u64))
 }
 
 /// Returns the length of a given packet, or a None if the first four bytes do not match NET_MAGIC_NUMBER.
-fn get_packet_length(to_ln: [u8; 6]) -> Option<u16> {
-    let (first_four, next_two) = to_ln.split_at(4);
-    let should_be_magic_num = LittleEndian::read_u32(&first_four);
+pub fn get_packet_length(to_ln: [u8; HEADER_LEN]) -> Option<u32> {
+    parse_packet_header(to_ln).map(|header| header.len)
+}
+
+/// Returns the length and CRC32 of the body of a given packet, or a None if the first four bytes do
+/// not match NET_MAGIC_NUMBER.
+///
+/// The length is without COMPRESSED_FLAG, see `parse_packet_header` to tell if the body is
+/// compressed.
+pub fn get_packet_header(header: [u8; HEADER_LEN]) -> Option<(u32, u32)> {
+    parse_packet_header(header).map(|header| (header.len, header.crc))
+}
+
+/// The parts of the header before every packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketHeader {
+    /// The length of the body as sent, without COMPRESSED_FLAG.
+    pub len: u32,
+    /// The CRC32 of the body as sent, before it is decompressed.
+    pub crc: u32,
+    /// If COMPRESSED_FLAG was set, so the body must be decompressed before it is deserialized.
+    pub compressed: bool,
+}
+
+/// Returns the header of a given packet, or a None if the first four bytes do not match
+/// NET_MAGIC_NUMBER.
+pub fn parse_packet_header(header: [u8; HEADER_LEN]) -> Option<PacketHeader> {
+    let should_be_magic_num = LittleEndian::read_u32(&header[..4]);
     if should_be_magic_num != NET_MAGIC_NUMBER {
         return None;
     }
-    let length = LittleEndian::read_u16(&next_two);
-    Some(length)
+    let length = LittleEndian::read_u32(&header[4..8]);
+    let crc = LittleEndian::read_u32(&header[8..]);
+    Some(PacketHeader {
+        len: length & !COMPRESSED_FLAG,
+        crc: crc,
+        compressed: length & COMPRESSED_FLAG != 0,
+    })
+}
+
+lazy_static! {
+    /// The table `crc32` looks up each byte in, built the first time it is needed.
+    static ref CRC32_TABLE: [u32; 256] = crc32_table();
+}
+
+/// Builds the table of the CRC32 of every byte, for `crc32`.
+fn crc32_table() -> [u32; 256] {
+    let mut table: [u32; 256] = [0; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut value = i as u32;
+        for _ in 0..8 {
+            value = if value & 1 == 1 {
+                0xEDB88320 ^ (value >> 1)
+            } else {
+                value >> 1
+            };
+        }
+        *entry = value;
+    }
+    table
+}
+
+/// The CRC32 of the bytes, using the same polynomial as zlib and ethernet.
+fn crc32(bytes: &[u8]) -> u32 {
+    let table = &*CRC32_TABLE;
+    let mut crc = !0;
+    for byte in bytes {
+        crc = table[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
 }
 
-fn handle_message(_message: NetworkPacket) {
-    unimplemented!()
+/// Frames a packet with it's header, ready to be written to a stream.
+///
+/// If compress_above is given, bodies larger than it are compressed with zlib, unless that would
+/// not make them any smaller.
+///
+/// # Errors
+/// * `SerializeError::PacketTooLarge` if the body would be larger than max_size bytes before it is
+///   compressed.
+fn seralize_packet(to_ser: &NetworkPacket,
+                   max_size: u32,
+                   compress_above: Option<usize>)
+                   -> Result<Vec<u8>, SerializeError> {
+    let encoded = serialize(to_ser, SizeLimit::Infinite).unwrap();
+    // Since the size limit is infinite and i'm not encoding to a stream, there is no error and I can safely unwrap();
+    frame_body(encoded, max_size, compress_above)
 }
 
-#[allow(unused)]
-fn seralize_packet(to_ser: &NetworkPacket) -> Vec<u8> {
+/// Frames an already serialized body, like `seralize_packet`.
+fn frame_body(mut encoded: Vec<u8>,
+              max_size: u32,
+              compress_above: Option<usize>)
+              -> Result<Vec<u8>, SerializeError> {
     let mut result: Vec<u8> = Vec::new();
     result.write_u32::<LittleEndian>(NET_MAGIC_NUMBER).unwrap();   // No possible errors here.
     // The NET_MAGIC_NUMBER is used before every packet, so incase the stream is desynced for whatever reason, the game doesn't just read arbratrary data and crash badly.
     // Instead, it can either recover somehow, by disconnecting and reconnecting, or just erroring gracefully.
-    let mut encoded = serialize(to_ser, SizeLimit::Infinite).unwrap();
-    // Since the size limit is infinite and i'm not encoding to a stream, there is no error and I can safely unwrap();
-    result.write_u16::<LittleEndian>(encoded.len() as u16).unwrap();
+    if encoded.len() > max_size as usize {
+        return Err(SerializeError::PacketTooLarge(encoded.len(), max_size));
+    }
+    let mut length_field = encoded.len() as u32;
+    if let Some(threshold) = compress_above {
+        if encoded.len() > threshold {
+            let compressed = compress(&encoded);
+            if compressed.len() < encoded.len() {
+                encoded = compressed;
+                length_field = encoded.len() as u32 | COMPRESSED_FLAG;
+            }
+        }
+    }
+    result.write_u32::<LittleEndian>(length_field).unwrap();
+    result.write_u32::<LittleEndian>(crc32(&encoded)).unwrap();
     result.append(&mut encoded);
-    result
+    Ok(result)
+}
+
+/// Compresses a body with zlib.
+fn compress(body: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+    // Writing to a Vec never fails.
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
 }
@@ -0,0 +1,593 @@
+//! Contains the IO thread driving every connection added under `IoModel::Polled`.
+//!
+//! The socket and queue of messages of each connection are registered with a single mio Poll.
+//! Bytes read from a socket are kept in the connection's read buffer untill a whole packet has
+//! arrived, which is then handled just like a recv thread would. Messages queued for a connection
+//! wake the thread up, and are framed onto it's write buffer, which is written as fast as the
+//! socket takes it.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::Read;
+use std::net::Shutdown;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::channel::{Receiver, Sender};
+use mio::tcp::TcpStream;
+
+use super::{CLOSE_DRAIN_MILLIS, HEADER_LEN, ConnectionMessage, ConnectionState, ControllerConfig,
+            DisconnectReason, Lanes, NetworkError, NetworkPacket, PacketHeader, Priority,
+            ReadResult, RecvSession, RecvState, Throttle, TokenBucket, WriteBuffer, finish_recv,
+            parse_packet_header, read_packet, send_error, take_token};
+
+/// The token the channel of new connections is registered with.
+const NEW_CONNECTIONS: Token = Token(0);
+
+/// How many bytes are read from a socket at a time.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// How many events are taken from the poll at a time.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// A handle to a running IO thread, used to hand it new connections.
+///
+/// The thread exits once this is dropped and every connection it drives has closed.
+pub struct IoLoop {
+    tx: Sender<PolledSocket>,
+}
+
+impl IoLoop {
+    /// Starts a new IO thread, driving no connections.
+    pub fn start() -> Result<IoLoop, io::Error> {
+        let poll = try!(Poll::new());
+        let (tx, rx) = mio::channel::channel();
+        try!(poll.register(&rx, NEW_CONNECTIONS, Ready::readable(), PollOpt::edge()));
+        thread::spawn(move || check_io_loop(poll, rx));
+        Ok(IoLoop { tx: tx })
+    }
+
+    /// Has the IO thread drive a new connection over the stream, taking messages from rx.
+    ///
+    /// The stream must already be non-blocking. If the connection can not be registered with the
+    /// poll, it is closed at once.
+    pub fn add(&self,
+               stream: TcpStream,
+               rx: Receiver<ConnectionMessage>,
+               state: RecvState,
+               config: ControllerConfig)
+               -> Result<(), io::Error> {
+        let socket = PolledSocket {
+            stream: stream,
+            rx: rx,
+            state: state,
+            config: config,
+        };
+        self.tx.send(socket).map_err(|_err| {
+            io::Error::new(io::ErrorKind::Other, "the IO thread is no longer running")
+        })
+    }
+}
+
+impl Debug for IoLoop {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "IoLoop")
+    }
+}
+
+/// A new connection on it's way to the IO thread.
+struct PolledSocket {
+    stream: TcpStream,
+    rx: Receiver<ConnectionMessage>,
+    state: RecvState,
+    config: ControllerConfig,
+}
+
+/// The token the stream of the connection in the slot is registered with.
+fn stream_token(slot: usize) -> Token {
+    Token(slot * 2 + 1)
+}
+
+/// The token the queue of the connection in the slot is registered with.
+fn channel_token(slot: usize) -> Token {
+    Token(slot * 2 + 2)
+}
+
+/// The slot of the connection a token other than NEW_CONNECTIONS belongs to.
+fn token_slot(token: Token) -> usize {
+    (token.0 - 1) / 2
+}
+
+/// Drives every connection handed over through new_connections, untill the IoLoop is dropped and
+/// they have all closed.
+///
+/// A connection is serviced whenever it's stream or queue has an event, or once anything it is
+/// waiting on comes due, such as a timeout or a throttled packet.
+fn check_io_loop(poll: Poll, new_connections: Receiver<PolledSocket>) {
+    let mut events = Events::with_capacity(EVENTS_CAPACITY);
+    let mut connections: HashMap<usize, PolledConnection> = HashMap::new();
+    let mut next_slot: usize = 0;
+    let mut accepting = true;
+    while accepting || !connections.is_empty() {
+        let now = Instant::now();
+        let timeout = connections.values()
+                                 .filter_map(|connection| connection.next_due())
+                                 .min()
+                                 .map(|due| {
+                                     if due > now {
+                                         due - now
+                                     } else {
+                                         Duration::from_millis(0)
+                                     }
+                                 });
+        if let Err(err) = poll.poll(&mut events, timeout) {
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("The IO thread failed to poll, closing every connection it drives: {}",
+                   err);
+            for (_slot, connection) in connections.drain() {
+                connection.state.closer.close();
+                finish_recv(&connection.state, DisconnectReason::Closed);
+            }
+            return;
+        }
+        let mut ready: HashSet<usize> = HashSet::new();
+        for event in events.iter() {
+            if event.token() != NEW_CONNECTIONS {
+                ready.insert(token_slot(event.token()));
+                continue;
+            }
+            loop {
+                let socket = match new_connections.try_recv() {
+                    Ok(socket) => socket,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        debug!("The IoLoop was dropped, the IO thread will exit once it's \
+                                connections have closed.");
+                        accepting = false;
+                        break;
+                    }
+                };
+                let slot = next_slot;
+                next_slot += 1;
+                if let Some(connection) = register(&poll, slot, socket) {
+                    connections.insert(slot, connection);
+                    ready.insert(slot);
+                }
+            }
+        }
+        let now = Instant::now();
+        let mut finished: Vec<usize> = Vec::new();
+        for (&slot, connection) in &mut connections {
+            let due = connection.next_due().map_or(false, |due| due <= now);
+            if due || ready.contains(&slot) {
+                connection.service(now);
+            }
+            if connection.is_finished() {
+                finished.push(slot);
+            }
+        }
+        for slot in finished {
+            if let Some(connection) = connections.remove(&slot) {
+                // The stream is still open through the connection's closer, so it would stay
+                // registered if it was only dropped.
+                let _ = poll.deregister(&connection.stream);
+                let _ = poll.deregister(&connection.rx);
+            }
+        }
+    }
+}
+
+/// Registers the stream and queue of a new connection with the poll under the slot.
+///
+/// If that fails, the connection is closed and None is returned.
+fn register(poll: &Poll, slot: usize, socket: PolledSocket) -> Option<PolledConnection> {
+    let result = poll.register(&socket.stream,
+                               stream_token(slot),
+                               Ready::readable() | Ready::writable(),
+                               PollOpt::edge())
+                     .and_then(|()| {
+                         poll.register(&socket.rx,
+                                       channel_token(slot),
+                                       Ready::readable(),
+                                       PollOpt::edge())
+                     });
+    if let Err(err) = result {
+        warn!("Failed to register socket with address {} with the IO thread: {}",
+              socket.state.addr,
+              err);
+        let _ = poll.deregister(&socket.stream);
+        socket.state.closer.close();
+        finish_recv(&socket.state, DisconnectReason::Closed);
+        return None;
+    }
+    Some(PolledConnection::new(socket))
+}
+
+/// A connection driven by the IO thread, along with everything it's send and recv threads would
+/// otherwise keep.
+struct PolledConnection {
+    stream: TcpStream,
+    rx: Receiver<ConnectionMessage>,
+    state: RecvState,
+    config: ControllerConfig,
+    session: RecvSession,
+    bucket: Option<TokenBucket>,
+    /// The packet the peer sent while the connection was being probed, handled before anything
+    /// is read.
+    first_packet: Option<NetworkPacket>,
+    /// Bytes read from the stream that do not make up a whole packet yet.
+    read_buffer: Vec<u8>,
+    /// Set while the front of read_buffer is being discarded for not starting with a valid header,
+    /// so that is only logged once.
+    resyncing: bool,
+    /// A packet that ran out of tokens, and when to try it again. Nothing is read from the stream
+    /// untill then, which makes TCP push back on the peer.
+    throttled: Option<(NetworkPacket, Instant)>,
+    handshake_deadline: Instant,
+    /// When bytes last arrived, which the idle timeout is counted from.
+    last_received: Instant,
+    /// Set once the recv side has finished with `finish_recv`.
+    recv_done: bool,
+    lanes: Lanes,
+    write_buffer: WriteBuffer,
+    keepalive_interval: Duration,
+    last_sent: Instant,
+    next_keepalive: Instant,
+    /// Set once Close has been taken from the queue. Nothing more is taken after it.
+    closing: bool,
+    /// When writing was shut down after closing, and the peer is given untill then to read
+    /// everything.
+    drain_until: Option<Instant>,
+    /// Set once the stream is shut down, either after draining or when writing to it failed.
+    send_done: bool,
+}
+
+impl PolledConnection {
+    fn new(mut socket: PolledSocket) -> PolledConnection {
+        let now = Instant::now();
+        let keepalive_interval = Duration::from_millis(socket.config.keepalive_millis);
+        PolledConnection {
+            session: RecvSession::new(&socket.state),
+            bucket: socket.state.rate_limit.map(TokenBucket::new),
+            first_packet: socket.state.first_packet.take(),
+            read_buffer: Vec::new(),
+            resyncing: false,
+            throttled: None,
+            handshake_deadline: socket.state.started + socket.state.handshake_timeout,
+            last_received: now,
+            recv_done: false,
+            lanes: Lanes::new(),
+            write_buffer: WriteBuffer::new(),
+            keepalive_interval: keepalive_interval,
+            last_sent: now,
+            next_keepalive: now + keepalive_interval,
+            closing: false,
+            drain_until: None,
+            send_done: false,
+            stream: socket.stream,
+            rx: socket.rx,
+            state: socket.state,
+            config: socket.config,
+        }
+    }
+
+    /// If both sides of the connection are done, so it can be dropped.
+    fn is_finished(&self) -> bool {
+        self.recv_done && self.send_done
+    }
+
+    /// The next time the connection must be serviced even if nothing happens on it's stream or
+    /// queue.
+    fn next_due(&self) -> Option<Instant> {
+        let mut due: Vec<Instant> = Vec::new();
+        if !self.recv_done {
+            match self.throttled {
+                Some((_, retry_at)) => due.push(retry_at),
+                None => due.push(self.recv_deadline()),
+            }
+        }
+        if !self.send_done {
+            due.push(self.next_keepalive);
+            if let Some(drain_until) = self.drain_until {
+                due.push(drain_until);
+            }
+        }
+        due.into_iter().min()
+    }
+
+    /// When the connection times out if nothing arrives, see `recv_packets`.
+    fn recv_deadline(&self) -> Instant {
+        if self.session.handshake_done {
+            self.last_received + self.state.idle_timeout
+        } else {
+            self.handshake_deadline
+        }
+    }
+
+    /// Reads and handles everything the peer sent, then writes everything queued for it.
+    fn service(&mut self, now: Instant) {
+        if !self.recv_done {
+            if let Err(reason) = self.recv(now) {
+                self.recv_done = true;
+                // Queues Close onto the connection's own queue, which is taken by send below.
+                finish_recv(&self.state, reason);
+            }
+        }
+        self.send(now);
+        if self.send_done && !self.recv_done {
+            self.recv_done = true;
+            finish_recv(&self.state, DisconnectReason::Closed);
+        }
+    }
+
+    /// Handles every whole packet that arrived, reading from the stream untill it has nothing
+    /// more. Returns why the connection closed if it did.
+    fn recv(&mut self, now: Instant) -> Result<(), DisconnectReason> {
+        if self.throttled.is_none() && now >= self.recv_deadline() {
+            if self.session.handshake_done {
+                info!("Connection with ip {} timed out.", self.state.addr);
+            }
+            return self.session.handle(&self.state, ReadResult::TimedOut);
+        }
+        if let Some(packet) = self.first_packet.take() {
+            self.state.stats.record_received();
+            try!(self.handle_packet(packet, true));
+        }
+        loop {
+            if let Some((packet, retry_at)) = self.throttled.take() {
+                if now < retry_at {
+                    self.throttled = Some((packet, retry_at));
+                    return Ok(());
+                }
+                try!(self.handle_packet(packet, false));
+            }
+            while self.throttled.is_none() {
+                match self.next_buffered() {
+                    Some(ReadResult::Packet(packet)) => {
+                        self.state.stats.record_received();
+                        try!(self.handle_packet(packet, true));
+                    }
+                    Some(result) => try!(self.session.handle(&self.state, result)),
+                    None => break,
+                }
+            }
+            if self.throttled.is_some() {
+                return Ok(());
+            }
+            let mut chunk: [u8; READ_CHUNK] = [0; READ_CHUNK];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    info!("Connection with ip {} closed.", self.state.addr);
+                    return Err(DisconnectReason::Closed);
+                }
+                Ok(read) => {
+                    self.state.stats.bytes_received.fetch_add(read as u64, Ordering::Relaxed);
+                    self.read_buffer.extend_from_slice(&chunk[..read]);
+                    self.last_received = now;
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    match err.kind() {
+                        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => {
+                            info!("Connection with ip {} reset or aborted.", self.state.addr);
+                        }
+                        _ => {
+                            warn!("Error not accounted for occoured on socket with address {}. \
+                                   display: {}",
+                                  self.state.addr,
+                                  err);
+                        }
+                    }
+                    return Err(DisconnectReason::Closed);
+                }
+            }
+        }
+    }
+
+    /// Lets the packet through the connection's RateLimit and handles it, or holds on to it in
+    /// throttled if it has to wait for a token.
+    ///
+    /// first_try is false if the packet was already throttled, see `take_token`.
+    fn handle_packet(&mut self,
+                     packet: NetworkPacket,
+                     first_try: bool)
+                     -> Result<(), DisconnectReason> {
+        if let Some(ref mut bucket) = self.bucket {
+            match take_token(bucket, &self.state, first_try) {
+                Throttle::Go => {}
+                Throttle::Wait(wait) => {
+                    self.throttled = Some((packet, Instant::now() + wait));
+                    return Ok(());
+                }
+                Throttle::Kill => return Err(send_error(&self.state, NetworkError::RateLimited)),
+            }
+        }
+        self.session.handle(&self.state, ReadResult::Packet(packet))
+    }
+
+    /// Takes the next whole packet off the front of the read buffer, or None if more bytes need
+    /// to arrive first.
+    ///
+    /// Like `read_packet`, if the buffer does not start with NET_MAGIC_NUMBER the connection is
+    /// killed if the game should crash, and otherwise bytes are discarded untill a valid header
+    /// starts it.
+    fn next_buffered(&mut self) -> Option<ReadResult> {
+        let max_packet_size = self.state.max_packet_size;
+        loop {
+            if self.read_buffer.len() < HEADER_LEN {
+                return None;
+            }
+            let header = match header_at(&self.read_buffer) {
+                Some(header) => header,
+                None => {
+                    if ::should_crash() {
+                        warn!("Packet from ip {} did not start with NET_MAGIC_NUMBER, killing \
+                               the connection.",
+                              self.state.addr);
+                        return Some(ReadResult::Closed);
+                    }
+                    if !self.resyncing {
+                        warn!("Packet from ip {} did not start with NET_MAGIC_NUMBER, \
+                               attempting to resynchronize.",
+                              self.state.addr);
+                        self.resyncing = true;
+                    }
+                    let last_start = self.read_buffer.len() - HEADER_LEN;
+                    let skip = (1..last_start + 1)
+                                   .find(|&start| header_at(&self.read_buffer[start..]).is_some())
+                                   .unwrap_or(last_start + 1);
+                    self.read_buffer.drain(..skip);
+                    continue;
+                }
+            };
+            self.resyncing = false;
+            if header.len > max_packet_size {
+                return Some(ReadResult::TooLarge(header.len));
+            }
+            let len = HEADER_LEN + header.len as usize;
+            if self.read_buffer.len() < len {
+                return None;
+            }
+            let result = read_packet(&mut &self.read_buffer[..len],
+                                     &self.state.addr,
+                                     max_packet_size);
+            self.read_buffer.drain(..len);
+            return Some(result);
+        }
+    }
+
+    /// Takes messages from the queue onto the lanes, then frames and writes as much as the stream
+    /// takes, like `check_stream_send`.
+    ///
+    /// Once Close has been taken and everything before it written, writing is shut down, and the
+    /// stream is shut down completely CLOSE_DRAIN_MILLIS later.
+    fn send(&mut self, now: Instant) {
+        if self.send_done {
+            return;
+        }
+        let mut more = self.take_messages();
+        if now >= self.next_keepalive {
+            self.next_keepalive = now + self.keepalive_interval;
+            if *self.state.state.lock().unwrap() != ConnectionState::Closing {
+                self.keepalive();
+            }
+        }
+        loop {
+            if !self.write_lanes(now) {
+                return;
+            }
+            // Messages left in the queue do not wake the thread again, so they are taken now that
+            // there is room for them.
+            if !more {
+                break;
+            }
+            more = self.take_messages();
+        }
+        if self.closing && self.drain_until.is_none() {
+            let _ = self.stream.shutdown(Shutdown::Write);
+            self.drain_until = Some(now + Duration::from_millis(CLOSE_DRAIN_MILLIS));
+        }
+        if let Some(drain_until) = self.drain_until {
+            if now >= drain_until {
+                let _ = self.stream.shutdown(Shutdown::Both);
+                self.send_done = true;
+            }
+        }
+    }
+
+    /// Takes messages from the queue onto the lanes, untill config.send_queue_depth packets are
+    /// queued across them or Close is taken.
+    ///
+    /// Returns true if it stopped with messages possibly left in the queue.
+    fn take_messages(&mut self) -> bool {
+        // Leaving the rest in the queue keeps it full, so senders see the backpressure.
+        while !self.closing {
+            if self.lanes.len() >= self.config.send_queue_depth {
+                return true;
+            }
+            let message = match self.rx.try_recv() {
+                Ok(message) => message,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => ConnectionMessage::Close,
+            };
+            match message {
+                ConnectionMessage::DoNothing => {}
+                ConnectionMessage::Close => self.closing = true,
+                ConnectionMessage::Keepalive => self.keepalive(),
+                ConnectionMessage::SendPacket(packet) => {
+                    let priority = packet.priority();
+                    self.lanes.push(&packet, priority);
+                }
+                ConnectionMessage::SendWithPriority(packet, priority) => {
+                    self.lanes.push(&packet, priority);
+                }
+            }
+        }
+        false
+    }
+
+    /// Frames and writes packets from the lanes untill they are empty.
+    ///
+    /// Returns false if it had to stop early, since the stream is full or writing to it failed.
+    fn write_lanes(&mut self, now: Instant) -> bool {
+        let compress_above = if self.state.compress.load(Ordering::SeqCst) {
+            Some(self.config.compression_threshold)
+        } else {
+            None
+        };
+        loop {
+            // Once closing, everything still queued is written before the stream is shut down.
+            while self.closing || self.write_buffer.len() < self.config.flush_size {
+                if !self.lanes.frame_next(&mut self.write_buffer,
+                                          &self.config,
+                                          compress_above,
+                                          &self.state.addr) {
+                    break;
+                }
+            }
+            if self.write_buffer.is_empty() {
+                return true;
+            }
+            match self.write_buffer.write_nonblocking(&mut self.stream, &self.state.stats) {
+                Ok(true) => self.last_sent = now,
+                // The stream is full, so this waits for it to become writable again.
+                Ok(false) => return false,
+                Err(err) => {
+                    info!("Failed to write to socket with address {}, shutting it down. \
+                           display: {}",
+                          self.state.addr,
+                          err);
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    self.send_done = true;
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Queues a Ping if nothing has been sent for the keepalive interval.
+    fn keepalive(&mut self) {
+        // Anything else being sent keeps the connection alive just as well.
+        if self.lanes.is_empty() && self.write_buffer.is_empty() &&
+           self.last_sent.elapsed() >= self.keepalive_interval {
+            let ping = NetworkPacket::Ping(self.state.stats.ping_timestamp());
+            self.lanes.push(&ping, Priority::Control);
+        }
+    }
+}
+
+/// The header at the start of the bytes, if they start with one.
+fn header_at(bytes: &[u8]) -> Option<PacketHeader> {
+    let mut header: [u8; HEADER_LEN] = [0; HEADER_LEN];
+    header.copy_from_slice(&bytes[..HEADER_LEN]);
+    parse_packet_header(header)
+}
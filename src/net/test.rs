@@ -1,19 +1,28 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::{Arc, Mutex, RwLock};
-use std::sync::mpsc::{Sender, channel};
-use std::time::Duration;
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6,
+               TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, channel, sync_channel};
+use std::time::{Duration, Instant};
 use std::thread;
 
-use test_util::{TEST_SLEEP_TIME_MILLIS, Tattle, start_log_once};
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::transport::{FaultyTransport, Faults, LoopbackStream, Transport, loopback_pair,
+                       loopback_pair_with_capacity};
+
+use test_util::{TEST_SLEEP_TIME_MILLIS, Tattle, start_log_once, tcp_pair};
 
 #[test]
 fn check_controller_channel_runs() {
     start_log_once();
     let (tx, rx): (Sender<super::ControllerMessage>, _) = channel();
-    let controller_raw = Arc::new(super::ControllerRaw {
-        connections: RwLock::new(Vec::new()),
-        tx: Mutex::new(tx),
-    });
+    let controller_raw = Arc::new(super::ControllerRaw::new(tx, Default::default()));
     let controller_raw_clone = Arc::downgrade(&controller_raw);
     thread::spawn(move || super::check_controller_channel(rx, controller_raw_clone));
     let tattle = Tattle::new();
@@ -31,10 +40,7 @@ fn check_controller_channel_runs() {
 fn check_controller_channel_exits() {
     start_log_once();
     let (tx_controller, rx_controller): (Sender<super::ControllerMessage>, _) = channel();
-    let controller_raw = Arc::new(super::ControllerRaw {
-        connections: RwLock::new(Vec::new()),
-        tx: Mutex::new(tx_controller),
-    });
+    let controller_raw = Arc::new(super::ControllerRaw::new(tx_controller, Default::default()));
     let (tx_thread, rx_thread) = channel::<()>();
     let controller_raw_clone = Arc::downgrade(&controller_raw);
     thread::spawn(move || {
@@ -63,3 +69,2466 @@ fn ip_localhost() {
     start_log_once();
     super::ip("localhost:80");
 }
+
+#[test]
+fn parse_addr_localhost() {
+    start_log_once();
+    let v4 = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 80));
+    assert_eq!(super::parse_addr("localhost:80"), Ok(v4));
+    assert_eq!(super::parse_addr("LocalHost:80"), Ok(v4));
+    assert_eq!(super::parse_addr_with("localhost:80",
+                                      super::LocalhostPolicy::Forbid,
+                                      super::AddrPreference::Any),
+               Err(super::AddrParseError::LocalhostForbidden));
+    assert_eq!(super::parse_addr_with("localhost:80",
+                                      super::LocalhostPolicy::Loopback,
+                                      super::AddrPreference::PreferV6),
+               Ok(super::ip("[::1]:80")));
+    assert_eq!(super::parse_addr("localhost:port"),
+               Err(super::AddrParseError::Unresolvable));
+}
+
+#[test]
+fn parse_addr_unresolvable() {
+    start_log_once();
+    for addr in &["", "127.0.0.1", "127.0.0.1:port", "127.0.0.1:99999"] {
+        assert_eq!(super::parse_addr(addr), Err(super::AddrParseError::Unresolvable));
+    }
+    assert_eq!(super::single_addr(Vec::new(), super::AddrPreference::Any),
+               Err(super::AddrParseError::Unresolvable));
+}
+
+#[test]
+fn parse_addr_ambiguous() {
+    start_log_once();
+    let any = super::AddrPreference::Any;
+    let addrs = vec![super::ip("127.0.0.1:80"), super::ip("[::1]:80")];
+    assert_eq!(super::single_addr(addrs.clone(), any),
+               Err(super::AddrParseError::Ambiguous(addrs)));
+    let repeated = vec![super::ip("127.0.0.1:80"), super::ip("127.0.0.1:80")];
+    assert_eq!(super::single_addr(repeated, any), Ok(super::ip("127.0.0.1:80")));
+}
+
+#[test]
+fn parse_addr_preference() {
+    start_log_once();
+    let addrs = vec![super::ip("[::1]:80"), super::ip("127.0.0.1:80"), super::ip("[::2]:80")];
+    assert_eq!(super::single_addr(addrs.clone(), super::AddrPreference::PreferV4),
+               Ok(super::ip("127.0.0.1:80")));
+    assert_eq!(super::single_addr(addrs, super::AddrPreference::PreferV6),
+               Err(super::AddrParseError::Ambiguous(vec![super::ip("[::1]:80"),
+                                                         super::ip("[::2]:80")])));
+    // Without any addresses of the preferred family, the others are still considered.
+    let v4_only = vec![super::ip("127.0.0.1:80")];
+    assert_eq!(super::single_addr(v4_only, super::AddrPreference::PreferV6),
+               Ok(super::ip("127.0.0.1:80")));
+}
+
+#[test]
+fn parse_addr_ipv6() {
+    start_log_once();
+    let v6 = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 8080, 0, 0));
+    assert_eq!(super::parse_addr("[::1]:8080"), Ok(v6));
+    assert_eq!(super::ip("[::1]:8080"), v6);
+}
+
+fn counters() -> Arc<super::ConnectionCounters> {
+    Arc::new(super::ConnectionCounters::new(Instant::now()))
+}
+
+fn no_compression() -> Arc<AtomicBool> {
+    Arc::new(AtomicBool::new(false))
+}
+
+#[test]
+fn check_stream_send_frames_packet() {
+    start_log_once();
+    let (local, mut remote) = loopback_pair();
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        super::check_stream_send(rx, local, Default::default(), counters(), no_compression())
+    });
+    let packet = super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue);
+    let expected = frame(&packet);
+    tx.send(super::ConnectionMessage::SendPacket(packet)).unwrap();
+    let mut header: [u8; 12] = [0; 12];
+    remote.read_exact(&mut header).unwrap();
+    assert_eq!(LittleEndian::read_u32(&header[..4]), super::NET_MAGIC_NUMBER);
+    let len = LittleEndian::read_u32(&header[4..8]) as usize;
+    assert_eq!(len, expected.len() - 12);
+    let mut body = vec![0; len];
+    remote.read_exact(&mut body).unwrap();
+    assert_eq!(&body[..], &expected[12..]);
+}
+
+/// Queues the packets before the send thread starts, so they are all taken at once, then checks
+/// they arrive intact and in order.
+fn assert_burst_arrives(flush_size: usize) {
+    let (local, mut remote) = loopback_pair();
+    let (tx, rx) = channel();
+    let packets: Vec<super::NetworkPacket> = (0..1000)
+        .map(|i| {
+            super::NetworkPacket::Message {
+                channel: "burst".to_owned(),
+                payload: vec![i as u8; i % 50],
+            }
+        })
+        .collect();
+    for packet in &packets {
+        tx.send(super::ConnectionMessage::SendPacket(packet.clone())).unwrap();
+    }
+    let config = super::ControllerConfig { flush_size: flush_size, ..Default::default() };
+    let stats = counters();
+    let thread_stats = stats.clone();
+    thread::spawn(move || {
+        super::check_stream_send(rx, local, config, thread_stats, no_compression())
+    });
+    let started = Instant::now();
+    for packet in packets {
+        assert_eq!(read_packet(&mut remote), packet);
+    }
+    debug!("Received a burst of 1000 packets with a flush size of {} in {:?}.",
+           flush_size,
+           started.elapsed());
+    assert!(eventually(|| stats.snapshot(super::ip("127.0.0.1:0")).packets_sent == 1000));
+}
+
+/// Checks the condition untill it is true, giving up after TEST_SLEEP_TIME_MILLIS.
+///
+/// Used instead of sleeping for the whole time when a thread only needs a moment to catch up.
+fn eventually<F: Fn() -> bool>(condition: F) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(TEST_SLEEP_TIME_MILLIS) {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    condition()
+}
+
+#[test]
+fn check_stream_send_coalesces_burst() {
+    start_log_once();
+    assert_burst_arrives(super::FLUSH_SIZE);
+}
+
+#[test]
+fn check_stream_send_small_flush_size() {
+    start_log_once();
+    assert_burst_arrives(1);
+}
+
+#[test]
+fn check_stream_send_exits() {
+    start_log_once();
+    let (local, _remote) = tcp_pair();
+    let (tx, rx) = channel::<super::ConnectionMessage>();
+    let (tx_thread, rx_thread) = channel::<()>();
+    thread::spawn(move || {
+        super::check_stream_send(rx, local, Default::default(), counters(), no_compression());
+        tx_thread.send(()).unwrap();
+    });
+    drop(tx);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    rx_thread.try_recv()
+             .expect("the function net::check_stream_send did not return after the channel \
+                      sending it messages was dropped.");
+}
+
+#[test]
+fn check_stream_recv_back_to_back() {
+    start_log_once();
+    let (local, mut remote) = loopback_pair();
+    let addr = local.peer_addr().unwrap();
+    let (tx, rx) = channel();
+    let (controller_tx, _controller_rx) = channel();
+    let (connection_tx, _connection_rx) = sync_channel(super::SEND_QUEUE_DEPTH);
+    let state = super::RecvState {
+        addr: addr,
+        id: super::ConnectionId(7),
+        accepted: false,
+        incoming_tx: tx,
+        controller_tx: controller_tx,
+        connection_tx: super::ConnectionSender::Threaded(connection_tx),
+        closer: Arc::new(super::StreamCloser::none()),
+        state: Arc::new(Mutex::new(super::ConnectionState::Handshaking)),
+        kick_reason: Arc::new(Mutex::new(None)),
+        idle_timeout: Duration::from_millis(super::IDLE_TIMEOUT_MILLIS),
+        handshake_timeout: Duration::from_millis(super::HANDSHAKE_TIMEOUT_MILLIS),
+        started: Instant::now(),
+        first_packet: None,
+        max_packet_size: super::MAX_PACKET_SIZE,
+        oversized_packets: Arc::new(AtomicUsize::new(0)),
+        subscribers: Arc::new(Mutex::new(HashMap::new())),
+        stats: counters(),
+        rate_limit: None,
+        compression: super::Compression::None,
+        should_crash: true,
+        display_name: String::new(),
+        password: None,
+        compress: Arc::new(AtomicBool::new(false)),
+        max_fragmented_transfers: super::MAX_FRAGMENTED_TRANSFERS,
+        max_fragment_bytes: super::MAX_FRAGMENT_BYTES,
+    };
+    thread::spawn(move || super::check_stream_recv(local, state));
+    let first = super::NetworkPacket::Init {
+        protocol: super::PROTOCOL_VERSION,
+        version: ::VERSION.to_owned(),
+        should_crash: true,
+        compression: super::Compression::None,
+        password: None,
+        display_name: String::new(),
+    };
+    let second = super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue);
+    let mut bytes = frame(&first);
+    bytes.append(&mut frame(&second));
+    remote.write_all(&bytes).unwrap();
+    assert_eq!(rx.recv().unwrap(), (super::ConnectionId(7), first));
+    assert_eq!(rx.recv().unwrap(), (super::ConnectionId(7), second));
+}
+
+#[test]
+fn resync_stream_skips_garbage() {
+    start_log_once();
+    let packet = super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue);
+    let packet = frame(&packet);
+    let mut bytes: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+    bytes.extend_from_slice(&packet);
+    let mut header: [u8; 12] = [0; 12];
+    let mut cursor = Cursor::new(bytes);
+    cursor.read_exact(&mut header).unwrap();
+    let header = super::resync_stream(&mut cursor, header).unwrap();
+    assert_eq!(header.len as usize, packet.len() - 12);
+    assert_eq!(header.crc, super::crc32(&packet[12..]));
+    let mut body = vec![0; header.len as usize];
+    cursor.read_exact(&mut body).unwrap();
+    assert_eq!(&body[..], &packet[12..]);
+}
+
+/// Frames a packet with the default maximum packet size.
+fn frame(packet: &super::NetworkPacket) -> Vec<u8> {
+    super::seralize_packet(packet, super::MAX_PACKET_SIZE, None).unwrap()
+}
+
+/// A Disconnect packet with a body of exactly the given size, which must be at least 12 bytes.
+fn packet_with_body_len(len: usize) -> super::NetworkPacket {
+    // The body is the u32 variant tag, then the reason as a u64 length followed by it's bytes.
+    super::NetworkPacket::Disconnect { reason: String::from_utf8(vec![b'a'; len - 12]).unwrap() }
+}
+
+/// Frames a packet and reads it back, checking it comes out the same.
+fn assert_round_trip(packet: super::NetworkPacket, max_size: u32) {
+    let bytes = super::seralize_packet(&packet, max_size, None).unwrap();
+    assert_read_back(bytes, packet);
+}
+
+/// Reads a framed packet, checking it comes out as the given packet.
+fn assert_read_back(bytes: Vec<u8>, packet: super::NetworkPacket) {
+    let addr = super::ip("127.0.0.1:0");
+    match super::read_packet(&mut Cursor::new(bytes), &addr, super::MAX_PACKET_SIZE) {
+        super::ReadResult::Packet(read) => assert_eq!(read, packet),
+        _ => panic!("the packet could not be read back"),
+    }
+}
+
+#[test]
+fn packet_length_empty_body() {
+    start_log_once();
+    let mut header: [u8; 12] = [0; 12];
+    LittleEndian::write_u32(&mut header[..4], super::NET_MAGIC_NUMBER);
+    assert_eq!(super::get_packet_length(header), Some(0));
+    LittleEndian::write_u32(&mut header[..4], 0);
+    assert_eq!(super::get_packet_length(header), None);
+}
+
+#[test]
+fn packet_round_trips() {
+    start_log_once();
+    assert_round_trip(packet_with_body_len(12), super::MAX_PACKET_SIZE);
+    assert_round_trip(packet_with_body_len(65535), super::MAX_PACKET_SIZE);
+    assert_round_trip(packet_with_body_len(65536), super::MAX_PACKET_SIZE);
+    assert_round_trip(packet_with_body_len(100000), 100000);
+}
+
+#[test]
+fn crc32_known_values() {
+    start_log_once();
+    assert_eq!(super::crc32(b""), 0);
+    assert_eq!(super::crc32(b"123456789"), 0xCBF43926);
+}
+
+#[test]
+fn packet_header_has_crc() {
+    start_log_once();
+    let bytes = frame(&packet_with_body_len(20));
+    let mut header: [u8; 12] = [0; 12];
+    header.copy_from_slice(&bytes[..12]);
+    assert_eq!(super::parse_packet_header(header),
+               Some(super::PacketHeader {
+                   len: 20,
+                   crc: super::crc32(&bytes[12..]),
+                   compressed: false,
+               }));
+    assert_eq!(super::get_packet_length(header), Some(20));
+    assert_eq!(super::get_packet_header(header), Some((20, super::crc32(&bytes[12..]))));
+}
+
+#[test]
+fn corrupt_packet_detected() {
+    start_log_once();
+    let mut bytes = frame(&packet_with_body_len(20));
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xFF;
+    let addr = super::ip("127.0.0.1:0");
+    match super::read_packet(&mut Cursor::new(bytes), &addr, super::MAX_PACKET_SIZE) {
+        super::ReadResult::Corrupt => {}
+        _ => panic!("the corrupted packet was not detected"),
+    }
+}
+
+#[test]
+fn corrupt_stream_closed() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut stream = handshaken_stream(addr);
+    let mut bytes = frame(&packet_with_body_len(20));
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xFF;
+    for _ in 0..super::MAX_CORRUPT_PACKETS {
+        stream.write_all(&bytes).unwrap();
+    }
+    assert_eq!(read_packet(&mut stream),
+               super::NetworkPacket::Error(super::NetworkError::CorruptStream));
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn oversized_header_drops_connection() {
+    start_log_once();
+    let config = super::ControllerConfig { max_packet_size: 1000, ..Default::default() };
+    let (server, addr) = listening_controller_with_config(config);
+    let mut stream = handshaken_stream(addr);
+    let mut header: [u8; 12] = [0; 12];
+    LittleEndian::write_u32(&mut header[..4], super::NET_MAGIC_NUMBER);
+    LittleEndian::write_u32(&mut header[4..8], 1001);
+    stream.write_all(&header).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.connections.read().unwrap().is_empty());
+    assert_eq!(server.oversized_packets(), 1);
+}
+
+#[test]
+fn max_packet_size_below_compressed_flag() {
+    start_log_once();
+    let config = super::ControllerConfig {
+        max_packet_size: super::COMPRESSED_FLAG,
+        ..Default::default()
+    };
+    let controller = super::Controller::new_with_config(config);
+    assert_eq!(controller.raw.config.read().unwrap().max_packet_size,
+               super::COMPRESSED_FLAG - 1);
+}
+
+#[test]
+fn deserialize_bounded() {
+    start_log_once();
+    let bytes = frame(&packet_with_body_len(200));
+    assert!(super::deserialize_packet(&bytes[12..], 100).is_err());
+    assert_eq!(super::deserialize_packet(&bytes[12..], 200).unwrap(),
+               packet_with_body_len(200));
+}
+
+/// A Message with a payload of the given length, which compresses well.
+fn compressible_message(len: usize) -> super::NetworkPacket {
+    super::NetworkPacket::Message {
+        channel: "compress".to_owned(),
+        payload: b"buildengine".iter().cloned().cycle().take(len).collect(),
+    }
+}
+
+/// A Message with a payload of the given length, which compresses badly.
+fn incompressible_message(len: usize) -> super::NetworkPacket {
+    let mut state: u32 = 12345;
+    let payload = (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect();
+    super::NetworkPacket::Message {
+        channel: "compress".to_owned(),
+        payload: payload,
+    }
+}
+
+/// The header of a framed packet.
+fn header_of(bytes: &[u8]) -> super::PacketHeader {
+    let mut header: [u8; 12] = [0; 12];
+    header.copy_from_slice(&bytes[..12]);
+    super::parse_packet_header(header).unwrap()
+}
+
+#[test]
+fn compressed_round_trips() {
+    start_log_once();
+    let packet = compressible_message(100000);
+    let bytes = super::seralize_packet(&packet, super::MAX_PACKET_SIZE, Some(1024)).unwrap();
+    assert!(header_of(&bytes).compressed);
+    assert!(bytes.len() < 10000, "the packet compressed to {} bytes", bytes.len());
+    assert_read_back(bytes, packet);
+}
+
+#[test]
+fn incompressible_sent_as_is() {
+    start_log_once();
+    let packet = incompressible_message(10000);
+    let bytes = super::seralize_packet(&packet, super::MAX_PACKET_SIZE, Some(1024)).unwrap();
+    assert!(!header_of(&bytes).compressed);
+    assert_eq!(bytes, frame(&packet));
+    assert_read_back(bytes, packet);
+}
+
+#[test]
+fn below_threshold_sent_as_is() {
+    start_log_once();
+    let packet = compressible_message(900);
+    let bytes = super::seralize_packet(&packet, super::MAX_PACKET_SIZE, Some(1024)).unwrap();
+    assert!(!header_of(&bytes).compressed);
+    assert_eq!(bytes, frame(&packet));
+}
+
+/// Replaces the body of a compressed packet, fixing up the header so only decompressing fails.
+fn with_compressed_body(body: &[u8]) -> Vec<u8> {
+    let mut bytes = frame(&super::NetworkPacket::Pong(0));
+    bytes.truncate(12);
+    LittleEndian::write_u32(&mut bytes[4..8], body.len() as u32 | super::COMPRESSED_FLAG);
+    LittleEndian::write_u32(&mut bytes[8..12], super::crc32(body));
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+#[test]
+fn corrupt_compressed_body_malformed() {
+    start_log_once();
+    let bytes = super::seralize_packet(&compressible_message(100000),
+                                       super::MAX_PACKET_SIZE,
+                                       Some(1024))
+                    .unwrap();
+    let mut body = bytes[12..].to_vec();
+    let middle = body.len() / 2;
+    body[middle] ^= 0xFF;
+    let addr = super::ip("127.0.0.1:0");
+    let corrupt = with_compressed_body(&body);
+    match super::read_packet(&mut Cursor::new(corrupt), &addr, super::MAX_PACKET_SIZE) {
+        super::ReadResult::Malformed => {}
+        _ => panic!("a corrupt compressed body was not malformed"),
+    }
+}
+
+#[test]
+fn compressed_body_bounded() {
+    start_log_once();
+    let bomb = super::compress(&vec![0; 100000]);
+    let addr = super::ip("127.0.0.1:0");
+    match super::read_packet(&mut Cursor::new(with_compressed_body(&bomb)), &addr, 50000) {
+        super::ReadResult::Malformed => {}
+        _ => panic!("a body decompressing above the maximum packet size was accepted"),
+    }
+}
+
+/// Sends a large compressible message from a client with the given compression to a server that
+/// offers zlib, returning how many bytes the client sent for it.
+fn bytes_sent_for_message(compression: super::Compression) -> u64 {
+    let (server, addr) = listening_controller();
+    let received = server.subscribe("compress");
+    let config = super::ControllerConfig { compression: compression, ..Default::default() };
+    let mut client = super::Controller::new_with_config(config);
+    let id = client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let before = client.stats(id).unwrap().bytes_sent;
+    let packet = compressible_message(100000);
+    client.send_to(id, packet.clone()).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (_from, payload) = received.try_recv().unwrap();
+    assert_eq!(super::NetworkPacket::Message {
+                   channel: "compress".to_owned(),
+                   payload: payload,
+               },
+               packet);
+    client.stats(id).unwrap().bytes_sent - before
+}
+
+#[test]
+fn compression_negotiated() {
+    start_log_once();
+    assert!(bytes_sent_for_message(super::Compression::Zlib) < 10000);
+    assert!(bytes_sent_for_message(super::Compression::None) > 100000);
+}
+
+#[test]
+fn packet_too_large() {
+    start_log_once();
+    assert_eq!(super::seralize_packet(&packet_with_body_len(100001), 100000, None),
+               Err(super::SerializeError::PacketTooLarge(100001, 100000)));
+}
+
+/// Reads a single framed packet from a raw stream, waiting at most TEST_SLEEP_TIME_MILLIS.
+fn read_packet<T: Transport + Read>(stream: &mut T) -> super::NetworkPacket {
+    stream.set_read_timeout(Some(Duration::from_millis(TEST_SLEEP_TIME_MILLIS))).unwrap();
+    let mut header: [u8; 12] = [0; 12];
+    stream.read_exact(&mut header).unwrap();
+    let len = super::get_packet_length(header).unwrap();
+    let mut body = vec![0; len as usize];
+    stream.read_exact(&mut body).unwrap();
+    super::deserialize_packet(&body, super::MAX_PACKET_SIZE).unwrap()
+}
+
+/// Receives the next packet from the controller that is not a `NetworkPacket::Init`.
+fn recv_after_init(controller: &super::Controller) -> (super::ConnectionId, super::NetworkPacket) {
+    loop {
+        match controller.try_recv_packet().unwrap() {
+            (_, super::NetworkPacket::Init { .. }) => continue,
+            other => return other,
+        }
+    }
+}
+
+/// Like recv_after_init, but returns None once there is nothing left to receive.
+fn recv_after_init_or_none(controller: &super::Controller)
+                           -> Option<(super::ConnectionId, super::NetworkPacket)> {
+    loop {
+        match controller.try_recv_packet() {
+            Some((_, super::NetworkPacket::Init { .. })) => continue,
+            other => return other,
+        }
+    }
+}
+
+/// Writes an Init packet with the given version to a raw stream.
+fn send_init(stream: &mut TcpStream, version: &str) {
+    send_init_with(stream, super::PROTOCOL_VERSION, version, true);
+}
+
+fn send_init_with(stream: &mut TcpStream, protocol: u32, version: &str, should_crash: bool) {
+    let init = super::NetworkPacket::Init {
+        protocol: protocol,
+        version: version.to_owned(),
+        should_crash: should_crash,
+        compression: super::Compression::None,
+        password: None,
+        display_name: String::new(),
+    };
+    stream.write_all(&frame(&init)).unwrap();
+}
+
+/// Creates a Controller listening on an ephemeral loopback port, and returns the address of it.
+fn listening_controller() -> (super::Controller, SocketAddr) {
+    listening_controller_with_config(super::ControllerConfig::default())
+}
+
+fn listening_controller_with_config(config: super::ControllerConfig)
+                                    -> (super::Controller, SocketAddr) {
+    let mut controller = super::Controller::new_with_config(config);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    controller.add_listener(listener).unwrap();
+    (controller, addr)
+}
+
+#[test]
+fn controller_connect() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut client = super::Controller::new_empty();
+    client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+    assert_eq!(client.raw.connections.read().unwrap().len(), 1);
+    for controller in &[server, client] {
+        let received = controller.raw.incoming_rx.lock().unwrap().try_recv();
+        match received {
+            Ok((_, super::NetworkPacket::Init { version, .. })) => assert_eq!(version, ::VERSION),
+            other => panic!("expected an Init packet, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn listener_reports_peer_addr() {
+    start_log_once();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = channel();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let thread_shutdown = shutdown.clone();
+    thread::spawn(move || {
+        super::check_listener(super::ListenerId(0), listener, tx, thread_shutdown)
+    });
+    let stream = TcpStream::connect(addr).unwrap();
+    match rx.recv().unwrap() {
+        super::ControllerMessage::AddSocket(accepted, peer_addr, None, None) => {
+            assert_eq!(peer_addr, stream.local_addr().unwrap());
+            assert_eq!(peer_addr, accepted.peer_addr().unwrap());
+        }
+        other => panic!("expected an AddSocket without a Sender, got {:?}", other),
+    }
+    shutdown.store(true, Ordering::SeqCst);
+}
+
+#[test]
+fn connection_peer_addr() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let stream = handshaken_stream(addr);
+    let (id, stats) = server.stats_all()[0];
+    assert_eq!(server.peer_addr(id), Some(stream.local_addr().unwrap()));
+    assert_eq!(stats.peer_addr, stream.local_addr().unwrap());
+    assert_eq!(server.peer_addr(super::ConnectionId(100)), None);
+    let mut client = super::Controller::new_empty();
+    let id = client.connect(addr).unwrap();
+    assert_eq!(client.peer_addr(id), Some(addr));
+}
+
+#[test]
+fn ipv6_loopback_connect() {
+    start_log_once();
+    let mut server = super::Controller::new_empty();
+    let listener = TcpListener::bind("[::1]:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    server.add_listener(listener).unwrap();
+    let mut client = super::Controller::new_empty();
+    let addr_str = format!("localhost:{}", addr.port());
+    let id = client.connect_to(&addr_str, super::AddrPreference::PreferV6).unwrap();
+    assert_eq!(client.peer_addr(id), Some(addr));
+    assert!(eventually(|| server.raw.connections.read().unwrap().len() == 1));
+    let (server_id, _) = server.stats_all()[0];
+    match server.peer_addr(server_id) {
+        Some(SocketAddr::V6(peer)) => assert_eq!(IpAddr::V6(*peer.ip()), addr.ip()),
+        other => panic!("expected an IPv6 peer, got {:?}", other),
+    }
+}
+
+#[test]
+fn listen_dual_stack_accepts_both() {
+    start_log_once();
+    // Find a port that is free for both families.
+    let port = TcpListener::bind("[::]:0").unwrap().local_addr().unwrap().port();
+    let mut server = super::Controller::new_empty();
+    let ids = server.listen_dual_stack(port).unwrap();
+    assert!(!ids.is_empty());
+    assert_eq!(server.raw.listeners.lock().unwrap().len(), ids.len());
+    let mut client = super::Controller::new_empty();
+    client.connect_to(&format!("127.0.0.1:{}", port), super::AddrPreference::Any).unwrap();
+    client.connect_to(&format!("[::1]:{}", port), super::AddrPreference::Any).unwrap();
+    assert!(eventually(|| server.raw.connections.read().unwrap().len() == 2));
+    assert!(client.connect_to("localhost", super::AddrPreference::Any).is_err());
+}
+
+#[test]
+fn send_to_reaches_peer() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut client = super::Controller::new_empty();
+    client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (id, _) = server.raw.incoming_rx.lock().unwrap().try_recv().unwrap();
+    let packet = super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue);
+    server.send_to(id, packet.clone()).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (_, received) = recv_after_init(&client);
+    assert_eq!(received, packet);
+}
+
+#[test]
+fn try_recv_packet_gets_init() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    assert_eq!(server.try_recv_packet(), None);
+    let mut client = super::Controller::new_empty();
+    client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let id = *server.raw.connections.read().unwrap().keys().next().unwrap();
+    match server.try_recv_packet() {
+        Some((from, super::NetworkPacket::Init { version, .. })) => {
+            assert_eq!(from, id);
+            assert_eq!(version, ::VERSION);
+        }
+        other => panic!("expected an Init packet, got {:?}", other),
+    }
+    assert_eq!(server.try_recv_packet(), None);
+}
+
+#[test]
+fn dropped_controller_stops_forwarding() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut client = super::Controller::new_empty();
+    let id = client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    drop(server);
+    let packet = super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue);
+    client.send_to(id, packet).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(client.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn message_reaches_subscribers() {
+    start_log_once();
+    let controller = super::Controller::new_empty();
+    let chat = controller.subscribe("chat");
+    let chat_too = controller.subscribe("chat");
+    let other = controller.subscribe("other");
+    let (client, server) = controller.add_loopback_pair().unwrap();
+    controller.send_message(client, "nobody", b"lost".to_vec()).unwrap();
+    controller.send_message(client, "chat", b"hello".to_vec()).unwrap();
+    assert_eq!(chat.recv().unwrap(), (server, b"hello".to_vec()));
+    assert_eq!(chat_too.recv().unwrap(), (server, b"hello".to_vec()));
+    assert!(other.try_recv().is_err());
+    assert_eq!(recv_after_init_or_none(&controller), None);
+    assert_eq!(controller.raw.connections.read().unwrap().len(), 2);
+}
+
+#[test]
+fn fragmented_message_reaches_subscribers() {
+    start_log_once();
+    let config = super::ControllerConfig { fragment_threshold: 1000, ..Default::default() };
+    let controller = super::Controller::new_with_config(config);
+    let chat = controller.subscribe("chat");
+    let (client, server) = controller.add_loopback_pair().unwrap();
+    let payload: Vec<u8> = (0..100000).map(|i| i as u8).collect();
+    controller.send_message(client, "chat", payload.clone()).unwrap();
+    assert_eq!(chat.recv().unwrap(), (server, payload));
+    assert!(controller.stats(server).unwrap().packets_received > 100);
+    assert_eq!(controller.raw.connections.read().unwrap().len(), 2);
+}
+
+#[test]
+fn gameplay_packet_overtakes_bulk_transfer() {
+    start_log_once();
+    let (local, mut remote) = loopback_pair();
+    let (tx, rx) = channel();
+    let large = super::NetworkPacket::Message {
+        channel: "large".to_owned(),
+        payload: vec![1; 10000],
+    };
+    let small = super::NetworkPacket::Message {
+        channel: "small".to_owned(),
+        payload: vec![2; 10],
+    };
+    let bulk = super::ConnectionMessage::SendWithPriority(large.clone(), super::Priority::Bulk);
+    tx.send(bulk).unwrap();
+    tx.send(super::ConnectionMessage::SendPacket(small.clone())).unwrap();
+    let config = super::ControllerConfig { fragment_threshold: 100, ..Default::default() };
+    let stats = counters();
+    let thread_stats = stats.clone();
+    thread::spawn(move || {
+        super::check_stream_send(rx, local, config, thread_stats, no_compression())
+    });
+    let mut reassembly = super::Reassembly::new(1, 100000);
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        if let Some(packet) = reassembly.handle(read_packet(&mut remote)).unwrap() {
+            received.push(packet);
+        }
+    }
+    assert_eq!(received, vec![small, large.clone()]);
+    // A FragmentStart, a Fragment for every 100 bytes of the body and a FragmentEnd.
+    let fragments = (frame(&large).len() - 12 + 99) / 100;
+    let addr = super::ip("127.0.0.1:0");
+    assert!(eventually(|| {
+        stats.snapshot(addr).lane(super::Priority::Bulk).packets_sent == fragments as u64 + 2
+    }));
+    let snapshot = stats.snapshot(addr);
+    assert_eq!(snapshot.lane(super::Priority::Gameplay).packets_sent, 1);
+    assert_eq!(snapshot.lane(super::Priority::Control).packets_sent, 0);
+}
+
+#[test]
+fn bulk_lane_not_starved() {
+    start_log_once();
+    let (local, _remote) = loopback_pair();
+    let mut lanes = super::Lanes::new();
+    for _ in 0..50 {
+        lanes.push(&super::NetworkPacket::Pong(0), super::Priority::Gameplay);
+    }
+    lanes.push(&super::NetworkPacket::Pong(1), super::Priority::Bulk);
+    let mut buffer = super::WriteBuffer::new();
+    let config = super::ControllerConfig::default();
+    for _ in 0..super::MAX_LANE_DEFICIT + 1 {
+        assert!(lanes.frame_next(&mut buffer, &config, None, &local));
+    }
+    let framed: Vec<super::Priority> = buffer.packet_lens.iter().map(|&(_, lane)| lane).collect();
+    assert_eq!(framed.last(), Some(&super::Priority::Bulk));
+    assert!(lanes.queues[super::Priority::Bulk.index()].is_empty());
+}
+
+#[test]
+fn default_priorities() {
+    start_log_once();
+    assert_eq!(super::NetworkPacket::Ping(0).priority(), super::Priority::Control);
+    assert_eq!(super::NetworkPacket::ScriptRequest { names: Vec::new() }.priority(),
+               super::Priority::Bulk);
+    assert_eq!(compressible_message(10).priority(), super::Priority::Gameplay);
+    for lane in 0..super::LANES {
+        assert_eq!(super::Priority::from_index(lane).index(), lane);
+    }
+}
+
+/// The FragmentStart for sending the packet in fragments with the given id.
+fn frame_start(id: u32, packet: &super::NetworkPacket) -> super::NetworkPacket {
+    super::NetworkPacket::FragmentStart {
+        id: id,
+        total_len: frame(packet).len() as u32 - 12,
+        channel: String::new(),
+    }
+}
+
+#[test]
+fn reassembly_round_trips() {
+    start_log_once();
+    let packet = packet_with_body_len(300);
+    let body = frame(&packet)[12..].to_vec();
+    let mut reassembly = super::Reassembly::new(1, 1000);
+    assert_eq!(reassembly.handle(frame_start(3, &packet)), Ok(None));
+    for (i, piece) in body.chunks(100).enumerate() {
+        let fragment = super::NetworkPacket::Fragment {
+            id: 3,
+            offset: i as u32 * 100,
+            data: piece.to_vec(),
+        };
+        assert_eq!(reassembly.handle(fragment), Ok(None));
+    }
+    let end = super::NetworkPacket::FragmentEnd {
+        id: 3,
+        checksum: super::crc32(&body),
+    };
+    assert_eq!(reassembly.handle(end), Ok(Some(packet)));
+    assert_eq!(reassembly.announced, 0);
+    let ping = super::NetworkPacket::Ping(5);
+    assert_eq!(reassembly.handle(ping.clone()), Ok(Some(ping)));
+}
+
+#[test]
+fn reassembly_rejects_bad_fragments() {
+    start_log_once();
+    let packet = packet_with_body_len(300);
+    let mut reassembly = super::Reassembly::new(4, 10000);
+    reassembly.handle(frame_start(1, &packet)).unwrap();
+    assert_eq!(reassembly.handle(frame_start(1, &packet)),
+               Err(super::NetworkError::BadFragment));
+    let unknown = super::NetworkPacket::Fragment {
+        id: 2,
+        offset: 0,
+        data: vec![0; 10],
+    };
+    assert_eq!(reassembly.handle(unknown), Err(super::NetworkError::BadFragment));
+    let skipped = super::NetworkPacket::Fragment {
+        id: 1,
+        offset: 10,
+        data: vec![0; 10],
+    };
+    assert_eq!(reassembly.handle(skipped), Err(super::NetworkError::BadFragment));
+    let body = frame(&packet)[12..].to_vec();
+    let whole = super::NetworkPacket::Fragment {
+        id: 1,
+        offset: 0,
+        data: body.clone(),
+    };
+    reassembly.handle(whole).unwrap();
+    let end = super::NetworkPacket::FragmentEnd {
+        id: 1,
+        checksum: super::crc32(&body) ^ 1,
+    };
+    assert_eq!(reassembly.handle(end), Err(super::NetworkError::BadFragment));
+}
+
+#[test]
+fn reassembly_limits_transfers() {
+    start_log_once();
+    let packet = packet_with_body_len(300);
+    let mut reassembly = super::Reassembly::new(2, 1000);
+    reassembly.handle(frame_start(1, &packet)).unwrap();
+    reassembly.handle(frame_start(2, &packet)).unwrap();
+    assert_eq!(reassembly.handle(frame_start(3, &packet)),
+               Err(super::NetworkError::FragmentLimit));
+    let mut reassembly = super::Reassembly::new(4, 1000);
+    reassembly.handle(frame_start(1, &packet)).unwrap();
+    let huge = super::NetworkPacket::FragmentStart {
+        id: 2,
+        total_len: 800,
+        channel: String::new(),
+    };
+    assert_eq!(reassembly.handle(huge), Err(super::NetworkError::FragmentLimit));
+}
+
+#[test]
+fn duplicate_fragment_start_closes_connection() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut stream = handshaken_stream(addr);
+    let start = frame_start(1, &packet_with_body_len(300));
+    stream.write_all(&frame(&start)).unwrap();
+    stream.write_all(&frame(&start)).unwrap();
+    assert_eq!(read_packet(&mut stream),
+               super::NetworkPacket::Error(super::NetworkError::BadFragment));
+    assert!(eventually(|| server.raw.connections.read().unwrap().is_empty()));
+}
+
+#[test]
+fn stats_count_both_directions() {
+    start_log_once();
+    let controller = super::Controller::new_empty();
+    let chat = controller.subscribe("chat");
+    let (client, server) = controller.add_loopback_pair().unwrap();
+    for _ in 0..3 {
+        controller.send_message(client, "chat", b"hello".to_vec()).unwrap();
+    }
+    controller.send_message(server, "chat", b"hi".to_vec()).unwrap();
+    let mut received: Vec<(super::ConnectionId, Vec<u8>)> = (0..4)
+        .map(|_| chat.recv().unwrap())
+        .collect();
+    received.sort();
+    assert_eq!(received,
+               vec![(client, b"hi".to_vec()),
+                    (server, b"hello".to_vec()),
+                    (server, b"hello".to_vec()),
+                    (server, b"hello".to_vec())]);
+    // The sending side counts a packet just after the receiving side may have handled it.
+    assert!(eventually(|| {
+        controller.stats(client).unwrap().packets_sent == 4 &&
+        controller.stats(server).unwrap().packets_sent == 2
+    }));
+    let client_stats = controller.stats(client).unwrap();
+    let server_stats = controller.stats(server).unwrap();
+    // Init and three messages one way, Init and a message the other.
+    assert_eq!(client_stats.packets_sent, 4);
+    assert_eq!(client_stats.packets_received, 2);
+    assert_eq!(client_stats.packets_sent, server_stats.packets_received);
+    assert_eq!(client_stats.packets_received, server_stats.packets_sent);
+    assert_eq!(client_stats.bytes_sent, server_stats.bytes_received);
+    assert_eq!(client_stats.bytes_received, server_stats.bytes_sent);
+    assert!(client_stats.bytes_sent > client_stats.bytes_received);
+    assert!(client_stats.last_activity >= client_stats.connected_at);
+    let mut all = controller.stats_all();
+    all.sort_by_key(|&(id, _)| id);
+    assert_eq!(all, vec![(client, client_stats), (server, server_stats)]);
+    assert_eq!(controller.stats(super::ConnectionId(100)), None);
+}
+
+#[test]
+fn loopback_stream_round_trip() {
+    start_log_once();
+    let (mut first, mut second) = loopback_pair();
+    first.write_all(b"hello").unwrap();
+    second.write_all(b"hi").unwrap();
+    let mut buf: [u8; 5] = [0; 5];
+    second.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    first.read_exact(&mut buf[..2]).unwrap();
+    assert_eq!(&buf[..2], b"hi");
+    first.write_all(b"bye").unwrap();
+    Transport::shutdown(&first, Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    second.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"bye".to_vec());
+    assert_eq!(first.write_frame(b"late").unwrap_err().kind(),
+               io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn loopback_stream_read_timeout() {
+    start_log_once();
+    let (mut first, _second) = loopback_pair();
+    Transport::set_read_timeout(&first, Some(Duration::from_millis(10))).unwrap();
+    let mut buf: [u8; 1] = [0];
+    assert_eq!(first.read_frame(&mut buf).unwrap_err().kind(),
+               io::ErrorKind::TimedOut);
+}
+
+/// A loopback pair whose first end injects faults into everything written to it.
+fn faulty_pair(seed: u64) -> (FaultyTransport<LoopbackStream>, LoopbackStream) {
+    let (first, second) = loopback_pair();
+    Transport::set_read_timeout(&second, Some(Duration::from_millis(100))).unwrap();
+    (FaultyTransport::new(first, seed), second)
+}
+
+/// Numbered packets to send through a faulty transport, to see which made it and in what order.
+fn numbered(count: u64) -> Vec<super::NetworkPacket> {
+    (0..count).map(super::NetworkPacket::Ping).collect()
+}
+
+/// Reads packets untill the stream times out, returning the numbers of the intact ones and how
+/// many were corrupt.
+fn read_numbered(stream: &mut LoopbackStream) -> (Vec<u64>, usize) {
+    let addr = super::ip("127.0.0.1:0");
+    let mut numbers = Vec::new();
+    let mut corrupt = 0;
+    loop {
+        match super::read_packet(stream, &addr, super::MAX_PACKET_SIZE) {
+            super::ReadResult::Packet(super::NetworkPacket::Ping(number)) => numbers.push(number),
+            super::ReadResult::Corrupt => corrupt += 1,
+            super::ReadResult::Closed | super::ReadResult::TimedOut => return (numbers, corrupt),
+            _ => panic!("a packet was mangled in a way other than corrupting it's body"),
+        }
+    }
+}
+
+#[test]
+fn faulty_transport_clean() {
+    start_log_once();
+    let (mut faulty, mut remote) = faulty_pair(1);
+    for packet in numbered(20) {
+        faulty.write_frame(&frame(&packet)).unwrap();
+    }
+    assert_eq!(read_numbered(&mut remote), ((0..20).collect(), 0));
+}
+
+#[test]
+fn faulty_transport_corrupts() {
+    start_log_once();
+    let (mut faulty, mut remote) = faulty_pair(2);
+    let handle = faulty.handle();
+    for packet in numbered(10) {
+        faulty.write_frame(&frame(&packet)).unwrap();
+    }
+    handle.set(Faults { corrupt_percent: 20, ..Default::default() });
+    for packet in numbered(200).into_iter().skip(10) {
+        faulty.write_frame(&frame(&packet)).unwrap();
+    }
+    let (numbers, corrupt) = read_numbered(&mut remote);
+    // Every corrupt packet is caught by it's checksum, and the stream stays in sync around it.
+    assert_eq!(numbers.len() + corrupt, 200);
+    assert!(corrupt > 10 && corrupt < 80, "{} packets were corrupt", corrupt);
+    assert_eq!(&numbers[..10], &(0..10).collect::<Vec<u64>>()[..]);
+    assert!(numbers.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn faulty_transport_drops() {
+    start_log_once();
+    let (mut faulty, mut remote) = faulty_pair(3);
+    faulty.handle().set(Faults { drop_percent: 50, ..Default::default() });
+    for packet in numbered(200) {
+        faulty.write_frame(&frame(&packet)).unwrap();
+    }
+    let (numbers, corrupt) = read_numbered(&mut remote);
+    assert_eq!(corrupt, 0);
+    assert!(numbers.len() > 50 && numbers.len() < 150,
+            "{} packets were not dropped",
+            numbers.len());
+    assert!(numbers.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn faulty_transport_duplicates() {
+    start_log_once();
+    let (mut faulty, mut remote) = faulty_pair(4);
+    faulty.handle().set(Faults { duplicate_percent: 100, ..Default::default() });
+    for packet in numbered(5) {
+        faulty.write_frame(&frame(&packet)).unwrap();
+    }
+    assert_eq!(read_numbered(&mut remote), (vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4], 0));
+}
+
+#[test]
+fn faulty_transport_reorders() {
+    start_log_once();
+    let (mut faulty, mut remote) = faulty_pair(5);
+    faulty.handle().set(Faults { reorder_percent: 100, ..Default::default() });
+    for packet in numbered(5) {
+        faulty.write_frame(&frame(&packet)).unwrap();
+    }
+    // The last frame is held back untill the stream is shut down.
+    Transport::shutdown(&faulty, Shutdown::Write).unwrap();
+    assert_eq!(read_numbered(&mut remote), (vec![1, 0, 3, 2, 4], 0));
+}
+
+#[test]
+fn faulty_transport_delays() {
+    start_log_once();
+    let (mut faulty, mut remote) = faulty_pair(6);
+    faulty.handle().set(Faults {
+        min_delay_millis: 20,
+        max_delay_millis: 40,
+        ..Default::default()
+    });
+    let started = Instant::now();
+    for packet in numbered(5) {
+        faulty.write_frame(&frame(&packet)).unwrap();
+    }
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(100), "writing took {:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(400), "writing took {:?}", elapsed);
+    assert_eq!(read_numbered(&mut remote), ((0..5).collect(), 0));
+}
+
+#[test]
+fn faulty_transport_drops_keepalives() {
+    start_log_once();
+    let config = super::ControllerConfig { keepalive_millis: 10, ..Default::default() };
+    let (local, mut remote) = loopback_pair();
+    Transport::set_read_timeout(&remote, Some(Duration::from_millis(100))).unwrap();
+    let faulty = FaultyTransport::new(local, 7);
+    let handle = faulty.handle();
+    handle.set(Faults { drop_percent: 100, ..Default::default() });
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        super::check_stream_send(rx, faulty, config, counters(), no_compression())
+    });
+    let addr = super::ip("127.0.0.1:0");
+    for _ in 0..5 {
+        thread::sleep(Duration::from_millis(20));
+        tx.send(super::ConnectionMessage::Keepalive).unwrap();
+    }
+    // Every Ping was dropped, so to the peer the connection looks idle.
+    match super::read_packet(&mut remote, &addr, super::MAX_PACKET_SIZE) {
+        super::ReadResult::TimedOut => {}
+        _ => panic!("a packet made it through a transport dropping everything"),
+    }
+    handle.set(Default::default());
+    thread::sleep(Duration::from_millis(20));
+    tx.send(super::ConnectionMessage::Keepalive).unwrap();
+    match read_packet(&mut remote) {
+        super::NetworkPacket::Ping(_) => {}
+        other => panic!("expected a Ping, got {:?}", other),
+    }
+}
+
+#[test]
+fn record_pong_smooths_rtt() {
+    start_log_once();
+    let stats = counters();
+    assert_eq!(stats.rtt(), None);
+    let first = stats.ping_timestamp();
+    thread::sleep(Duration::from_millis(20));
+    let sample = stats.record_pong(first).unwrap();
+    assert!(sample >= Duration::from_millis(20));
+    assert_eq!(stats.rtt(), Some(sample));
+    // A repeated Pong, one for an older Ping, and one from the future are ignored.
+    assert_eq!(stats.record_pong(first), None);
+    assert_eq!(stats.record_pong(first - 1), None);
+    assert_eq!(stats.record_pong(stats.ping_timestamp() + 1000000000), None);
+    assert_eq!(stats.rtt(), Some(sample));
+    // A much faster round trip only moves the estimate part of the way.
+    let smoothed = stats.record_pong(stats.ping_timestamp()).unwrap();
+    assert!(smoothed < sample);
+    assert!(smoothed > sample / 2);
+}
+
+#[test]
+fn rtt_measured_over_keepalives() {
+    start_log_once();
+    let config = super::ControllerConfig { keepalive_millis: 20, ..Default::default() };
+    let controller = super::Controller::new_with_config(config);
+    let (first, second) = controller.add_loopback_pair().unwrap();
+    assert!(eventually(|| controller.rtt(first).is_some() && controller.rtt(second).is_some()));
+    assert!(controller.stats(first).unwrap().rtt.is_some());
+    let counters = controller.raw.connections.read().unwrap()[&first].stats.clone();
+    assert!(counters.rtt_nanos.load(Ordering::Relaxed) > 0);
+    controller.kick(first, "done").unwrap();
+    assert!(eventually(|| controller.rtt(first).is_none()));
+}
+
+#[test]
+fn loopback_pair_handshaken() {
+    start_log_once();
+    let controller = super::Controller::new_empty();
+    let (first, second) = controller.add_loopback_pair().unwrap();
+    assert!(first != second);
+    let connections = controller.raw.connections.read().unwrap();
+    for id in &[first, second] {
+        assert_eq!(*connections[id].state.lock().unwrap(),
+                   super::ConnectionState::Ready);
+    }
+}
+
+/// Writes pongs, which the peer handles without replying, untill it stops reading.
+///
+/// Returns how many were written.
+fn flood_pongs(stream: &mut TcpStream, count: usize) -> usize {
+    let bytes = frame(&super::NetworkPacket::Pong(0));
+    for sent in 0..count {
+        if stream.write_all(&bytes).is_err() {
+            return sent;
+        }
+    }
+    count
+}
+
+fn rate_limited_config(mode: super::RateLimitMode) -> super::ControllerConfig {
+    super::ControllerConfig {
+        rate_limit: Some(super::RateLimit {
+            packets_per_second: 20,
+            burst: 10,
+            mode: mode,
+        }),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn token_bucket_refills() {
+    start_log_once();
+    let mut bucket = super::TokenBucket::new(super::RateLimit {
+        packets_per_second: 1000,
+        burst: 2,
+        mode: super::RateLimitMode::Throttle,
+    });
+    assert_eq!(bucket.take(), None);
+    assert_eq!(bucket.take(), None);
+    let wait = bucket.take().expect("the bucket should be empty");
+    assert!(wait <= Duration::from_millis(1));
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(bucket.take(), None);
+    assert_eq!(bucket.take(), None);
+    assert!(bucket.take().is_some());
+}
+
+#[test]
+fn rate_limit_throttles() {
+    start_log_once();
+    let (server, addr) =
+        listening_controller_with_config(rate_limited_config(super::RateLimitMode::Throttle));
+    let mut stream = handshaken_stream(addr);
+    assert_eq!(flood_pongs(&mut stream, 200), 200);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (_id, stats) = server.stats_all()[0];
+    // The Init and burst are let through at once, then about one every 50 milliseconds.
+    assert!(stats.packets_received < 30, "{} packets were let through", stats.packets_received);
+    assert!(stats.throttle_events > 0);
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+}
+
+#[test]
+fn rate_limit_kills() {
+    start_log_once();
+    let mode = super::RateLimitMode::Kill { grace_millis: 100 };
+    let (server, addr) = listening_controller_with_config(rate_limited_config(mode));
+    let mut stream = handshaken_stream(addr);
+    flood_pongs(&mut stream, 200);
+    assert_eq!(read_packet(&mut stream),
+               super::NetworkPacket::Error(super::NetworkError::RateLimited));
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn dropped_subscriber_removed() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    drop(server.subscribe("chat"));
+    let mut client = super::Controller::new_empty();
+    let id = client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    client.send_message(id, "chat", b"hello".to_vec()).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.subscribers.lock().unwrap().is_empty());
+}
+
+#[test]
+fn send_message_not_ready() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let _stream = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let id = *server.raw.connections.read().unwrap().keys().next().unwrap();
+    assert_eq!(server.send_message(id, "chat", Vec::new()),
+               Err(super::SendError::NotReady(id)));
+}
+
+#[test]
+fn send_to_errors() {
+    start_log_once();
+    let controller = super::Controller::new_empty();
+    let packet = super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue);
+    assert_eq!(controller.send_to(super::ConnectionId(3), packet.clone()),
+               Err(super::SendError::UnknownConnection(super::ConnectionId(3))));
+    let (tx, rx) = sync_channel(super::SEND_QUEUE_DEPTH);
+    drop(rx);
+    let connection = super::Connection {
+        id: super::ConnectionId(3),
+        peer_addr: super::ip("127.0.0.1:0"),
+        channel: Mutex::new(super::ConnectionSender::Threaded(tx)),
+        state: Arc::new(Mutex::new(super::ConnectionState::Ready)),
+        stats: counters(),
+        kick_reason: Arc::new(Mutex::new(None)),
+        closer: Arc::new(super::StreamCloser::none()),
+    };
+    controller.raw.connections.write().unwrap().insert(super::ConnectionId(3), connection);
+    assert_eq!(controller.send_to(super::ConnectionId(3), packet.clone()),
+               Err(super::SendError::ConnectionClosed(super::ConnectionId(3))));
+    let (tx, _rx) = sync_channel(super::SEND_QUEUE_DEPTH);
+    let connection = super::Connection {
+        id: super::ConnectionId(4),
+        peer_addr: super::ip("127.0.0.1:0"),
+        channel: Mutex::new(super::ConnectionSender::Threaded(tx)),
+        state: Arc::new(Mutex::new(super::ConnectionState::Closing)),
+        stats: counters(),
+        kick_reason: Arc::new(Mutex::new(None)),
+        closer: Arc::new(super::StreamCloser::none()),
+    };
+    controller.raw.connections.write().unwrap().insert(super::ConnectionId(4), connection);
+    assert_eq!(controller.send_to(super::ConnectionId(4), packet),
+               Err(super::SendError::ConnectionClosed(super::ConnectionId(4))));
+}
+
+/// Registers a connection over a loopback stream holding at most 64 bytes, returning the peer's
+/// end without anything reading from it.
+fn paused_connection(controller: &super::Controller,
+                     config: super::ControllerConfig)
+                     -> (super::ConnectionId, LoopbackStream) {
+    let (local, remote) = loopback_pair_with_capacity(64);
+    let id = super::ConnectionId(9);
+    let connection = super::spawn_stream_threads(&controller.raw,
+                                                 local,
+                                                 super::ip("127.0.0.1:0"),
+                                                 id,
+                                                 true,
+                                                 config,
+                                                 super::Probe::none(),
+                                                 None)
+                         .unwrap();
+    controller.raw.connections.write().unwrap().insert(id, connection);
+    (id, remote)
+}
+
+/// Queues Pings untill the connection's queue is full, returning how many were queued.
+fn fill_queue(controller: &super::Controller, id: super::ConnectionId) -> usize {
+    for queued in 0..10000 {
+        match controller.send_to(id, super::NetworkPacket::Ping(queued as u64)) {
+            Ok(()) => {}
+            Err(super::SendError::QueueFull(_)) => return queued,
+            Err(err) => panic!("queueing a Ping failed: {}", err),
+        }
+    }
+    panic!("the queue never filled up");
+}
+
+/// Like fill_queue, but waits for the send thread to be stuck writing first, so nothing more is
+/// taken from the queue afterwards.
+fn fill_queue_stalled(controller: &super::Controller, id: super::ConnectionId) {
+    fill_queue(controller, id);
+    thread::sleep(Duration::from_millis(50));
+    fill_queue(controller, id);
+}
+
+fn queue_config() -> super::ControllerConfig {
+    super::ControllerConfig {
+        send_queue_depth: 4,
+        lag_threshold_millis: 100,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn send_queue_fills_behind_paused_peer() {
+    start_log_once();
+    let controller = super::Controller::new_with_config(queue_config());
+    let (id, _remote) = paused_connection(&controller, queue_config());
+    assert!(fill_queue(&controller, id) >= 4);
+    fill_queue_stalled(&controller, id);
+    assert!(!controller.stats(id).unwrap().lagging);
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(controller.send_to(id, super::NetworkPacket::Ping(0)),
+               Err(super::SendError::QueueFull(id)));
+    let stats = controller.stats(id).unwrap();
+    assert!(stats.lagging);
+    assert!(stats.queue_full_events >= 3);
+    assert_eq!(controller.broadcast(super::NetworkPacket::Ping(0)), 0);
+    assert!(controller.raw.connections.read().unwrap().contains_key(&id));
+}
+
+#[test]
+fn send_to_blocking_waits_for_room() {
+    start_log_once();
+    let controller = super::Controller::new_with_config(queue_config());
+    let (id, mut remote) = paused_connection(&controller, queue_config());
+    fill_queue_stalled(&controller, id);
+    assert_eq!(controller.send_to_blocking(id,
+                                           super::NetworkPacket::Ping(0),
+                                           Duration::from_millis(20)),
+               Err(super::SendError::QueueFull(id)));
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        Transport::set_read_timeout(&remote, Some(Duration::from_millis(200))).unwrap();
+        let mut buf = [0; 256];
+        while let Ok(read) = remote.read(&mut buf) {
+            if read == 0 {
+                break;
+            }
+        }
+    });
+    controller.send_to_blocking(id, super::NetworkPacket::Ping(0), Duration::from_millis(2000))
+              .unwrap();
+    assert!(!controller.stats(id).unwrap().lagging);
+}
+
+#[test]
+fn lagging_connection_dropped() {
+    start_log_once();
+    let config = super::ControllerConfig { drop_lagging: true, ..queue_config() };
+    let controller = super::Controller::new_with_config(config.clone());
+    let (id, _remote) = paused_connection(&controller, config);
+    fill_queue_stalled(&controller, id);
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(controller.send_to(id, super::NetworkPacket::Ping(0)),
+               Err(super::SendError::QueueFull(id)));
+    assert!(eventually(|| controller.raw.connections.read().unwrap().is_empty()));
+}
+
+#[test]
+fn broadcast_reaches_all() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut client_one = super::Controller::new_empty();
+    let mut client_two = super::Controller::new_empty();
+    client_one.connect(addr).unwrap();
+    client_two.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let packet = super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue);
+    assert_eq!(server.broadcast(packet.clone()), 2);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    for client in &[client_one, client_two] {
+        let (_, received) = recv_after_init(client);
+        assert_eq!(received, packet);
+    }
+}
+
+#[test]
+fn broadcast_prunes_dead() {
+    start_log_once();
+    let controller = super::Controller::new_empty();
+    let (tx, rx) = sync_channel(super::SEND_QUEUE_DEPTH);
+    drop(rx);
+    let connection = super::Connection {
+        id: super::ConnectionId(0),
+        peer_addr: super::ip("127.0.0.1:0"),
+        channel: Mutex::new(super::ConnectionSender::Threaded(tx)),
+        state: Arc::new(Mutex::new(super::ConnectionState::Ready)),
+        stats: counters(),
+        kick_reason: Arc::new(Mutex::new(None)),
+        closer: Arc::new(super::StreamCloser::none()),
+    };
+    controller.raw.connections.write().unwrap().insert(super::ConnectionId(0), connection);
+    let packet = super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue);
+    assert_eq!(controller.broadcast(packet), 0);
+    assert!(controller.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn disconnect_removes_connection() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let stream = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+    drop(stream);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn max_connected_clients_enforced() {
+    start_log_once();
+    // Every stream comes from the same address, which should only be limited by max_clients.
+    let config = super::ControllerConfig {
+        max_connections_per_address: super::MAX_CONNECTED_CLIENTS,
+        ..Default::default()
+    };
+    let (server, addr) = listening_controller_with_config(config);
+    let mut streams: Vec<TcpStream> = Vec::new();
+    for _ in 0..super::MAX_CONNECTED_CLIENTS {
+        streams.push(TcpStream::connect(addr).unwrap());
+    }
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let mut extra = TcpStream::connect(addr).unwrap();
+    assert_eq!(read_packet(&mut extra),
+               super::NetworkPacket::Error(super::NetworkError::ServerFull));
+    assert_eq!(server.raw.connections.read().unwrap().len(),
+               super::MAX_CONNECTED_CLIENTS);
+}
+
+#[test]
+fn controller_config_default() {
+    start_log_once();
+    assert_eq!(super::ControllerConfig::default().max_clients,
+               super::MAX_CONNECTED_CLIENTS);
+    let controller = super::Controller::new_empty();
+    assert_eq!(controller.raw.config.read().unwrap().max_clients,
+               super::MAX_CONNECTED_CLIENTS);
+    assert_eq!(super::ControllerConfig::default().max_connections_per_address,
+               super::MAX_CONNECTIONS_PER_ADDRESS);
+}
+
+#[test]
+fn max_connections_per_address_enforced() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut streams: Vec<TcpStream> = Vec::new();
+    for _ in 0..super::MAX_CONNECTIONS_PER_ADDRESS {
+        streams.push(handshaken_stream(addr));
+    }
+    let mut extra = TcpStream::connect(addr).unwrap();
+    assert_eq!(read_packet(&mut extra),
+               super::NetworkPacket::Error(super::NetworkError::TooManyConnectionsFromAddress));
+    assert_eq!(server.raw.connections.read().unwrap().len(),
+               super::MAX_CONNECTIONS_PER_ADDRESS);
+    streams.pop();
+    assert!(eventually(|| {
+        server.raw.connections.read().unwrap().len() < super::MAX_CONNECTIONS_PER_ADDRESS
+    }));
+    handshaken_stream(addr);
+}
+
+#[test]
+fn normalize_ip_unmaps_ipv4() {
+    start_log_once();
+    let mapped: IpAddr = "::ffff:127.0.0.1".parse().unwrap();
+    let v4: IpAddr = "127.0.0.1".parse().unwrap();
+    assert_eq!(super::normalize_ip(mapped), v4);
+    assert_eq!(super::normalize_ip(v4), v4);
+    let v6: IpAddr = "::1".parse().unwrap();
+    assert_eq!(super::normalize_ip(v6), v6);
+    let controller = super::Controller::new_empty();
+    controller.ban(mapped, None);
+    assert!(controller.raw.is_banned(v4));
+    assert!(controller.unban(v4));
+}
+
+#[test]
+fn max_clients_one() {
+    start_log_once();
+    let config = super::ControllerConfig { max_clients: 1, ..Default::default() };
+    let (server, addr) = listening_controller_with_config(config);
+    let _first = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let mut second = TcpStream::connect(addr).unwrap();
+    assert_eq!(read_packet(&mut second),
+               super::NetworkPacket::Error(super::NetworkError::ServerFull));
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+}
+
+#[test]
+fn probes_limited() {
+    start_log_once();
+    let config = super::ControllerConfig { max_clients: 2, ..Default::default() };
+    let (server, addr) = listening_controller_with_config(config);
+    // Neither sends anything, so both are probed untill PROBE_MILLIS runs out.
+    let _first = TcpStream::connect(addr).unwrap();
+    let _second = TcpStream::connect(addr).unwrap();
+    assert!(eventually(|| {
+        server.raw.probing.lock().unwrap().values().fold(0, |total, &count| total + count) == 2
+    }));
+    let mut third = TcpStream::connect(addr).unwrap();
+    third.set_read_timeout(Some(Duration::from_millis(TEST_SLEEP_TIME_MILLIS))).unwrap();
+    let mut buf: [u8; 1] = [0; 1];
+    match third.read(&mut buf) {
+        Ok(0) => {}
+        Err(ref err) if err.kind() == io::ErrorKind::ConnectionReset => {}
+        other => panic!("expected the third socket to be closed, got {:?}", other),
+    }
+    assert!(eventually(|| server.raw.connections.read().unwrap().len() == 2));
+    assert!(eventually(|| server.raw.probing.lock().unwrap().is_empty()));
+}
+
+#[test]
+fn set_max_clients_keeps_existing() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let _first = TcpStream::connect(addr).unwrap();
+    let _second = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    server.set_max_clients(1);
+    let mut third = TcpStream::connect(addr).unwrap();
+    assert_eq!(read_packet(&mut third),
+               super::NetworkPacket::Error(super::NetworkError::ServerFull));
+    assert_eq!(server.raw.connections.read().unwrap().len(), 2);
+}
+
+#[test]
+fn accept_hook_denies() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let tattle = Tattle::new();
+    let tattle_clone = tattle.clone();
+    server.set_accept_hook(Box::new(move |_addr| {
+        tattle_clone.call();
+        false
+    }));
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_eq!(read_packet(&mut stream),
+               super::NetworkPacket::Error(super::NetworkError::ConnectionDenied));
+    assert_eq!(tattle.get(), 1);
+    let mut client = super::Controller::new_empty();
+    client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (_, received) = client.raw.incoming_rx.lock().unwrap().try_recv().unwrap();
+    assert_eq!(received,
+               super::NetworkPacket::Error(super::NetworkError::ConnectionDenied));
+    assert!(client.raw.connections.read().unwrap().is_empty());
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn accept_hook_gets_peer_addr() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    server.set_accept_hook(Box::new(move |addr| {
+        tx.lock().unwrap().send(*addr).unwrap();
+        true
+    }));
+    let stream = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert_eq!(rx.try_recv().unwrap(), stream.local_addr().unwrap());
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+}
+
+fn assert_banned(addr: SocketAddr) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert_eq!(read_packet(&mut stream),
+               super::NetworkPacket::Error(super::NetworkError::Banned));
+}
+
+#[test]
+fn ban_expires() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let duration = Duration::from_millis(TEST_SLEEP_TIME_MILLIS * 2);
+    server.ban(addr.ip(), Some(duration));
+    assert_banned(addr);
+    assert!(server.raw.connections.read().unwrap().is_empty());
+    thread::sleep(duration);
+    handshaken_stream(addr);
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+    assert!(server.raw.bans.lock().unwrap().is_empty());
+    assert!(!server.unban(addr.ip()));
+}
+
+#[test]
+fn ban_checked_before_accept_hook() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let tattle = Tattle::new();
+    let tattle_clone = tattle.clone();
+    server.set_accept_hook(Box::new(move |_addr| {
+        tattle_clone.call();
+        true
+    }));
+    server.ban(addr.ip(), None);
+    assert_banned(addr);
+    assert_eq!(tattle.get(), 0);
+    assert!(server.unban(addr.ip()));
+    handshaken_stream(addr);
+    assert_eq!(tattle.get(), 1);
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+}
+
+#[test]
+fn kick_and_ban_resolves_addr() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut stream = handshaken_stream(addr);
+    let (id, _) = server.stats_all()[0];
+    server.kick_and_ban(id, "cheating").unwrap();
+    assert_eq!(read_packet(&mut stream),
+               super::NetworkPacket::Disconnect { reason: "cheating".to_owned() });
+    assert!(server.raw.bans.lock().unwrap().contains_key(&stream.local_addr().unwrap().ip()));
+    assert_banned(addr);
+    assert_eq!(server.kick_and_ban(id, "again"),
+               Err(super::SendError::UnknownConnection(id)));
+}
+
+#[test]
+fn handshake_version_mismatch() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    // Newer and older peers alike, as the magic number they frame their Init with is the same.
+    for &protocol in &[super::PROTOCOL_VERSION + 1, super::PROTOCOL_VERSION - 1] {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        send_init_with(&mut stream, protocol, ::VERSION, true);
+        assert_eq!(read_packet(&mut stream),
+                   super::NetworkPacket::Error(super::NetworkError::VersionMismatch(
+                       super::PROTOCOL_VERSION,
+                       protocol,
+                       ::VERSION.to_owned(),
+                       ::VERSION.to_owned())));
+    }
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn handshake_neither_should_crash() {
+    start_log_once();
+    let config = super::ControllerConfig { should_crash: Some(false), ..Default::default() };
+    let (server, addr) = listening_controller_with_config(config);
+    let mut stream = TcpStream::connect(addr).unwrap();
+    send_init_with(&mut stream, super::PROTOCOL_VERSION, ::VERSION, false);
+    assert_eq!(read_packet(&mut stream),
+               super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue));
+    assert!(eventually(|| server.raw.connections.read().unwrap().is_empty()));
+    // A peer that should crash may still connect to it.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    send_init(&mut stream, ::VERSION);
+    match read_packet(&mut stream) {
+        super::NetworkPacket::Init { should_crash, .. } => assert!(!should_crash),
+        other => panic!("expected an Init packet, got {:?}", other),
+    }
+}
+
+#[test]
+fn should_crash_both_true_not_answered() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut stream = handshaken_stream(addr);
+    let error = super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue);
+    stream.write_all(&frame(&error)).unwrap();
+    assert!(eventually(|| {
+        match server.try_recv_packet() {
+            Some((_, packet)) => packet == error,
+            None => false,
+        }
+    }));
+    stream.set_read_timeout(Some(Duration::from_millis(TEST_SLEEP_TIME_MILLIS))).unwrap();
+    let mut buf = [0; 1];
+    match stream.read(&mut buf) {
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                        err.kind() == io::ErrorKind::TimedOut => {}
+        other => panic!("the error was answered: {:?}", other),
+    }
+}
+
+#[test]
+fn handshake_other_game_version() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    send_init(&mut stream, "99.0.0");
+    match read_packet(&mut stream) {
+        super::NetworkPacket::Init { protocol, .. } => {
+            assert_eq!(protocol, super::PROTOCOL_VERSION)
+        }
+        other => panic!("expected an Init packet, got {:?}", other),
+    }
+    assert!(eventually(|| {
+        let connections = server.raw.connections.read().unwrap();
+        connections.values().all(|connection| {
+            *connection.state.lock().unwrap() == super::ConnectionState::Ready
+        })
+    }));
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+}
+
+#[test]
+fn handshake_answered_with_init() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    send_init(&mut stream, ::VERSION);
+    match read_packet(&mut stream) {
+        super::NetworkPacket::Init { version, .. } => assert_eq!(version, ::VERSION),
+        other => panic!("expected an Init packet, got {:?}", other),
+    }
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+}
+
+#[test]
+fn connection_state_follows_handshake() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let state = {
+        let connections = server.raw.connections.read().unwrap();
+        connections.values().next().unwrap().state.clone()
+    };
+    assert_eq!(*state.lock().unwrap(), super::ConnectionState::Handshaking);
+    send_init(&mut stream, ::VERSION);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert_eq!(*state.lock().unwrap(), super::ConnectionState::Ready);
+    drop(stream);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert_eq!(*state.lock().unwrap(), super::ConnectionState::Closing);
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn remove_listener_frees_port() {
+    start_log_once();
+    let mut controller = super::Controller::new_empty();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let id = controller.add_listener(listener).unwrap();
+    assert!(controller.remove_listener(id));
+    assert!(!controller.remove_listener(id));
+    TcpListener::bind(addr).unwrap();
+}
+
+#[test]
+fn shutdown_closes_everything() {
+    start_log_once();
+    let (mut server, addr) = listening_controller();
+    let mut client = super::Controller::new_empty();
+    client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+    server.shutdown();
+    assert!(server.raw.connections.read().unwrap().is_empty());
+    assert!(server.raw.listeners.lock().unwrap().is_empty());
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(client.raw.connections.read().unwrap().is_empty());
+    let (_, received) = recv_after_init(&client);
+    assert_eq!(received,
+               super::NetworkPacket::Disconnect { reason: super::SHUTDOWN_REASON.to_owned() });
+    TcpListener::bind(addr).unwrap();
+}
+
+#[test]
+fn kick_from_server() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut client = super::Controller::new_empty();
+    client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (id, _) = server.raw.incoming_rx.lock().unwrap().try_recv().unwrap();
+    server.kick(id, "server restarting").unwrap();
+    assert!(server.raw.connections.read().unwrap().is_empty());
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (_, received) = recv_after_init(&client);
+    assert_eq!(received,
+               super::NetworkPacket::Disconnect { reason: "server restarting".to_owned() });
+    assert!(client.raw.connections.read().unwrap().is_empty());
+    assert_eq!(server.kick(id, "again"),
+               Err(super::SendError::UnknownConnection(id)));
+}
+
+#[test]
+fn kick_closing_connection_errors() {
+    start_log_once();
+    let controller = super::Controller::new_empty();
+    let (tx, rx) = sync_channel(super::SEND_QUEUE_DEPTH);
+    let connection = super::Connection {
+        id: super::ConnectionId(5),
+        peer_addr: super::ip("127.0.0.1:0"),
+        channel: Mutex::new(super::ConnectionSender::Threaded(tx)),
+        state: Arc::new(Mutex::new(super::ConnectionState::Closing)),
+        stats: counters(),
+        kick_reason: Arc::new(Mutex::new(None)),
+        closer: Arc::new(super::StreamCloser::none()),
+    };
+    controller.raw.connections.write().unwrap().insert(super::ConnectionId(5), connection);
+    assert_eq!(controller.kick(super::ConnectionId(5), "late"),
+               Err(super::SendError::ConnectionClosed(super::ConnectionId(5))));
+    assert!(rx.try_recv().is_err());
+    assert_eq!(controller.raw.connections.read().unwrap().len(), 1);
+}
+
+#[test]
+fn kick_from_client() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut client = super::Controller::new_empty();
+    let id = client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    client.kick(id, "quitting").unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (_, received) = recv_after_init(&server);
+    assert_eq!(received, super::NetworkPacket::Disconnect { reason: "quitting".to_owned() });
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+/// Tattles on every callback, keeping the reasons connections were removed for.
+struct TattleHandler {
+    connects: Tattle,
+    disconnects: Tattle,
+    errors: Tattle,
+    reasons: Arc<Mutex<Vec<(super::ConnectionId, super::DisconnectReason)>>>,
+}
+
+impl super::ControllerHandler for TattleHandler {
+    fn on_connect(&mut self, _id: super::ConnectionId, _addr: SocketAddr) {
+        self.connects.call();
+    }
+
+    fn on_disconnect(&mut self, id: super::ConnectionId, reason: super::DisconnectReason) {
+        self.reasons.lock().unwrap().push((id, reason));
+        self.disconnects.call();
+    }
+
+    fn on_error(&mut self, _id: super::ConnectionId, err: super::NetworkError) {
+        assert_eq!(err, super::NetworkError::ServerFull);
+        self.errors.call();
+    }
+}
+
+#[test]
+fn handler_callbacks_fire() {
+    start_log_once();
+    let controller = super::Controller::new_empty();
+    let (connects, disconnects, errors) = (Tattle::new(), Tattle::new(), Tattle::new());
+    let reasons = Arc::new(Mutex::new(Vec::new()));
+    controller.set_handler(Box::new(TattleHandler {
+        connects: connects.clone(),
+        disconnects: disconnects.clone(),
+        errors: errors.clone(),
+        reasons: reasons.clone(),
+    }));
+    let (first, second) = controller.add_loopback_pair().unwrap();
+    assert!(eventually(|| connects.get() == 2));
+    controller.send_to(first, super::NetworkPacket::Error(super::NetworkError::ServerFull))
+              .unwrap();
+    assert!(eventually(|| errors.get() == 1));
+    controller.kick(first, "bye").unwrap();
+    assert!(eventually(|| disconnects.get() == 2));
+    let mut reasons = reasons.lock().unwrap().clone();
+    reasons.sort_by_key(|&(id, _)| id);
+    assert_eq!(reasons,
+               vec![(first, super::DisconnectReason::Kicked("bye".to_owned())),
+                    (second, super::DisconnectReason::Disconnected("bye".to_owned()))]);
+    assert_eq!(connects.get(), 2);
+    assert_eq!(errors.get(), 1);
+}
+
+/// Connects a raw stream to the address and completes the handshake on it.
+fn handshaken_stream(addr: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    send_init(&mut stream, ::VERSION);
+    match read_packet(&mut stream) {
+        super::NetworkPacket::Init { .. } => {}
+        other => panic!("expected an Init packet, got {:?}", other),
+    }
+    stream
+}
+
+#[test]
+fn idle_peer_pruned() {
+    start_log_once();
+    let config = super::ControllerConfig { idle_timeout_millis: 100, ..Default::default() };
+    let (server, addr) = listening_controller_with_config(config);
+    let _stream = handshaken_stream(addr);
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+fn handshake_timeout_config() -> super::ControllerConfig {
+    super::ControllerConfig { handshake_timeout_millis: 200, ..Default::default() }
+}
+
+#[test]
+fn silent_peer_dropped_after_handshake_timeout() {
+    start_log_once();
+    let (server, addr) = listening_controller_with_config(handshake_timeout_config());
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert!(eventually(|| server.raw.connections.read().unwrap().len() == 1));
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.connections.read().unwrap().is_empty());
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).unwrap();
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn handshake_deadline_not_extended() {
+    start_log_once();
+    let (server, addr) = listening_controller_with_config(handshake_timeout_config());
+    let mut stream = TcpStream::connect(addr).unwrap();
+    // Pings are answered, but do not count towards the handshake.
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(TEST_SLEEP_TIME_MILLIS) {
+        if stream.write_all(&frame(&super::NetworkPacket::Ping(0))).is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn handshaken_peer_outlives_handshake_timeout() {
+    start_log_once();
+    let (server, addr) = listening_controller_with_config(handshake_timeout_config());
+    let _stream = handshaken_stream(addr);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+}
+
+#[test]
+fn ping_answered_with_pong() {
+    start_log_once();
+    let (_server, addr) = listening_controller();
+    let mut stream = handshaken_stream(addr);
+    stream.write_all(&frame(&super::NetworkPacket::Ping(42))).unwrap();
+    assert_eq!(read_packet(&mut stream), super::NetworkPacket::Pong(42));
+}
+
+#[test]
+fn keepalive_sends_ping() {
+    start_log_once();
+    let config = super::ControllerConfig { keepalive_millis: 50, ..Default::default() };
+    let (_server, addr) = listening_controller_with_config(config);
+    let mut stream = handshaken_stream(addr);
+    match read_packet(&mut stream) {
+        super::NetworkPacket::Ping(_) => {}
+        other => panic!("expected a Ping packet, got {:?}", other),
+    }
+}
+
+#[test]
+fn keepalive_keeps_connection() {
+    start_log_once();
+    let config = super::ControllerConfig {
+        keepalive_millis: 50,
+        idle_timeout_millis: 200,
+        ..Default::default()
+    };
+    let (server, addr) = listening_controller_with_config(config.clone());
+    let mut client = super::Controller::new_with_config(config);
+    client.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS * 2));
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+    assert_eq!(client.raw.connections.read().unwrap().len(), 1);
+}
+
+#[test]
+fn listener_survives_aborted_sockets() {
+    start_log_once();
+    // The aborted sockets may not have been removed yet when the last one is accepted.
+    let config = super::ControllerConfig { max_connections_per_address: 11, ..Default::default() };
+    let (server, addr) = listening_controller_with_config(config);
+    for _ in 0..10 {
+        drop(TcpStream::connect(addr).unwrap());
+    }
+    let _stream = handshaken_stream(addr);
+    assert_eq!(server.raw.listeners.lock().unwrap().len(), 1);
+    assert!(server.take_listener_errors().is_empty());
+}
+
+#[test]
+fn transient_accept_errors() {
+    start_log_once();
+    assert!(super::is_transient_accept_error(io::ErrorKind::ConnectionAborted));
+    assert!(super::is_transient_accept_error(io::ErrorKind::Interrupted));
+    assert!(super::is_transient_accept_error(io::ErrorKind::WouldBlock));
+    assert!(!super::is_transient_accept_error(io::ErrorKind::Other));
+}
+
+#[test]
+fn listener_died_is_reported() {
+    start_log_once();
+    let (server, _addr) = listening_controller();
+    let id = *server.raw.listeners.lock().unwrap().keys().next().unwrap();
+    let err = io::Error::new(io::ErrorKind::Other, "too many open files");
+    server.raw.tx.lock().unwrap().send(super::ControllerMessage::ListenerDied(id, err)).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.listeners.lock().unwrap().is_empty());
+    let errors = server.take_listener_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, id);
+    assert_eq!(errors[0].1.kind(), io::ErrorKind::Other);
+    assert!(server.take_listener_errors().is_empty());
+}
+
+#[test]
+fn validate_init_versions() {
+    start_log_once();
+    for remote in &["0.2.0", "0.3.0", "1.2.0", "0.2", "not a version"] {
+        assert!(super::validate_init((3, "0.2.0"), true, (3, remote), true).is_ok());
+    }
+    assert_eq!(super::validate_init((3, "0.2.0"), true, (4, "0.2.0"), true),
+               Err(super::NetworkError::VersionMismatch(3,
+                                                        4,
+                                                        "0.2.0".to_owned(),
+                                                        "0.2.0".to_owned())));
+    assert!(super::validate_init((4, "0.2.0"), true, (3, "0.2.0"), true).is_err());
+}
+
+#[test]
+fn validate_init_should_crash() {
+    start_log_once();
+    assert!(super::validate_init((1, "0.2.0"), false, (1, "0.2.0"), true).is_ok());
+    assert!(super::validate_init((1, "0.2.0"), true, (1, "0.2.0"), false).is_ok());
+    assert_eq!(super::validate_init((1, "0.2.0"), false, (1, "0.2.0"), false),
+               Err(super::NetworkError::ShouldCrashBothTrue));
+}
+
+fn test_server_info() -> super::ServerInfo {
+    super::ServerInfo {
+        name: "Test server".to_owned(),
+        players: 1,
+        max_players: 1,
+        motd: "Welcome".to_owned(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn query_server_answered_when_full() {
+    start_log_once();
+    let config = super::ControllerConfig { max_clients: 1, ..Default::default() };
+    let (server, addr) = listening_controller_with_config(config);
+    let connects = Tattle::new();
+    server.set_handler(Box::new(TattleHandler {
+        connects: connects.clone(),
+        disconnects: Tattle::new(),
+        errors: Tattle::new(),
+        reasons: Arc::new(Mutex::new(Vec::new())),
+    }));
+    server.set_server_info(test_server_info());
+    let _player = handshaken_stream(addr);
+    let timeout = Duration::from_millis(TEST_SLEEP_TIME_MILLIS);
+    assert_eq!(super::query_server(addr, timeout).unwrap(), test_server_info());
+    assert_eq!(super::query_server(addr, timeout).unwrap().version, ::VERSION);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+    assert_eq!(connects.get(), 1);
+}
+
+#[test]
+fn query_server_refused_when_banned() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    server.ban(addr.ip(), None);
+    match super::query_server(addr, Duration::from_millis(TEST_SLEEP_TIME_MILLIS)) {
+        Err(super::QueryError::Refused(super::NetworkError::Banned)) => {}
+        other => panic!("expected the query to be refused, got {:?}", other),
+    }
+}
+
+#[test]
+fn query_server_times_out() {
+    start_log_once();
+    // The socket is accepted by the os, but nothing ever answers it.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let started = Instant::now();
+    match super::query_server(addr, Duration::from_millis(100)) {
+        Err(super::QueryError::TimedOut) => {}
+        other => panic!("expected the query to time out, got {:?}", other),
+    }
+    assert!(started.elapsed() < Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+}
+
+#[test]
+fn hmac_sha1_rfc_2202() {
+    start_log_once();
+    let expected = [0xef, 0xfc, 0xdf, 0x6a, 0xe5, 0xeb, 0x2f, 0xa2, 0xd2, 0x74, 0x16, 0xd5, 0xf1,
+                    0x84, 0xdf, 0x9c, 0x25, 0x9a, 0x7c, 0x79];
+    assert_eq!(super::hmac_sha1(b"Jefe", b"what do ya want for nothing?"), expected);
+    assert!(super::password_proof("hunter2", 1) != super::password_proof("hunter2", 2));
+    assert!(super::password_proof("hunter2", 1) != super::password_proof("hunter3", 1));
+}
+
+fn all_ready(controller: &super::Controller) -> bool {
+    let connections = controller.raw.connections.read().unwrap();
+    !connections.is_empty() &&
+    connections.values().all(|connection| {
+        *connection.state.lock().unwrap() == super::ConnectionState::Ready
+    })
+}
+
+#[test]
+fn correct_password_connects() {
+    start_log_once();
+    let config = super::ControllerConfig {
+        password: Some("hunter2".to_owned()),
+        ..Default::default()
+    };
+    let (server, addr) = listening_controller_with_config(config.clone());
+    let mut client = super::Controller::new_empty();
+    client.connect_with_password(addr, "hunter2").unwrap();
+    assert!(eventually(|| all_ready(&server) && all_ready(&client)));
+    let pair = super::Controller::new_with_config(config);
+    pair.add_loopback_pair().unwrap();
+    assert!(eventually(|| all_ready(&pair)));
+}
+
+#[test]
+fn wrong_password_rejected() {
+    start_log_once();
+    let config = super::ControllerConfig {
+        password: Some("hunter2".to_owned()),
+        ..Default::default()
+    };
+    let (server, addr) = listening_controller_with_config(config);
+    let mut wrong = super::Controller::new_empty();
+    wrong.connect_with_password(addr, "hunter3").unwrap();
+    let mut missing = super::Controller::new_empty();
+    missing.connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    for client in &[wrong, missing] {
+        let (_, received) = recv_after_init(client);
+        assert_eq!(received,
+                   super::NetworkPacket::Error(super::NetworkError::BadCredentials));
+        assert!(eventually(|| client.raw.connections.read().unwrap().is_empty()));
+    }
+    assert!(eventually(|| server.raw.connections.read().unwrap().is_empty()));
+}
+
+#[test]
+fn passwordless_server_ignores_password() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let init = super::NetworkPacket::Init {
+        protocol: super::PROTOCOL_VERSION,
+        version: ::VERSION.to_owned(),
+        should_crash: true,
+        compression: super::Compression::None,
+        password: Some(vec![1, 2, 3]),
+        display_name: String::new(),
+    };
+    stream.write_all(&frame(&init)).unwrap();
+    match read_packet(&mut stream) {
+        super::NetworkPacket::Init { password, .. } => assert_eq!(password, None),
+        other => panic!("expected an Init packet, got {:?}", other),
+    }
+    let mut client = super::Controller::new_empty();
+    client.connect_with_password(addr, "hunter2").unwrap();
+    assert!(eventually(|| all_ready(&server) && all_ready(&client)));
+}
+
+fn polled_config() -> super::ControllerConfig {
+    super::ControllerConfig { io_model: super::IoModel::Polled, ..Default::default() }
+}
+
+/// A server listening like listening_controller and a client connected to it, both driving their
+/// connections from an IO thread.
+fn polled_pair() -> (super::Controller, super::Controller, super::ConnectionId) {
+    let (server, addr) = listening_controller_with_config(polled_config());
+    let mut client = super::Controller::new_with_config(polled_config());
+    let id = client.connect(addr).unwrap();
+    assert!(eventually(|| all_ready(&server) && all_ready(&client)));
+    (server, client, id)
+}
+
+#[test]
+fn polled_connect() {
+    start_log_once();
+    let (server, client, _id) = polled_pair();
+    assert!(server.raw.io_loop.lock().unwrap().is_some());
+    assert!(client.raw.io_loop.lock().unwrap().is_some());
+    for controller in &[server, client] {
+        match controller.try_recv_packet() {
+            Some((_, super::NetworkPacket::Init { version, .. })) => {
+                assert_eq!(version, ::VERSION)
+            }
+            other => panic!("expected an Init packet, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn polled_send_to_reaches_peer() {
+    start_log_once();
+    let (server, client, _id) = polled_pair();
+    let (id, _) = server.try_recv_packet().unwrap();
+    let packet = super::NetworkPacket::Error(super::NetworkError::ShouldCrashBothTrue);
+    server.send_to(id, packet.clone()).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (_, received) = recv_after_init(&client);
+    assert_eq!(received, packet);
+    assert_eq!(server.broadcast(super::NetworkPacket::Pong(3)), 1);
+}
+
+#[test]
+fn polled_many_connections() {
+    start_log_once();
+    let (server, addr) = listening_controller_with_config(polled_config());
+    let streams: Vec<TcpStream> = (0..10).map(|_| handshaken_stream(addr)).collect();
+    assert_eq!(server.raw.connections.read().unwrap().len(), streams.len());
+    assert_eq!(server.broadcast(super::NetworkPacket::Pong(5)), streams.len());
+    for mut stream in streams {
+        assert_eq!(read_packet(&mut stream), super::NetworkPacket::Pong(5));
+    }
+}
+
+#[test]
+fn polled_fragmented_message_reaches_subscribers() {
+    start_log_once();
+    let config = super::ControllerConfig { fragment_threshold: 1000, ..polled_config() };
+    let (server, addr) = listening_controller_with_config(config.clone());
+    let chat = server.subscribe("chat");
+    let mut client = super::Controller::new_with_config(config);
+    let id = client.connect(addr).unwrap();
+    assert!(eventually(|| all_ready(&server) && all_ready(&client)));
+    let payload: Vec<u8> = (0..1000000).map(|i| i as u8).collect();
+    client.send_message(id, "chat", payload.clone()).unwrap();
+    let (_from, received) = chat.recv().unwrap();
+    assert_eq!(received, payload);
+}
+
+#[test]
+fn polled_kick_from_server() {
+    start_log_once();
+    let (server, client, _id) = polled_pair();
+    let (id, _) = server.try_recv_packet().unwrap();
+    server.kick(id, "server restarting").unwrap();
+    assert!(server.raw.connections.read().unwrap().is_empty());
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (_, received) = recv_after_init(&client);
+    assert_eq!(received,
+               super::NetworkPacket::Disconnect { reason: "server restarting".to_owned() });
+    assert!(client.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn polled_corrupt_stream_closed() {
+    start_log_once();
+    let (server, addr) = listening_controller_with_config(polled_config());
+    let mut stream = handshaken_stream(addr);
+    let mut bytes = frame(&packet_with_body_len(20));
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xFF;
+    for _ in 0..super::MAX_CORRUPT_PACKETS {
+        stream.write_all(&bytes).unwrap();
+    }
+    assert_eq!(read_packet(&mut stream),
+               super::NetworkPacket::Error(super::NetworkError::CorruptStream));
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn polled_idle_peer_pruned() {
+    start_log_once();
+    let config = super::ControllerConfig { idle_timeout_millis: 100, ..polled_config() };
+    let (server, addr) = listening_controller_with_config(config);
+    let _stream = handshaken_stream(addr);
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert!(server.raw.connections.read().unwrap().is_empty());
+}
+
+#[test]
+fn polled_rate_limit_throttles() {
+    start_log_once();
+    let config = super::ControllerConfig {
+        io_model: super::IoModel::Polled,
+        ..rate_limited_config(super::RateLimitMode::Throttle)
+    };
+    let (server, addr) = listening_controller_with_config(config);
+    let mut stream = handshaken_stream(addr);
+    assert_eq!(flood_pongs(&mut stream, 200), 200);
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (_id, stats) = server.stats_all()[0];
+    assert!(stats.packets_received < 30, "{} packets were let through", stats.packets_received);
+    assert!(stats.throttle_events > 0);
+    assert_eq!(server.raw.connections.read().unwrap().len(), 1);
+}
+
+/// Takes at most limit bytes per write, then fails with WouldBlock untill it is emptied.
+struct ChokedWriter {
+    written: Vec<u8>,
+    limit: usize,
+}
+
+impl Write for ChokedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written.len() >= self.limit {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "choked"));
+        }
+        let len = cmp::min(buf.len(), self.limit - self.written.len());
+        self.written.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn write_nonblocking_resumes() {
+    start_log_once();
+    let stats = counters();
+    let mut buffer = super::WriteBuffer::new();
+    let packet = super::NetworkPacket::Pong(1);
+    let addr = super::ip("127.0.0.1:0");
+    buffer.push(&packet, super::MAX_PACKET_SIZE, None, super::Priority::Control, &addr);
+    buffer.push(&packet, super::MAX_PACKET_SIZE, None, super::Priority::Control, &addr);
+    let len = frame(&packet).len();
+    let mut writer = ChokedWriter {
+        written: Vec::new(),
+        limit: len + 1,
+    };
+    assert_eq!(buffer.write_nonblocking(&mut writer, &stats).unwrap(), false);
+    assert_eq!(stats.snapshot(addr).packets_sent, 1);
+    writer.limit = len * 2;
+    assert_eq!(buffer.write_nonblocking(&mut writer, &stats).unwrap(), true);
+    assert_eq!(stats.snapshot(addr).packets_sent, 2);
+    let mut expected = frame(&packet);
+    expected.append(&mut frame(&packet));
+    assert_eq!(writer.written, expected);
+}
+
+#[test]
+fn client_end_to_end() {
+    start_log_once();
+    let (server, addr) = listening_controller();
+    let config = super::ControllerConfig { keepalive_millis: 50, ..Default::default() };
+    let mut client = super::client::Client::spawn_with_config(addr, config).unwrap();
+    let packets = client.recv();
+    match packets.recv().unwrap() {
+        super::NetworkPacket::Init { version, .. } => assert_eq!(version, ::VERSION),
+        other => panic!("expected an Init packet, got {:?}", other),
+    }
+    let event = super::NetworkPacket::Event {
+        name: "test".to_owned(),
+        args: Vec::new(),
+    };
+    client.send(event.clone()).unwrap();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    let (id, received) = recv_after_init(&server);
+    assert_eq!(received, event);
+    server.send_to(id, event.clone()).unwrap();
+    assert_eq!(packets.recv().unwrap(), event);
+    assert!(eventually(|| client.rtt().is_some()));
+    client.shutdown();
+    thread::sleep(Duration::from_millis(TEST_SLEEP_TIME_MILLIS));
+    assert_eq!(recv_after_init(&server),
+               (id,
+                super::NetworkPacket::Disconnect { reason: super::SHUTDOWN_REASON.to_owned() }));
+    assert!(client.rtt().is_none());
+}
+
+#[test]
+fn client_spawn_fails_without_server() {
+    start_log_once();
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    match super::client::Client::spawn(addr) {
+        Err(::InitError::IoError(_)) => {}
+        other => panic!("expected an IoError, got {:?}", other),
+    }
+}
+
+#[test]
+fn client_spawn_refused() {
+    start_log_once();
+    let config = super::ControllerConfig { max_clients: 0, ..Default::default() };
+    let (_server, addr) = listening_controller_with_config(config);
+    match super::client::Client::spawn(addr) {
+        Err(::InitError::NetError(super::NetworkError::ServerFull)) => {}
+        other => panic!("expected a NetError, got {:?}", other),
+    }
+}
+
+#[test]
+fn init_error_cause_chain() {
+    let err: ::InitError = io::Error::new(io::ErrorKind::AddrInUse, "address in use").into();
+    match err {
+        ::InitError::IoError(ref inner) => assert_eq!(inner.kind(), io::ErrorKind::AddrInUse),
+        ref other => panic!("expected an IoError, got {:?}", other),
+    }
+    assert_eq!(err.cause().unwrap().description(), "address in use");
+    let err: ::InitError = super::NetworkError::BadCredentials.into();
+    assert_eq!(err.description(), super::NetworkError::BadCredentials.description());
+    assert_eq!(err.cause().unwrap().description(),
+               super::NetworkError::BadCredentials.description());
+    assert!(err.to_string().starts_with("NetError: "));
+}
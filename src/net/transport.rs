@@ -0,0 +1,439 @@
+//! Contains the byte streams connections are carried over.
+//!
+//! The send and recv threads of a connection only talk to the peer through a Transport, so they
+//! can be driven by an in-memory `LoopbackStream` as well as a `TcpStream`. Either can be wrapped
+//! in a `FaultyTransport` to see how connections cope with a bad network.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A reliable, ordered stream of bytes to a peer.
+///
+/// Every handle made with `try_clone` refers to the same stream, like with a TcpStream.
+pub trait Transport: Send + Sized + 'static {
+    /// Reads the next bytes from the peer into buf, returning how many were read.
+    ///
+    /// Blocks untill there is at least one byte, and returns 0 once the peer has closed it's side.
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, io::Error>;
+
+    /// Writes all of the bytes to the peer at once, then flushes them.
+    ///
+    /// The bytes may hold any number of framed packets.
+    fn write_frame(&mut self, bytes: &[u8]) -> Result<(), io::Error>;
+
+    /// Sets how long `read_frame` may block for before failing with `ErrorKind::TimedOut` or
+    /// `ErrorKind::WouldBlock`. None blocks forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error>;
+
+    /// Closes the reading half, the writing half, or both halves of the stream.
+    fn shutdown(&self, how: Shutdown) -> Result<(), io::Error>;
+
+    /// The address of the peer, used in logs.
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error>;
+
+    /// Creates another handle to the same stream.
+    fn try_clone(&self) -> Result<Self, io::Error>;
+}
+
+impl Transport for TcpStream {
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.read(buf)
+    }
+
+    fn write_frame(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        try!(self.write_all(bytes));
+        self.flush()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<(), io::Error> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn try_clone(&self) -> Result<TcpStream, io::Error> {
+        TcpStream::try_clone(self)
+    }
+}
+
+/// The bytes going one way through a loopback pair.
+struct Pipe {
+    state: Mutex<PipeState>,
+    /// Notified whenever bytes are written or read, or the pipe is closed.
+    changed: Condvar,
+    /// How many bytes may be waiting to be read before writing blocks, or None for no limit.
+    capacity: Option<usize>,
+}
+
+#[derive(Debug)]
+struct PipeState {
+    bytes: VecDeque<u8>,
+    /// Set once either end shuts down it's half. Bytes already written can still be read.
+    closed: bool,
+}
+
+impl Pipe {
+    fn new(capacity: Option<usize>) -> Pipe {
+        Pipe {
+            state: Mutex::new(PipeState {
+                bytes: VecDeque::new(),
+                closed: false,
+            }),
+            changed: Condvar::new(),
+            capacity: capacity,
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+impl Debug for Pipe {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("Pipe")
+           .field("state", &self.state)
+           .field("capacity", &self.capacity)
+           .finish()
+    }
+}
+
+/// One end of an in-memory stream, made with `loopback_pair`.
+///
+/// Behaves like a TcpStream connected to the other end, without any sockets. Mostly useful for
+/// tests, through `Controller::add_loopback_pair`.
+#[derive(Debug, Clone)]
+pub struct LoopbackStream {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    /// Shared by every handle to this end, like a socket option.
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+    peer_addr: SocketAddr,
+}
+
+/// Creates two LoopbackStreams, where everything written to one can be read from the other.
+pub fn loopback_pair() -> (LoopbackStream, LoopbackStream) {
+    make_pair(None)
+}
+
+/// Like `loopback_pair`, but writing blocks while capacity bytes are waiting to be read, like a
+/// TcpStream whose peer has stopped reading.
+pub fn loopback_pair_with_capacity(capacity: usize) -> (LoopbackStream, LoopbackStream) {
+    make_pair(Some(capacity))
+}
+
+fn make_pair(capacity: Option<usize>) -> (LoopbackStream, LoopbackStream) {
+    let there = Arc::new(Pipe::new(capacity));
+    let back = Arc::new(Pipe::new(capacity));
+    // The loopback address with port 0 makes it obvious in logs that there is no real socket.
+    let addr = super::ip("127.0.0.1:0");
+    let first = LoopbackStream {
+        incoming: back.clone(),
+        outgoing: there.clone(),
+        read_timeout: Arc::new(Mutex::new(None)),
+        peer_addr: addr,
+    };
+    let second = LoopbackStream {
+        incoming: there,
+        outgoing: back,
+        read_timeout: Arc::new(Mutex::new(None)),
+        peer_addr: addr,
+    };
+    (first, second)
+}
+
+impl Transport for LoopbackStream {
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let timeout = *self.read_timeout.lock().unwrap();
+        let started = Instant::now();
+        let mut state = self.incoming.state.lock().unwrap();
+        loop {
+            if !state.bytes.is_empty() {
+                let read = cmp::min(buf.len(), state.bytes.len());
+                for (byte, slot) in state.bytes.drain(..read).zip(buf.iter_mut()) {
+                    *slot = byte;
+                }
+                // Writers may be waiting for room.
+                self.incoming.changed.notify_all();
+                return Ok(read);
+            }
+            if state.closed {
+                return Ok(0);
+            }
+            state = match timeout {
+                Some(timeout) => {
+                    let elapsed = started.elapsed();
+                    if elapsed >= timeout {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                                  "timed out reading from a loopback stream"));
+                    }
+                    self.incoming.changed.wait_timeout(state, timeout - elapsed).unwrap().0
+                }
+                None => self.incoming.changed.wait(state).unwrap(),
+            };
+        }
+    }
+
+    fn write_frame(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        let mut state = self.outgoing.state.lock().unwrap();
+        let mut written = 0;
+        loop {
+            if state.closed {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                          "the loopback stream has been shut down"));
+            }
+            let room = match self.outgoing.capacity {
+                Some(capacity) => capacity.saturating_sub(state.bytes.len()),
+                None => bytes.len(),
+            };
+            let end = cmp::min(written + room, bytes.len());
+            state.bytes.extend(bytes[written..end].iter().cloned());
+            written = end;
+            self.outgoing.changed.notify_all();
+            if written == bytes.len() {
+                return Ok(());
+            }
+            state = self.outgoing.changed.wait(state).unwrap();
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        if timeout == Some(Duration::new(0, 0)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "cannot set a 0 duration timeout"));
+        }
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<(), io::Error> {
+        match how {
+            Shutdown::Read => self.incoming.close(),
+            Shutdown::Write => self.outgoing.close(),
+            Shutdown::Both => {
+                self.incoming.close();
+                self.outgoing.close();
+            }
+        }
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        Ok(self.peer_addr)
+    }
+
+    fn try_clone(&self) -> Result<LoopbackStream, io::Error> {
+        Ok(self.clone())
+    }
+}
+
+impl Read for LoopbackStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.read_frame(buf)
+    }
+}
+
+impl Write for LoopbackStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        try!(self.write_frame(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// What faults a FaultyTransport injects into the frames written through it.
+///
+/// Percentages are the chance out of 100 of each frame being affected. The default injects
+/// nothing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Faults {
+    /// The shortest time each frame is held up for before it is written.
+    pub min_delay_millis: u64,
+    /// The longest time each frame is held up for. Delays are spread evenly between the two.
+    pub max_delay_millis: u64,
+    /// Frames that are dropped are never written, but writing them still succeeds.
+    pub drop_percent: u32,
+    /// Frames that are duplicated are written twice in a row.
+    pub duplicate_percent: u32,
+    /// Frames that are reordered are held back and written after the next frame.
+    pub reorder_percent: u32,
+    /// Frames that are corrupted have one random byte flipped.
+    ///
+    /// The header at the start of the frame is left alone, so the corruption is caught by the
+    /// checksum of the packet.
+    pub corrupt_percent: u32,
+}
+
+/// Changes the faults of a FaultyTransport while it is in use.
+///
+/// Shared by every handle to the transport, and cloning it gives another handle to the same faults.
+#[derive(Clone, Debug)]
+pub struct FaultHandle(Arc<Mutex<FaultState>>);
+
+#[derive(Debug)]
+struct FaultState {
+    faults: Faults,
+    /// The state of a xorshift generator, so the same seed always injects the same faults.
+    rng: u64,
+    /// A frame held back by reordering, written after the next one.
+    held: Option<Vec<u8>>,
+}
+
+impl FaultState {
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn roll(&mut self, percent: u32) -> bool {
+        self.next_random() % 100 < percent as u64
+    }
+}
+
+impl FaultHandle {
+    /// Replaces the faults, which affects every frame written from now on.
+    pub fn set(&self, faults: Faults) {
+        self.0.lock().unwrap().faults = faults;
+    }
+
+    /// The faults currently being injected.
+    pub fn get(&self) -> Faults {
+        self.0.lock().unwrap().faults
+    }
+}
+
+/// Wraps a transport, messing with the frames written to it to simulate a bad network.
+///
+/// Only writes are affected, so wrap both ends of a connection to affect both directions. Reading
+/// is passed straight through.
+#[derive(Debug)]
+pub struct FaultyTransport<T: Transport> {
+    inner: T,
+    handle: FaultHandle,
+}
+
+impl<T: Transport> FaultyTransport<T> {
+    /// Wraps the transport without injecting any faults yet.
+    ///
+    /// The seed decides which frames are affected, so a test can be repeated exactly.
+    pub fn new(inner: T, seed: u64) -> FaultyTransport<T> {
+        FaultyTransport {
+            inner: inner,
+            handle: FaultHandle(Arc::new(Mutex::new(FaultState {
+                faults: Faults::default(),
+                // Xorshift never leaves 0, so it can't be used as the starting state.
+                rng: if seed == 0 {
+                    0x2545F4914F6CDD1D
+                } else {
+                    seed
+                },
+                held: None,
+            }))),
+        }
+    }
+
+    /// A handle for changing the faults.
+    pub fn handle(&self) -> FaultHandle {
+        self.handle.clone()
+    }
+
+    /// Writes the frame held back by reordering, if there is one.
+    fn write_held(&mut self) -> Result<(), io::Error> {
+        let held = self.handle.0.lock().unwrap().held.take();
+        match held {
+            Some(held) => self.inner.write_frame(&held),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.inner.read_frame(buf)
+    }
+
+    fn write_frame(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        let mut bytes = bytes.to_vec();
+        let (delay, drop, duplicate, reorder) = {
+            let mut state = self.handle.0.lock().unwrap();
+            let faults = state.faults;
+            let spread = faults.max_delay_millis.saturating_sub(faults.min_delay_millis);
+            let delay = faults.min_delay_millis + state.next_random() % (spread + 1);
+            let drop = state.roll(faults.drop_percent);
+            let duplicate = state.roll(faults.duplicate_percent);
+            let reorder = state.roll(faults.reorder_percent) && state.held.is_none();
+            if state.roll(faults.corrupt_percent) && bytes.len() > super::HEADER_LEN {
+                let body_len = (bytes.len() - super::HEADER_LEN) as u64;
+                let index = super::HEADER_LEN + (state.next_random() % body_len) as usize;
+                bytes[index] ^= 0xFF;
+            }
+            (delay, drop, duplicate, reorder)
+        };
+        if delay > 0 {
+            thread::sleep(Duration::from_millis(delay));
+        }
+        if drop {
+            return Ok(());
+        }
+        if reorder {
+            self.handle.0.lock().unwrap().held = Some(bytes);
+            return Ok(());
+        }
+        try!(self.inner.write_frame(&bytes));
+        if duplicate {
+            try!(self.inner.write_frame(&bytes));
+        }
+        self.write_held()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<(), io::Error> {
+        if how != Shutdown::Read {
+            // Whatever was held back still goes out before the stream closes, like it was only
+            // delayed.
+            let held = self.handle.0.lock().unwrap().held.take();
+            if let Some(held) = held {
+                let mut inner = try!(self.inner.try_clone());
+                let _ = inner.write_frame(&held);
+            }
+        }
+        self.inner.shutdown(how)
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.inner.peer_addr()
+    }
+
+    fn try_clone(&self) -> Result<FaultyTransport<T>, io::Error> {
+        Ok(FaultyTransport {
+            inner: try!(self.inner.try_clone()),
+            handle: self.handle.clone(),
+        })
+    }
+}
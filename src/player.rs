@@ -0,0 +1,153 @@
+//! Contains the players of a server, each tied to the connection it joined on.
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+
+use net::{ConnectionId, NetworkError};
+
+/// The most characters a player's name may have.
+pub const MAX_NAME_LEN: usize = 24;
+
+/// Identifies a player for the lifetime of the `PlayerRegistry` that added it.
+///
+/// Ids are never reused, so a player that leaves and joins again gets a new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PlayerId(pub u64);
+
+/// A player on a server, added once the handshake of it's connection is done.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Player {
+    pub id: PlayerId,
+    /// The name the player asked for in it's `net::NetworkPacket::Init`, or one made up for it if
+    /// it asked for none.
+    pub name: String,
+    /// The connection the player joined on, which events are sent to it through.
+    pub connection: ConnectionId,
+}
+
+/// The players on a server, with their names unique, ignoring case.
+#[derive(Debug)]
+pub struct PlayerRegistry {
+    next_id: u64,
+    players: HashMap<PlayerId, Player>,
+    /// The id of the player with each name, lowercased.
+    by_name: HashMap<String, PlayerId>,
+    by_connection: HashMap<ConnectionId, PlayerId>,
+}
+
+impl PlayerRegistry {
+    /// Constructs a registry without any players.
+    pub fn new() -> PlayerRegistry {
+        PlayerRegistry {
+            next_id: 1,
+            players: HashMap::new(),
+            by_name: HashMap::new(),
+            by_connection: HashMap::new(),
+        }
+    }
+
+    /// Adds a player with the name on the connection, returning it's id.
+    ///
+    /// A player asking for an empty name is named `player<id>`, such as `player3`, with a
+    /// number after it as well if someone already took it.
+    ///
+    /// # Errors
+    /// * `NetworkError::InvalidName` if the name isn't a valid name, see `is_valid_name`.
+    /// * `NetworkError::NameTaken` if another player already has the name, ignoring case.
+    ///
+    /// # Panics
+    /// If the connection already has a player.
+    pub fn add(&mut self, name: &str, connection: ConnectionId) -> Result<PlayerId, NetworkError> {
+        assert!(!self.by_connection.contains_key(&connection),
+                "connection {} already has a player",
+                connection.0);
+        let id = PlayerId(self.next_id);
+        let name = if name.is_empty() {
+            let mut made_up = format!("player{}", id.0);
+            let mut suffix = 2;
+            while self.by_name.contains_key(&made_up) {
+                made_up = format!("player{}_{}", id.0, suffix);
+                suffix += 1;
+            }
+            made_up
+        } else if !is_valid_name(name) {
+            return Err(NetworkError::InvalidName);
+        } else if self.by_name.contains_key(&name.to_ascii_lowercase()) {
+            return Err(NetworkError::NameTaken);
+        } else {
+            name.to_owned()
+        };
+        self.next_id += 1;
+        self.by_name.insert(name.to_ascii_lowercase(), id);
+        self.by_connection.insert(connection, id);
+        self.players.insert(id,
+                            Player {
+                                id: id,
+                                name: name,
+                                connection: connection,
+                            });
+        Ok(id)
+    }
+
+    /// Removes the player on the connection, returning it, or None if the connection has no
+    /// player, such as when it never finished it's handshake.
+    pub fn remove_connection(&mut self, connection: ConnectionId) -> Option<Player> {
+        let id = match self.by_connection.remove(&connection) {
+            Some(id) => id,
+            None => return None,
+        };
+        let player = self.players.remove(&id).unwrap();
+        self.by_name.remove(&player.name.to_ascii_lowercase());
+        Some(player)
+    }
+
+    /// The player with the id, or None if there is none.
+    pub fn get(&self, id: PlayerId) -> Option<&Player> {
+        self.players.get(&id)
+    }
+
+    /// The player with the name, ignoring case, or None if there is none.
+    pub fn by_name(&self, name: &str) -> Option<&Player> {
+        self.by_name.get(&name.to_ascii_lowercase()).map(|id| &self.players[id])
+    }
+
+    /// The player on the connection, or None if there is none.
+    pub fn by_connection(&self, connection: ConnectionId) -> Option<&Player> {
+        self.by_connection.get(&connection).map(|id| &self.players[id])
+    }
+
+    /// Every player, in the order they were added.
+    pub fn list(&self) -> Vec<&Player> {
+        let mut players: Vec<&Player> = self.players.values().collect();
+        players.sort_by_key(|player| player.id);
+        players
+    }
+
+    /// The number of players.
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    /// If there are no players.
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+}
+
+impl Default for PlayerRegistry {
+    fn default() -> Self {
+        PlayerRegistry::new()
+    }
+}
+
+/// If the name can be asked for by a player: from 1 to MAX_NAME_LEN ascii letters, digits,
+/// underscores and dashes.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LEN &&
+    name.chars().all(|c| {
+        match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' => true,
+            _ => false,
+        }
+    })
+}
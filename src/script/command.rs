@@ -0,0 +1,138 @@
+//! Contains the splitting of the lines given to `Engine::run_command` into words, and the errors
+//! running them can give.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::mem;
+
+/// Splits a command line into words at whitespace, such as `kick 3 "too much lag"` into `kick`,
+/// `3` and `too much lag`.
+///
+/// Double or single quotes keep the whitespace in them in the word. A backslash takes the
+/// character after it as it is, such as a quote, a backslash or a space, apart from in single
+/// quotes, where everything up to the closing quote is taken as it is. Quotes with nothing between
+/// them give an empty word.
+///
+/// # Errors
+/// * `CommandError::Syntax` if a quote isn't closed, or the line ends with a backslash.
+pub fn split_command(line: &str) -> Result<Vec<String>, CommandError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // If a word has started, which a pair of quotes does even with nothing between them.
+    let mut in_word = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                match chars.next() {
+                    Some(escaped) => word.push(escaped),
+                    None => return Err(trailing_backslash()),
+                }
+                in_word = true;
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            match chars.next() {
+                                Some(escaped) => word.push(escaped),
+                                None => return Err(trailing_backslash()),
+                            }
+                        }
+                        Some(c) => word.push(c),
+                        None => {
+                            return Err(CommandError::Syntax("a double quote isn't closed"
+                                                                .to_owned()))
+                        }
+                    }
+                }
+            }
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => {
+                            return Err(CommandError::Syntax("a single quote isn't closed"
+                                                                .to_owned()))
+                        }
+                    }
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(mem::replace(&mut word, String::new()));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+fn trailing_backslash() -> CommandError {
+    CommandError::Syntax("the line ends with a backslash".to_owned())
+}
+
+/// An error that can occour running a command with `Engine::run_command`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandError {
+    /// The line has no words in it.
+    Empty,
+    /// The line can't be split into words, with why, such as a quote not being closed.
+    Syntax(String),
+    /// No command has the name.
+    UnknownCommand(String),
+    /// The command runs lua code, which is only allowed once commands are trusted, see
+    /// `Engine::set_commands_trusted`.
+    NotTrusted(String),
+    /// The handler of the command failed with the message.
+    HandlerError {
+        command: String,
+        message: String,
+    },
+}
+
+impl Display for CommandError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CommandError::Empty => write!(fmt, "Empty: {}", self.description()),
+            CommandError::Syntax(ref why) => {
+                write!(fmt, "Syntax: The line can't be split: {}", why)
+            }
+            CommandError::UnknownCommand(ref name) => {
+                write!(fmt, "UnknownCommand: There is no command {}.", name)
+            }
+            CommandError::NotTrusted(ref name) => {
+                write!(fmt,
+                       "NotTrusted: {} runs lua code, which commands aren't trusted to.",
+                       name)
+            }
+            CommandError::HandlerError { ref command, ref message } => {
+                write!(fmt, "HandlerError: The command {} failed: {}", command, message)
+            }
+        }
+    }
+}
+
+impl Error for CommandError {
+    fn description(&self) -> &str {
+        match *self {
+            CommandError::Empty => "the line has no command in it",
+            CommandError::Syntax(_) => "the line can't be split into words",
+            CommandError::UnknownCommand(_) => "there is no command with the name",
+            CommandError::NotTrusted(_) => "the command runs lua code, and commands aren't trusted",
+            CommandError::HandlerError { .. } => "the handler of the command failed",
+        }
+    }
+}
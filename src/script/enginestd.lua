@@ -1,26 +1,672 @@
 local buildengine = {}
+-- Rust functions registered with script::Engine::register_fn.
+buildengine.native = prelude_buildengine.native
 prelude_buildengine.events = {}
+-- Events queued by buildengine.send_to, taken by the engine with script::Engine::take_outgoing.
+prelude_buildengine.outgoing = {}
 
-function buildengine.subscribe (event_name, action)
+-- Logs with the log crate, as buildengine.log.debug, info, warn and error, each taking a message
+-- and formatting it with string.format if it is given anything after it. The target is "lua::"
+-- followed by the name of the module calling it, or "lua" if that can't be told.
+buildengine.log = {}
+for _,level in ipairs({"debug", "info", "warn", "error"}) do
+    buildengine.log[level] = function (message, ...)
+        if select("#", ...) > 0 then
+            message = string.format(message, ...)
+        else
+            message = tostring(message)
+        end
+        local module = prelude_buildengine.caller_module(2)
+        local target = module and "lua::" .. module or "lua"
+        prelude_buildengine["log_" .. level](target, message)
+    end
+end
+
+-- Every module has an environment of it's own for it's globals, so this is where modules keep
+-- state they mean to share with each other.
+buildengine.shared = {}
+
+-- Returned by a handler to stop the handlers after it from being called, such as to veto the
+-- action the event is for.
+buildengine.CANCEL = setmetatable({}, {__tostring = function () return "buildengine.CANCEL" end})
+
+-- The id given to the next handler, so ids are never reused.
+prelude_buildengine.next_handler_id = 1
+
+-- The names of the events handlers were added to that are wildcards, which are checked against
+-- every event activated, in the order they were first added.
+prelude_buildengine.wildcards = {}
+
+local function is_wildcard (event_name)
+    -- If the event name is "*", which every event matches, or ends in ".*", which every event
+    -- with the rest of the name and a "." at the start of it's name matches.
+    return event_name == "*" or event_name:sub(-2) == ".*"
+end
+
+local function forget_event (event_name)
+    -- Removes the event, once it has no handlers left.
+    prelude_buildengine.events[event_name] = nil
+    if is_wildcard(event_name) then
+        for i,wildcard in ipairs(prelude_buildengine.wildcards) do
+            if wildcard == event_name then
+                table.remove(prelude_buildengine.wildcards, i)
+                break
+            end
+        end
+    end
+end
+
+function buildengine.on (event_name, action)
+    -- Calls action whenever the event is activated, after the handlers added before it.
+    -- Returns the id of the handler, to remove it with buildengine.off.
+    -- The handler belongs to the module calling this, so it is removed when that is reloaded.
+    -- Event names may be split into namespaces with dots, such as "player.move". A handler added
+    -- to "player.*" is called for every event in the player namespace, and one added to "*" for
+    -- every event, after the handlers of the event itself, and is given the name of the event
+    -- before the arguments.
+    local handler = {
+        id = prelude_buildengine.next_handler_id,
+        action = action,
+        module = prelude_buildengine.caller_module(2),
+    }
+    prelude_buildengine.next_handler_id = prelude_buildengine.next_handler_id + 1
     if prelude_buildengine.events[event_name] == nil then
-        prelude_buildengine.events[event_name] = { action }
+        prelude_buildengine.events[event_name] = { handler }
+        if is_wildcard(event_name) then
+            table.insert(prelude_buildengine.wildcards, event_name)
+        end
     else
-        table.insert(prelude_buildengine.events[event_name], action)
+        table.insert(prelude_buildengine.events[event_name], handler)
+    end
+    return handler.id
+end
+buildengine.subscribe = buildengine.on
+
+function buildengine.off (event_name, handler_id)
+    -- Removes the handler with the id from the event, returning if it was found.
+    -- The handler isn't called again, even if the event is being activated.
+    local handlers = prelude_buildengine.events[event_name]
+    if handlers == nil then
+        return false
+    end
+    for i,handler in ipairs(handlers) do
+        if handler.id == handler_id then
+            handler.removed = true
+            table.remove(handlers, i)
+            if #handlers == 0 then
+                forget_event(event_name)
+            end
+            return true
+        end
+    end
+    return false
+end
+
+function prelude_buildengine.remove_handlers (module, first_id, last_id)
+    -- Removes every handler belonging to the module with an id from first_id up to, but not
+    -- including, last_id, as if by buildengine.off.
+    for event_name,handlers in pairs(prelude_buildengine.events) do
+        for i = #handlers, 1, -1 do
+            local handler = handlers[i]
+            if handler.module == module and handler.id >= first_id and handler.id < last_id then
+                handler.removed = true
+                table.remove(handlers, i)
+            end
+        end
+        if #handlers == 0 then
+            forget_event(event_name)
+        end
+    end
+end
+
+function buildengine.clear_event (event_name)
+    -- Removes every handler of the event, as if by buildengine.off.
+    local handlers = prelude_buildengine.events[event_name]
+    if handlers == nil then
+        return
+    end
+    for i,handler in ipairs(handlers) do
+        handler.removed = true
+    end
+    forget_event(event_name)
+end
+
+-- If the time each handler takes is recorded in prelude_buildengine.profiles, set by
+-- script::Engine::set_profiling.
+prelude_buildengine.profiling = false
+-- The time taken by each handler for each event it was called for, as {module, event name,
+-- handler id, calls, total seconds, most seconds}, in the order they were first called.
+-- Read by script::Engine::profiling_report.
+prelude_buildengine.profiles = {}
+-- The entries of prelude_buildengine.profiles, by event name then handler id.
+local profiles_by_event = {}
+-- The seconds a single call of a handler may take before it is logged, or nil to not log slow
+-- handlers, set by script::Engine::set_slow_handler_threshold.
+prelude_buildengine.slow_handler_threshold = nil
+
+local function record_time (event_name, handler, seconds)
+    -- Adds a call of the handler taking the seconds to it's profile, and logs it if it was slow.
+    if prelude_buildengine.profiling then
+        local by_id = profiles_by_event[event_name]
+        if by_id == nil then
+            by_id = {}
+            profiles_by_event[event_name] = by_id
+        end
+        local profile = by_id[handler.id]
+        if profile == nil then
+            profile = {handler.module or "", event_name, handler.id, 0, 0, 0}
+            by_id[handler.id] = profile
+            table.insert(prelude_buildengine.profiles, profile)
+        end
+        profile[4] = profile[4] + 1
+        profile[5] = profile[5] + seconds
+        profile[6] = math.max(profile[6], seconds)
     end
+    local threshold = prelude_buildengine.slow_handler_threshold
+    if threshold ~= nil and seconds > threshold then
+        prelude_buildengine.log_slow_handler("handler " .. handler.id .. " of module " ..
+                                             tostring(handler.module) .. " for the event " ..
+                                             event_name, seconds)
+    end
+end
+
+function prelude_buildengine.clear_profiles ()
+    prelude_buildengine.profiles = {}
+    profiles_by_event = {}
 end
 
 function buildengine.activate_event (event_name, ...)
-    local event_args = ...
-    local events_calling = prelude_buildengine.events[event_name]
-    for i,event_calling in pairs(events_calling) do
-        if event_args then
-            event_args = event_calling(unpack(event_args))
-        else
-            event_args = event_calling()
+    -- Calls the handlers the event had when it was activated, in the order they were added,
+    -- skipping any removed by an earlier one, untill one returns buildengine.CANCEL.
+    -- The handlers of the wildcards matching the event are called after, see buildengine.on.
+    -- The first handler is given the arguments after the event name, and each after it every
+    -- value the one before returned, nils included. Every value the last one returned is returned.
+    if event_name == "" or is_wildcard(event_name) then
+        error("can't activate the event " .. string.format("%q", event_name), 2)
+    end
+    local event_args = table.pack(...)
+    -- Each {handler, if it was added to a wildcard}.
+    local calling = {}
+    for i,handler in ipairs(prelude_buildengine.events[event_name] or {}) do
+        table.insert(calling, {handler, false})
+    end
+    for i,wildcard in ipairs(prelude_buildengine.wildcards) do
+        local prefix = wildcard:sub(1, -2)
+        if event_name:sub(1, #prefix) == prefix then
+            for j,handler in ipairs(prelude_buildengine.events[wildcard]) do
+                table.insert(calling, {handler, true})
+            end
         end
     end
-    return event_args
+    if #calling == 0 then
+        return
+    end
+    for i,call in ipairs(calling) do
+        local handler = call[1]
+        if not handler.removed then
+            local timing = prelude_buildengine.profiling or
+                           prelude_buildengine.slow_handler_threshold ~= nil
+            local started = timing and prelude_buildengine.clock()
+            local results
+            if call[2] then
+                results = table.pack(xpcall(handler.action,
+                                            prelude_buildengine.record_traceback,
+                                            event_name,
+                                            table.unpack(event_args, 1, event_args.n)))
+            else
+                results = table.pack(xpcall(handler.action,
+                                            prelude_buildengine.record_traceback,
+                                            table.unpack(event_args, 1, event_args.n)))
+            end
+            if timing then
+                record_time(event_name, handler, prelude_buildengine.clock() - started)
+            end
+            if not results[1] then
+                -- Read by script::Engine::exec_event to tell which handler failed.
+                prelude_buildengine.handler_error = {i, tostring(results[2]), handler.module}
+                error(prelude_buildengine.handler_error[2], 0)
+            end
+            if results[2] == buildengine.CANCEL then
+                -- Read by script::Engine::exec_event to tell which handler cancelled the event.
+                prelude_buildengine.cancelled_by = i
+                return
+            end
+            event_args = table.pack(table.unpack(results, 2, results.n))
+        end
+    end
+    return table.unpack(event_args, 1, event_args.n)
 end
 prelude_buildengine.activate_event = buildengine.activate_event
 
+if not prelude_buildengine.untrusted then
+    function buildengine.load_module (name, source)
+        -- Adds a module and loads it, as script::Engine::load_module does.
+        if prelude_buildengine.reserved_modules[name] then
+            error("the module name " .. name .. " is reserved by the engine", 2)
+        end
+        if prelude_buildengine.modules[name] ~= nil then
+            error("there is already a module " .. name, 2)
+        end
+        prelude_buildengine.reload_module(name, source)
+    end
+
+    function buildengine.unload_module (name)
+        -- Removes a module and every handler it added, as script::Engine::unload_module does.
+        if prelude_buildengine.reserved_modules[name] then
+            error("the module name " .. name .. " is reserved by the engine", 2)
+        end
+        if prelude_buildengine.modules[name] == nil then
+            error("there is no module " .. name, 2)
+        end
+        prelude_buildengine.unload_module(name)
+    end
+end
+
+-- Events queued by buildengine.queue_event, each {name, arguments packed by table.pack}, from
+-- first to last.
+prelude_buildengine.queued_events = {first = 1, last = 0}
+
+function buildengine.queue_event (event_name, ...)
+    -- Activates the event with the arguments once the events queued before it have run, rather
+    -- than now, so it's handlers never run inside the handlers of another event.
+    -- Queued events are run every tick, by script::Engine::process_queued_events.
+    if type(event_name) ~= "string" then
+        error("the name of a queued event must be a string, not a " .. type(event_name), 2)
+    end
+    local queue = prelude_buildengine.queued_events
+    queue.last = queue.last + 1
+    queue[queue.last] = {event_name, table.pack(...)}
+end
+prelude_buildengine.queue_event = buildengine.queue_event
+
+function prelude_buildengine.queued_event_count ()
+    local queue = prelude_buildengine.queued_events
+    return queue.last - queue.first + 1
+end
+
+function prelude_buildengine.next_queued_event ()
+    -- The name of the event to run next, or nil if none are queued. False if the next entry isn't
+    -- an event, such as one a script put in the queue itself, to be taken off with
+    -- prelude_buildengine.drop_queued_event.
+    local queue = prelude_buildengine.queued_events
+    if queue.first > queue.last then
+        return nil
+    end
+    local event = queue[queue.first]
+    if type(event) ~= "table" or type(event[1]) ~= "string" or type(event[2]) ~= "table" then
+        return false
+    end
+    return event[1]
+end
+
+function prelude_buildengine.drop_queued_event ()
+    -- Takes the next entry off the queue without running it.
+    local queue = prelude_buildengine.queued_events
+    queue[queue.first] = nil
+    queue.first = queue.first + 1
+end
+
+function prelude_buildengine.run_queued_event ()
+    -- Takes the next event off the queue and activates it.
+    local queue = prelude_buildengine.queued_events
+    local event = queue[queue.first]
+    queue[queue.first] = nil
+    queue.first = queue.first + 1
+    buildengine.activate_event(event[1], table.unpack(event[2], 1, event[2].n))
+end
+
+function prelude_buildengine.snapshot_queue ()
+    -- The queued events, first to run first, each as {name, arguments packed by table.pack}.
+    -- Read by script::Engine::snapshot.
+    local queue = prelude_buildengine.queued_events
+    local events = {}
+    for i = queue.first, queue.last do
+        table.insert(events, queue[i])
+    end
+    return events
+end
+
+-- The callbacks of the timers that haven't fired or been cancelled, by the id of their timer,
+-- which is kept by script::Engine.
+prelude_buildengine.timers = {}
+
+-- The longest a timer may be started for, about 136 years, so the time it is due can't overflow.
+local MAX_TIMER_SECONDS = 2 ^ 32
+
+local function start_timer (seconds, callback, repeating)
+    -- Errors at level 3, blaming the caller of buildengine.after or buildengine.every.
+    if type(seconds) ~= "number" or seconds ~= seconds or seconds > MAX_TIMER_SECONDS then
+        error("the seconds of a timer must be a number no more than " .. MAX_TIMER_SECONDS, 3)
+    end
+    local id = prelude_buildengine.add_timer(seconds, repeating)
+    prelude_buildengine.timers[id] = {callback = callback, repeating = repeating}
+    return id
+end
+
+function buildengine.after (seconds, callback)
+    -- Calls callback once, after the seconds have passed. Returns the id of the timer, to cancel it
+    -- with buildengine.cancel_timer.
+    return start_timer(seconds, callback, false)
+end
+
+function buildengine.every (seconds, callback)
+    -- Calls callback every time the seconds pass, untill it is cancelled with
+    -- buildengine.cancel_timer. Returns the id of the timer.
+    return start_timer(seconds, callback, true)
+end
+
+function buildengine.cancel_timer (id)
+    -- Stops the timer with the id from firing. Does nothing if it already fired or was cancelled.
+    if prelude_buildengine.timers[id] == nil then
+        return
+    end
+    prelude_buildengine.timers[id] = nil
+    prelude_buildengine.remove_timer(id)
+end
+
+function buildengine.resume_timer (id, callback)
+    -- Gives the timer with the id the callback, such as a timer restored by
+    -- script::Engine::restore, which can't restore callbacks. Meant to be called by the handlers
+    -- of "on_restore", with the ids of the timers saved in the state returned by the handlers of
+    -- "on_snapshot".
+    local repeating = prelude_buildengine.timer_repeats(id)
+    if repeating == nil then
+        error("there is no timer with the id " .. tostring(id), 2)
+    end
+    prelude_buildengine.timers[id] = {callback = callback, repeating = repeating}
+end
+
+function prelude_buildengine.fire_timer (id)
+    -- Calls the callback of the timer with the id, as it is due. Called by
+    -- script::Engine::advance_time.
+    local timer = prelude_buildengine.timers[id]
+    if timer == nil then
+        return
+    end
+    if not timer.repeating then
+        prelude_buildengine.timers[id] = nil
+    end
+    timer.callback()
+end
+
+-- The game time, kept by script::Engine. It only moves as the engine ticks, by the same time
+-- whatever the tick rate, and is carried over snapshots, so it is what to time things against
+-- rather than os.time or os.clock.
+buildengine.time = {}
+
+function buildengine.time.now ()
+    -- The seconds of game time that have passed, which the timers started with buildengine.after
+    -- and buildengine.every are kept against.
+    return prelude_buildengine.time_now()
+end
+
+function buildengine.time.tick ()
+    -- The number of ticks that have finished, which is the number given to "on_tick" while it
+    -- runs.
+    return prelude_buildengine.time_tick()
+end
+
+function buildengine.time.delta ()
+    -- The seconds the last tick to finish took, or 0 before the first one has.
+    return prelude_buildengine.time_delta()
+end
+
+function buildengine.send_to (connection_id, event_name, args)
+    -- Queues the event to be sent to the connection, with the arguments in the args table.
+    table.insert(prelude_buildengine.outgoing, {connection_id, event_name, args or {}})
+end
+
+-- Random numbers from a generator kept by script::Engine, so the same seed always gives the same
+-- numbers, and a snapshot of the engine carries on with the same numbers once restored.
+buildengine.random = {}
+
+local function check_whole (number, name, function_name)
+    if type(number) ~= "number" or math.floor(number) ~= number then
+        error("bad argument " .. name .. " to " .. function_name .. ", expected a whole number", 3)
+    end
+end
+
+function buildengine.random.seed (seed)
+    -- Restarts the generator from the seed, a whole number, as script::Engine::set_rng_seed does.
+    check_whole(seed, "seed", "random.seed")
+    prelude_buildengine.random_seed(seed)
+end
+
+function buildengine.random.int (min, max)
+    -- A whole number from min to max, including both.
+    check_whole(min, "min", "random.int")
+    check_whole(max, "max", "random.int")
+    if min > max then
+        error("the range " .. min .. " to " .. max .. " is empty", 2)
+    end
+    return prelude_buildengine.random_int(min, max)
+end
+
+function buildengine.random.float ()
+    -- A number from 0 up to, but not including, 1.
+    return prelude_buildengine.random_float()
+end
+
+function buildengine.random.shuffle (array)
+    -- Puts the values of the array in a random order, returning it.
+    for i = #array, 2, -1 do
+        local j = prelude_buildengine.random_int(1, i)
+        array[i], array[j] = array[j], array[i]
+    end
+    return array
+end
+
+-- Values kept by script::Engine, which can save them to disk with script::Engine::save_storage
+-- and load them back with script::Engine::load_storage, so they outlive the process.
+buildengine.storage = {}
+-- Kept here so scripts replacing load don't break buildengine.storage.get.
+local load = load
+
+local function unstorable (value, seen)
+    -- The type of the first value in the value that can't be stored, such as "function", or nil
+    -- if it can all be stored. Tables are checked along with their keys and values, but not their
+    -- metatables.
+    local kind = type(value)
+    if kind == "table" then
+        if seen[value] then
+            return "table containing itself"
+        end
+        seen[value] = true
+        for k,v in pairs(value) do
+            local found = unstorable(k, seen) or unstorable(v, seen)
+            if found ~= nil then
+                return found
+            end
+        end
+        seen[value] = nil
+    elseif kind ~= "nil" and kind ~= "boolean" and kind ~= "number" and kind ~= "string" then
+        return kind
+    end
+end
+
+function buildengine.storage.set (key, value)
+    -- Stores a copy of the value under the key, which must be a string, replacing what was stored
+    -- under it before, or removes it if the value is nil.
+    -- The value may be a table of other values, but not a function, userdata or thread, or a
+    -- table containing itself.
+    if type(key) ~= "string" then
+        error("storage keys must be strings, not " .. type(key), 2)
+    end
+    local found = unstorable(value, {})
+    if found ~= nil then
+        error("can't store a " .. found .. " under the key " .. key, 2)
+    end
+    prelude_buildengine.storage_set(key, value)
+end
+
+function buildengine.storage.get (key)
+    -- A copy of the value stored under the key, or nil if there is none.
+    return load(prelude_buildengine.storage_get(tostring(key)), "storage", "t", {})()
+end
+
+function buildengine.storage.delete (key)
+    -- Removes the value stored under the key, if there is one.
+    prelude_buildengine.storage_set(tostring(key), nil)
+end
+
+-- Sending events to the peers of the engine, and managing their connections, through the network
+-- given to script::Engine with set_network. Without one, such as in single player, every function
+-- errors.
+buildengine.net = {}
+
+local function call_net (native, ...)
+    -- Calls the native function, which returns lua code giving it's result, or nil and the
+    -- message of the error, raised at the caller of the function of buildengine.net.
+    local result, err = load(native(...), "net", "t", {})()
+    if err ~= nil then
+        error(err, 3)
+    end
+    return result
+end
+
+local function check_net_event (event_name, args, function_name)
+    if type(event_name) ~= "string" then
+        error("bad argument event_name to " .. function_name .. ", expected a string", 3)
+    end
+    if type(args) ~= "table" then
+        error("bad argument args to " .. function_name .. ", expected a table", 3)
+    end
+    -- Rust can't read a table containing itself, so it is caught here.
+    if unstorable(args, {}) == "table containing itself" then
+        error("bad argument args to " .. function_name .. ", a table contains itself", 3)
+    end
+end
+
+function buildengine.net.send (connection_id, event_name, args)
+    -- Sends the event to the connection, with the values in the args array as it's arguments.
+    check_whole(connection_id, "connection_id", "net.send")
+    args = args or {}
+    check_net_event(event_name, args, "net.send")
+    call_net(prelude_buildengine.net_send, connection_id, {event_name, args})
+end
+
+function buildengine.net.broadcast (event_name, args)
+    -- Sends the event to every connection, with the values in the args array as it's arguments,
+    -- returning the number of connections it was sent to.
+    args = args or {}
+    check_net_event(event_name, args, "net.broadcast")
+    return call_net(prelude_buildengine.net_broadcast, event_name, args)
+end
+
+function buildengine.net.kick (connection_id, reason)
+    -- Closes the connection, telling the peer the reason.
+    check_whole(connection_id, "connection_id", "net.kick")
+    call_net(prelude_buildengine.net_kick, connection_id, tostring(reason or ""))
+end
+
+function buildengine.net.peer_count ()
+    -- The number of connections.
+    return call_net(prelude_buildengine.net_peer_count)
+end
+
+function buildengine.net.stats (connection_id)
+    -- The traffic on the connection, as a table with bytes_sent, bytes_received,
+    -- connected_seconds, and rtt_ms once the round trip time is known, or nil if there is no such
+    -- connection. Cheap enough to read every tick.
+    check_whole(connection_id, "connection_id", "net.stats")
+    return call_net(prelude_buildengine.net_stats, connection_id)
+end
+
+function buildengine.net.peer_addr (connection_id)
+    -- The address of the peer on the other end of the connection, such as "127.0.0.1:4000", or
+    -- nil if there is no such connection.
+    check_whole(connection_id, "connection_id", "net.peer_addr")
+    return call_net(prelude_buildengine.net_peer_addr, connection_id)
+end
+
+-- The players on a server, as tables of their id, name, and the id of the connection they joined
+-- on, which buildengine.send_to and buildengine.net take. A client has none.
+buildengine.players = {}
+
+function buildengine.players.list ()
+    -- An array of every player, in the order they joined.
+    return load(prelude_buildengine.players_list(), "players", "t", {})()
+end
+
+function buildengine.players.get (id_or_name)
+    -- The player with the id, or the name ignoring case, or nil if there is none.
+    local kind = type(id_or_name)
+    if kind ~= "number" and kind ~= "string" then
+        error("bad argument id_or_name to players.get, expected a number or a string", 2)
+    end
+    return load(prelude_buildengine.players_get(id_or_name), "players", "t", {})()
+end
+
+function buildengine.players.count ()
+    -- The number of players.
+    return prelude_buildengine.players_count()
+end
+
+-- Commands run by script::Engine::run_command, such as from a server console. Commands registered
+-- in rust with script::Engine::register_command are run before these.
+buildengine.commands = {}
+-- The handler of each command, by name.
+prelude_buildengine.commands = {}
+-- The help of each command, by name, kept apart so rust can read it.
+prelude_buildengine.command_help = {}
+
+function buildengine.commands.register (name, help, handler)
+    -- Registers the command, replacing any registered with the name before. The handler is called
+    -- with each argument as a string, and returns the text to print, if any.
+    if type(name) ~= "string" or name == "" or name:find("%s") then
+        error("bad argument name to commands.register, expected a string without whitespace", 2)
+    end
+    if type(handler) ~= "function" then
+        error("bad argument handler to commands.register, expected a function", 2)
+    end
+    prelude_buildengine.commands[name] = handler
+    prelude_buildengine.command_help[name] = tostring(help or "")
+end
+
+function buildengine.commands.unregister (name)
+    -- Removes the command registered with the name, if there is one.
+    prelude_buildengine.commands[name] = nil
+    prelude_buildengine.command_help[name] = nil
+end
+
+function prelude_buildengine.run_command (name, ...)
+    -- Runs the command registered with the name, returning the text it printed.
+    local output = prelude_buildengine.commands[name](...)
+    if output == nil then
+        return ""
+    end
+    return tostring(output)
+end
+
+-- What script::Engine tells scripts of the engine running them, such as it's version and tick
+-- rate, set with script::Engine::set_info. It is read-only, so a script can't change what another
+-- sees.
+local info_fields = {}
+buildengine.info = prelude_buildengine.freeze(info_fields, "buildengine.info")
+-- Kept here so scripts replacing them can't change what buildengine.info says.
+local engine_info = prelude_buildengine.engine_info
+local has_feature = prelude_buildengine.has_feature
+
+local function has (feature)
+    -- If the engine has the feature, such as "timers", so scripts can check for an API before
+    -- using it.
+    if type(feature) ~= "string" then
+        error("bad argument to 'has' (string expected, got " .. type(feature) .. ")", 2)
+    end
+    return has_feature(feature)
+end
+
+function prelude_buildengine.refresh_info ()
+    -- Reads the fields of buildengine.info from script::Engine again, done by it's set_info.
+    local values = load(engine_info(), "info", "t", {})()
+    for key in pairs(info_fields) do
+        info_fields[key] = nil
+    end
+    for key,value in pairs(values) do
+        info_fields[key] = value
+    end
+    info_fields.has = has
+end
+prelude_buildengine.refresh_info()
+
 return buildengine;
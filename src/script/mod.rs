@@ -2,14 +2,39 @@
 
 #[cfg(test)]
 mod test;
+mod command;
+mod rng;
+mod timer;
+mod watcher;
 
-use std::collections::HashMap;
+pub use self::command::{CommandError, split_command};
+pub use self::watcher::ScriptWatcher;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::{TryFrom, TryInto};
+use std::env;
 use std::error::Error;
+use std::ffi::OsStr;
 use std::fmt::{Debug, Display, Formatter};
 use std::fmt;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::u64;
 
-use hlua::{Lua, LuaError, LuaFunction, LuaTable};
+use bincode::SizeLimit;
+use bincode::serde::{DeserializeError, deserialize_from, serialize};
+use hlua::{Lua, LuaError, LuaFunction, LuaTable, function0, function1, function2};
 use hlua::any::AnyLuaValue;
+use log::LogLevel;
+
+use player::{Player, PlayerId, PlayerRegistry};
+use self::rng::Rng;
+use self::timer::Timers;
 
 /// The engine lua standard library. Contains functionality relating to making a game with the engine.
 ///
@@ -19,163 +44,4044 @@ const ENGINE_STD: &'static str = include_str!("enginestd.lua");
 /// A piece of code run before the main script.
 const PRELUDE: &'static str = include_str!("prelude.lua");
 
+/// Code removing the functions scripts don't get under `SandboxLevel::Trusted`.
+const TRUSTED_SANDBOX: &'static str = include_str!("sandbox_trusted.lua");
+
+/// Code removing the libraries and functions scripts don't get under `SandboxLevel::Untrusted`.
+///
+/// They are removed from package.loaded as well, so require can't bring them back.
+const UNTRUSTED_SANDBOX: &'static str = include_str!("sandbox_untrusted.lua");
+
+/// The most queued events `::Engine::tick` runs each tick, with
+/// `Engine::process_queued_events`.
+pub const QUEUED_EVENTS_PER_TICK: usize = 1024;
+
+/// How many more arguments than the values in it's table of them a native function may be given,
+/// which are nil. The number of arguments is given by lua, so it isn't trusted to size anything.
+const MAX_NATIVE_NILS: usize = 256;
+
+/// The features scripts can check for with `buildengine.info.has`, which every engine has. Add to
+/// it as APIs land, so scripts can tell if the engine running them is new enough.
+pub const FEATURES: &'static [&'static str] = &["events",
+                                                 "wildcard_events",
+                                                 "queued_events",
+                                                 "send_to",
+                                                 "timers",
+                                                 "storage",
+                                                 "snapshots",
+                                                 "random",
+                                                 "log",
+                                                 "shared",
+                                                 "profiling",
+                                                 "info",
+                                                 "net",
+                                                 "commands",
+                                                 "players",
+                                                 "time"];
+
+/// What scripts are told of the engine running them, as the fields of `buildengine.info`, set
+/// with `Engine::set_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EngineInfo {
+    /// The version of the engine, `::VERSION`.
+    pub version: String,
+    /// The version of the network protocol, `net::PROTOCOL_VERSION`.
+    pub protocol_version: u32,
+    /// If the scripts are run by a server.
+    pub is_server: bool,
+    /// If the scripts are run by a client.
+    pub is_client: bool,
+    /// The ticks per second of the `::Engine` running the scripts, or None if there isn't one.
+    pub tick_rate: Option<u32>,
+    /// The operating system, as given by `std::env::consts::OS`, such as `linux`.
+    pub os: String,
+    /// The features `buildengine.info.has` is true for.
+    pub features: HashSet<String>,
+}
+
+impl Default for EngineInfo {
+    /// The info of scripts run outside of an `::Engine`, neither by a server or a client, with
+    /// every feature in FEATURES.
+    fn default() -> Self {
+        EngineInfo {
+            version: ::VERSION.to_owned(),
+            protocol_version: ::net::PROTOCOL_VERSION,
+            is_server: false,
+            is_client: false,
+            tick_rate: None,
+            os: env::consts::OS.to_owned(),
+            features: FEATURES.iter().map(|feature| (*feature).to_owned()).collect(),
+        }
+    }
+}
+
+impl EngineInfo {
+    /// The fields of `buildengine.info`, apart from has, as a lua table.
+    fn to_lua(&self) -> AnyLuaValue {
+        let string = |val: &str| AnyLuaValue::LuaString(val.to_owned());
+        let mut fields = vec![(string("version"), string(&self.version)),
+                              (string("protocol_version"),
+                               AnyLuaValue::LuaNumber(self.protocol_version as f64)),
+                              (string("is_server"), AnyLuaValue::LuaBoolean(self.is_server)),
+                              (string("is_client"), AnyLuaValue::LuaBoolean(self.is_client)),
+                              (string("os"), string(&self.os))];
+        if let Some(tick_rate) = self.tick_rate {
+            fields.push((string("tick_rate"), AnyLuaValue::LuaNumber(tick_rate as f64)));
+        }
+        AnyLuaValue::LuaArray(fields)
+    }
+}
+
+/// The most errors `Engine::recent_errors` keeps, dropping the oldest past it.
+pub const RECENT_ERRORS_KEPT: usize = 32;
+
+/// How much of the lua standard library scripts get.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SandboxLevel {
+    /// Every library, for scripts trusted as much as the engine itself, such as the game scripts
+    /// run by a server.
+    Full,
+    /// Every library apart from `os.execute` and `io.popen`, so scripts can't run other programs,
+    /// for scripts that are trusted but shouldn't need to.
+    Trusted,
+    /// No io or package, and only `os.time` and `os.clock` from os. dofile and loadfile are
+    /// removed, load and loadstring only take text chunks, and require only finds the scripts
+    /// given to the engine. Scripts can't reach the filesystem or run other programs. For scripts
+    /// run on a player's machine, such as by a client.
+    Untrusted,
+}
+
+/// The default `ExecutionLimit::instructions`.
+pub const DEFAULT_LIMIT_INSTRUCTIONS: u64 = 1_000_000_000;
+
+/// The default `ExecutionLimit::millis`.
+pub const DEFAULT_LIMIT_MILLIS: u64 = 10_000;
+
+/// How long the scripts may run for each event, and for the init script, before they are aborted.
+///
+/// The limits are checked every thousand lua instructions, so time spent in a single call to a
+/// rust function, such as a long `string.rep`, can't be cut short.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExecutionLimit {
+    /// The most lua instructions that may be run, or None for no limit.
+    pub instructions: Option<u64>,
+    /// The most milliseconds the scripts may run for, or None for no limit.
+    pub millis: Option<u64>,
+}
+
+impl Default for ExecutionLimit {
+    fn default() -> Self {
+        ExecutionLimit {
+            instructions: Some(DEFAULT_LIMIT_INSTRUCTIONS),
+            millis: Some(DEFAULT_LIMIT_MILLIS),
+        }
+    }
+}
+
+/// What loading the scripts does when the init script is missing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitPolicy {
+    /// The scripts must include an init script that isn't empty, as otherwise none of them would
+    /// ever run, such as when the init script was left out when packaging a game.
+    RequireInit,
+    /// The scripts are loaded without an init script, as if it was empty, with a warning.
+    AllowMissing,
+}
+
+impl Default for InitPolicy {
+    fn default() -> Self {
+        InitPolicy::RequireInit
+    }
+}
+
 /// Handles the scripts, their state, and their execution.
 pub struct Engine<'lua> {
     /// The interpreter used for the scripts.
     pub interpreter: Lua<'lua>,
+    /// When the scripts last started running, for the watchdog to enforce the execution limit.
+    started: Rc<Cell<Instant>>,
+    /// The timers started with `buildengine.after` and `buildengine.every`, which keep the game
+    /// time `buildengine.time.now` reads.
+    timers: Rc<RefCell<Timers>>,
+    /// The number of ticks advanced with `advance_tick`.
+    tick: Rc<Cell<u64>>,
+    /// The length of the last tick advanced with `advance_tick`.
+    delta: Rc<Cell<Duration>>,
+    /// The values scripts stored with `buildengine.storage.set`, by key.
+    storage: Rc<RefCell<HashMap<String, LuaValueRepr>>>,
+    /// The generator behind `buildengine.random`.
+    rng: Rc<RefCell<Rng>>,
+    /// What `buildengine.info` tells the scripts.
+    info: Rc<RefCell<EngineInfo>>,
+    /// The last RECENT_ERRORS_KEPT errors of the scripts, oldest first.
+    recent_errors: VecDeque<ScriptErrorReport>,
+    /// If "on_error" is being executed, so an error in it's handlers isn't reported to them.
+    reporting_error: bool,
+    /// What `run_init` does when the init script is missing.
+    init_policy: InitPolicy,
+    /// The network `buildengine.net` goes through, if there is one.
+    network: Rc<RefCell<Option<Box<ScriptNetwork>>>>,
+    /// The players `buildengine.players` reads, if there are any.
+    players: Rc<RefCell<Option<Rc<RefCell<PlayerRegistry>>>>>,
+    /// The commands registered with `register_command`, and the built in ones, by name.
+    commands: HashMap<String, Command<'lua>>,
+    /// If commands that run lua code, such as `lua`, may be run.
+    commands_trusted: bool,
+    /// The limits passed to the watchdog each time it is started.
+    execution_limit: ExecutionLimit,
+    /// The most bytes the interpreter may use after a full collection of garbage, or None if there
+    /// is no limit.
+    memory_limit: Option<usize>,
+    /// The module `replace_module` or `unload_module` asked the prelude to load or unload, taken
+    /// by `prelude_buildengine.run_module_request`.
+    module_request: Rc<RefCell<Option<ModuleRequest>>>,
+    /// What `start_watchdog` or `stop_watchdog` asked the watchdog to do, taken by
+    /// `prelude_buildengine.run_watchdog_request`.
+    watchdog_request: Rc<RefCell<Option<WatchdogRequest>>>,
+}
+
+/// A module to load or unload, for `prelude_buildengine.run_module_request`.
+enum ModuleRequest {
+    /// The name and source of a module to load, replacing any module with the name.
+    Load(String, String),
+    /// The name of a module to unload.
+    Unload(String),
+}
+
+/// What the watchdog should do, for `prelude_buildengine.run_watchdog_request`.
+enum WatchdogRequest {
+    /// Start with the limits on the instructions, milliseconds and bytes of memory, if any.
+    Start(Option<u64>, Option<u64>, Option<u64>),
+    /// Stop, so code is never aborted.
+    Stop,
+}
+
+/// A command registered in rust, for `Engine::run_command`.
+struct Command<'lua> {
+    help: String,
+    handler: CommandHandler<'lua>,
+}
+
+enum CommandHandler<'lua> {
+    Rust(Box<FnMut(&[String]) -> Result<String, String> + 'lua>),
+    Builtin(Builtin),
+}
+
+/// The commands every engine has, see `Engine::run_command`.
+#[derive(Clone, Copy, Debug)]
+enum Builtin {
+    Help,
+    Lua,
+    Scripts,
 }
 
 impl<'lua> Engine<'lua> {
-    /// Constructs a script::Engine and loads the given scripts.
+    /// Constructs a script::Engine and loads the given scripts, with `SandboxLevel::Full`.
     ///
     /// The interpreter is initalized with the lua standard library, and the engine std.
     ///
     /// The prelude_buildengine.modules table is initalized with the source code of the scripts passed through the scripts parameter,
-    /// sans the init entry, which is executed.
-    pub fn new(mut scripts: HashMap<String, String>) -> Result<Self, LuaError> {
-        scripts.insert("buildengine".to_owned(), ENGINE_STD.to_owned());
+    /// sans the init entry. Every module is then loaded in the order given by `scripts_by_name`
+    /// and `order_scripts`, and the init entry is executed last.
+    ///
+    /// Each script is named after it's module in errors and tracebacks, such as
+    /// `[string "mymodule"]:12`, and the init script is named `init`.
+    ///
+    /// Each module runs in an environment of it's own, so the globals one sets can't overwrite
+    /// another's. Globals a module hasn't set are read from the shared globals, which have the
+    /// standard library and the globals the init script sets, which runs in them directly. The
+    /// environment is kept when the module is reloaded. Modules share state on purpose through
+    /// the `buildengine.shared` table.
+    ///
+    /// The scripts must include an init script that isn't empty, see `InitPolicy::RequireInit`, and
+    /// `new_with_policy` to allow it to be missing.
+    ///
+    /// A script's dependencies being missing or forming a cycle, or the init script being missing,
+    /// is reported as a `LuaError::ExecutionError`, use `new_ordered` to get the `::InitError`
+    /// instead. Requiring a module that is still loading errors as well, rather than getting it
+    /// half loaded, with a message naming the modules involved such as
+    /// `circular require: a -> b -> a`.
+    pub fn new(scripts: HashMap<String, String>) -> Result<Self, LuaError> {
+        Engine::new_with_sandbox(scripts, SandboxLevel::Full)
+    }
+
+    /// Constructs a script::Engine like `new`, giving the scripts only the parts of the lua
+    /// standard library allowed by the sandbox level.
+    ///
+    /// The libraries are removed before any script runs, so none of them can keep a reference to
+    /// one. A failure of the prelude or sandbox is reported as a `LuaError::ExecutionError`.
+    pub fn new_with_sandbox(scripts: HashMap<String, String>,
+                            sandbox: SandboxLevel)
+                            -> Result<Self, LuaError> {
+        Engine::new_with_policy(scripts, sandbox, InitPolicy::default())
+    }
+
+    /// Constructs a script::Engine like `new_with_sandbox`, with the policy for when the init
+    /// script is missing.
+    pub fn new_with_policy(scripts: HashMap<String, String>,
+                           sandbox: SandboxLevel,
+                           policy: InitPolicy)
+                           -> Result<Self, LuaError> {
+        let mut engine = try!(Engine::new_empty(sandbox)
+                                  .map_err(|err| LuaError::ExecutionError(err.to_string())));
+        engine.set_init_policy(policy);
+        try!(engine.run_init(scripts));
+        Ok(engine)
+    }
+
+    /// Constructs a script::Engine like `new_with_sandbox`, loading the modules in the order they
+    /// are given in, apart from each being loaded after the modules it depends on, as sorted by
+    /// `order_scripts`.
+    ///
+    /// # Errors
+    /// * `::InitError::ScriptDependencyCycle` or `::InitError::MissingScriptDependency` if the
+    ///   dependencies of the scripts can't be satisfied.
+    /// * `::InitError::ScriptError` if a module or the init script failed to load.
+    /// * `::InitError::ScriptEngineError` if the prelude or sandbox failed, see `new_empty`, or
+    ///   the init script is missing or empty, see `run_init_ordered`.
+    pub fn new_ordered(scripts: Vec<(String, String)>,
+                       sandbox: SandboxLevel)
+                       -> Result<Self, ::InitError> {
+        let mut engine = try!(Engine::new_empty(sandbox)
+                                  .map_err(::InitError::ScriptEngineError));
+        try!(engine.run_init_ordered(scripts));
+        Ok(engine)
+    }
+
+    /// Constructs a script::Engine from a snapshot taken with `snapshot`, loading the given scripts
+    /// like `new_with_sandbox`, under the sandbox level.
+    ///
+    /// See `run_restore`, to set up things such as native functions and limits before the scripts
+    /// are loaded.
+    ///
+    /// # Errors
+    /// The same as `run_restore`, with a failure of the prelude or sandbox reported as
+    /// `SnapshotError::ScriptError`.
+    pub fn restore(scripts: HashMap<String, String>,
+                   snapshot: EngineSnapshot,
+                   sandbox: SandboxLevel)
+                   -> Result<Self, SnapshotError> {
+        let mut engine = try!(Engine::new_empty(sandbox).map_err(|err| {
+            SnapshotError::ScriptError(LuaError::ExecutionError(err.to_string()))
+        }));
+        try!(engine.run_restore(scripts, snapshot));
+        Ok(engine)
+    }
+
+    /// Restores a snapshot taken with `snapshot` into an engine made with `new_empty`, loading the
+    /// given scripts with `run_init`, as done by `restore`.
+    ///
+    /// The stored values and timers are restored before the scripts are loaded, so they can read
+    /// the stored values as they load, and the queued events and the generator behind
+    /// `buildengine.random` once they have, after any events the scripts queued while loading.
+    /// Then the event "on_restore" is executed with the state the handlers of "on_snapshot"
+    /// returned.
+    ///
+    /// The callbacks of the timers weren't saved, so the handlers of "on_restore" must give them
+    /// back with `buildengine.resume_timer(id, callback)`, such as from ids they saved in their
+    /// state. Restored timers left without a callback are cancelled.
+    ///
+    /// # Errors
+    /// * `SnapshotError::ScriptError` if the scripts failed to load.
+    /// * `SnapshotError::Event` if queueing an event or executing "on_restore" failed.
+    pub fn run_restore(&mut self,
+                       scripts: HashMap<String, String>,
+                       snapshot: EngineSnapshot)
+                       -> Result<(), SnapshotError> {
+        *self.storage.borrow_mut() = snapshot.storage;
+        self.timers.borrow_mut().set_now(duration_from_secs(snapshot.game_time));
+        self.tick.set(snapshot.tick);
+        for timer in &snapshot.timers {
+            self.timers.borrow_mut().insert(timer.id,
+                                            duration_from_secs(timer.due_in),
+                                            timer.interval.map(duration_from_secs));
+        }
+        if let Err(err) = self.run_init(scripts) {
+            return Err(SnapshotError::ScriptError(err));
+        }
+        self.set_rng_seed(snapshot.rng_state);
+        for (name, args) in snapshot.queued_events {
+            let mut converted = Vec::new();
+            for arg in args {
+                converted.push(try!(AnyLuaValue::try_from(arg)
+                                        .map_err(|err| SnapshotError::Event(err.into()))));
+            }
+            try!(self.queue_event(&name, converted).map_err(SnapshotError::Event));
+        }
+        let state = try!(AnyLuaValue::try_from(snapshot.state)
+                             .map_err(|err| SnapshotError::Event(err.into())));
+        match self.exec_event("on_restore", vec![state]) {
+            Ok(_) | Err(ExecEventError::EngineStdNotImported) => {}
+            Err(err) => return Err(SnapshotError::Event(err)),
+        }
+        for timer in &snapshot.timers {
+            let code = format!("return prelude_buildengine ~= nil and \
+                                prelude_buildengine.timers ~= nil and \
+                                prelude_buildengine.timers[{}] ~= nil",
+                               timer.id);
+            // A timer whose callback can't be read, such as when a script broke the table of
+            // them, wasn't resumed either.
+            let resumed: bool = self.interpreter.execute(&code).unwrap_or(false);
+            if !resumed {
+                warn!("Cancelling timer {}, as it wasn't given a callback by on_restore.",
+                      timer.id);
+                self.timers.borrow_mut().cancel(timer.id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Constructs a script::Engine with the prelude loaded and the sandbox applied, but no scripts,
+    /// so things such as native functions can be set up before they are loaded with `run_init`.
+    ///
+    /// `print` is replaced to log what it is given at the info level with the target `lua`, and
+    /// `buildengine.log` logs at other levels, so what scripts print goes wherever the log does.
+    ///
+    /// Under `SandboxLevel::Trusted` and `SandboxLevel::Untrusted` the prelude_buildengine table
+    /// is protected, so scripts can't replace or remove it. Under `SandboxLevel::Full` they can,
+    /// after which executing events fails with `ExecEventError::PreludeMissing`.
+    ///
+    /// # Errors
+    /// * `InitError::PreludeError` if the prelude errored.
+    /// * `InitError::SandboxError` if applying the sandbox errored.
+    /// * `InitError::PreludeMissing` if the prelude didn't make the prelude_buildengine table.
+    pub fn new_empty(sandbox: SandboxLevel) -> Result<Self, InitError> {
         let mut lua = Lua::new();
         lua.openlibs();
-        lua.execute::<()>(PRELUDE).expect("error in prelude module of engine");
-        let mut main = "".to_owned();
+        try!(lua.execute::<()>(PRELUDE).map_err(InitError::PreludeError));
+        let sandboxed = match sandbox {
+            SandboxLevel::Full => Ok(()),
+            SandboxLevel::Trusted => lua.execute::<()>(TRUSTED_SANDBOX),
+            SandboxLevel::Untrusted => lua.execute::<()>(UNTRUSTED_SANDBOX),
+        };
+        try!(sandboxed.map_err(InitError::SandboxError));
+        let started = Rc::new(Cell::new(Instant::now()));
+        let watchdog_started = started.clone();
+        let timers = Rc::new(RefCell::new(Timers::new()));
+        let tick = Rc::new(Cell::new(0));
+        let delta = Rc::new(Cell::new(Duration::from_secs(0)));
+        let storage = Rc::new(RefCell::new(HashMap::new()));
+        let seed = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() ^ since.subsec_nanos() as u64,
+            Err(_) => 0,
+        };
+        debug!("Seeding the random number generator of the scripts with {}.", seed);
+        let rng = Rc::new(RefCell::new(Rng::new(seed)));
+        let info = Rc::new(RefCell::new(EngineInfo::default()));
+        let network: Rc<RefCell<Option<Box<ScriptNetwork>>>> = Rc::new(RefCell::new(None));
+        let players: Rc<RefCell<Option<Rc<RefCell<PlayerRegistry>>>>> = Rc::new(RefCell::new(None));
+        let module_request = Rc::new(RefCell::new(None));
+        let watchdog_request = Rc::new(RefCell::new(None));
+        {
+            let mut prelude_table: LuaTable<_> = match lua.get("prelude_buildengine") {
+                Some(prelude_table) => prelude_table,
+                None => return Err(InitError::PreludeMissing),
+            };
+            prelude_table.set("elapsed_millis",
+                              function0(move || {
+                                  let elapsed = watchdog_started.get().elapsed();
+                                  elapsed.as_secs() as f64 * 1000.0 +
+                                  elapsed.subsec_nanos() as f64 / 1e6
+                              }));
+            let add_timers = timers.clone();
+            prelude_table.set("add_timer",
+                              function2(move |seconds: f64, repeat: bool| {
+                                  let delay = duration_from_secs(seconds);
+                                  add_timers.borrow_mut().add(delay, repeat) as f64
+                              }));
+            let remove_timers = timers.clone();
+            prelude_table.set("remove_timer",
+                              function1(move |id: f64| {
+                                  remove_timers.borrow_mut().cancel(id as u64)
+                              }));
+            let repeats_timers = timers.clone();
+            prelude_table.set("timer_repeats",
+                              function1(move |id: f64| {
+                                  match repeats_timers.borrow().repeats(id as u64) {
+                                      Some(repeats) => AnyLuaValue::LuaBoolean(repeats),
+                                      None => AnyLuaValue::LuaNil,
+                                  }
+                              }));
+            let now_timers = timers.clone();
+            prelude_table.set("time_now",
+                              function0(move || duration_secs(now_timers.borrow().now())));
+            let tick_count = tick.clone();
+            prelude_table.set("time_tick", function0(move || tick_count.get() as f64));
+            let tick_delta = delta.clone();
+            prelude_table.set("time_delta", function0(move || duration_secs(tick_delta.get())));
+            let set_storage = storage.clone();
+            prelude_table.set("storage_set",
+                              function2(move |key: String, value: AnyLuaValue| {
+                                  match LuaValueRepr::from(value) {
+                                      LuaValueRepr::Nil => set_storage.borrow_mut().remove(&key),
+                                      value => set_storage.borrow_mut().insert(key, value),
+                                  };
+                              }));
+            let get_storage = storage.clone();
+            prelude_table.set("storage_get",
+                              function1(move |key: String| {
+                                  // Read with load, as hlua can't push nested tables.
+                                  let mut code = "return ".to_owned();
+                                  let value = get_storage.borrow().get(&key).cloned();
+                                  match value.map(AnyLuaValue::try_from) {
+                                      Some(Ok(value)) => write_lua_literal(&value, &mut code),
+                                      _ => code.push_str("nil"),
+                                  }
+                                  code
+                              }));
+            for &(name, level) in &[("log_debug", LogLevel::Debug),
+                                    ("log_info", LogLevel::Info),
+                                    ("log_warn", LogLevel::Warn),
+                                    ("log_error", LogLevel::Error)] {
+                prelude_table.set(name,
+                                  function2(move |target: String, message: String| {
+                                      log!(target: &target, level, "{}", message);
+                                  }));
+            }
+            let seed_rng = rng.clone();
+            prelude_table.set("random_seed",
+                              function1(move |seed: f64| {
+                                  seed_rng.borrow_mut().seed(seed as i64 as u64)
+                              }));
+            let int_rng = rng.clone();
+            prelude_table.set("random_int",
+                              function2(move |min: f64, max: f64| -> Result<f64, String> {
+                                  // Rng::int panics on an empty range, which can't unwind
+                                  // through lua, so it is raised as a lua error instead.
+                                  if !min.is_finite() || !max.is_finite() {
+                                      return Err(format!("the range {} to {} isn't finite",
+                                                         min,
+                                                         max));
+                                  }
+                                  if min > max {
+                                      return Err(format!("the range {} to {} is empty", min, max));
+                                  }
+                                  Ok(int_rng.borrow_mut().int(min as i64, max as i64) as f64)
+                              }));
+            let float_rng = rng.clone();
+            prelude_table.set("random_float",
+                              function0(move || float_rng.borrow_mut().float()));
+            let created = Instant::now();
+            prelude_table.set("clock",
+                              function0(move || duration_secs(created.elapsed())));
+            let fields_info = info.clone();
+            prelude_table.set("engine_info",
+                              function0(move || {
+                                  // Read with load, like storage_get.
+                                  let mut code = "return ".to_owned();
+                                  write_lua_literal(&fields_info.borrow().to_lua(), &mut code);
+                                  code
+                              }));
+            let features_info = info.clone();
+            prelude_table.set("has_feature",
+                              function1(move |feature: String| {
+                                  features_info.borrow().features.contains(&feature)
+                              }));
+            let send_network = network.clone();
+            prelude_table.set("net_send",
+                              function2(move |to: f64, event: AnyLuaValue| {
+                                  // Given {name, args} by buildengine.net.send, but a script
+                                  // can call it with anything.
+                                  let fields = match try_any_lua_to_vec(event) {
+                                      Ok(fields) => fields,
+                                      Err(err) => {
+                                          return net_result(Err(format!("bad argument event \
+                                                                         to net.send: {}",
+                                                                        err)))
+                                      }
+                                  };
+                                  let mut fields = fields.into_iter();
+                                  let name = match fields.next() {
+                                      Some(AnyLuaValue::LuaString(name)) => name,
+                                      _ => String::new(),
+                                  };
+                                  let args = fields.next().unwrap_or(AnyLuaValue::LuaNil);
+                                  net_result(with_network(&send_network, |network| {
+                                      let args = try!(net_event_args("net.send", args));
+                                      try!(network.send(to as u64, name, args));
+                                      Ok(AnyLuaValue::LuaBoolean(true))
+                                  }))
+                              }));
+            let broadcast_network = network.clone();
+            prelude_table.set("net_broadcast",
+                              function2(move |name: String, args: AnyLuaValue| {
+                                  net_result(with_network(&broadcast_network, |network| {
+                                      let args = try!(net_event_args("net.broadcast", args));
+                                      let sent = network.broadcast(name, args);
+                                      Ok(AnyLuaValue::LuaNumber(sent as f64))
+                                  }))
+                              }));
+            let kick_network = network.clone();
+            prelude_table.set("net_kick",
+                              function2(move |to: f64, reason: String| {
+                                  net_result(with_network(&kick_network, |network| {
+                                      try!(network.kick(to as u64, &reason));
+                                      Ok(AnyLuaValue::LuaBoolean(true))
+                                  }))
+                              }));
+            let count_network = network.clone();
+            prelude_table.set("net_peer_count",
+                              function0(move || {
+                                  net_result(with_network(&count_network, |network| {
+                                      Ok(AnyLuaValue::LuaNumber(network.peer_count() as f64))
+                                  }))
+                              }));
+            let addr_network = network.clone();
+            prelude_table.set("net_peer_addr",
+                              function1(move |to: f64| {
+                                  net_result(with_network(&addr_network, |network| {
+                                      Ok(match network.peer_addr(to as u64) {
+                                          Some(addr) => AnyLuaValue::LuaString(addr),
+                                          None => AnyLuaValue::LuaNil,
+                                      })
+                                  }))
+                              }));
+            let stats_network = network.clone();
+            prelude_table.set("net_stats",
+                              function1(move |to: f64| {
+                                  net_result(with_network(&stats_network, |network| {
+                                      Ok(match network.stats(to as u64) {
+                                          Some(stats) => stats.to_lua(),
+                                          None => AnyLuaValue::LuaNil,
+                                      })
+                                  }))
+                              }));
+            let list_players = players.clone();
+            prelude_table.set("players_list",
+                              function0(move || {
+                                  let mut list = Vec::new();
+                                  if let Some(ref registry) = *list_players.borrow() {
+                                      for (index, player) in registry.borrow()
+                                                                     .list()
+                                                                     .into_iter()
+                                                                     .enumerate() {
+                                          list.push((AnyLuaValue::LuaNumber(index as f64 + 1.0),
+                                                     player_to_lua(player)));
+                                      }
+                                  }
+                                  return_code(&AnyLuaValue::LuaArray(list))
+                              }));
+            let get_players = players.clone();
+            prelude_table.set("players_get",
+                              function1(move |key: AnyLuaValue| {
+                                  let mut found = AnyLuaValue::LuaNil;
+                                  if let Some(ref registry) = *get_players.borrow() {
+                                      let registry = registry.borrow();
+                                      let player = match key {
+                                          AnyLuaValue::LuaNumber(id) => {
+                                              registry.get(PlayerId(id as u64))
+                                          }
+                                          AnyLuaValue::LuaString(ref name) => {
+                                              registry.by_name(name)
+                                          }
+                                          _ => None,
+                                      };
+                                      if let Some(player) = player {
+                                          found = player_to_lua(player);
+                                      }
+                                  }
+                                  return_code(&found)
+                              }));
+            let count_players = players.clone();
+            prelude_table.set("players_count",
+                              function0(move || {
+                                  match *count_players.borrow() {
+                                      Some(ref registry) => registry.borrow().len() as f64,
+                                      None => 0.0,
+                                  }
+                              }));
+            prelude_table.set("log_slow_handler",
+                              function2(|handler: String, seconds: f64| {
+                                  warn!("The {} took {:.3} seconds.", handler, seconds);
+                              }));
+            let take_request = module_request.clone();
+            prelude_table.set("take_module_request",
+                              function0(move || {
+                                  let mut code = "return ".to_owned();
+                                  match take_request.borrow_mut().take() {
+                                      Some(ModuleRequest::Load(name, source)) => {
+                                          code.push_str("\"load\", ");
+                                          write_lua_string(&name, &mut code);
+                                          code.push_str(", ");
+                                          write_lua_string(&source, &mut code);
+                                      }
+                                      Some(ModuleRequest::Unload(name)) => {
+                                          code.push_str("\"unload\", ");
+                                          write_lua_string(&name, &mut code);
+                                      }
+                                      None => code.push_str("nil"),
+                                  }
+                                  code
+                              }));
+            let take_request = watchdog_request.clone();
+            prelude_table.set("take_watchdog_request",
+                              function0(move || {
+                                  match take_request.borrow_mut().take() {
+                                      Some(WatchdogRequest::Start(instructions, millis, bytes)) => {
+                                          format!("return \"start\", {}, {}, {}",
+                                                  lua_limit(instructions),
+                                                  lua_limit(millis),
+                                                  lua_limit(bytes))
+                                      }
+                                      Some(WatchdogRequest::Stop) => "return \"stop\"".to_owned(),
+                                      None => "return nil".to_owned(),
+                                  }
+                              }));
+        }
+        // Before any script has run, so the clock can't have been replaced, and no script can have
+        // kept the functions requests are taken from, or the functions taking tables before they
+        // were guarded.
+        try!(lua.execute::<()>("prelude_buildengine.set_clock(prelude_buildengine.elapsed_millis)
+                                prelude_buildengine.set_module_requests(
+                                    prelude_buildengine.take_module_request)
+                                prelude_buildengine.set_watchdog_requests(
+                                    prelude_buildengine.take_watchdog_request)
+                                prelude_buildengine.guard_natives({\"storage_set\",
+                                                                   \"net_send\",
+                                                                   \"net_broadcast\",
+                                                                   \"players_get\"})")
+                .map_err(InitError::PreludeError));
+        let mut engine = Engine {
+            interpreter: lua,
+            started: started,
+            timers: timers,
+            tick: tick,
+            delta: delta,
+            storage: storage,
+            rng: rng,
+            info: info,
+            recent_errors: VecDeque::new(),
+            reporting_error: false,
+            init_policy: InitPolicy::default(),
+            network: network,
+            players: players,
+            commands: HashMap::new(),
+            commands_trusted: false,
+            execution_limit: ExecutionLimit::default(),
+            memory_limit: None,
+            module_request: module_request,
+            watchdog_request: watchdog_request,
+        };
+        engine.register_builtin("help",
+                                "Lists every command, or describes the one named.",
+                                Builtin::Help);
+        engine.register_builtin("lua",
+                                "Runs the rest of the line as lua code, printing what it returns. \
+                                 Only when commands are trusted.",
+                                Builtin::Lua);
+        engine.register_builtin("scripts", "Lists the loaded modules.", Builtin::Scripts);
+        Ok(engine)
+    }
+
+    /// Sets how long the scripts may run for each event, and for the init script.
+    ///
+    /// Scripts running for longer are aborted with `ExecEventError::Timeout`, or a lua error for
+    /// the init script.
+    pub fn set_execution_limit(&mut self, limit: ExecutionLimit) {
+        self.execution_limit = limit;
+    }
+
+    /// The execution limit set with `set_execution_limit`, which is `ExecutionLimit::default()`
+    /// by default.
+    pub fn execution_limit(&self) -> ExecutionLimit {
+        self.execution_limit
+    }
+
+    /// Sets what `run_init` and `run_init_ordered` do when the init script is missing, which is
+    /// `InitPolicy::RequireInit` by default.
+    pub fn set_init_policy(&mut self, policy: InitPolicy) {
+        self.init_policy = policy;
+    }
+
+    /// Sets the most memory the interpreter may use while running the scripts for an event, or for
+    /// the init script, in bytes, or None for no limit, which is the default.
+    ///
+    /// Memory is checked along with the execution limit, every thousand lua instructions. Once
+    /// over the limit garbage is collected, and if it is still over the scripts are aborted with
+    /// `ExecEventError::MemoryLimitExceeded`, or a lua error for the init script. A single call
+    /// allocating a lot, such as a long `string.rep`, can't be stopped before it returns.
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) {
+        self.memory_limit = bytes;
+    }
+
+    /// The memory limit set with `set_memory_limit`, in bytes.
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// The memory used by the interpreter, in bytes.
+    ///
+    /// # Errors
+    /// * `ExecEventError::PreludeMissing` if the prelude_buildengine table is gone, or a script
+    ///   replaced it's memory_usage.
+    pub fn lua_memory_usage(&mut self) -> Result<usize, ExecEventError> {
+        let usage: Result<f64, LuaError> =
+            self.interpreter.execute("return prelude_buildengine.memory_usage()");
+        usage.map(|usage| usage as usize).map_err(|_| ExecEventError::PreludeMissing)
+    }
+
+    /// Runs the lua code with the watchdog enforcing the execution limit.
+    fn execute_watched(&mut self, code: &str) -> Result<(), LuaError> {
+        self.start_watchdog();
+        let result = self.interpreter.execute::<()>(code);
+        self.stop_watchdog();
+        result
+    }
+
+    /// Makes the watchdog abort lua code once it exceeds the execution limit, counting from now.
+    ///
+    /// The limits are passed to the watchdog each time, with
+    /// `prelude_buildengine.run_watchdog_request`, so scripts can't change them, or stop the
+    /// watchdog. Only logged if it fails, such as if the prelude_buildengine table is gone.
+    fn start_watchdog(&mut self) {
+        self.started.set(Instant::now());
+        let memory_limit = self.memory_limit.map(|bytes| bytes as u64);
+        let request = WatchdogRequest::Start(self.execution_limit.instructions,
+                                             self.execution_limit.millis,
+                                             memory_limit);
+        if let Err(err) = self.run_watchdog_request(request) {
+            warn!("Failed to start the watchdog: {}", lua_error_message(&err));
+        }
+    }
+
+    /// Stops the watchdog, so code is never aborted. Only logged if it fails.
+    fn stop_watchdog(&mut self) {
+        if let Err(err) = self.run_watchdog_request(WatchdogRequest::Stop) {
+            warn!("Failed to stop the watchdog: {}", lua_error_message(&err));
+        }
+    }
+
+    /// Asks the prelude to start or stop the watchdog, clearing the request if the prelude didn't
+    /// take it.
+    fn run_watchdog_request(&mut self, request: WatchdogRequest) -> Result<(), LuaError> {
+        *self.watchdog_request.borrow_mut() = Some(request);
+        let result = self.interpreter.execute::<()>("prelude_buildengine.run_watchdog_request()");
+        *self.watchdog_request.borrow_mut() = None;
+        result
+    }
+
+    /// If the prelude_buildengine table is still there, which a script under
+    /// `SandboxLevel::Full` can remove.
+    fn has_prelude(&mut self) -> bool {
+        let prelude_table: Option<LuaTable<_>> = self.interpreter.get("prelude_buildengine");
+        prelude_table.is_some()
+    }
+
+    /// Loads the given scripts into an engine made with `new_empty`, executing the init entry, as
+    /// done by `new`.
+    pub fn run_init(&mut self, scripts: HashMap<String, String>) -> Result<(), LuaError> {
+        match self.run_init_ordered(scripts_by_name(scripts)) {
+            Ok(()) => Ok(()),
+            Err(::InitError::ScriptError(err)) => Err(err),
+            Err(err) => Err(LuaError::ExecutionError(err.to_string())),
+        }
+    }
+
+    /// Loads the given scripts into an engine made with `new_empty`, as done by `new_ordered`.
+    ///
+    /// Under `InitPolicy::RequireInit` the scripts must include an init script with more than
+    /// whitespace in it, or `::InitError::ScriptEngineError` is returned with
+    /// `InitError::MissingInitScript` or `InitError::EmptyInitScript`. Under
+    /// `InitPolicy::AllowMissing` a missing init script is only logged.
+    ///
+    /// A script named "buildengine", or starting with RESERVED_MODULE_PREFIX, is refused with
+    /// `InitError::ReservedModuleName` before any script runs, so it can't stand in for the engine
+    /// std. If the scripts changed the source of the engine std while loading,
+    /// `InitError::EngineStdReplaced` is returned.
+    pub fn run_init_ordered(&mut self,
+                            scripts: Vec<(String, String)>)
+                            -> Result<(), ::InitError> {
+        if let Some(&(ref name, _)) = scripts.iter()
+                                             .find(|&&(ref name, _)| {
+                                                 name != "init" && is_reserved_module(name)
+                                             }) {
+            return Err(::InitError::ScriptEngineError(InitError::ReservedModuleName(name.clone())));
+        }
+        let scripts = try!(order_scripts(scripts));
+        let init_empty = scripts.iter()
+                                .find(|&&(ref name, _)| name == "init")
+                                .map(|&(_, ref body)| body.trim().is_empty());
+        match (init_empty, self.init_policy) {
+            (None, InitPolicy::RequireInit) => {
+                let mut names: Vec<String> = scripts.iter()
+                                                    .map(|&(ref name, _)| name.clone())
+                                                    .collect();
+                names.sort();
+                return Err(::InitError::ScriptEngineError(InitError::MissingInitScript(names)));
+            }
+            (Some(true), InitPolicy::RequireInit) => {
+                return Err(::InitError::ScriptEngineError(InitError::EmptyInitScript));
+            }
+            (None, InitPolicy::AllowMissing) => {
+                warn!("Loading {} scripts without an init script, so only the modules will run.",
+                      scripts.len());
+            }
+            (Some(_), _) => {}
+        }
+        let mut main = String::new();
+        let mut order = vec!["buildengine".to_owned()];
         {
             // Set up module table.
-            let mut prelude_table: LuaTable<_> = lua.get("prelude_buildengine")
-                                                    .expect("loaded prelude but \
-                                                             prelude_buildengine table was not \
-                                                             found");
-            let mut modules = prelude_table.empty_array("modules");
-            for script in scripts {
-                let (name, body) = script;
-                if name == "init".to_owned() {
-                    main = body;
-                    continue;
+            let mut prelude_table: LuaTable<_> = match self.interpreter
+                                                           .get("prelude_buildengine") {
+                Some(prelude_table) => prelude_table,
+                None => return Err(::InitError::ScriptEngineError(InitError::PreludeMissing)),
+            };
+            {
+                let mut modules = prelude_table.empty_array("modules");
+                for (name, body) in scripts {
+                    if name == "init" {
+                        main = body;
+                    } else {
+                        order.push(name.clone());
+                        modules.set(name, body);
+                    }
+                }
+                modules.set("buildengine", ENGINE_STD);
+            }
+            {
+                let mut module_order = prelude_table.empty_array("module_order");
+                for (i, name) in order.into_iter().enumerate() {
+                    module_order.set((i + 1) as f64, name);
                 }
-                modules.set(name, body);
             }
+            prelude_table.set("init_src", main);
         }
-        try!(lua.execute::<()>(&main));
-        Ok(Engine { interpreter: lua })
+        let syntax_error: AnyLuaValue =
+            try!(self.interpreter.execute("return prelude_buildengine.compile_init()"));
+        if let AnyLuaValue::LuaString(err) = syntax_error {
+            return Err(::InitError::ScriptError(LuaError::SyntaxError(err)));
+        }
+        try!(self.execute_watched("prelude_buildengine.load_modules() prelude_buildengine.init()"));
+        // The scripts could have swapped the engine std for their own, which would be taken as the
+        // engine's by every module requiring it from then on.
+        if self.has_prelude() {
+            match self.get_path("prelude_buildengine.modules.buildengine") {
+                Some(AnyLuaValue::LuaString(ref source)) if source == ENGINE_STD => {}
+                _ => return Err(::InitError::ScriptEngineError(InitError::EngineStdReplaced)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the source of a module and loads it again, then executes the event
+    /// "on_module_reloaded" with the name of the module.
+    ///
+    /// Scripts requiring the module from then on get the new version. The handlers the old
+    /// version added with `buildengine.on` are removed once the new version has loaded.
+    ///
+    /// # Errors
+    /// * `ReloadError::ReservedName` for "init" or "buildengine".
+    /// * `ReloadError::UnknownModule` if no module has the name.
+    /// * `ReloadError::SyntaxError` if the new source doesn't compile, or
+    ///   `ReloadError::RuntimeError` if it errors while loading. The old version is kept as it
+    ///   was, handlers and all.
+    /// * `ReloadError::Event` if executing "on_module_reloaded" failed. The new version is still
+    ///   loaded.
+    pub fn reload_module(&mut self, name: &str, new_source: String) -> Result<(), ReloadError> {
+        try!(check_module_name(name));
+        if !self.has_module(name) {
+            return Err(ReloadError::UnknownModule(name.to_owned()));
+        }
+        try!(self.replace_module(name, new_source));
+        match self.exec_event("on_module_reloaded",
+                              vec![AnyLuaValue::LuaString(name.to_owned())]) {
+            Ok(_) | Err(ExecEventError::EngineStdNotImported) => Ok(()),
+            Err(err) => Err(ReloadError::Event(err)),
+        }
+    }
+
+    /// Adds a module and loads it, so the handlers it adds are live without it being required by
+    /// another script, and scripts can require it.
+    ///
+    /// Scripts can do the same with `buildengine.load_module(name, source)`, unless they are
+    /// under `SandboxLevel::Untrusted`.
+    ///
+    /// # Errors
+    /// * `ReloadError::ReservedName` for "init" or "buildengine", so the engine std can't be
+    ///   shadowed.
+    /// * `ReloadError::DuplicateModule` if a module already has the name. Use `reload_module` to
+    ///   replace it.
+    /// * `ReloadError::SyntaxError` if the source doesn't compile, or `ReloadError::RuntimeError`
+    ///   if it errors while loading, in which case the module is not added.
+    pub fn load_module(&mut self, name: &str, source: String) -> Result<(), ReloadError> {
+        try!(check_module_name(name));
+        if self.has_module(name) {
+            return Err(ReloadError::DuplicateModule(name.to_owned()));
+        }
+        self.replace_module(name, source)
+    }
+
+    /// Removes a module, along with every handler it added. Scripts that already required it
+    /// keep what they got, but it can't be required again.
+    ///
+    /// Scripts can do the same with `buildengine.unload_module(name)`, unless they are under
+    /// `SandboxLevel::Untrusted`.
+    ///
+    /// # Errors
+    /// * `ReloadError::ReservedName` for "init" or "buildengine".
+    /// * `ReloadError::UnknownModule` if no module has the name.
+    pub fn unload_module(&mut self, name: &str) -> Result<(), ReloadError> {
+        try!(check_module_name(name));
+        if !self.has_module(name) {
+            return Err(ReloadError::UnknownModule(name.to_owned()));
+        }
+        *self.module_request.borrow_mut() = Some(ModuleRequest::Unload(name.to_owned()));
+        let result = self.call_prelude_fn("run_module_request", Vec::new());
+        *self.module_request.borrow_mut() = None;
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(ReloadError::RuntimeError(lua_error_message(&err))),
+        }
+    }
+
+    /// If a module has the name.
+    pub fn has_module(&mut self, name: &str) -> bool {
+        let mut prelude_table: LuaTable<_> = match self.interpreter.get("prelude_buildengine") {
+            Some(prelude_table) => prelude_table,
+            // The modules went with the prelude_buildengine table.
+            None => return false,
+        };
+        let mut modules: LuaTable<_> = match prelude_table.get("modules") {
+            Some(modules) => modules,
+            // No scripts have been loaded yet.
+            None => return false,
+        };
+        let source: Option<String> = modules.get(name);
+        source.is_some()
+    }
+
+    /// Sets the source of the module and loads it, keeping the old version if it fails to.
+    ///
+    /// The prelude is asked to load it with `prelude_buildengine.run_module_request`, which loads
+    /// only what was asked here, so scripts that can call it can't load modules of their own.
+    fn replace_module(&mut self, name: &str, source: String) -> Result<(), ReloadError> {
+        if !self.has_prelude() {
+            return Err(ReloadError::Event(ExecEventError::PreludeMissing));
+        }
+        *self.module_request.borrow_mut() = Some(ModuleRequest::Load(name.to_owned(), source));
+        self.start_watchdog();
+        let result = self.call_prelude_fn("run_module_request", Vec::new());
+        self.stop_watchdog();
+        // Cleared even if the call failed before taking it, so it can't be run later.
+        *self.module_request.borrow_mut() = None;
+        if let Err(err) = result {
+            let syntax_error: Option<bool> = {
+                let mut prelude_table: LuaTable<_> = match self.interpreter
+                                                               .get("prelude_buildengine") {
+                    Some(prelude_table) => prelude_table,
+                    None => return Err(ReloadError::Event(ExecEventError::PreludeMissing)),
+                };
+                prelude_table.get("reload_syntax_error")
+            };
+            let message = match err {
+                LuaError::ExecutionError(message) => message,
+                other => lua_error_message(&other),
+            };
+            return Err(if syntax_error == Some(true) {
+                ReloadError::SyntaxError(message)
+            } else {
+                ReloadError::RuntimeError(message)
+            });
+        }
+        Ok(())
+    }
+
+    /// Makes the rust function callable by scripts as `buildengine.native.<name>`, replacing any
+    /// function registered with the name before.
+    ///
+    /// The function is given every argument it is called with, with nil for any left out between
+    /// others, and what it returns is returned to the script, or nothing if it returns None. Tables
+    /// it returns can't have tables in them. It is given at most `MAX_NATIVE_NILS` nils more than
+    /// the arguments that aren't nil, so a script can't make it allocate without bound. Calls with
+    /// a table containing itself, or tables nested too deep, error before it is called.
+    ///
+    /// # Errors
+    /// * `ExecEventError::PreludeMissing` if the prelude_buildengine table, it's table of natives,
+    ///   or the function guarding them, is gone.
+    pub fn register_fn<F>(&mut self, name: &str, mut f: F) -> Result<(), ExecEventError>
+        where F: FnMut(Vec<AnyLuaValue>) -> Option<AnyLuaValue> + 'lua
+    {
+        {
+            let mut prelude_table: LuaTable<_> = match self.interpreter.get("prelude_buildengine") {
+                Some(prelude_table) => prelude_table,
+                None => return Err(ExecEventError::PreludeMissing),
+            };
+            let mut native_raw: LuaTable<_> = match prelude_table.get("native_raw") {
+                Some(native_raw) => native_raw,
+                None => return Err(ExecEventError::PreludeMissing),
+            };
+            native_raw.set(name,
+                           function2(move |count: f64, args: AnyLuaValue| {
+                               f(native_args(count, args)).unwrap_or(AnyLuaValue::LuaNil)
+                           }));
+        }
+        // Guarded at once, so scripts can never call it with arguments rust can't read.
+        let mut code = "prelude_buildengine.guard_native(".to_owned();
+        write_lua_string(name, &mut code);
+        code.push(')');
+        self.interpreter.execute::<()>(&code).map_err(|_| ExecEventError::PreludeMissing)
+    }
+
+    /// Runs the lua code, such as from a server console, returning what it evaluates to.
+    ///
+    /// The code may be an expression, such as `1 + 1`, or statements returning a value, such as
+    /// `return x, y`. More than one value is returned as an array of them, and none as nil. It
+    /// runs in the same interpreter as the scripts, with the same sandbox and execution limit, and
+    /// is named `eval` in errors and tracebacks.
+    ///
+    /// # Errors
+    /// * `LuaError::SyntaxError` if the code doesn't compile.
+    /// * `LuaError::ExecutionError` if it errors while running, such as by exceeding the
+    ///   execution limit. The traceback can be had with `last_traceback`.
+    pub fn eval(&mut self, code: &str) -> Result<AnyLuaValue, LuaError> {
+        self.eval_named("eval", code, false)
+    }
+
+    /// Runs the lua code like `eval`, but as if it were part of the module, so it is named after
+    /// the module in errors and tracebacks, and handlers it adds with `buildengine.on` are removed
+    /// along with the module's when it is reloaded or unloaded.
+    ///
+    /// The code runs in the module's environment, so the globals it sets are the module's own, as
+    /// read by `get_module_global`.
+    ///
+    /// # Errors
+    /// The same as `eval`, or `LuaError::ExecutionError` if there is no module with the name.
+    pub fn eval_in_module(&mut self, name: &str, code: &str) -> Result<AnyLuaValue, LuaError> {
+        if !self.has_module(name) {
+            return Err(LuaError::ExecutionError(format!("there is no module {}", name)));
+        }
+        self.eval_named(name, code, true)
+    }
+
+    /// Runs the lua code for `eval`, named name, in the environment of the module with the name if
+    /// in_module is true.
+    fn eval_named(&mut self,
+                  name: &str,
+                  code: &str,
+                  in_module: bool)
+                  -> Result<AnyLuaValue, LuaError> {
+        {
+            let mut prelude_table: LuaTable<_> = match self.interpreter
+                                                           .get("prelude_buildengine") {
+                Some(prelude_table) => prelude_table,
+                None => return Err(prelude_missing_error()),
+            };
+            prelude_table.set("eval_name", name);
+            prelude_table.set("eval_src", code);
+            prelude_table.set("eval_in_module", in_module);
+        }
+        let syntax_error: AnyLuaValue =
+            try!(self.interpreter.execute("return prelude_buildengine.compile_eval()"));
+        if let AnyLuaValue::LuaString(err) = syntax_error {
+            return Err(LuaError::SyntaxError(err));
+        }
+        try!(self.execute_watched("prelude_buildengine.run_eval()"));
+        // The code may have removed the table itself.
+        let mut prelude_table: LuaTable<_> = match self.interpreter.get("prelude_buildengine") {
+            Some(prelude_table) => prelude_table,
+            None => return Ok(AnyLuaValue::LuaNil),
+        };
+        let ret: Option<AnyLuaValue> = prelude_table.get("eval_ret");
+        prelude_table.set("eval_ret", AnyLuaValue::LuaNil);
+        Ok(ret.unwrap_or(AnyLuaValue::LuaNil))
     }
 
     /// Call a given lua event with the given arguments.
     ///
     /// This calls every event with the name, with first the arguments vector passed, then the return of the last event,
     /// then the return of that event, and so on, untill all events of the name have been called.
-    /// The returns of that event is then returned.
+    /// The returns of that event is then returned, as `EventOutcome::Completed`. Every value a
+    /// handler returns is passed on, nils included.
+    ///
+    /// A handler returning `buildengine.CANCEL` stops the rest from being called, giving
+    /// `EventOutcome::Cancelled`. A handler that errors stops the rest from being called as well.
+    ///
+    /// The handlers added to wildcards matching the event, such as `player.*` for `player.move`,
+    /// are called after it's own, see `buildengine.on`. The name can't be empty or a wildcard
+    /// itself, giving `ExecEventError::InvalidEventName`.
+    ///
+    /// The handlers of the event are given exactly the arguments, without the event name, which
+    /// only the handlers of wildcards get, before the arguments.
+    ///
+    /// If a script removed the prelude_buildengine table, which only scripts under
+    /// `SandboxLevel::Full` can, `ExecEventError::PreludeMissing` is returned instead.
+    ///
+    /// If the scripts error, the event "on_error" is executed with a table of the `event`, the
+    /// `module` of the handler if it is known, the `message` and the `traceback`, before the error
+    /// is returned. The error is kept for `recent_errors` as well.
     pub fn exec_event(&mut self,
-                      event_name: String,
-                      mut args: Vec<AnyLuaValue>)
-                      -> Result<Vec<AnyLuaValue>, ExecEventError> {
-        args.insert(0, AnyLuaValue::LuaString(event_name));
+                      event_name: &str,
+                      args: Vec<AnyLuaValue>)
+                      -> Result<EventOutcome, ExecEventError> {
+        if !is_valid_event_name(event_name) {
+            return Err(ExecEventError::InvalidEventName(event_name.to_owned()));
+        }
         {
-            let mut prelude_table: LuaTable<_> = self.interpreter
-                                                     .get("prelude_buildengine")
-                                                     .expect("the prelude_table wasn't found. \
-                                                              was the prelude properly loaded?");
+            let mut prelude_table: LuaTable<_> = match self.interpreter
+                                                           .get("prelude_buildengine") {
+                Some(prelude_table) => prelude_table,
+                None => return Err(ExecEventError::PreludeMissing),
+            };
             let a_event: Option<_> = prelude_table.get::<LuaFunction<_>, _>("activate_event");
             if a_event.is_none() {
                 return Err(ExecEventError::EngineStdNotImported);
             }
+            prelude_table.set("handler_error", AnyLuaValue::LuaNil);
+            prelude_table.set("cancelled_by", AnyLuaValue::LuaNil);
         }
-        match self.call_prelude_fn("activate_event", args) {
-            Ok(Some(ret)) => Ok(any_lua_to_vec(ret)),
-            Ok(None) => Ok(Vec::new()),
-            Err(err) => Err(err.into()),
+        self.start_watchdog();
+        let result = self.call_prelude_fn_event("activate_event", Some(event_name), &args);
+        self.stop_watchdog();
+        let returns = match result {
+            Ok(returns) => returns,
+            Err(err) => {
+                let err = self.event_error(event_name.to_owned(), err);
+                self.report_error(&err);
+                return Err(err);
+            }
+        };
+        let cancelled_by: Option<f64> = {
+            // A handler may have removed it.
+            let mut prelude_table: LuaTable<_> = match self.interpreter
+                                                           .get("prelude_buildengine") {
+                Some(prelude_table) => prelude_table,
+                None => return Err(ExecEventError::PreludeMissing),
+            };
+            prelude_table.get("cancelled_by")
+        };
+        match cancelled_by {
+            Some(handler) => Ok(EventOutcome::Cancelled { by_handler: handler as usize }),
+            None => Ok(EventOutcome::Completed(returns)),
         }
     }
 
-    /// Call the given lua function in the prelude table with the given arguments.
-    pub fn call_prelude_fn(&mut self,
-                           fn_to_call: &str,
-                           args: Vec<AnyLuaValue>)
-                           -> Result<Option<AnyLuaValue>, LuaError> {
-        let mut prelude_table: LuaTable<_> = self.interpreter
-                                                 .get("prelude_buildengine")
-                                                 .expect("the prelude_table wasn't found. was \
-                                                          the prelude properly loaded?");
-        prelude_table.set("fn_to_call", fn_to_call);
-        prelude_table.set("args", args);
-        {
-            let mut call_fn_lua: LuaFunction<_> = prelude_table.get("call_prelude_fn")
-                                                               .expect("prelude_buildengine.\
-                                                                        call_prelude_fn not \
-                                                                        found. was the prelude \
-                                                                        properly loaded?");
-            try!(call_fn_lua.call::<()>());
-        }
-        let ret: Option<AnyLuaValue> = prelude_table.get("ret");
-        Ok(ret)
+    /// Executes the event like `exec_event`, converting the values it returns to a rust type, such
+    /// as `let allowed: bool = try!(engine.exec_event_as("can_place_block", args));`.
+    ///
+    /// A cancelled event returns no values, so it converts as if it returned nil. See `FromLua` for
+    /// the types that can be converted to.
+    ///
+    /// # Errors
+    /// The same as `exec_event`, or `ExecEventError::ReturnTypeMismatch` if the values couldn't
+    /// be converted.
+    pub fn exec_event_as<T: FromLua>(&mut self,
+                                     event_name: &str,
+                                     args: Vec<AnyLuaValue>)
+                                     -> Result<T, ExecEventError> {
+        let returns = try!(self.exec_event(event_name, args)).returns();
+        T::from_lua_returns(returns).map_err(|mismatch| {
+            ExecEventError::ReturnTypeMismatch {
+                event: event_name.to_owned(),
+                mismatch: mismatch,
+            }
+        })
     }
-}
 
-impl<'lua> Debug for Engine<'lua> {
-    fn fmt(&self, _fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        Ok(())
+    /// Queues an event to be executed by `process_queued_events` once the events queued before it
+    /// have run, as scripts do with `buildengine.queue_event(name, ...)`.
+    ///
+    /// # Errors
+    /// * `ExecEventError::InvalidEventName` if the name can't be activated, as for `exec_event`.
+    /// * `ExecEventError::PreludeMissing` if a script removed the prelude_buildengine table.
+    /// * `ExecEventError::EngineStdNotImported` if the engine std wasn't loaded, so there is no
+    ///   queue.
+    /// * `ExecEventError::LuaError` if the arguments couldn't be queued.
+    pub fn queue_event(&mut self,
+                       event_name: &str,
+                       args: Vec<AnyLuaValue>)
+                       -> Result<(), ExecEventError> {
+        if !is_valid_event_name(event_name) {
+            return Err(ExecEventError::InvalidEventName(event_name.to_owned()));
+        }
+        if !self.has_prelude() {
+            return Err(ExecEventError::PreludeMissing);
+        }
+        if self.get_path("prelude_buildengine.queue_event").is_none() {
+            return Err(ExecEventError::EngineStdNotImported);
+        }
+        match self.call_prelude_fn_event("queue_event", Some(event_name), &args) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(self.event_error(event_name.to_owned(), err)),
+        }
     }
-}
 
-/// An error that can ocour executing an event.
-#[derive(Debug)]
-pub enum ExecEventError {
-    /// The buildengine standard library was not imported, so events aren't avalable.
-    EngineStdNotImported,
-    /// A lua error ocoured executing the event.
-    LuaError(LuaError),
-}
+    /// The number of events queued with `queue_event` or `buildengine.queue_event` that haven't
+    /// run yet.
+    pub fn queued_event_count(&mut self) -> usize {
+        if self.get_path("prelude_buildengine.queued_event_count").is_none() {
+            return 0;
+        }
+        let count: Result<f64, LuaError> =
+            self.interpreter.execute("return prelude_buildengine.queued_event_count()");
+        count.map(|count| count as usize).unwrap_or(0)
+    }
 
-impl Display for ExecEventError {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        match *self {
-            ExecEventError::EngineStdNotImported => {
-                write!(fmt,
-                       "the standard library for the engine was not imported while trying to \
-                        execute an event")
+    /// Executes up to max queued events, first queued first, returning how many were executed.
+    /// Done every tick by `::Engine::tick`, with a max of QUEUED_EVENTS_PER_TICK.
+    ///
+    /// Unlike an event executed with `exec_event`, or activated by a script with
+    /// `buildengine.activate_event`, which runs inside whatever activated it, each queued event
+    /// runs to completion before the next starts, and never inside the handlers of another event.
+    /// Events queued while these run go to the back of the queue, and are executed by the same
+    /// call unless max is reached first, in which case they stay queued for the next call. What
+    /// the handlers return is discarded.
+    ///
+    /// # Errors
+    /// The same as `exec_event`, for the first event that failed. It is taken off the queue, but
+    /// the events after it stay queued.
+    pub fn process_queued_events(&mut self, max: usize) -> Result<usize, ExecEventError> {
+        if !self.has_prelude() {
+            return Err(ExecEventError::PreludeMissing);
+        }
+        if self.get_path("prelude_buildengine.run_queued_event").is_none() {
+            return Ok(0);
+        }
+        let mut executed = 0;
+        while executed < max {
+            // The event before may have removed the prelude_buildengine table.
+            let event: Option<AnyLuaValue> =
+                self.interpreter.execute("return prelude_buildengine.next_queued_event()").ok();
+            let event = match event {
+                Some(AnyLuaValue::LuaString(event)) => event,
+                Some(AnyLuaValue::LuaBoolean(false)) => {
+                    warn!("Dropping a queued event that isn't an event name and it's arguments.");
+                    if self.interpreter
+                           .execute::<()>("prelude_buildengine.drop_queued_event()")
+                           .is_err() {
+                        return Err(ExecEventError::PreludeMissing);
+                    }
+                    continue;
+                }
+                Some(_) => break,
+                None => return Err(ExecEventError::PreludeMissing),
+            };
+            {
+                let mut prelude_table: LuaTable<_> = match self.interpreter
+                                                               .get("prelude_buildengine") {
+                    Some(prelude_table) => prelude_table,
+                    None => return Err(ExecEventError::PreludeMissing),
+                };
+                prelude_table.set("handler_error", AnyLuaValue::LuaNil);
+                prelude_table.set("cancelled_by", AnyLuaValue::LuaNil);
             }
-            ExecEventError::LuaError(ref err) => {
-                write!(fmt,
-                       "an unknown lua error occoured while executing an event: {:?}",
-                       err)
+            self.start_watchdog();
+            let result = self.call_prelude_fn("run_queued_event", Vec::new());
+            self.stop_watchdog();
+            if let Err(err) = result {
+                let err = self.event_error(event, err);
+                self.report_error(&err);
+                return Err(err);
             }
+            executed += 1;
         }
+        Ok(executed)
     }
-}
 
-impl Error for ExecEventError {
-    fn description(&self) -> &str {
-        match *self {
-            ExecEventError::EngineStdNotImported => {
-                "the standard library for the engine was not imported while trying to execute an \
-                 event"
+    /// Moves the game time, which the timers started with `buildengine.after` and
+    /// `buildengine.every` are kept against, forward, calling the callbacks of the timers that
+    /// are due, soonest first. Done every tick by `advance_tick`, without counting a tick.
+    ///
+    /// Each timer fires at most once per call, and a timer started by a callback is due no sooner
+    /// than the next call. A repeating timer that missed more than one interval fires once.
+    ///
+    /// # Errors
+    /// The first error from a callback, as if it were the event `timer <id>`, such as
+    /// `ExecEventError::Timeout`. The callbacks after it are still called, and any other errors
+    /// logged.
+    pub fn advance_time(&mut self, dt: Duration) -> Result<(), ExecEventError> {
+        let due = self.timers.borrow_mut().advance(dt);
+        let mut result = Ok(());
+        for id in due {
+            {
+                let mut prelude_table: LuaTable<_> = match self.interpreter
+                                                               .get("prelude_buildengine") {
+                    Some(prelude_table) => prelude_table,
+                    None => return Err(ExecEventError::PreludeMissing),
+                };
+                prelude_table.set("handler_error", AnyLuaValue::LuaNil);
             }
-            ExecEventError::LuaError(ref _err) => {
-                "an unknown lua error occoured while executing an event."
+            self.start_watchdog();
+            let fired = self.call_prelude_fn("fire_timer", vec![AnyLuaValue::LuaNumber(id as f64)]);
+            self.stop_watchdog();
+            if let Err(err) = fired {
+                let err = self.event_error(format!("timer {}", id), err);
+                self.report_error(&err);
+                if result.is_ok() {
+                    result = Err(err);
+                } else {
+                    warn!("A timer callback failed: {}", err);
+                }
             }
         }
+        result
     }
-}
 
-impl From<LuaError> for ExecEventError {
-    fn from(err: LuaError) -> Self {
-        ExecEventError::LuaError(err)
+    /// Advances a tick of length dt, as done every tick by `::Engine::tick` once "on_tick" has
+    /// run: counts the tick for `tick`, sets the delta `buildengine.time.delta` reads, then moves
+    /// the game time forward with `advance_time`.
+    ///
+    /// # Errors
+    /// The same as `advance_time`. The tick is counted either way.
+    pub fn advance_tick(&mut self, dt: Duration) -> Result<(), ExecEventError> {
+        self.tick.set(self.tick.get() + 1);
+        self.delta.set(dt);
+        self.advance_time(dt)
     }
-}
 
-/// Converts a lua array with whole, numeric keys to a rust vector.
-pub fn any_lua_to_vec(any: AnyLuaValue) -> Vec<AnyLuaValue> {
-    let as_array = match any {
-        AnyLuaValue::LuaArray(arr) => arr, // Ye a pirate!
-        _ => panic!("called any_lua_to_vec on a non-array lua value: {:?}", any),
-    };
-    let mut vec: Vec<AnyLuaValue> = Vec::new();
-    for value in as_array {
-        let (index, value) = value;
-        let index = match index {
-            AnyLuaValue::LuaNumber(num) => num,
-            _ => panic!("called any_lua_to_vec on array with non-number indexes"),
+    /// The game time scripts read with `buildengine.time.now`, which is the time advanced so far
+    /// with `advance_time` and `advance_tick`, carried over snapshots. It never depends on the
+    /// clock, so it only moves as the engine ticks.
+    pub fn game_time(&self) -> Duration {
+        self.timers.borrow().now()
+    }
+
+    /// The number of ticks advanced with `advance_tick`, which scripts read with
+    /// `buildengine.time.tick`, carried over snapshots.
+    pub fn tick(&self) -> u64 {
+        self.tick.get()
+    }
+
+    /// The number of timers started with `buildengine.after` and `buildengine.every` that haven't
+    /// fired or been cancelled.
+    pub fn timer_count(&self) -> usize {
+        self.timers.borrow().count()
+    }
+
+    /// Writes the values scripts stored with `buildengine.storage.set` to the file, replacing it,
+    /// so they can be loaded with `load_storage` once the engine is restarted.
+    ///
+    /// # Errors
+    /// If the file couldn't be written.
+    pub fn save_storage(&self, path: &Path) -> io::Result<()> {
+        use std::io::Write;
+        let encoded = match serialize(&*self.storage.borrow(), SizeLimit::Infinite) {
+            Ok(encoded) => encoded,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
         };
-        let index = index as usize;
-        vec.insert(index, value);
+        let mut file = try!(File::create(path));
+        file.write_all(&encoded)
+    }
+
+    /// Replaces the values stored with `buildengine.storage.set` with those saved to the file by
+    /// `save_storage`.
+    ///
+    /// # Errors
+    /// If the file couldn't be read, or an `io::ErrorKind::InvalidData` error if it isn't storage
+    /// saved by `save_storage`, in which case the stored values are left as they were.
+    pub fn load_storage(&mut self, path: &Path) -> io::Result<()> {
+        let mut file = try!(File::open(path));
+        let loaded: HashMap<String, LuaValueRepr> =
+            match deserialize_from(&mut file, SizeLimit::Infinite) {
+                Ok(loaded) => loaded,
+                Err(DeserializeError::IoError(err)) => return Err(err),
+                Err(err) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+                }
+            };
+        *self.storage.borrow_mut() = loaded;
+        Ok(())
+    }
+
+    /// Captures the state of the scripts, to be restored with `restore`, such as once the process
+    /// restarts.
+    ///
+    /// The snapshot has the values stored with `buildengine.storage`, the queued events, the
+    /// timers that haven't fired, the state of the generator behind `buildengine.random`, and
+    /// whatever the handlers of the event "on_snapshot" return, which is executed to take it.
+    /// Functions, userdata and threads can't be captured, so the callbacks of timers are left out,
+    /// and the state and the arguments of queued events can't contain them.
+    ///
+    /// # Errors
+    /// * `SnapshotError::Event` if executing "on_snapshot" failed.
+    /// * `SnapshotError::Unserializable` if the state or the arguments of a queued event contain a
+    ///   function, userdata or thread.
+    pub fn snapshot(&mut self) -> Result<EngineSnapshot, SnapshotError> {
+        let state = match self.exec_event("on_snapshot", Vec::new()) {
+            Ok(outcome) => outcome.returns().into_iter().next().unwrap_or(AnyLuaValue::LuaNil),
+            Err(ExecEventError::EngineStdNotImported) => AnyLuaValue::LuaNil,
+            Err(err) => return Err(SnapshotError::Event(err)),
+        };
+        let state = LuaValueRepr::from(state);
+        if has_opaque(&state) {
+            return Err(SnapshotError::Unserializable("the state returned by on_snapshot"
+                                                         .to_owned()));
+        }
+        let mut queued_events = Vec::new();
+        if self.get_path("prelude_buildengine.snapshot_queue").is_some() {
+            let queued = match self.call_prelude_fn("snapshot_queue", Vec::new()) {
+                Ok(queued) => queued.unwrap_or(AnyLuaValue::LuaNil),
+                Err(err) => {
+                    return Err(SnapshotError::Event(self.event_error("on_snapshot".to_owned(),
+                                                                     err)))
+                }
+            };
+            for event in try_any_lua_to_vec(queued).unwrap_or(Vec::new()) {
+                let mut fields = try_any_lua_to_vec(event).unwrap_or(Vec::new()).into_iter();
+                let (name, packed) = match (fields.next(), fields.next()) {
+                    (Some(AnyLuaValue::LuaString(name)), Some(packed)) => (name, packed),
+                    _ => continue,
+                };
+                let args: Vec<LuaValueRepr> =
+                    unpack_returns(packed).into_iter().map(LuaValueRepr::from).collect();
+                if args.iter().any(has_opaque) {
+                    return Err(SnapshotError::Unserializable(format!("the arguments of the \
+                                                                      queued event {}",
+                                                                     name)));
+                }
+                queued_events.push((name, args));
+            }
+        }
+        let timers = self.timers
+                         .borrow()
+                         .snapshot()
+                         .into_iter()
+                         .map(|(id, due_in, interval)| {
+                             TimerSnapshot {
+                                 id: id,
+                                 due_in: duration_secs(due_in),
+                                 interval: interval.map(duration_secs),
+                             }
+                         })
+                         .collect();
+        Ok(EngineSnapshot {
+            storage: self.storage.borrow().clone(),
+            queued_events: queued_events,
+            timers: timers,
+            state: state,
+            rng_state: self.rng_state(),
+            game_time: duration_secs(self.timers.borrow().now()),
+            tick: self.tick.get(),
+        })
+    }
+
+    /// Restarts the generator behind `buildengine.random` from the seed, so the numbers scripts
+    /// get from it are the same every time it is given that seed. It is seeded from the clock when
+    /// the engine is constructed.
+    pub fn set_rng_seed(&mut self, seed: u64) {
+        self.rng.borrow_mut().seed(seed);
+    }
+
+    /// The state of the generator behind `buildengine.random`, which is a seed that carries on
+    /// from where it is now when given to `set_rng_seed`.
+    pub fn rng_state(&self) -> u64 {
+        self.rng.borrow().state()
+    }
+
+    /// Sets what `buildengine.info` tells the scripts, which is `EngineInfo::default()` untill
+    /// then. `::Engine` sets it before loading the scripts, with it's role and tick rate.
+    pub fn set_info(&mut self, info: EngineInfo) {
+        *self.info.borrow_mut() = info;
+        if self.get_path("prelude_buildengine.refresh_info").is_some() {
+            if let Err(err) = self.call_prelude_fn("refresh_info", Vec::new()) {
+                warn!("Failed to update buildengine.info: {}", lua_error_message(&err));
+            }
+        }
+    }
+
+    /// What `buildengine.info` tells the scripts.
+    pub fn info(&self) -> EngineInfo {
+        self.info.borrow().clone()
+    }
+
+    /// Sets the network scripts send events to their peers through with `buildengine.net`, or None
+    /// for none, which is the default. `::Engine` sets it to it's controller before loading the
+    /// scripts.
+    ///
+    /// Without a network, such as in single player or tests, every function of `buildengine.net`
+    /// raises a lua error, which scripts can catch with pcall.
+    pub fn set_network(&mut self, network: Option<Box<ScriptNetwork>>) {
+        *self.network.borrow_mut() = network;
+    }
+
+    /// If `buildengine.net` has a network to go through, see `set_network`.
+    pub fn has_network(&self) -> bool {
+        self.network.borrow().is_some()
+    }
+
+    /// Sets the players `buildengine.players` reads, or None for none, which is the default.
+    /// `::Engine` shares it's players with the script engine of a server.
+    ///
+    /// Without players, such as on a client, `buildengine.players` lists none.
+    pub fn set_players(&mut self, players: Option<Rc<RefCell<PlayerRegistry>>>) {
+        *self.players.borrow_mut() = players;
+    }
+
+    /// Registers a command for `run_command`, replacing the one registered in rust with the name
+    /// before, built in ones included. Commands registered in rust are run before those scripts
+    /// registered with the same name.
+    ///
+    /// The handler is given the arguments after the name of the command, and returns the text to
+    /// print, or the message of why it failed.
+    pub fn register_command<F>(&mut self, name: &str, help: &str, handler: F)
+        where F: FnMut(&[String]) -> Result<String, String> + 'lua
+    {
+        self.commands.insert(name.to_owned(),
+                             Command {
+                                 help: help.to_owned(),
+                                 handler: CommandHandler::Rust(Box::new(handler)),
+                             });
+    }
+
+    fn register_builtin(&mut self, name: &str, help: &str, builtin: Builtin) {
+        self.commands.insert(name.to_owned(),
+                             Command {
+                                 help: help.to_owned(),
+                                 handler: CommandHandler::Builtin(builtin),
+                             });
+    }
+
+    /// Sets if commands that run lua code, such as `lua`, may be run, which they can't by default.
+    /// Only trust a console that only admins can reach.
+    pub fn set_commands_trusted(&mut self, trusted: bool) {
+        self.commands_trusted = trusted;
+    }
+
+    /// Runs a command line, such as from a server console, returning the text it printed.
+    ///
+    /// The line is split into words with `split_command`, the first being the name of the command
+    /// and the rest it's arguments. Commands registered with `register_command` are looked up
+    /// first, then those scripts registered with `buildengine.commands.register(name, help,
+    /// handler)`, whose handler is called with each argument as a string, under the execution
+    /// limit.
+    ///
+    /// Every engine has the commands:
+    ///
+    /// * `help`, listing every command with it's help, or `help <name>` for only that command.
+    /// * `lua <code>`, evaluating the rest of the line with `eval`, only once commands are
+    ///   trusted, see `set_commands_trusted`.
+    /// * `scripts`, listing the loaded modules.
+    ///
+    /// # Errors
+    /// * `CommandError::Empty` if the line has no words in it.
+    /// * `CommandError::Syntax` if the line can't be split into words.
+    /// * `CommandError::UnknownCommand` if no command has the name.
+    /// * `CommandError::NotTrusted` for `lua` when commands aren't trusted.
+    /// * `CommandError::HandlerError` if the handler of the command failed, such as by a lua
+    ///   error.
+    pub fn run_command(&mut self, line: &str) -> Result<String, CommandError> {
+        let mut words = try!(split_command(line)).into_iter();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return Err(CommandError::Empty),
+        };
+        let args: Vec<String> = words.collect();
+        let builtin = match self.commands.get_mut(&name) {
+            Some(&mut Command { handler: CommandHandler::Rust(ref mut handler), .. }) => {
+                return handler(&args).map_err(|message| {
+                    CommandError::HandlerError {
+                        command: name.clone(),
+                        message: message,
+                    }
+                });
+            }
+            Some(&mut Command { handler: CommandHandler::Builtin(builtin), .. }) => Some(builtin),
+            None => None,
+        };
+        match builtin {
+            Some(Builtin::Help) => self.command_help(args.first().map(|name| &name[..])),
+            Some(Builtin::Lua) => {
+                if !self.commands_trusted {
+                    return Err(CommandError::NotTrusted(name));
+                }
+                // The code is taken from the line as it is, rather than the words it was split
+                // into, so it's quotes are kept.
+                let code = line.trim_left().splitn(2, char::is_whitespace).nth(1).unwrap_or("");
+                match self.eval(code) {
+                    Ok(value) => Ok(command_output(&value)),
+                    Err(err) => {
+                        Err(CommandError::HandlerError {
+                            command: name,
+                            message: lua_error_message(&err),
+                        })
+                    }
+                }
+            }
+            Some(Builtin::Scripts) => Ok(self.module_names().join("\n")),
+            None => self.run_lua_command(name, args),
+        }
+    }
+
+    /// Runs the command scripts registered with the name, for `run_command`.
+    fn run_lua_command(&mut self, name: String, args: Vec<String>) -> Result<String, CommandError> {
+        if self.get_path("prelude_buildengine.run_command").is_none() ||
+           !self.lua_commands().contains_key(&name) {
+            return Err(CommandError::UnknownCommand(name));
+        }
+        let mut call_args = vec![AnyLuaValue::LuaString(name.clone())];
+        call_args.extend(args.into_iter().map(AnyLuaValue::LuaString));
+        self.start_watchdog();
+        let result = self.call_prelude_fn("run_command", call_args);
+        self.stop_watchdog();
+        match result {
+            Ok(Some(output)) => Ok(command_output(&output)),
+            Ok(None) => Ok(String::new()),
+            Err(err) => {
+                Err(CommandError::HandlerError {
+                    command: name,
+                    message: lua_error_message(&err),
+                })
+            }
+        }
+    }
+
+    /// The text of the `help` command, for every command, or only the one named.
+    fn command_help(&mut self, name: Option<&str>) -> Result<String, CommandError> {
+        let mut commands = self.lua_commands();
+        for (command, registered) in &self.commands {
+            commands.insert(command.clone(), registered.help.clone());
+        }
+        match name {
+            Some(name) => {
+                match commands.get(name) {
+                    Some(help) => Ok(format!("{} - {}", name, help)),
+                    None => Err(CommandError::UnknownCommand(name.to_owned())),
+                }
+            }
+            None => {
+                let mut lines: Vec<String> = commands.iter()
+                                                     .map(|(name, help)| {
+                                                         format!("{} - {}", name, help)
+                                                     })
+                                                     .collect();
+                lines.sort();
+                Ok(lines.join("\n"))
+            }
+        }
+    }
+
+    /// The help of each command scripts registered, by name.
+    fn lua_commands(&mut self) -> HashMap<String, String> {
+        let mut commands = HashMap::new();
+        if let Some(AnyLuaValue::LuaArray(entries)) =
+               self.get_path("prelude_buildengine.command_help") {
+            for entry in entries {
+                if let (AnyLuaValue::LuaString(name), AnyLuaValue::LuaString(help)) = entry {
+                    commands.insert(name, help);
+                }
+            }
+        }
+        commands
+    }
+
+    /// The names of the loaded modules, sorted, for the `scripts` command.
+    fn module_names(&mut self) -> Vec<String> {
+        let mut names = Vec::new();
+        if let Some(AnyLuaValue::LuaArray(entries)) = self.get_path("prelude_buildengine.modules") {
+            for (name, _) in entries {
+                if let AnyLuaValue::LuaString(name) = name {
+                    names.push(name);
+                }
+            }
+        }
+        names.sort();
+        names
+    }
+
+    /// Sets if the time each handler takes is recorded, for `profiling_report`. Off by default, as
+    /// it costs a little every time a handler is called.
+    ///
+    /// Turning it off keeps what was recorded, use `clear_profiling` to forget it.
+    ///
+    /// # Errors
+    /// * `ExecEventError::PreludeMissing` if the prelude_buildengine table is gone.
+    pub fn set_profiling(&mut self, enabled: bool) -> Result<(), ExecEventError> {
+        let mut prelude_table: LuaTable<_> = match self.interpreter.get("prelude_buildengine") {
+            Some(prelude_table) => prelude_table,
+            None => return Err(ExecEventError::PreludeMissing),
+        };
+        prelude_table.set("profiling", enabled);
+        Ok(())
+    }
+
+    /// Forgets the time recorded for every handler. Does nothing if the engine std wasn't
+    /// imported, as nothing can have been recorded.
+    ///
+    /// # Errors
+    /// * `ExecEventError::PreludeMissing` if the prelude_buildengine table is gone, or a script
+    ///   replaced it's clear_profiles.
+    pub fn clear_profiling(&mut self) -> Result<(), ExecEventError> {
+        if !self.has_prelude() {
+            return Err(ExecEventError::PreludeMissing);
+        }
+        if self.get_path("prelude_buildengine.clear_profiles").is_some() {
+            try!(self.interpreter
+                     .execute::<()>("prelude_buildengine.clear_profiles()")
+                     .map_err(|_| ExecEventError::PreludeMissing));
+        }
+        Ok(())
+    }
+
+    /// The time taken by each handler for each event it was called for while profiling was on,
+    /// the handler that took the most time in total first.
+    ///
+    /// The time taken by a handler includes the time taken by the handlers of any events it
+    /// activated.
+    pub fn profiling_report(&mut self) -> Vec<HandlerProfile> {
+        let profiles = self.get_path("prelude_buildengine.profiles").map(LuaValueRepr::from);
+        let entries = match profiles {
+            Some(LuaValueRepr::Array(entries)) => entries,
+            // The engine std was not imported, or nothing was recorded.
+            _ => return Vec::new(),
+        };
+        let mut report: Vec<HandlerProfile> =
+            entries.into_iter().filter_map(HandlerProfile::from_repr).collect();
+        report.sort_by(|a, b| b.total.cmp(&a.total));
+        report
+    }
+
+    /// Sets how long a single call of a handler may take before a warning naming it is logged, or
+    /// None to not log slow handlers, which is the default.
+    ///
+    /// This works whether profiling is on or not.
+    ///
+    /// # Errors
+    /// * `ExecEventError::PreludeMissing` if the prelude_buildengine table is gone.
+    pub fn set_slow_handler_threshold(&mut self,
+                                      threshold: Option<Duration>)
+                                      -> Result<(), ExecEventError> {
+        let mut prelude_table: LuaTable<_> = match self.interpreter.get("prelude_buildengine") {
+            Some(prelude_table) => prelude_table,
+            None => return Err(ExecEventError::PreludeMissing),
+        };
+        match threshold {
+            Some(threshold) => {
+                prelude_table.set("slow_handler_threshold", duration_secs(threshold))
+            }
+            None => prelude_table.set("slow_handler_threshold", AnyLuaValue::LuaNil),
+        }
+        Ok(())
+    }
+
+    /// The lua stack traceback of where the error making the last call to `call_prelude_fn` fail
+    /// was raised, or None if it didn't fail or failed before any lua code was run.
+    pub fn last_traceback(&mut self) -> Option<String> {
+        let mut prelude_table: LuaTable<_> = match self.interpreter.get("prelude_buildengine") {
+            Some(prelude_table) => prelude_table,
+            None => return None,
+        };
+        prelude_table.get("traceback")
+    }
+
+    /// The value of the global variable, or None if it is nil.
+    pub fn get_global(&mut self, name: &str) -> Option<AnyLuaValue> {
+        let value: Option<AnyLuaValue> = self.interpreter.get(name);
+        match value {
+            Some(AnyLuaValue::LuaNil) | None => None,
+            value => value,
+        }
+    }
+
+    /// The value of the global variable the module set, or None if it is nil or there is no such
+    /// module.
+    ///
+    /// Each module has an environment of it's own, which the globals it sets go in, see `new`.
+    /// Globals it only reads from the shared globals, such as the standard library, aren't
+    /// included.
+    pub fn get_module_global(&mut self, module: &str, name: &str) -> Option<AnyLuaValue> {
+        let mut code = "return prelude_buildengine.module_global(".to_owned();
+        write_lua_literal(&AnyLuaValue::LuaString(module.to_owned()), &mut code);
+        code.push_str(", ");
+        write_lua_literal(&AnyLuaValue::LuaString(name.to_owned()), &mut code);
+        code.push(')');
+        // Fails if the prelude_buildengine table is gone, along with the environments.
+        match self.interpreter.execute(&code) {
+            Ok(AnyLuaValue::LuaNil) | Err(_) => None,
+            Ok(value) => Some(value),
+        }
+    }
+
+    /// Sets the global variable, which may be a table of tables, or removes it if the value is
+    /// nil.
+    ///
+    /// # Errors
+    /// * `PathError::PreludeMissing` if the prelude_buildengine table is gone, which setting it
+    ///   goes through.
+    pub fn set_global(&mut self, name: &str, value: AnyLuaValue) -> Result<(), PathError> {
+        // A path of a single part is the global itself, even if it has dots in it.
+        self.set_path_parts(&[name], value, false)
+    }
+
+    /// The value at a dotted path of fields in the globals, such as
+    /// `prelude_buildengine.modules.foo`, or None if it is nil, or any value on the way to it is
+    /// nil or isn't a table.
+    pub fn get_path(&mut self, path: &str) -> Option<AnyLuaValue> {
+        if !is_valid_path(path) {
+            return None;
+        }
+        let mut code = "return prelude_buildengine.get_path(".to_owned();
+        write_lua_literal(&AnyLuaValue::LuaString(path.to_owned()), &mut code);
+        code.push(')');
+        match self.interpreter.execute(&code) {
+            Ok(AnyLuaValue::LuaNil) | Err(_) => None,
+            Ok(value) => Some(value),
+        }
+    }
+
+    /// Sets the value at a dotted path of fields in the globals, such as `config.player.speed`,
+    /// creating the tables on the way to it that are nil if create_tables is true.
+    ///
+    /// Metatables are ignored, so fields are set as they are, even on a table that would reject
+    /// them.
+    ///
+    /// # Errors
+    /// * `PathError::InvalidPath` if the path is empty or has an empty part, such as `a..b`.
+    /// * `PathError::MissingTable` if a table on the way is nil, and create_tables is false.
+    /// * `PathError::NotATable` if a value on the way isn't a table.
+    /// * `PathError::PreludeMissing` if the prelude_buildengine table is gone.
+    pub fn set_path(&mut self,
+                    path: &str,
+                    value: AnyLuaValue,
+                    create_tables: bool)
+                    -> Result<(), PathError> {
+        if !is_valid_path(path) {
+            return Err(PathError::InvalidPath(path.to_owned()));
+        }
+        let parts: Vec<&str> = path.split('.').collect();
+        self.set_path_parts(&parts, value, create_tables)
+    }
+
+    /// Sets the value at the path of fields in the globals, for `set_path`.
+    fn set_path_parts(&mut self,
+                      parts: &[&str],
+                      value: AnyLuaValue,
+                      create_tables: bool)
+                      -> Result<(), PathError> {
+        // Built as code, as hlua can't push nested tables, and the parts may have dots in them.
+        let mut code = "prelude_buildengine.set_path_parts({".to_owned();
+        for part in parts {
+            write_lua_literal(&AnyLuaValue::LuaString((*part).to_owned()), &mut code);
+            code.push_str(", ");
+        }
+        code.push_str("}, ");
+        write_lua_literal(&value, &mut code);
+        code.push_str(if create_tables { ", true)" } else { ", false)" });
+        if self.interpreter.execute::<()>(&code).is_err() {
+            return Err(PathError::PreludeMissing);
+        }
+        let mut prelude_table: LuaTable<_> = match self.interpreter.get("prelude_buildengine") {
+            Some(prelude_table) => prelude_table,
+            None => return Err(PathError::PreludeMissing),
+        };
+        let error: Option<String> = prelude_table.get("path_error");
+        let error_at: Option<String> = prelude_table.get("path_error_at");
+        match (error, error_at) {
+            (Some(ref error), Some(at)) if error == "missing" => Err(PathError::MissingTable(at)),
+            (Some(_), Some(at)) => Err(PathError::NotATable(at)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The number of handlers the event has, added with `buildengine.on` and not yet removed.
+    pub fn handler_count(&mut self, event_name: &str) -> usize {
+        let mut prelude_table: LuaTable<_> = match self.interpreter.get("prelude_buildengine") {
+            Some(prelude_table) => prelude_table,
+            // The handlers went with the prelude_buildengine table.
+            None => return 0,
+        };
+        let mut events: LuaTable<_> = match prelude_table.get("events") {
+            Some(events) => events,
+            // The engine std was not imported, so there can't be any handlers.
+            None => return 0,
+        };
+        let handlers: Option<AnyLuaValue> = events.get(event_name);
+        match handlers.map(LuaValueRepr::from) {
+            Some(LuaValueRepr::Array(handlers)) => handlers.len(),
+            _ => 0,
+        }
+    }
+
+    /// The error for a failed event, which is `ExecEventError::HandlerError` if one of it's
+    /// handlers errored.
+    fn event_error(&mut self, event: String, error: LuaError) -> ExecEventError {
+        let (timed_out, memory_exceeded, handler_error) = {
+            let mut prelude_table: LuaTable<_> = match self.interpreter
+                                                           .get("prelude_buildengine") {
+                Some(prelude_table) => prelude_table,
+                None => return ExecEventError::PreludeMissing,
+            };
+            let timed_out: Option<bool> = prelude_table.get("timed_out");
+            let memory_exceeded: Option<f64> = prelude_table.get("memory_exceeded");
+            let handler_error: Option<AnyLuaValue> = prelude_table.get("handler_error");
+            (timed_out, memory_exceeded, handler_error)
+        };
+        if timed_out == Some(true) {
+            return ExecEventError::Timeout { event: event };
+        }
+        if let Some(usage) = memory_exceeded {
+            return ExecEventError::MemoryLimitExceeded {
+                event: event,
+                usage: usage as usize,
+            };
+        }
+        let traceback = self.last_traceback();
+        // Set by activate_event as {index of the handler, message, module of the handler}.
+        if let Some(LuaValueRepr::Array(fields)) = handler_error.map(LuaValueRepr::from) {
+            if let (Some(&LuaValueRepr::Number(handler)),
+                    Some(&LuaValueRepr::String(ref message))) = (fields.get(0), fields.get(1)) {
+                let module = match fields.get(2) {
+                    Some(&LuaValueRepr::String(ref module)) => Some(module.clone()),
+                    _ => None,
+                };
+                return ExecEventError::HandlerError {
+                    event: event,
+                    handler: handler as usize,
+                    module: module,
+                    message: message.clone(),
+                    traceback: traceback,
+                };
+            }
+        }
+        ExecEventError::LuaError {
+            event: event,
+            error: error,
+            traceback: traceback,
+        }
+    }
+
+    /// Keeps the error of an event in `recent_errors`, and executes the event "on_error" with a
+    /// table of it's event, module, message and traceback, unless it came from "on_error" itself,
+    /// in which case it is only logged.
+    fn report_error(&mut self, err: &ExecEventError) {
+        let report = match ScriptErrorReport::from_error(err) {
+            Some(report) => report,
+            None => return,
+        };
+        if self.recent_errors.len() >= RECENT_ERRORS_KEPT {
+            self.recent_errors.pop_front();
+        }
+        self.recent_errors.push_back(report.clone());
+        if self.reporting_error {
+            warn!("A handler of on_error failed: {}", err);
+            return;
+        }
+        let string = |val: &str| AnyLuaValue::LuaString(val.to_owned());
+        let mut fields = vec![(string("event"), string(&report.event)),
+                              (string("message"), string(&report.message))];
+        if let Some(ref module) = report.module {
+            fields.push((string("module"), string(module)));
+        }
+        if let Some(ref traceback) = report.traceback {
+            fields.push((string("traceback"), string(traceback)));
+        }
+        self.reporting_error = true;
+        // An error of "on_error" itself is kept and logged by the call reporting it.
+        let _ = self.exec_event("on_error", vec![AnyLuaValue::LuaArray(fields)]);
+        self.reporting_error = false;
+    }
+
+    /// The last RECENT_ERRORS_KEPT errors of the scripts, oldest first, such as for a launcher to
+    /// show.
+    ///
+    /// Every error from executing an event, processing the queued events or firing a timer is
+    /// kept, including those of the handlers of "on_error", but not errors such as
+    /// `ExecEventError::InvalidEventName` that happen before any script runs.
+    pub fn recent_errors(&self) -> Vec<ScriptErrorReport> {
+        self.recent_errors.iter().cloned().collect()
+    }
+
+    /// Forgets the errors kept for `recent_errors`.
+    pub fn clear_recent_errors(&mut self) {
+        self.recent_errors.clear();
+    }
+
+    /// Takes the events queued by scripts with `buildengine.send_to` since the last call, in the
+    /// order they were queued.
+    ///
+    /// Queued entries that aren't a connection id, an event name and an array of arguments are
+    /// logged and skipped.
+    pub fn take_outgoing(&mut self) -> Vec<OutgoingEvent> {
+        let queued: Option<AnyLuaValue> = {
+            let mut prelude_table: LuaTable<_> = match self.interpreter
+                                                           .get("prelude_buildengine") {
+                Some(prelude_table) => prelude_table,
+                // Removed by a script, so nothing can be taken.
+                None => return Vec::new(),
+            };
+            prelude_table.get("outgoing")
+        };
+        let entries = match queued.map(LuaValueRepr::from) {
+            Some(LuaValueRepr::Array(ref entries)) if entries.is_empty() => return Vec::new(),
+            Some(LuaValueRepr::Array(entries)) => entries,
+            // The engine std was not imported, so nothing could be queued.
+            _ => return Vec::new(),
+        };
+        if let Err(err) = self.interpreter.execute::<()>("prelude_buildengine.outgoing = {}") {
+            warn!("Failed to clear the events queued by scripts: {}", lua_error_message(&err));
+        }
+        let mut outgoing = Vec::new();
+        for entry in entries {
+            match OutgoingEvent::from_repr(entry) {
+                Ok(event) => outgoing.push(event),
+                Err(entry) => warn!("Skipping a malformed event queued by a script: {:?}", entry),
+            }
+        }
+        outgoing
+    }
+
+    /// Call the given lua function in the prelude table with the given arguments, returning the
+    /// first value it returns, or None if that is nil or it returns nothing.
+    ///
+    /// Each call has a slot of it's own in prelude_buildengine.calls, removed once it returns, so
+    /// the function may itself make calls, such as with `prelude_buildengine.call`, without
+    /// disturbing this one.
+    ///
+    /// If the call fails, the traceback of the error can be had with `last_traceback`.
+    pub fn call_prelude_fn(&mut self,
+                           fn_to_call: &str,
+                           args: Vec<AnyLuaValue>)
+                           -> Result<Option<AnyLuaValue>, LuaError> {
+        let returns = try!(self.call_prelude_fn_multi(fn_to_call, args));
+        match returns.into_iter().next() {
+            Some(AnyLuaValue::LuaNil) | None => Ok(None),
+            ret => Ok(ret),
+        }
+    }
+
+    /// Call the given lua function in the prelude table with the given arguments like
+    /// `call_prelude_fn`, returning every value it returns, nils included.
+    pub fn call_prelude_fn_multi(&mut self,
+                                 fn_to_call: &str,
+                                 args: Vec<AnyLuaValue>)
+                                 -> Result<Vec<AnyLuaValue>, LuaError> {
+        self.call_prelude_fn_event(fn_to_call, None, &args)
+    }
+
+    /// Makes the call for `call_prelude_fn_multi`, giving the function the event name, if there
+    /// is one, as it's first argument, ahead of args.
+    fn call_prelude_fn_event(&mut self,
+                             fn_to_call: &str,
+                             event_name: Option<&str>,
+                             args: &[AnyLuaValue])
+                             -> Result<Vec<AnyLuaValue>, LuaError> {
+        try!(self.interpreter.execute::<()>("prelude_buildengine.traceback = nil"));
+        // hlua can't push nested tables, so the arguments are built by lua code instead.
+        let mut code = "return prelude_buildengine.begin_call(".to_owned();
+        write_lua_string(fn_to_call, &mut code);
+        let count = args.len() + if event_name.is_some() { 1 } else { 0 };
+        write!(code, ", {}, {{", count).unwrap();
+        if let Some(event_name) = event_name {
+            write_lua_string(event_name, &mut code);
+            code.push_str(", ");
+        }
+        for arg in args {
+            write_lua_literal(arg, &mut code);
+            code.push_str(", ");
+        }
+        code.push_str("})");
+        let id: f64 = try!(self.interpreter.execute(&code));
+        let result = self.interpreter
+                         .execute::<()>(&format!("prelude_buildengine.call_prelude_fn({})", id));
+        // Removed even if the call failed, so it's slot doesn't linger.
+        let packed: AnyLuaValue =
+            try!(self.interpreter.execute(&format!("return prelude_buildengine.end_call({})", id)));
+        try!(result);
+        Ok(unpack_returns(packed))
+    }
+}
+
+impl<'lua> Debug for Engine<'lua> {
+    fn fmt(&self, _fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        Ok(())
+    }
+}
+
+/// An event queued by a script with `buildengine.send_to`, taken with `Engine::take_outgoing`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutgoingEvent {
+    /// The id of the connection to send the event to.
+    pub to: u64,
+    /// The name of the event.
+    pub name: String,
+    /// The arguments to the event.
+    pub args: Vec<LuaValueRepr>,
+}
+
+impl OutgoingEvent {
+    /// Reads an entry of prelude_buildengine.outgoing, or returns it back if it is malformed.
+    fn from_repr(entry: LuaValueRepr) -> Result<OutgoingEvent, LuaValueRepr> {
+        let fields = match entry {
+            LuaValueRepr::Array(fields) => fields,
+            other => return Err(other),
+        };
+        if fields.len() != 3 {
+            return Err(LuaValueRepr::Array(fields));
+        }
+        match (&fields[0], &fields[1], &fields[2]) {
+            (&LuaValueRepr::Number(to),
+             &LuaValueRepr::String(ref name),
+             &LuaValueRepr::Array(ref args)) if to >= 0.0 && to.fract() == 0.0 => {
+                return Ok(OutgoingEvent {
+                    to: to as u64,
+                    name: name.clone(),
+                    args: args.clone(),
+                })
+            }
+            _ => {}
+        }
+        Err(LuaValueRepr::Array(fields))
+    }
+}
+
+/// The network scripts reach through `buildengine.net`, given to an engine with
+/// `Engine::set_network`. `::Engine` gives it it's `net::Controller`.
+///
+/// Errors are returned as the messages of the lua errors they raise.
+pub trait ScriptNetwork {
+    /// Sends the event, with the arguments, to the connection with the id.
+    fn send(&self, to: u64, event_name: String, args: Vec<LuaValueRepr>) -> Result<(), String>;
+
+    /// Sends the event, with the arguments, to every connection, returning how many it was sent
+    /// to.
+    fn broadcast(&self, event_name: String, args: Vec<LuaValueRepr>) -> usize;
+
+    /// Closes the connection with the id, telling the peer the reason.
+    fn kick(&self, to: u64, reason: &str) -> Result<(), String>;
+
+    /// The number of connections.
+    fn peer_count(&self) -> usize;
+
+    /// The address of the peer on the other end of the connection with the id, or None if no
+    /// connection has the id.
+    fn peer_addr(&self, to: u64) -> Option<String>;
+
+    /// The traffic on the connection with the id, or None if no connection has the id.
+    ///
+    /// Scripts may read it every tick, so it should be cheap, such as by not waiting on any lock.
+    fn stats(&self, to: u64) -> Option<PeerStats>;
+}
+
+/// The traffic on a connection, as scripts get it from `buildengine.net.stats`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerStats {
+    /// The smoothed round trip time in milliseconds, or None if it isn't known yet.
+    pub rtt_ms: Option<f64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// The seconds since the connection was made.
+    pub connected_seconds: f64,
+}
+
+impl PeerStats {
+    /// The stats as the table scripts get, with rtt_ms left out if it isn't known.
+    fn to_lua(&self) -> AnyLuaValue {
+        let mut fields = vec![("bytes_sent", AnyLuaValue::LuaNumber(self.bytes_sent as f64)),
+                              ("bytes_received",
+                               AnyLuaValue::LuaNumber(self.bytes_received as f64)),
+                              ("connected_seconds",
+                               AnyLuaValue::LuaNumber(self.connected_seconds))];
+        if let Some(rtt_ms) = self.rtt_ms {
+            fields.push(("rtt_ms", AnyLuaValue::LuaNumber(rtt_ms)));
+        }
+        AnyLuaValue::LuaArray(fields.into_iter()
+                                    .map(|(key, value)| {
+                                        (AnyLuaValue::LuaString(key.to_owned()), value)
+                                    })
+                                    .collect())
+    }
+}
+
+/// A player as the table `buildengine.players` gives scripts.
+fn player_to_lua(player: &Player) -> AnyLuaValue {
+    let string = |val: &str| AnyLuaValue::LuaString(val.to_owned());
+    AnyLuaValue::LuaArray(vec![(string("id"), AnyLuaValue::LuaNumber(player.id.0 as f64)),
+                               (string("name"), string(&player.name)),
+                               (string("connection"),
+                                AnyLuaValue::LuaNumber(player.connection.0 as f64))])
+}
+
+/// Lua code returning the value, for natives returning tables, which hlua can't push.
+fn return_code(value: &AnyLuaValue) -> String {
+    let mut code = "return ".to_owned();
+    write_lua_literal(value, &mut code);
+    code
+}
+
+/// Calls the function with the network of an engine, or errors if it has none.
+fn with_network<F>(network: &RefCell<Option<Box<ScriptNetwork>>>,
+                   f: F)
+                   -> Result<AnyLuaValue, String>
+    where F: FnOnce(&ScriptNetwork) -> Result<AnyLuaValue, String>
+{
+    match *network.borrow() {
+        Some(ref network) => f(&**network),
+        None => Err("buildengine.net is not available, as the engine has no network".to_owned()),
+    }
+}
+
+/// Lua code returning the result of a native function behind `buildengine.net`, or nil and the
+/// message of the error, read with load as hlua can't push nested tables.
+fn net_result(result: Result<AnyLuaValue, String>) -> String {
+    let mut code = "return ".to_owned();
+    match result {
+        Ok(value) => write_lua_literal(&value, &mut code),
+        Err(message) => {
+            code.push_str("nil, ");
+            write_lua_string(&message, &mut code);
+        }
+    }
+    code
+}
+
+/// Converts the array of arguments given to a function of `buildengine.net`, or errors naming the
+/// field that can't be sent, such as `args[2].callback`.
+fn net_event_args(function_name: &str, args: AnyLuaValue) -> Result<Vec<LuaValueRepr>, String> {
+    let values = try!(try_any_lua_to_vec(args).map_err(|err| {
+        format!("bad argument args to {}: {}", function_name, err)
+    }));
+    let mut reprs = Vec::new();
+    for (i, value) in values.into_iter().enumerate() {
+        let repr = LuaValueRepr::from(value);
+        if let Some(field) = opaque_field(&repr, format!("args[{}]", i + 1)) {
+            return Err(format!("bad argument args to {}: {} is a function, userdata or thread, \
+                                which can't be sent",
+                               function_name,
+                               field));
+        }
+        reprs.push(repr);
+    }
+    Ok(reprs)
+}
+
+/// The state of the scripts, taken with `Engine::snapshot` and restored with `Engine::restore`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    /// The values stored with `buildengine.storage`, by key.
+    pub storage: HashMap<String, LuaValueRepr>,
+    /// The events queued to run, first to run first, with their arguments.
+    pub queued_events: Vec<(String, Vec<LuaValueRepr>)>,
+    /// The timers that hadn't fired or been cancelled, soonest first.
+    pub timers: Vec<TimerSnapshot>,
+    /// What the handlers of "on_snapshot" returned, given to the handlers of "on_restore".
+    pub state: LuaValueRepr,
+    /// The state of the generator behind `buildengine.random`, as given by `Engine::rng_state`.
+    pub rng_state: u64,
+    /// The seconds of game time advanced, as given by `Engine::game_time`.
+    pub game_time: f64,
+    /// The number of ticks advanced, as given by `Engine::tick`.
+    pub tick: u64,
+}
+
+/// A timer started with `buildengine.after` or `buildengine.every`, as captured by
+/// `Engine::snapshot`, without it's callback.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimerSnapshot {
+    /// The id of the timer, which it keeps once restored.
+    pub id: u64,
+    /// The seconds untill the timer was due.
+    pub due_in: f64,
+    /// How often the timer repeats in seconds, or None if it only fires once.
+    pub interval: Option<f64>,
+}
+
+/// An error that can occour taking a snapshot with `Engine::snapshot`, or restoring one with
+/// `Engine::restore`.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The scripts failed to load.
+    ScriptError(LuaError),
+    /// Executing "on_snapshot" or "on_restore", or queueing a restored event, failed.
+    Event(ExecEventError),
+    /// The part of the snapshot described contains a function, userdata or thread.
+    Unserializable(String),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SnapshotError::ScriptError(ref err) => {
+                write!(fmt, "ScriptError: The scripts failed to load: {}", lua_error_message(err))
+            }
+            SnapshotError::Event(ref err) => write!(fmt, "Event: {}", err),
+            SnapshotError::Unserializable(ref what) => {
+                write!(fmt,
+                       "Unserializable: {} contains a function, userdata or thread.",
+                       what)
+            }
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn description(&self) -> &str {
+        match *self {
+            SnapshotError::ScriptError(_) => "ScriptError: The scripts failed to load.",
+            SnapshotError::Event(_) => "Event: Executing an event failed.",
+            SnapshotError::Unserializable(_) => {
+                "Unserializable: The snapshot contains a function, userdata or thread."
+            }
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            SnapshotError::Event(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// If the value is or contains a function, userdata or thread.
+fn has_opaque(value: &LuaValueRepr) -> bool {
+    match *value {
+        LuaValueRepr::Opaque => true,
+        LuaValueRepr::Array(ref values) => values.iter().any(has_opaque),
+        LuaValueRepr::Table(ref pairs) => {
+            pairs.iter().any(|&(ref key, ref value)| has_opaque(key) || has_opaque(value))
+        }
+        _ => false,
+    }
+}
+
+/// The path to the first function, userdata or thread in the value, starting from the path of the
+/// value itself, such as `args[1].player.callback`, or None if it has none.
+fn opaque_field(value: &LuaValueRepr, path: String) -> Option<String> {
+    match *value {
+        LuaValueRepr::Opaque => Some(path),
+        LuaValueRepr::Array(ref values) => {
+            values.iter()
+                  .enumerate()
+                  .filter_map(|(i, value)| opaque_field(value, format!("{}[{}]", path, i + 1)))
+                  .next()
+        }
+        LuaValueRepr::Table(ref pairs) => {
+            for &(ref key, ref value) in pairs {
+                if has_opaque(key) {
+                    return Some(format!("a key of {}", path));
+                }
+                let field = match *key {
+                    LuaValueRepr::String(ref key) if is_lua_name(key) => {
+                        format!("{}.{}", path, key)
+                    }
+                    LuaValueRepr::String(ref key) => format!("{}[{:?}]", path, key),
+                    LuaValueRepr::Number(key) => format!("{}[{}]", path, key),
+                    LuaValueRepr::Boolean(key) => format!("{}[{}]", path, key),
+                    _ => format!("{}[table]", path),
+                };
+                if let Some(found) = opaque_field(value, field) {
+                    return Some(found);
+                }
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// If the string can be written as a field name in lua, such as `player_1`, rather than in
+/// brackets.
+fn is_lua_name(name: &str) -> bool {
+    match name.chars().next() {
+        Some('a'...'z') | Some('A'...'Z') | Some('_') => {}
+        _ => return false,
+    }
+    name.chars().all(|c| {
+        match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => true,
+            _ => false,
+        }
+    })
+}
+
+/// The time taken by a handler for an event, recorded while profiling was on, see
+/// `Engine::profiling_report`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandlerProfile {
+    /// The module that added the handler.
+    pub module: String,
+    /// The name of the event, rather than the wildcard, for handlers added to a wildcard.
+    pub event: String,
+    /// The id of the handler, as returned by `buildengine.on`.
+    pub handler_id: u64,
+    /// The number of times the handler was called for the event.
+    pub calls: u64,
+    /// The time taken by every call.
+    pub total: Duration,
+    /// The time taken by the slowest call.
+    pub max: Duration,
+}
+
+impl HandlerProfile {
+    /// Reads an entry of prelude_buildengine.profiles, or None if it is malformed.
+    fn from_repr(entry: LuaValueRepr) -> Option<HandlerProfile> {
+        let fields = match entry {
+            LuaValueRepr::Array(fields) => fields,
+            _ => return None,
+        };
+        if fields.len() != 6 {
+            return None;
+        }
+        match (&fields[0], &fields[1], &fields[2], &fields[3], &fields[4], &fields[5]) {
+            (&LuaValueRepr::String(ref module),
+             &LuaValueRepr::String(ref event),
+             &LuaValueRepr::Number(handler_id),
+             &LuaValueRepr::Number(calls),
+             &LuaValueRepr::Number(total),
+             &LuaValueRepr::Number(max)) => {
+                Some(HandlerProfile {
+                    module: module.clone(),
+                    event: event.clone(),
+                    handler_id: handler_id as u64,
+                    calls: calls as u64,
+                    total: duration_from_secs(total),
+                    max: duration_from_secs(max),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How executing an event went, when none of it's handlers errored.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventOutcome {
+    /// Every handler was called, and the last returned these values.
+    Completed(Vec<AnyLuaValue>),
+    /// The handler at the given index, counting from 1 among the handlers the event had when it
+    /// was activated, returned `buildengine.CANCEL`, so the handlers after it weren't called.
+    Cancelled {
+        by_handler: usize,
+    },
+}
+
+impl EventOutcome {
+    /// The values returned by the last handler, which is none if the event was cancelled.
+    pub fn returns(self) -> Vec<AnyLuaValue> {
+        match self {
+            EventOutcome::Completed(returns) => returns,
+            EventOutcome::Cancelled { .. } => Vec::new(),
+        }
+    }
+
+    /// If a handler cancelled the event.
+    pub fn is_cancelled(&self) -> bool {
+        match *self {
+            EventOutcome::Completed(_) => false,
+            EventOutcome::Cancelled { .. } => true,
+        }
+    }
+}
+
+/// An error that can occour constructing a script::Engine, with `new_empty`, or loading it's
+/// scripts with `run_init_ordered`.
+#[derive(Debug)]
+pub enum InitError {
+    /// The prelude errored while running.
+    PreludeError(LuaError),
+    /// The code applying the sandbox errored while running.
+    SandboxError(LuaError),
+    /// The prelude ran, but the prelude_buildengine table wasn't there after it.
+    PreludeMissing,
+    /// The scripts, with the given names, didn't include an init script under
+    /// `InitPolicy::RequireInit`.
+    MissingInitScript(Vec<String>),
+    /// The init script was empty, or only whitespace, under `InitPolicy::RequireInit`.
+    EmptyInitScript,
+    /// One of the scripts has a name reserved by the engine, see `is_reserved_module`, so it could
+    /// be taken for the engine std.
+    ReservedModuleName(String),
+    /// The source of the engine std in prelude_buildengine.modules was changed while the scripts
+    /// were loading.
+    EngineStdReplaced,
+}
+
+impl Display for InitError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            InitError::PreludeError(ref err) => {
+                write!(fmt, "PreludeError: The prelude failed: {}", lua_error_message(err))
+            }
+            InitError::SandboxError(ref err) => {
+                write!(fmt,
+                       "SandboxError: Applying the sandbox failed: {}",
+                       lua_error_message(err))
+            }
+            InitError::PreludeMissing => write!(fmt, "PreludeMissing: {}", self.description()),
+            InitError::MissingInitScript(ref names) => {
+                write!(fmt,
+                       "MissingInitScript: There is no init script among the scripts: [{}]",
+                       names.join(", "))
+            }
+            InitError::EmptyInitScript => write!(fmt, "EmptyInitScript: {}", self.description()),
+            InitError::ReservedModuleName(ref name) => {
+                write!(fmt, "ReservedModuleName: {} is reserved by the engine.", name)
+            }
+            InitError::EngineStdReplaced => {
+                write!(fmt, "EngineStdReplaced: {}", self.description())
+            }
+        }
+    }
+}
+
+impl Error for InitError {
+    fn description(&self) -> &str {
+        match *self {
+            InitError::PreludeError(_) => "the prelude failed to run",
+            InitError::SandboxError(_) => "applying the sandbox failed",
+            InitError::PreludeMissing => "the prelude didn't make the prelude_buildengine table",
+            InitError::MissingInitScript(_) => "there is no init script among the scripts",
+            InitError::EmptyInitScript => "the init script is empty",
+            InitError::ReservedModuleName(_) => "a script has a name reserved by the engine",
+            InitError::EngineStdReplaced => "the scripts replaced the source of the engine std",
+        }
+    }
+}
+
+/// An error that can ocour executing an event.
+#[derive(Debug)]
+pub enum ExecEventError {
+    /// The buildengine standard library was not imported, so events aren't avalable.
+    EngineStdNotImported,
+    /// A lua error ocoured executing the event with the given name, outside of any of it's
+    /// handlers.
+    LuaError {
+        event: String,
+        error: LuaError,
+        traceback: Option<String>,
+    },
+    /// The handler at the given index, counting from 1 among the handlers the event had when it
+    /// was activated, errored with the message while executing the event with the given name.
+    /// The module that added the handler is given if it is known.
+    HandlerError {
+        event: String,
+        handler: usize,
+        module: Option<String>,
+        message: String,
+        traceback: Option<String>,
+    },
+    /// An argument to the event could not be converted to a lua value.
+    BadArgument(LuaReprError),
+    /// The prelude_buildengine table is gone, such as from a script setting it to nil, so no events
+    /// can be executed.
+    PreludeMissing,
+    /// The event name is empty, or is a wildcard such as `player.*`, which handlers can be added
+    /// to but can't be activated.
+    InvalidEventName(String),
+    /// The event with the given name ran over the execution limit, so it was aborted.
+    Timeout {
+        event: String,
+    },
+    /// The event with the given name made the interpreter use more memory than the memory limit,
+    /// so it was aborted. The memory in use at the time, in bytes, is given.
+    MemoryLimitExceeded {
+        event: String,
+        usage: usize,
+    },
+    /// The values the event with the given name returned couldn't be converted to the type asked
+    /// for with `Engine::exec_event_as`.
+    ReturnTypeMismatch {
+        event: String,
+        mismatch: LuaTypeMismatch,
+    },
+}
+
+impl Display for ExecEventError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ExecEventError::EngineStdNotImported => {
+                write!(fmt,
+                       "the standard library for the engine was not imported while trying to \
+                        execute an event")
+            }
+            ExecEventError::LuaError { ref event, ref error, .. } => {
+                write!(fmt,
+                       "a lua {} occoured while executing event {}",
+                       lua_error_message(error),
+                       event)
+            }
+            ExecEventError::HandlerError { ref event, handler, ref message, .. } => {
+                write!(fmt,
+                       "handler {} of event {} errored: {}",
+                       handler,
+                       event,
+                       message)
+            }
+            ExecEventError::BadArgument(ref err) => {
+                write!(fmt,
+                       "an argument to an event could not be converted to a lua value: {}",
+                       err)
+            }
+            ExecEventError::PreludeMissing => {
+                write!(fmt,
+                       "the prelude_buildengine table is missing, so events can't be executed")
+            }
+            ExecEventError::InvalidEventName(ref event) => {
+                write!(fmt, "{:?} is not a name an event can be executed with", event)
+            }
+            ExecEventError::Timeout { ref event } => {
+                write!(fmt, "event {} ran over the execution limit and was aborted", event)
+            }
+            ExecEventError::MemoryLimitExceeded { ref event, usage } => {
+                write!(fmt,
+                       "event {} ran over the memory limit using {} bytes and was aborted",
+                       event,
+                       usage)
+            }
+            ExecEventError::ReturnTypeMismatch { ref event, ref mismatch } => {
+                write!(fmt, "the values event {} returned had the wrong type: {}", event, mismatch)
+            }
+        }
+    }
+}
+
+impl ExecEventError {
+    /// The lua stack traceback of where the error was raised, with the module and line of each
+    /// function called, for showing along with the error.
+    ///
+    /// None if the error wasn't raised by lua code.
+    pub fn traceback(&self) -> Option<&str> {
+        match *self {
+            ExecEventError::LuaError { ref traceback, .. } |
+            ExecEventError::HandlerError { ref traceback, .. } => {
+                traceback.as_ref().map(|traceback| &traceback[..])
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Error for ExecEventError {
+    fn description(&self) -> &str {
+        match *self {
+            ExecEventError::EngineStdNotImported => {
+                "the standard library for the engine was not imported while trying to execute an \
+                 event"
+            }
+            ExecEventError::LuaError { .. } => "a lua error occoured while executing an event.",
+            ExecEventError::HandlerError { .. } => "a handler errored while executing an event.",
+            ExecEventError::BadArgument(ref _err) => {
+                "an argument to an event could not be converted to a lua value."
+            }
+            ExecEventError::PreludeMissing => "the prelude_buildengine table is missing.",
+            ExecEventError::InvalidEventName(_) => "an event name was empty or a wildcard.",
+            ExecEventError::Timeout { .. } => "an event ran over the execution limit.",
+            ExecEventError::MemoryLimitExceeded { .. } => "an event ran over the memory limit.",
+            ExecEventError::ReturnTypeMismatch { .. } => {
+                "the values an event returned had the wrong type."
+            }
+        }
+    }
+}
+
+/// An error of the scripts, as kept by `Engine::recent_errors`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptErrorReport {
+    /// The name of the event the error happened executing, or `timer <id>` for a timer.
+    pub event: String,
+    /// The module of the handler that errored, if it is known.
+    pub module: Option<String>,
+    /// The message of the error.
+    pub message: String,
+    /// The lua stack traceback of where the error was raised, if it was raised by lua code.
+    pub traceback: Option<String>,
+    /// When the error happened.
+    pub time: SystemTime,
+}
+
+impl ScriptErrorReport {
+    /// The report of the error, or None if it happened before any script ran, such as
+    /// `ExecEventError::InvalidEventName`.
+    fn from_error(err: &ExecEventError) -> Option<ScriptErrorReport> {
+        let (event, module, message) = match *err {
+            ExecEventError::LuaError { ref event, ref error, .. } => {
+                (event, None, lua_error_message(error))
+            }
+            ExecEventError::HandlerError { ref event, ref module, ref message, .. } => {
+                (event, module.clone(), message.clone())
+            }
+            ExecEventError::Timeout { ref event } |
+            ExecEventError::MemoryLimitExceeded { ref event, .. } => (event, None, err.to_string()),
+            _ => return None,
+        };
+        Some(ScriptErrorReport {
+            event: event.clone(),
+            module: module,
+            message: message,
+            traceback: err.traceback().map(|traceback| traceback.to_owned()),
+            time: SystemTime::now(),
+        })
+    }
+}
+
+/// If the event name isn't empty or a wildcard, so it can be activated.
+fn is_valid_event_name(event_name: &str) -> bool {
+    !event_name.is_empty() && event_name != "*" && !event_name.ends_with(".*")
+}
+
+/// The error of lua code run through the prelude_buildengine table once it is gone.
+fn prelude_missing_error() -> LuaError {
+    LuaError::ExecutionError("the prelude_buildengine table is gone".to_owned())
+}
+
+/// Describes the kind of a lua error, along with it's message if it has one.
+pub fn lua_error_message(err: &LuaError) -> String {
+    match *err {
+        LuaError::SyntaxError(ref message) => format!("syntax error: {}", message),
+        LuaError::ExecutionError(ref message) => format!("runtime error: {}", message),
+        LuaError::ReadError(ref err) => format!("read error: {}", err),
+        LuaError::WrongType => "wrong type error: a value was not of the type expected".to_owned(),
+    }
+}
+
+impl From<LuaReprError> for ExecEventError {
+    fn from(err: LuaReprError) -> Self {
+        ExecEventError::BadArgument(err)
+    }
+}
+
+/// Loads every .lua file in the directory and the directories in it as scripts, to be given to
+/// `Engine::new`.
+///
+/// Each script is named after it's path in the directory with the extension removed, with `.`
+/// between each directory, so `foo/bar.lua` is the module `foo.bar`, and `init.lua` is the init
+/// script. Other files are ignored, as are links to directories, though links to files are
+/// followed.
+///
+/// Module names are compared ignoring case, so scripts that load on one platform load on any
+/// other, as some filesystems, such as on Windows, ignore case.
+///
+/// # Errors
+/// * `ScriptLoadError::Io` if reading a directory or file failed.
+/// * `ScriptLoadError::NotUtf8` if a file, or the name of a file or directory it is in, is not
+///   valid UTF-8.
+/// * `ScriptLoadError::DuplicateModule` if two files have the same module name.
+pub fn load_scripts_from_dir(path: &Path) -> Result<HashMap<String, String>, ScriptLoadError> {
+    let mut scripts = HashMap::new();
+    let mut loaded_from = HashMap::new();
+    try!(load_dir(path, path, &mut scripts, &mut loaded_from));
+    Ok(scripts)
+}
+
+/// Loads the scripts in dir, a directory in root, and every directory in it.
+///
+/// loaded_from holds the path of every script loaded so far, by it's lowercased module name.
+fn load_dir(root: &Path,
+            dir: &Path,
+            scripts: &mut HashMap<String, String>,
+            loaded_from: &mut HashMap<String, PathBuf>)
+            -> Result<(), ScriptLoadError> {
+    let entries = try!(fs::read_dir(dir).map_err(|err| ScriptLoadError::Io(dir.to_owned(), err)));
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = try!(entry.map_err(|err| ScriptLoadError::Io(dir.to_owned(), err)));
+        paths.push(entry.path());
+    }
+    // Sorted so the same directory always gives the same errors.
+    paths.sort();
+    for path in paths {
+        let metadata = try!(fs::metadata(&path)
+                                .map_err(|err| ScriptLoadError::Io(path.clone(), err)));
+        if metadata.is_dir() {
+            let link = try!(fs::symlink_metadata(&path)
+                                .map_err(|err| ScriptLoadError::Io(path.clone(), err)));
+            // A link back up the tree would be followed untill the stack overflows.
+            if link.file_type().is_symlink() {
+                warn!("Skipping {}, as it links to a directory.", path.display());
+                continue;
+            }
+            try!(load_dir(root, &path, scripts, loaded_from));
+            continue;
+        }
+        if path.extension() != Some(OsStr::new("lua")) {
+            continue;
+        }
+        let name = try!(module_name(root, &path));
+        let mut bytes = Vec::new();
+        try!(File::open(&path)
+                 .and_then(|mut file| file.read_to_end(&mut bytes))
+                 .map_err(|err| ScriptLoadError::Io(path.clone(), err)));
+        let source = try!(String::from_utf8(bytes)
+                              .map_err(|_err| ScriptLoadError::NotUtf8(path.clone())));
+        let key = name.to_lowercase();
+        if let Some(other) = loaded_from.get(&key) {
+            return Err(ScriptLoadError::DuplicateModule(name, path.clone(), other.clone()));
+        }
+        loaded_from.insert(key, path.clone());
+        scripts.insert(name, source);
+    }
+    Ok(())
+}
+
+/// The name of the module for the .lua file at path, in root.
+fn module_name(root: &Path, path: &Path) -> Result<String, ScriptLoadError> {
+    let relative = path.strip_prefix(root).expect("a script was loaded from outside it's root");
+    let mut parts = Vec::new();
+    for component in relative.with_extension("").components() {
+        match component.as_os_str().to_str() {
+            Some(part) => parts.push(part.to_owned()),
+            None => return Err(ScriptLoadError::NotUtf8(path.to_owned())),
+        }
+    }
+    Ok(parts.join("."))
+}
+
+/// The scripts sorted by name, the order `Engine::new` loads them in before taking their
+/// dependencies into account.
+pub fn scripts_by_name(scripts: HashMap<String, String>) -> Vec<(String, String)> {
+    let mut scripts: Vec<(String, String)> = scripts.into_iter().collect();
+    scripts.sort_by(|a, b| a.0.cmp(&b.0));
+    scripts
+}
+
+/// The modules a script depends on, declared in the comments at the top of it as
+/// `--@depends foo, bar`, which may be given more than once.
+///
+/// Only the comments before the first line of code are read.
+pub fn script_dependencies(source: &str) -> Vec<String> {
+    let mut dependencies = Vec::new();
+    for line in source.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with("--") {
+            break;
+        }
+        if line.starts_with("--@depends ") {
+            for name in line["--@depends ".len()..].split(',') {
+                let name = name.trim();
+                if !name.is_empty() {
+                    dependencies.push(name.to_owned());
+                }
+            }
+        }
+    }
+    dependencies
+}
+
+/// Sorts the scripts so each comes after the scripts it depends on, as given by
+/// `script_dependencies`, keeping the order they are given in otherwise. The init script is
+/// always last, and every script may depend on the engine std, "buildengine".
+///
+/// # Errors
+/// * `::InitError::MissingScriptDependency` if a script depends on one that isn't given, or on the
+///   init script.
+/// * `::InitError::ScriptDependencyCycle` if scripts depend on each other, with the names of the
+///   scripts in the cycle, starting and ending with the same one.
+pub fn order_scripts(scripts: Vec<(String, String)>)
+                     -> Result<Vec<(String, String)>, ::InitError> {
+    let dependencies: Vec<Vec<String>> = scripts.iter()
+                                                .map(|&(_, ref source)| script_dependencies(source))
+                                                .collect();
+    let mut indexes = HashMap::new();
+    for (i, &(ref name, _)) in scripts.iter().enumerate() {
+        indexes.insert(name.clone(), i);
+    }
+    for (i, &(ref name, _)) in scripts.iter().enumerate() {
+        for dependency in &dependencies[i] {
+            if !can_depend_on(dependency, indexes.contains_key(dependency)) {
+                return Err(::InitError::MissingScriptDependency(name.clone(), dependency.clone()));
+            }
+        }
+    }
+    let mut placed = vec![false; scripts.len()];
+    let mut order = Vec::with_capacity(scripts.len());
+    while order.len() < scripts.len() {
+        let remaining = scripts.len() - order.len();
+        let next = (0..scripts.len()).find(|&i| {
+            !placed[i] && (scripts[i].0 != "init" || remaining == 1) &&
+            dependencies[i].iter().all(|dependency| {
+                indexes.get(dependency).map(|&dep| placed[dep]).unwrap_or(true)
+            })
+        });
+        match next {
+            Some(i) => {
+                placed[i] = true;
+                order.push(i);
+            }
+            None => {
+                // Every script left, other than init, depends on another one left, so following
+                // the dependencies from any of them leads into a cycle. Init is left as well
+                // untill the others are placed, but nothing can depend on it.
+                let unplaced_dependency = |i: usize| {
+                    dependencies[i]
+                        .iter()
+                        .filter_map(|dependency| indexes.get(dependency).cloned())
+                        .find(|&dep| !placed[dep])
+                };
+                let mut path: Vec<usize> = Vec::new();
+                let mut next = (0..scripts.len())
+                                   .find(|&i| !placed[i] && unplaced_dependency(i).is_some());
+                while let Some(current) = next {
+                    if path.contains(&current) {
+                        break;
+                    }
+                    path.push(current);
+                    next = unplaced_dependency(current);
+                }
+                let current = match next {
+                    Some(current) => current,
+                    None => {
+                        let left = (0..scripts.len())
+                                       .filter(|&i| !placed[i])
+                                       .map(|i| scripts[i].0.clone())
+                                       .collect();
+                        return Err(::InitError::ScriptDependencyCycle(left));
+                    }
+                };
+                let start = path.iter().position(|&i| i == current).unwrap();
+                let mut cycle: Vec<String> = path[start..]
+                                                 .iter()
+                                                 .map(|&i| scripts[i].0.clone())
+                                                 .collect();
+                cycle.push(scripts[current].0.clone());
+                return Err(::InitError::ScriptDependencyCycle(cycle));
+            }
+        }
+    }
+    let mut scripts: Vec<Option<(String, String)>> = scripts.into_iter().map(Some).collect();
+    Ok(order.into_iter().map(|i| scripts[i].take().unwrap()).collect())
+}
+
+/// If a script can depend on the named script, given if one of the scripts has the name.
+fn can_depend_on(dependency: &str, exists: bool) -> bool {
+    dependency != "init" && (dependency == "buildengine" || exists)
+}
+
+/// Checks the scripts for syntax errors and missing dependencies without running any of them,
+/// such as before uploading them to a server.
+///
+/// Each script is only compiled, in an interpreter of it's own, so nothing in the scripts or the
+/// prelude is ever executed. Every problem with every script is returned, sorted by the name of
+/// the script, and none if they would load.
+pub fn validate(scripts: &HashMap<String, String>) -> Vec<ScriptDiagnostic> {
+    let mut names: Vec<&String> = scripts.keys().collect();
+    names.sort();
+    let mut lua = Lua::new();
+    lua.openlibs();
+    let mut diagnostics = Vec::new();
+    for name in names {
+        let source = &scripts[name];
+        lua.set("validate_name", &name[..]);
+        lua.set("validate_src", &source[..]);
+        // Only as text, as the engine never loads precompiled chunks.
+        let syntax_error: Result<AnyLuaValue, LuaError> =
+            lua.execute("local _, err = load(validate_src, validate_name, \"t\") return err");
+        let syntax_error = match syntax_error {
+            Ok(syntax_error) => syntax_error,
+            Err(err) => AnyLuaValue::LuaString(lua_error_message(&err)),
+        };
+        if let AnyLuaValue::LuaString(message) = syntax_error {
+            diagnostics.push(ScriptDiagnostic::SyntaxError {
+                script: name.clone(),
+                line: error_line(&message),
+                message: message,
+            });
+        }
+        for dependency in script_dependencies(source) {
+            if !can_depend_on(&dependency, scripts.contains_key(&dependency)) {
+                diagnostics.push(ScriptDiagnostic::MissingDependency {
+                    script: name.clone(),
+                    dependency: dependency,
+                });
+            }
+        }
+    }
+    diagnostics
+}
+
+/// The line a lua error message, such as `[string "mymodule"]:12: unexpected symbol`, points to.
+fn error_line(message: &str) -> Option<usize> {
+    let after = &message[message.find("]:").map(|i| i + 2).unwrap_or(message.len())..];
+    after.split(':').next().and_then(|line| line.parse().ok())
+}
+
+/// A problem with a script found by `validate`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptDiagnostic {
+    /// The script doesn't compile, with the line of the error if the message has one.
+    SyntaxError {
+        script: String,
+        line: Option<usize>,
+        message: String,
+    },
+    /// The script depends on one that doesn't exist, or on the init script.
+    MissingDependency {
+        script: String,
+        dependency: String,
+    },
+}
+
+impl Display for ScriptDiagnostic {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ScriptDiagnostic::SyntaxError { ref script, line: Some(line), ref message } => {
+                write!(fmt, "SyntaxError: {} line {}: {}", script, line, message)
+            }
+            ScriptDiagnostic::SyntaxError { ref script, line: None, ref message } => {
+                write!(fmt, "SyntaxError: {}: {}", script, message)
+            }
+            ScriptDiagnostic::MissingDependency { ref script, ref dependency } => {
+                write!(fmt,
+                       "MissingDependency: {} depends on {}, which doesn't exist.",
+                       script,
+                       dependency)
+            }
+        }
+    }
+}
+
+/// An error that can occour loading scripts with `load_scripts_from_dir`.
+#[derive(Debug)]
+pub enum ScriptLoadError {
+    /// Reading the file or directory at the path failed.
+    Io(PathBuf, io::Error),
+    /// The file at the path, or it's name, is not valid UTF-8.
+    NotUtf8(PathBuf),
+    /// The file at the first path has the same module name as the file at the second path, apart
+    /// from case.
+    DuplicateModule(String, PathBuf, PathBuf),
+}
+
+impl Display for ScriptLoadError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ScriptLoadError::Io(ref path, ref err) => {
+                write!(fmt, "Io: Reading {} failed: {}", path.display(), err)
+            }
+            ScriptLoadError::NotUtf8(ref path) => {
+                write!(fmt, "NotUtf8: {} is not valid UTF-8.", path.display())
+            }
+            ScriptLoadError::DuplicateModule(ref name, ref path, ref other) => {
+                write!(fmt,
+                       "DuplicateModule: {} is module {}, which {} already is.",
+                       path.display(),
+                       name,
+                       other.display())
+            }
+        }
+    }
+}
+
+impl Error for ScriptLoadError {
+    fn description(&self) -> &str {
+        match *self {
+            ScriptLoadError::Io(_, ref err) => err.description(),
+            ScriptLoadError::NotUtf8(_) => "NotUtf8: A script is not valid UTF-8.",
+            ScriptLoadError::DuplicateModule(..) => {
+                "DuplicateModule: Two scripts have the same module name."
+            }
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ScriptLoadError::Io(_, ref err) => Some(err),
+            ScriptLoadError::NotUtf8(_) | ScriptLoadError::DuplicateModule(..) => None,
+        }
+    }
+}
+
+/// The names of scripts that can't be loaded, reloaded or unloaded as modules: the init script,
+/// and the engine std.
+pub const RESERVED_MODULES: [&'static str; 2] = ["init", "buildengine"];
+
+/// The start of the names of modules kept for the engine std, such as `buildengine.physics`, which
+/// scripts can't use for their own modules.
+pub const RESERVED_MODULE_PREFIX: &'static str = "buildengine.";
+
+/// If the name is one of RESERVED_MODULES, or starts with RESERVED_MODULE_PREFIX.
+pub fn is_reserved_module(name: &str) -> bool {
+    RESERVED_MODULES.contains(&name) || name.starts_with(RESERVED_MODULE_PREFIX)
+}
+
+/// Errors if the name is reserved, see `is_reserved_module`.
+fn check_module_name(name: &str) -> Result<(), ReloadError> {
+    if is_reserved_module(name) {
+        Err(ReloadError::ReservedName(name.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+/// An error that can occour loading, reloading or unloading a module, such as with
+/// `Engine::reload_module`.
+#[derive(Debug)]
+pub enum ReloadError {
+    /// The name is reserved by the engine, see `is_reserved_module`.
+    ReservedName(String),
+    /// There is no module with the name.
+    UnknownModule(String),
+    /// A module already has the name.
+    DuplicateModule(String),
+    /// The new source of the module has a syntax error, with the message.
+    SyntaxError(String),
+    /// The new version of the module errored while loading, with the message.
+    RuntimeError(String),
+    /// Executing "on_module_reloaded" failed.
+    Event(ExecEventError),
+}
+
+impl Display for ReloadError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ReloadError::ReservedName(ref name) => {
+                write!(fmt, "ReservedName: {} is reserved by the engine.", name)
+            }
+            ReloadError::UnknownModule(ref name) => {
+                write!(fmt, "UnknownModule: There is no module {}.", name)
+            }
+            ReloadError::DuplicateModule(ref name) => {
+                write!(fmt, "DuplicateModule: There is already a module {}.", name)
+            }
+            ReloadError::SyntaxError(ref message) => {
+                write!(fmt, "SyntaxError: The new source has a syntax error: {}", message)
+            }
+            ReloadError::RuntimeError(ref message) => {
+                write!(fmt, "RuntimeError: The new version errored while loading: {}", message)
+            }
+            ReloadError::Event(ref err) => {
+                write!(fmt, "Event: Executing on_module_reloaded failed: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for ReloadError {
+    fn description(&self) -> &str {
+        match *self {
+            ReloadError::ReservedName(_) => "ReservedName: The name is reserved by the engine.",
+            ReloadError::UnknownModule(_) => "UnknownModule: There is no module with the name.",
+            ReloadError::DuplicateModule(_) => "DuplicateModule: A module already has the name.",
+            ReloadError::SyntaxError(_) => "SyntaxError: The new source has a syntax error.",
+            ReloadError::RuntimeError(_) => {
+                "RuntimeError: The new version of the module errored while loading."
+            }
+            ReloadError::Event(_) => "Event: Executing on_module_reloaded failed.",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ReloadError::Event(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// If the dotted path of fields is not empty, and has no empty parts.
+fn is_valid_path(path: &str) -> bool {
+    path.split('.').all(|part| !part.is_empty())
+}
+
+/// An error that can occour setting a value with `Engine::set_path`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathError {
+    /// The path is empty or has an empty part.
+    InvalidPath(String),
+    /// The table at the path, on the way to the one being set, is nil.
+    MissingTable(String),
+    /// The value at the path, on the way to the one being set, isn't a table.
+    NotATable(String),
+    /// The prelude_buildengine table, which paths are set through, is gone, such as from a script
+    /// setting it to nil.
+    PreludeMissing,
+}
+
+impl Display for PathError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            PathError::InvalidPath(ref path) => {
+                write!(fmt, "InvalidPath: {:?} is not a valid path.", path)
+            }
+            PathError::MissingTable(ref path) => write!(fmt, "MissingTable: {} is nil.", path),
+            PathError::NotATable(ref path) => write!(fmt, "NotATable: {} isn't a table.", path),
+            PathError::PreludeMissing => write!(fmt, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for PathError {
+    fn description(&self) -> &str {
+        match *self {
+            PathError::InvalidPath(_) => "InvalidPath: The path is empty or has an empty part.",
+            PathError::MissingTable(_) => "MissingTable: A table on the way is nil.",
+            PathError::NotATable(_) => "NotATable: A value on the way isn't a table.",
+            PathError::PreludeMissing => {
+                "PreludeMissing: The prelude_buildengine table is gone, so nothing can be set."
+            }
+        }
+    }
+}
+
+/// A copy of a lua value that can be serialized, to send it over the network or save it.
+///
+/// Converting from an AnyLuaValue never fails, and converting back gives the same value, unless it
+/// can't exist in lua.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LuaValueRepr {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    /// A table with the keys 1 to the length of the Vec, as created by `{a, b, c}` in lua.
+    Array(Vec<LuaValueRepr>),
+    /// Any other table, as it's key value pairs in no particular order.
+    Table(Vec<(LuaValueRepr, LuaValueRepr)>),
+    /// A function, userdata, or thread. These can't be copied, so only the fact there was one is
+    /// kept.
+    Opaque,
+}
+
+impl From<AnyLuaValue> for LuaValueRepr {
+    fn from(value: AnyLuaValue) -> LuaValueRepr {
+        match value {
+            AnyLuaValue::LuaNil => LuaValueRepr::Nil,
+            AnyLuaValue::LuaBoolean(val) => LuaValueRepr::Boolean(val),
+            AnyLuaValue::LuaNumber(val) => LuaValueRepr::Number(val),
+            AnyLuaValue::LuaString(val) => LuaValueRepr::String(val),
+            AnyLuaValue::LuaArray(pairs) => {
+                let pairs: Vec<(LuaValueRepr, LuaValueRepr)> =
+                    pairs.into_iter()
+                         .map(|(key, value)| (key.into(), value.into()))
+                         .collect();
+                match array_indexes(&pairs) {
+                    Some(indexes) => {
+                        let mut indexed: Vec<(usize, LuaValueRepr)> =
+                            indexes.into_iter()
+                                   .zip(pairs.into_iter().map(|(_key, value)| value))
+                                   .collect();
+                        indexed.sort_by_key(|&(index, _)| index);
+                        LuaValueRepr::Array(indexed.into_iter().map(|(_, value)| value).collect())
+                    }
+                    None => LuaValueRepr::Table(pairs),
+                }
+            }
+            AnyLuaValue::LuaOther => LuaValueRepr::Opaque,
+        }
+    }
+}
+
+/// Returns the index of each pair if the keys are exactly the whole numbers 1 to the number of
+/// pairs, in any order.
+fn array_indexes(pairs: &[(LuaValueRepr, LuaValueRepr)]) -> Option<Vec<usize>> {
+    let mut seen = vec![false; pairs.len()];
+    let mut indexes = Vec::new();
+    for &(ref key, _) in pairs {
+        let index = match *key {
+            LuaValueRepr::Number(num) if num >= 1.0 && num <= pairs.len() as f64 &&
+                                         num.fract() == 0.0 => num as usize,
+            _ => return None,
+        };
+        if seen[index - 1] {
+            return None;
+        }
+        seen[index - 1] = true;
+        indexes.push(index);
+    }
+    Some(indexes)
+}
+
+impl TryFrom<LuaValueRepr> for AnyLuaValue {
+    type Err = LuaReprError;
+
+    fn try_from(repr: LuaValueRepr) -> Result<AnyLuaValue, LuaReprError> {
+        Ok(match repr {
+            LuaValueRepr::Nil => AnyLuaValue::LuaNil,
+            LuaValueRepr::Boolean(val) => AnyLuaValue::LuaBoolean(val),
+            LuaValueRepr::Number(val) => AnyLuaValue::LuaNumber(val),
+            LuaValueRepr::String(val) => AnyLuaValue::LuaString(val),
+            LuaValueRepr::Array(values) => {
+                let mut pairs = Vec::new();
+                for (i, value) in values.into_iter().enumerate() {
+                    pairs.push((AnyLuaValue::LuaNumber((i + 1) as f64), try!(value.try_into())));
+                }
+                AnyLuaValue::LuaArray(pairs)
+            }
+            LuaValueRepr::Table(repr_pairs) => {
+                let mut pairs = Vec::new();
+                for (key, value) in repr_pairs {
+                    match key {
+                        LuaValueRepr::Nil => return Err(LuaReprError::NilKey),
+                        LuaValueRepr::Number(num) if num.is_nan() => {
+                            return Err(LuaReprError::NanKey)
+                        }
+                        _ => {}
+                    }
+                    pairs.push((try!(key.try_into()), try!(value.try_into())));
+                }
+                AnyLuaValue::LuaArray(pairs)
+            }
+            LuaValueRepr::Opaque => return Err(LuaReprError::Opaque),
+        })
+    }
+}
+
+/// An error that can occour converting a LuaValueRepr back into a lua value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LuaReprError {
+    /// A table has a nil key, which lua does not allow.
+    NilKey,
+    /// A table has a NaN key, which lua does not allow.
+    NanKey,
+    /// The value is, or contains, a function, userdata, or thread, which can not be recreated.
+    Opaque,
+}
+
+impl Display for LuaReprError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(fmt, "{}", self.description())
+    }
+}
+
+impl Error for LuaReprError {
+    fn description(&self) -> &str {
+        match *self {
+            LuaReprError::NilKey => "NilKey: A table has a nil key.",
+            LuaReprError::NanKey => "NanKey: A table has a NaN key.",
+            LuaReprError::Opaque => {
+                "Opaque: The value contains a function, userdata, or thread, which can't be \
+                 recreated."
+            }
+        }
+    }
+}
+
+/// The values in a table packed by `table.pack`, which has the number of them as `n` so trailing
+/// nils aren't lost, or none if it is nil.
+fn unpack_returns(packed: AnyLuaValue) -> Vec<AnyLuaValue> {
+    let pairs = match packed {
+        AnyLuaValue::LuaArray(pairs) => pairs,
+        _ => return Vec::new(),
+    };
+    let count = pairs.iter()
+                     .filter_map(|&(ref key, ref value)| {
+                         match (key, value) {
+                             (&AnyLuaValue::LuaString(ref key), &AnyLuaValue::LuaNumber(count))
+                                 if key == "n" => Some(count as usize),
+                             _ => None,
+                         }
+                     })
+                     .next()
+                     .unwrap_or(0);
+    let mut values = vec![AnyLuaValue::LuaNil; count];
+    for (key, value) in pairs {
+        if let AnyLuaValue::LuaNumber(index) = key {
+            let index = index as usize;
+            if index >= 1 && index <= count {
+                values[index - 1] = value;
+            }
+        }
+    }
+    values
+}
+
+/// A limit given to the watchdog as a lua literal, nil for none.
+fn lua_limit(limit: Option<u64>) -> String {
+    match limit {
+        Some(limit) => limit.to_string(),
+        None => "nil".to_owned(),
+    }
+}
+
+/// The duration of a number of seconds, or 0 if it isn't positive. Seconds too many for a
+/// Duration give the longest one.
+fn duration_from_secs(seconds: f64) -> Duration {
+    if seconds.is_nan() || seconds <= 0.0 {
+        Duration::from_secs(0)
+    } else if seconds >= u64::MAX as f64 {
+        Duration::new(u64::MAX, 999_999_999)
+    } else {
+        Duration::new(seconds.trunc() as u64, (seconds.fract() * 1e9) as u32)
+    }
+}
+
+/// The number of seconds in the duration.
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Appends a lua string literal of the string to code.
+fn write_lua_string(string: &str, code: &mut String) {
+    code.push('"');
+    for byte in string.bytes() {
+        match byte {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b' ' => code.push(byte as char),
+            _ => write!(code, "\\{:03}", byte).unwrap(),
+        }
+    }
+    code.push('"');
+}
+
+/// Appends lua code evaluating to the value to the string.
+fn write_lua_literal(value: &AnyLuaValue, code: &mut String) {
+    match *value {
+        AnyLuaValue::LuaNil | AnyLuaValue::LuaOther => code.push_str("nil"),
+        AnyLuaValue::LuaBoolean(val) => code.push_str(if val { "true" } else { "false" }),
+        AnyLuaValue::LuaNumber(val) => {
+            if val.is_nan() {
+                code.push_str("(0/0)");
+            } else if val.is_infinite() {
+                code.push_str(if val > 0.0 { "(1/0)" } else { "(-1/0)" });
+            } else {
+                // Display gives the shortest representation that reads back as the same number.
+                write!(code, "({})", val).unwrap();
+            }
+        }
+        AnyLuaValue::LuaString(ref val) => write_lua_string(val, code),
+        AnyLuaValue::LuaArray(ref pairs) => {
+            code.push('{');
+            for &(ref key, ref value) in pairs {
+                code.push('[');
+                write_lua_literal(key, code);
+                code.push_str("] = ");
+                write_lua_literal(value, code);
+                code.push_str(", ");
+            }
+            code.push('}');
+        }
+    }
+}
+
+/// The text a command prints for a value it returned. Strings are printed as they are, numbers
+/// as they would by lua, and other values as lua literals.
+fn command_output(value: &AnyLuaValue) -> String {
+    match *value {
+        AnyLuaValue::LuaString(ref string) => string.clone(),
+        AnyLuaValue::LuaNumber(number) if number.is_finite() => number.to_string(),
+        ref value => {
+            let mut output = String::new();
+            write_lua_literal(value, &mut output);
+            output
+        }
+    }
+}
+
+/// The arguments a native function was called with, from the number of them and the table of
+/// them made by prelude_buildengine.native.
+///
+/// The arguments past MAX_NATIVE_NILS more than the values in the table are left out, and the
+/// vector grows as the values are put in it, so neither the count nor the indexes lua gives can
+/// make it larger than that.
+fn native_args(count: f64, args: AnyLuaValue) -> Vec<AnyLuaValue> {
+    let pairs = match args {
+        AnyLuaValue::LuaArray(pairs) => pairs,
+        _ => Vec::new(),
+    };
+    let max_len = (pairs.len() + MAX_NATIVE_NILS) as f64;
+    // Compared as floats, as a count that isn't a whole number from 0 can't be cast.
+    let len = if count >= 0.0 && count.fract() == 0.0 {
+        if count < max_len { count } else { max_len }
+    } else {
+        0.0
+    };
+    let mut vec = Vec::new();
+    for (key, value) in pairs {
+        if let AnyLuaValue::LuaNumber(index) = key {
+            if index >= 1.0 && index <= len && index.fract() == 0.0 {
+                let index = index as usize;
+                if vec.len() < index {
+                    vec.resize(index, AnyLuaValue::LuaNil);
+                }
+                vec[index - 1] = value;
+            }
+        }
+    }
+    // The nils after the last value, which aren't in the table.
+    vec.resize(len as usize, AnyLuaValue::LuaNil);
+    vec
+}
+
+/// Converts a lua array with whole, numeric keys to a rust vector.
+///
+/// # Panics
+/// If `try_any_lua_to_vec` would return an error.
+pub fn any_lua_to_vec(any: AnyLuaValue) -> Vec<AnyLuaValue> {
+    match try_any_lua_to_vec(any) {
+        Ok(vec) => vec,
+        Err(err) => panic!("called any_lua_to_vec on a value that isn't an array: {}", err),
+    }
+}
+
+/// Converts a lua array to a rust vector, with the value at lua index 1 first.
+///
+/// The keys may be in any order, as lua gives them. Lua arrays start at 1, so a key of 0, as
+/// tables made for 0-based languages have, is not an index. Indexes missing from the array, such
+/// as `{[1] = "a", [3] = "c"}`, are filled with `AnyLuaValue::LuaNil`, the value lua gives when
+/// they are indexed.
+///
+/// # Errors
+/// * `LuaConversionError::NotATable` if the value is not a table.
+/// * `LuaConversionError::BadKey` if a key is not a number, such as in a table mixing an array
+///   with string keys.
+/// * `LuaConversionError::NotAnIndex` if a key is a number other than a whole number from 1.
+/// * `LuaConversionError::TooSparse` if less than half the indexes up to the highest are in the
+///   array, so a table such as `{[1e9] = true}` can't fill memory with nils.
+pub fn try_any_lua_to_vec(any: AnyLuaValue) -> Result<Vec<AnyLuaValue>, LuaConversionError> {
+    let pairs = match any {
+        AnyLuaValue::LuaArray(pairs) => pairs, // Ye a pirate!
+        other => return Err(LuaConversionError::NotATable(lua_type_name(&other))),
+    };
+    let mut indexed = Vec::with_capacity(pairs.len());
+    for (key, value) in pairs {
+        indexed.push((try!(lua_index(key)), value));
+    }
+    fill_indexes(indexed, AnyLuaValue::LuaNil)
+}
+
+/// The array index the key is, if it is a whole number from 1.
+fn lua_index(key: AnyLuaValue) -> Result<f64, LuaConversionError> {
+    match key {
+        AnyLuaValue::LuaNumber(num) if num >= 1.0 && num.fract() == 0.0 => Ok(num),
+        AnyLuaValue::LuaNumber(num) => Err(LuaConversionError::NotAnIndex(num)),
+        other => Err(LuaConversionError::BadKey(lua_type_name(&other))),
+    }
+}
+
+/// Places each value at it's index in a vector, filling the gaps with nil, as described in
+/// `try_any_lua_to_vec`.
+fn fill_indexes<T: Clone>(indexed: Vec<(f64, T)>, nil: T) -> Result<Vec<T>, LuaConversionError> {
+    let highest = indexed.iter().fold(0.0, |highest, &(index, _)| f64::max(highest, index));
+    if highest > (indexed.len() * 2) as f64 {
+        return Err(LuaConversionError::TooSparse {
+            len: indexed.len(),
+            highest: highest,
+        });
+    }
+    let mut vec = vec![nil; highest as usize];
+    for (index, value) in indexed {
+        vec[index as usize - 1] = value;
+    }
+    Ok(vec)
+}
+
+/// Converts a lua table with string keys to a rust map.
+///
+/// # Errors
+/// * `LuaConversionError::NotATable` if the value is not a table.
+/// * `LuaConversionError::BadKey` if a key is not a string. Use `any_lua_to_data` for tables
+///   that are part array.
+pub fn any_lua_to_map(any: AnyLuaValue)
+                      -> Result<HashMap<String, AnyLuaValue>, LuaConversionError> {
+    let pairs = match any {
+        AnyLuaValue::LuaArray(pairs) => pairs,
+        other => return Err(LuaConversionError::NotATable(lua_type_name(&other))),
+    };
+    let mut map = HashMap::with_capacity(pairs.len());
+    for (key, value) in pairs {
+        match key {
+            AnyLuaValue::LuaString(key) => {
+                map.insert(key, value);
+            }
+            other => return Err(LuaConversionError::BadKey(lua_type_name(&other))),
+        }
+    }
+    Ok(map)
+}
+
+/// Converts a rust map to a lua table with the same keys, the inverse of `any_lua_to_map`.
+pub fn map_to_any_lua(map: HashMap<String, AnyLuaValue>) -> AnyLuaValue {
+    AnyLuaValue::LuaArray(map.into_iter()
+                             .map(|(key, value)| (AnyLuaValue::LuaString(key), value))
+                             .collect())
+}
+
+/// The deepest tables may be nested when converted by `any_lua_to_data`, counting the outermost
+/// table as 1.
+pub const MAX_TABLE_DEPTH: usize = 64;
+
+/// Structured data from lua, such as the arguments of a network event or state being saved, with
+/// every table split into it's array and map parts.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LuaData {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Table {
+        /// The values with whole number keys from 1, as converted by `try_any_lua_to_vec`.
+        array: Vec<LuaData>,
+        /// The values with string keys.
+        map: HashMap<String, LuaData>,
+    },
+}
+
+/// Converts a lua value and every table in it to `LuaData`, nesting tables at most
+/// `MAX_TABLE_DEPTH` deep.
+///
+/// # Errors
+/// The same as `any_lua_to_data_with_depth`.
+pub fn any_lua_to_data(any: AnyLuaValue) -> Result<LuaData, LuaConversionError> {
+    any_lua_to_data_with_depth(any, MAX_TABLE_DEPTH)
+}
+
+/// Converts a lua value and every table in it to `LuaData`, nesting tables at most max_depth
+/// deep.
+///
+/// # Errors
+/// * `LuaConversionError::BadValue` if a value is a function, userdata, or thread.
+/// * `LuaConversionError::BadKey` if a key is not a string or a number.
+/// * `LuaConversionError::NotAnIndex` or `LuaConversionError::TooSparse` if the array part of a
+///   table can't be converted, as described in `try_any_lua_to_vec`.
+/// * `LuaConversionError::TooDeep` if tables are nested deeper than max_depth, such as when a
+///   script builds a table containing itself.
+pub fn any_lua_to_data_with_depth(any: AnyLuaValue,
+                                  max_depth: usize)
+                                  -> Result<LuaData, LuaConversionError> {
+    data_at_depth(any, 1, max_depth)
+}
+
+/// Converts the value for `any_lua_to_data_with_depth`, where a table would be nested depth deep.
+fn data_at_depth(any: AnyLuaValue,
+                 depth: usize,
+                 max_depth: usize)
+                 -> Result<LuaData, LuaConversionError> {
+    match any {
+        AnyLuaValue::LuaNil => Ok(LuaData::Nil),
+        AnyLuaValue::LuaBoolean(val) => Ok(LuaData::Boolean(val)),
+        AnyLuaValue::LuaNumber(val) => Ok(LuaData::Number(val)),
+        AnyLuaValue::LuaString(val) => Ok(LuaData::String(val)),
+        AnyLuaValue::LuaOther => Err(LuaConversionError::BadValue("other")),
+        AnyLuaValue::LuaArray(pairs) => {
+            if depth > max_depth {
+                return Err(LuaConversionError::TooDeep(max_depth));
+            }
+            let mut indexed = Vec::new();
+            let mut map = HashMap::new();
+            for (key, value) in pairs {
+                let value = try!(data_at_depth(value, depth + 1, max_depth));
+                match key {
+                    AnyLuaValue::LuaString(key) => {
+                        map.insert(key, value);
+                    }
+                    key => indexed.push((try!(lua_index(key)), value)),
+                }
+            }
+            Ok(LuaData::Table {
+                array: try!(fill_indexes(indexed, LuaData::Nil)),
+                map: map,
+            })
+        }
+    }
+}
+
+/// Converts `LuaData` back into a lua value, the inverse of `any_lua_to_data`.
+pub fn data_to_any_lua(data: LuaData) -> AnyLuaValue {
+    match data {
+        LuaData::Nil => AnyLuaValue::LuaNil,
+        LuaData::Boolean(val) => AnyLuaValue::LuaBoolean(val),
+        LuaData::Number(val) => AnyLuaValue::LuaNumber(val),
+        LuaData::String(val) => AnyLuaValue::LuaString(val),
+        LuaData::Table { array, map } => {
+            let mut pairs: Vec<(AnyLuaValue, AnyLuaValue)> =
+                array.into_iter()
+                     .enumerate()
+                     .map(|(i, value)| {
+                         (AnyLuaValue::LuaNumber(i as f64 + 1.0), data_to_any_lua(value))
+                     })
+                     .collect();
+            pairs.extend(map.into_iter().map(|(key, value)| {
+                (AnyLuaValue::LuaString(key), data_to_any_lua(value))
+            }));
+            AnyLuaValue::LuaArray(pairs)
+        }
+    }
+}
+
+/// A rust type the values returned by an event can be converted to, with `Engine::exec_event_as`.
+///
+/// Implemented for bool, i64, f64, String, and Option, Vec and tuples of up to 4 elements of those.
+/// The first value returned is converted, or nil if there were none, apart from tuples, which
+/// convert a value returned for each of their elements in order, any past them being ignored.
+pub trait FromLua: Sized {
+    /// The lua type converted from, such as `boolean` or `array of string`, for errors.
+    fn expected() -> String;
+
+    /// Converts a single lua value.
+    fn from_lua(value: AnyLuaValue) -> Result<Self, LuaTypeMismatch>;
+
+    /// Converts the values an event returned, which is the first of them unless overridden.
+    fn from_lua_returns(values: Vec<AnyLuaValue>) -> Result<Self, LuaTypeMismatch> {
+        let first = values.into_iter().next().unwrap_or(AnyLuaValue::LuaNil);
+        Self::from_lua(first).map_err(|mismatch| mismatch.within("return value 1"))
+    }
+}
+
+impl FromLua for bool {
+    fn expected() -> String {
+        "boolean".to_owned()
+    }
+
+    fn from_lua(value: AnyLuaValue) -> Result<Self, LuaTypeMismatch> {
+        match value {
+            AnyLuaValue::LuaBoolean(val) => Ok(val),
+            other => Err(LuaTypeMismatch::new(Self::expected(), &other)),
+        }
+    }
+}
+
+impl FromLua for i64 {
+    fn expected() -> String {
+        "integer".to_owned()
+    }
+
+    /// Numbers with a fraction, or too large for an i64, aren't converted rather than rounded.
+    fn from_lua(value: AnyLuaValue) -> Result<Self, LuaTypeMismatch> {
+        // i64::max_value() as f64 rounds up to 2^63, which is out of range itself.
+        let in_range = |val: f64| val >= i64::min_value() as f64 && val < i64::max_value() as f64;
+        match value {
+            AnyLuaValue::LuaNumber(val) if val.fract() == 0.0 && in_range(val) => Ok(val as i64),
+            AnyLuaValue::LuaNumber(val) => {
+                Err(LuaTypeMismatch {
+                    at: String::new(),
+                    expected: Self::expected(),
+                    found: format!("number {}", val),
+                })
+            }
+            other => Err(LuaTypeMismatch::new(Self::expected(), &other)),
+        }
+    }
+}
+
+impl FromLua for f64 {
+    fn expected() -> String {
+        "number".to_owned()
+    }
+
+    fn from_lua(value: AnyLuaValue) -> Result<Self, LuaTypeMismatch> {
+        match value {
+            AnyLuaValue::LuaNumber(val) => Ok(val),
+            other => Err(LuaTypeMismatch::new(Self::expected(), &other)),
+        }
+    }
+}
+
+impl FromLua for String {
+    fn expected() -> String {
+        "string".to_owned()
+    }
+
+    /// Numbers aren't converted, though lua would convert them to strings in most places.
+    fn from_lua(value: AnyLuaValue) -> Result<Self, LuaTypeMismatch> {
+        match value {
+            AnyLuaValue::LuaString(val) => Ok(val),
+            other => Err(LuaTypeMismatch::new(Self::expected(), &other)),
+        }
+    }
+}
+
+impl<T: FromLua> FromLua for Option<T> {
+    fn expected() -> String {
+        format!("{} or nil", T::expected())
+    }
+
+    /// Nil is None, and anything else is converted to T.
+    fn from_lua(value: AnyLuaValue) -> Result<Self, LuaTypeMismatch> {
+        match value {
+            AnyLuaValue::LuaNil => Ok(None),
+            other => {
+                T::from_lua(other).map(Some).map_err(|mismatch| {
+                    // Nil would only have done for the value itself, not one inside it.
+                    if mismatch.at.is_empty() {
+                        LuaTypeMismatch { expected: Self::expected(), ..mismatch }
+                    } else {
+                        mismatch
+                    }
+                })
+            }
+        }
+    }
+}
+
+impl<T: FromLua> FromLua for Vec<T> {
+    fn expected() -> String {
+        format!("array of {}", T::expected())
+    }
+
+    /// Converts an array, as `try_any_lua_to_vec` does, with the missing indexes being nil.
+    fn from_lua(value: AnyLuaValue) -> Result<Self, LuaTypeMismatch> {
+        let values = try!(lua_array_values(value, Self::expected()));
+        let mut vec = Vec::with_capacity(values.len());
+        for (i, value) in values.into_iter().enumerate() {
+            let converted = T::from_lua(value)
+                                .map_err(|mismatch| mismatch.within(&format!("element {}", i + 1)));
+            vec.push(try!(converted));
+        }
+        Ok(vec)
+    }
+}
+
+/// Implements `FromLua` for a tuple of the type parameters given, converting an array for a
+/// single value, and a value returned for each element.
+macro_rules! tuple_from_lua {
+    ($($elem:ident),+) => {
+        impl<$($elem: FromLua),+> FromLua for ($($elem,)+) {
+            fn expected() -> String {
+                let elems: Vec<String> = vec![$($elem::expected()),+];
+                format!("({})", elems.join(", "))
+            }
+
+            fn from_lua(value: AnyLuaValue) -> Result<Self, LuaTypeMismatch> {
+                let values = try!(lua_array_values(value, Self::expected()));
+                let mut values = values.into_iter();
+                let mut index = 0;
+                Ok(($({
+                    index += 1;
+                    let value = values.next().unwrap_or(AnyLuaValue::LuaNil);
+                    try!($elem::from_lua(value).map_err(|mismatch| {
+                        mismatch.within(&format!("element {}", index))
+                    }))
+                },)+))
+            }
+
+            fn from_lua_returns(values: Vec<AnyLuaValue>) -> Result<Self, LuaTypeMismatch> {
+                let mut values = values.into_iter();
+                let mut index = 0;
+                Ok(($({
+                    index += 1;
+                    let value = values.next().unwrap_or(AnyLuaValue::LuaNil);
+                    try!($elem::from_lua(value).map_err(|mismatch| {
+                        mismatch.within(&format!("return value {}", index))
+                    }))
+                },)+))
+            }
+        }
+    }
+}
+
+tuple_from_lua!(A);
+tuple_from_lua!(A, B);
+tuple_from_lua!(A, B, C);
+tuple_from_lua!(A, B, C, D);
+
+/// The values of the lua array, for converting it to a type expecting one.
+fn lua_array_values(value: AnyLuaValue,
+                    expected: String)
+                    -> Result<Vec<AnyLuaValue>, LuaTypeMismatch> {
+    try_any_lua_to_vec(value).map_err(|err| {
+        let found = match err {
+            LuaConversionError::NotATable(type_name) => type_name.to_owned(),
+            err => format!("table that isn't an array ({})", err),
+        };
+        LuaTypeMismatch {
+            at: String::new(),
+            expected: expected,
+            found: found,
+        }
+    })
+}
+
+/// A lua value that couldn't be converted to a rust type with `FromLua`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuaTypeMismatch {
+    /// Where the value was in what was converted, such as `return value 2, element 3`, or empty
+    /// if it was what was converted.
+    pub at: String,
+    /// The lua type the rust type converts from, such as `array of string`.
+    pub expected: String,
+    /// What the value was, such as `nil`, or `number 1.5` where an integer was expected.
+    pub found: String,
+}
+
+impl LuaTypeMismatch {
+    /// A mismatch of the value, found by it's lua type.
+    fn new(expected: String, value: &AnyLuaValue) -> LuaTypeMismatch {
+        LuaTypeMismatch {
+            at: String::new(),
+            expected: expected,
+            found: lua_type_name(value).to_owned(),
+        }
+    }
+
+    /// The mismatch, of a value that was at the place given in what was converted.
+    fn within(mut self, place: &str) -> LuaTypeMismatch {
+        self.at = if self.at.is_empty() {
+            place.to_owned()
+        } else {
+            format!("{}, {}", place, self.at)
+        };
+        self
+    }
+}
+
+impl Display for LuaTypeMismatch {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        if !self.at.is_empty() {
+            try!(write!(fmt, "{}: ", self.at));
+        }
+        write!(fmt, "expected {}, found {}", self.expected, self.found)
+    }
+}
+
+impl Error for LuaTypeMismatch {
+    fn description(&self) -> &str {
+        "a lua value had the wrong type to be converted"
+    }
+}
+
+/// The name lua gives the type of the value, or "other" for a function, userdata, or thread.
+pub fn lua_type_name(value: &AnyLuaValue) -> &'static str {
+    match *value {
+        AnyLuaValue::LuaNil => "nil",
+        AnyLuaValue::LuaBoolean(_) => "boolean",
+        AnyLuaValue::LuaNumber(_) => "number",
+        AnyLuaValue::LuaString(_) => "string",
+        AnyLuaValue::LuaArray(_) => "table",
+        AnyLuaValue::LuaOther => "other",
+    }
+}
+
+/// An error that can occour converting a lua value into a rust collection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LuaConversionError {
+    /// The value, of the lua type named, is not a table.
+    NotATable(&'static str),
+    /// A table has a key of the lua type named, which the collection can't have.
+    BadKey(&'static str),
+    /// A key of an array is a number that isn't a whole number from 1.
+    NotAnIndex(f64),
+    /// An array has only len of the indexes up to it's highest.
+    TooSparse {
+        len: usize,
+        highest: f64,
+    },
+    /// A value is of the lua type named, which the collection can't have.
+    BadValue(&'static str),
+    /// Tables are nested deeper than the given depth.
+    TooDeep(usize),
+}
+
+impl Display for LuaConversionError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            LuaConversionError::NotATable(type_name) => {
+                write!(fmt, "NotATable: Expected a table, found a {}.", type_name)
+            }
+            LuaConversionError::BadKey(type_name) => {
+                write!(fmt, "BadKey: A table has a key that is a {}.", type_name)
+            }
+            LuaConversionError::NotAnIndex(num) => {
+                write!(fmt, "NotAnIndex: An array has the key {}, which isn't an index.", num)
+            }
+            LuaConversionError::TooSparse { len, highest } => {
+                write!(fmt,
+                       "TooSparse: An array has {} values, but goes up to index {}.",
+                       len,
+                       highest)
+            }
+            LuaConversionError::BadValue(type_name) => {
+                write!(fmt, "BadValue: A value is a {}, which can't be converted.", type_name)
+            }
+            LuaConversionError::TooDeep(depth) => {
+                write!(fmt, "TooDeep: Tables are nested deeper than {}.", depth)
+            }
+        }
+    }
+}
+
+impl Error for LuaConversionError {
+    fn description(&self) -> &str {
+        match *self {
+            LuaConversionError::NotATable(_) => "NotATable: The value is not a table.",
+            LuaConversionError::BadKey(_) => "BadKey: A table has a key of the wrong type.",
+            LuaConversionError::NotAnIndex(_) => {
+                "NotAnIndex: An array has a key that isn't an index."
+            }
+            LuaConversionError::TooSparse { .. } => {
+                "TooSparse: An array is missing most of the indexes below it's highest."
+            }
+            LuaConversionError::BadValue(_) => "BadValue: A value can't be converted.",
+            LuaConversionError::TooDeep(_) => "TooDeep: Tables are nested too deep.",
+        }
     }
-    vec
 }
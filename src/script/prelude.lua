@@ -1,27 +1,673 @@
 prelude_buildengine = {}
+-- Kept as a local as well, so the functions here, such as the watchdog's hook, keep working if a
+-- script removes the global.
+local prelude_buildengine = prelude_buildengine
+
+-- Kept here so the sandbox removing debug, or a script replacing it, doesn't stop tracebacks or
+-- the watchdog.
+local traceback = debug.traceback
+local sethook = debug.sethook
+local getinfo = debug.getinfo
+local collectgarbage = collectgarbage
+-- Kept here so the watchdog can hook every coroutine, see coroutine.create below.
+local coroutine_create = coroutine.create
+local coroutine_wrap = coroutine.wrap
+local type = type
+-- Kept here as the untrusted sandbox removes package, which require keeps using.
+local loaded = package.loaded
+local require = require
+local pack = table.pack
+local unpack = table.unpack
+local concat = table.concat
+local gmatch = string.gmatch
+local tostring = tostring
+local select = select
+local setmetatable = setmetatable
+local rawset = rawset
+local next = next
+local globals = _G
+-- Only used with text chunks, for the requests of prelude_buildengine.run_module_request and
+-- prelude_buildengine.run_watchdog_request.
+local raw_load = load
+
+-- How many instructions run between each check of the execution limit.
+local WATCHDOG_INTERVAL = 1000
+
+-- The names of the modules being loaded, innermost last, so a module requiring one that is still
+-- loading errors instead of loading it again untill the stack overflows.
+local loading = {}
+
+-- The environment of each module by name, which the globals it sets go in, so modules can't
+-- overwrite each other's. Globals a module hasn't set are read from the shared globals, which it
+-- can't set.
+prelude_buildengine.module_envs = {}
+
+-- A read-only view of the shared globals, which every module environment reads from. Each
+-- environment has a metatable of it's own that can't be read or replaced, so a module can't get
+-- at the view, or change what the others read.
+local module_base = setmetatable({}, {
+    __index = globals,
+    __newindex = function (_, key)
+        error("the shared globals are read-only, so " .. tostring(key) .. " can't be set", 2)
+    end,
+    __pairs = function ()
+        local key = nil
+        return function ()
+            local value
+            key, value = next(globals, key)
+            return key, value
+        end
+    end,
+    __metatable = false,
+})
+
+function prelude_buildengine.module_env (name)
+    -- The environment of the module, made the first time it is asked for.
+    local env = prelude_buildengine.module_envs[name]
+    if env == nil then
+        env = setmetatable({}, {__index = module_base, __metatable = false})
+        env._G = env
+        prelude_buildengine.module_envs[name] = env
+    end
+    return env
+end
+
+function prelude_buildengine.module_global (module, name)
+    -- The global the module set, or nil if there is no such module. Read by
+    -- script::Engine::get_module_global.
+    local env = prelude_buildengine.module_envs[module]
+    return env and rawget(env, name)
+end
 
 function prelude_buildengine.package_searcher (modname)
     -- Assumes that prelude_buildengine.modules has the source code of all the modules that can be imported.
     -- That table is added in a step of initing the interpreter.
-    modsrc = prelude_buildengine.modules[modname]
+    -- Requiring a module that is still loading errors with the modules involved, such as
+    -- "circular require: a -> b -> a", rather than returning the module half loaded.
+    local modsrc = prelude_buildengine.modules[modname]
     if modsrc == nil then
         return nil
     end
-    return load(modsrc, modname)
+    for i, name in ipairs(loading) do
+        if name == modname then
+            local cycle = {}
+            for j = i, #loading do
+                cycle[#cycle + 1] = loading[j]
+            end
+            cycle[#cycle + 1] = modname
+            error("circular require: " .. table.concat(cycle, " -> "), 0)
+        end
+    end
+    local chunk, err = load(modsrc, modname, "t", prelude_buildengine.module_env(modname))
+    if chunk == nil then
+        return nil, err
+    end
+    return function (...)
+        loading[#loading + 1] = modname
+        -- Caught so the module is taken off loading even if it errors, recording the traceback
+        -- first as it would be lost by rethrowing.
+        local ok, result = xpcall(chunk, prelude_buildengine.record_traceback, ...)
+        loading[#loading] = nil
+        if not ok then
+            error(result, 0)
+        end
+        return result
+    end
+end
+
+-- The names that can't be loaded, reloaded or unloaded as modules, as in
+-- script::RESERVED_MODULES.
+prelude_buildengine.reserved_modules = {init = true, buildengine = true}
+
+function prelude_buildengine.caller_module (level)
+    -- The name of the module the function level levels above the one calling this was loaded
+    -- from, counting that function as 1, which is "init" for the init script.
+    local info = getinfo(level + 1, "S")
+    if info == nil then
+        return nil
+    end
+    return info.source
+end
+
+-- Takes the request script::Engine made to load or unload a module, as lua code returning "load"
+-- and the name and source of the module, or "unload" and it's name. Set once by
+-- prelude_buildengine.set_module_requests, so scripts can't make requests of their own.
+local take_module_request = nil
+-- Set by prelude_buildengine.lock_modules, after which modules can only be loaded and unloaded by
+-- script::Engine.
+local modules_locked = false
+
+function prelude_buildengine.set_module_requests (take)
+    -- Sets the function requests are taken from, once, before any script has run, and removes it
+    -- from prelude_buildengine, so scripts can't take a request before it is run.
+    take_module_request = take
+    prelude_buildengine.take_module_request = nil
+    prelude_buildengine.set_module_requests = nil
+end
+
+function prelude_buildengine.lock_modules ()
+    -- Makes prelude_buildengine.reload_module and prelude_buildengine.unload_module error, so the
+    -- scripts can't load or unload modules themselves. Called by the untrusted sandbox.
+    modules_locked = true
+    prelude_buildengine.lock_modules = nil
+end
+
+local function replace_module (name, src)
+    -- Replaces the module with the source and loads it again, removing the handlers the old
+    -- version added once the new version has loaded.
+    -- If the new version doesn't compile or errors while loading, the old version is kept as it
+    -- was and the error raised. prelude_buildengine.reload_syntax_error is set if it didn't
+    -- compile. The new version runs in the old version's environment, so it keeps the globals the
+    -- old version set.
+    prelude_buildengine.reload_syntax_error = false
+    local _, err = load(src, name)
+    if err ~= nil then
+        prelude_buildengine.reload_syntax_error = true
+        error(err, 0)
+    end
+    local old_src = prelude_buildengine.modules[name]
+    local old_loaded = loaded[name]
+    -- Handler ids only go up, so the new version's handlers are the ones from here on.
+    local first_id = prelude_buildengine.next_handler_id or 1
+    prelude_buildengine.modules[name] = src
+    loaded[name] = nil
+    local ok, err = pcall(require, name)
+    local remove_handlers = prelude_buildengine.remove_handlers
+    if not ok then
+        prelude_buildengine.modules[name] = old_src
+        loaded[name] = old_loaded
+        if remove_handlers ~= nil then
+            remove_handlers(name, first_id, prelude_buildengine.next_handler_id)
+        end
+        error(err, 0)
+    end
+    if remove_handlers ~= nil then
+        remove_handlers(name, 1, first_id)
+    end
+end
+
+local function remove_module (name)
+    -- Removes the module, it's environment and every handler it added, so requiring it fails.
+    prelude_buildengine.modules[name] = nil
+    prelude_buildengine.module_envs[name] = nil
+    loaded[name] = nil
+    if prelude_buildengine.remove_handlers ~= nil then
+        prelude_buildengine.remove_handlers(name, 1, prelude_buildengine.next_handler_id)
+    end
+end
+
+function prelude_buildengine.reload_module (name, src)
+    -- Replaces the module with the source and loads it again, see replace_module, unless
+    -- prelude_buildengine.lock_modules has been called.
+    if modules_locked then
+        error("modules can't be loaded or unloaded by these scripts", 2)
+    end
+    replace_module(name, src)
+end
+
+function prelude_buildengine.unload_module (name)
+    -- Removes the module, see remove_module, unless prelude_buildengine.lock_modules has been
+    -- called.
+    if modules_locked then
+        error("modules can't be loaded or unloaded by these scripts", 2)
+    end
+    remove_module(name)
+end
+
+function prelude_buildengine.run_module_request ()
+    -- Loads or unloads the module script::Engine requested, which it can do even once
+    -- prelude_buildengine.lock_modules has been called. A script calling this can only run the
+    -- request as it was made.
+    local kind, name, src = raw_load(take_module_request(), "request", "t", {})()
+    if kind == "load" then
+        replace_module(name, src)
+    elseif kind == "unload" then
+        remove_module(name)
+    else
+        error("no module was requested to be loaded or unloaded", 2)
+    end
+end
+
+function prelude_buildengine.load_modules ()
+    -- Requires every module named in prelude_buildengine.module_order, in that order, so their
+    -- top-level code, and the handlers it adds, run in the same order every time.
+    for _, name in ipairs(prelude_buildengine.module_order) do
+        require(name)
+    end
+end
+
+function prelude_buildengine.compile_init ()
+    -- Compiles prelude_buildengine.init_src into prelude_buildengine.init, named "init" in
+    -- errors and tracebacks. Returns the syntax error if there is one.
+    local init, err = load(prelude_buildengine.init_src, "init")
+    prelude_buildengine.init = init
+    return err
+end
+
+function prelude_buildengine.compile_eval ()
+    -- Compiles prelude_buildengine.eval_src into prelude_buildengine.eval_chunk, named
+    -- prelude_buildengine.eval_name in errors and tracebacks, in the environment of the module
+    -- with that name if prelude_buildengine.eval_in_module is true. It is compiled as an
+    -- expression if it is one, so "1 + 1" returns 2. Returns the syntax error if there is one.
+    local src = prelude_buildengine.eval_src
+    local name = prelude_buildengine.eval_name
+    local env = globals
+    if prelude_buildengine.eval_in_module then
+        env = prelude_buildengine.module_env(name)
+    end
+    local chunk = load("return " .. src, name, "t", env)
+    local err = nil
+    if chunk == nil then
+        chunk, err = load(src, name, "t", env)
+    end
+    prelude_buildengine.eval_chunk = chunk
+    return err
+end
+
+function prelude_buildengine.run_eval ()
+    -- Runs prelude_buildengine.eval_chunk, placing what it returns in
+    -- prelude_buildengine.eval_ret, with more than one value collapsed into an array.
+    prelude_buildengine.traceback = nil
+    local chunk = prelude_buildengine.eval_chunk
+    prelude_buildengine.eval_chunk = nil
+    local results = pack(xpcall(chunk, prelude_buildengine.record_traceback))
+    if not results[1] then
+        error(results[2], 0)
+    end
+    if results.n <= 2 then
+        prelude_buildengine.eval_ret = results[2]
+    else
+        prelude_buildengine.eval_ret = {unpack(results, 2, results.n)}
+    end
+end
+
+function prelude_buildengine.get_path (path)
+    -- The value at the dotted path of fields in the globals, such as "a.b.c", or nil if a value
+    -- on the way is nil or isn't a table.
+    local value = globals
+    for part in gmatch(path, "[^.]+") do
+        if type(value) ~= "table" then
+            return nil
+        end
+        value = value[part]
+    end
+    return value
+end
+
+function prelude_buildengine.set_path_parts (parts, value, create)
+    -- Sets the value at the path of fields in the globals, an array of their names, creating the
+    -- tables on the way that are nil if create is true. If one is nil otherwise
+    -- prelude_buildengine.path_error is set to "missing", or to "not_table" if one isn't a table,
+    -- with prelude_buildengine.path_error_at the dotted path to it. Metatables are ignored.
+    prelude_buildengine.path_error = nil
+    prelude_buildengine.path_error_at = nil
+    local tbl = globals
+    for i = 1, #parts - 1 do
+        local field = rawget(tbl, parts[i])
+        if field == nil and create then
+            field = {}
+            rawset(tbl, parts[i], field)
+        end
+        if type(field) ~= "table" then
+            prelude_buildengine.path_error = field == nil and "missing" or "not_table"
+            prelude_buildengine.path_error_at = concat(parts, ".", 1, i)
+            return
+        end
+        tbl = field
+    end
+    rawset(tbl, parts[#parts], value)
+end
+
+function prelude_buildengine.record_traceback (err)
+    -- A message handler for xpcall, placing the traceback of where the error was raised in
+    -- prelude_buildengine.traceback, unless one was already recorded for the error.
+    -- prelude_buildengine.traceback is read and cleared by script::Engine::call_prelude_fn.
+    if prelude_buildengine.traceback == nil then
+        prelude_buildengine.traceback = traceback(nil, 2)
+    end
+    return err
 end
 
 table.insert(package.searchers, 1, prelude_buildengine.package_searcher)
 
-function prelude_buildengine.call_prelude_fn ()
-    -- Assumes that prelude_buildengine.fn_to_call is a string pointing to the function to call, and
-    -- prelude_buildengine.args is the arguments to that function. The return value of the function is then placed in
-    -- prelude_buildengine.ret.
+-- How deeply tables passed to rust functions may be nested. Rust reads them recursively, so
+-- tables nested without bound, or containing themselves, would overflow it's stack.
+local MAX_NESTING = 64
+
+local function nesting (value, depth, visiting, heights)
+    -- How deeply tables are nested in the value, 0 if it isn't a table, or nil and the problem if a
+    -- table in it contains itself, or tables are nested more than MAX_NESTING deep counting the
+    -- depth the value is at. Each table is only walked once, however many tables it is in.
+    if type(value) ~= "table" then
+        return 0
+    end
+    local height = heights[value]
+    if height == nil then
+        if visiting[value] then
+            return nil, "a table contains itself"
+        end
+        if depth >= MAX_NESTING then
+            return nil, "tables are nested more than " .. MAX_NESTING .. " deep"
+        end
+        visiting[value] = true
+        height = 0
+        for k,v in next, value do
+            local key_height, problem = nesting(k, depth + 1, visiting, heights)
+            if key_height == nil then
+                return nil, problem
+            end
+            local value_height, problem = nesting(v, depth + 1, visiting, heights)
+            if value_height == nil then
+                return nil, problem
+            end
+            if key_height > height then
+                height = key_height
+            end
+            if value_height > height then
+                height = value_height
+            end
+        end
+        visiting[value] = nil
+        height = height + 1
+        heights[value] = height
+    end
+    if depth + height > MAX_NESTING then
+        return nil, "tables are nested more than " .. MAX_NESTING .. " deep"
+    end
+    return height
+end
+
+local function nesting_problem (value)
+    -- Why rust can't read the value, if it can't.
+    local _, problem = nesting(value, 0, {}, {})
+    return problem
+end
+
+function prelude_buildengine.guard_natives (names)
+    -- Replaces each function named in prelude_buildengine by one raising an error if rust can't
+    -- read one of it's arguments, before calling it. Called once by script::Engine, before any
+    -- script has run, so scripts can't keep the functions that aren't guarded.
+    for _, name in ipairs(names) do
+        local native = prelude_buildengine[name]
+        prelude_buildengine[name] = function (...)
+            for i = 1, select("#", ...) do
+                local problem = nesting_problem((select(i, ...)))
+                if problem ~= nil then
+                    error("bad argument #" .. i .. " to " .. name .. ", " .. problem, 2)
+                end
+            end
+            return native(...)
+        end
+    end
+    prelude_buildengine.guard_natives = nil
+end
+
+-- Rust functions registered with script::Engine::register_fn by name, each taking the number of
+-- arguments and a table of them.
+prelude_buildengine.native_raw = {}
+
+function prelude_buildengine.guard_native (name)
+    -- Replaces the function registered with the name by one raising an error if rust can't read
+    -- it's arguments, before calling it. Called by script::Engine::register_fn as it registers
+    -- each function, so it is never called with arguments it can't read.
+    local native = prelude_buildengine.native_raw[name]
+    prelude_buildengine.native_raw[name] = function (count, args)
+        local problem = nesting_problem(args)
+        if problem ~= nil then
+            error("bad argument to native." .. name .. ", " .. problem, 3)
+        end
+        return native(count, args)
+    end
+end
+
+-- Exposed as buildengine.native. Looks the function up each time, so one registered again
+-- replaces the old one even for scripts that already had it.
+prelude_buildengine.native = setmetatable({}, {__index = function (native, name)
+    if prelude_buildengine.native_raw[name] == nil then
+        return nil
+    end
+    return function (...)
+        return prelude_buildengine.native_raw[name](select("#", ...), {...})
+    end
+end})
+
+-- prelude_buildengine.log_debug, log_info, log_warn and log_error are set by script::Engine, each
+-- taking the target and message to log at their level with the log crate.
+
+function print (...)
+    -- Logs the arguments at the info level with the target "lua", separated by tabs as stock print
+    -- would write them, rather than writing them to stdout, where redirected logs would miss them.
+    local parts = {}
+    for i = 1, select("#", ...) do
+        parts[i] = tostring((select(i, ...)))
+    end
+    prelude_buildengine.log_info("lua", concat(parts, "\t"))
+end
+
+local function memory_usage ()
+    return collectgarbage("count") * 1024
+end
+
+function prelude_buildengine.memory_usage ()
+    -- The memory used by the interpreter, in bytes.
+    return memory_usage()
+end
+
+-- The milliseconds since the watchdog was started, given by script::Engine with
+-- prelude_buildengine.set_clock. Kept here, as scripts can replace anything in
+-- prelude_buildengine.
+local elapsed_millis = nil
+
+function prelude_buildengine.set_clock (clock)
+    -- Sets the function the watchdog reads the milliseconds it has run for from, once, before any
+    -- script has run.
+    elapsed_millis = clock
+    prelude_buildengine.set_clock = nil
+end
+
+-- The limits of the code being run and the instructions it has run, set by start_watchdog, or nil
+-- while the watchdog is stopped.
+local watchdog = nil
+
+local function watchdog_hook ()
+    -- Errors once the code has run more than watchdog.limit_instructions instructions, or for
+    -- more than watchdog.limit_millis milliseconds, setting prelude_buildengine.timed_out, or once
+    -- the interpreter uses more than watchdog.limit_memory bytes even after collecting garbage,
+    -- setting prelude_buildengine.memory_exceeded to the usage.
+    if watchdog == nil then
+        return
+    end
+    watchdog.instructions = watchdog.instructions + WATCHDOG_INTERVAL
+    if (watchdog.limit_instructions ~= nil and watchdog.instructions > watchdog.limit_instructions)
+       or (watchdog.limit_millis ~= nil and elapsed_millis ~= nil and
+           elapsed_millis() > watchdog.limit_millis) then
+        prelude_buildengine.timed_out = true
+        error("the execution limit was exceeded", 0)
+    end
+    if watchdog.limit_memory ~= nil and memory_usage() > watchdog.limit_memory then
+        collectgarbage("collect")
+        local usage = memory_usage()
+        if usage > watchdog.limit_memory then
+            prelude_buildengine.memory_exceeded = usage
+            error("the memory limit was exceeded", 0)
+        end
+    end
+end
+
+local function start_watchdog (limit_instructions, limit_millis, limit_memory)
+    -- Makes the code run from now on error once it has run more than limit_instructions
+    -- instructions, or for more than limit_millis milliseconds, or once the interpreter uses more
+    -- than limit_memory bytes even after collecting garbage. Any limit may be nil for none.
+    -- Once over a limit it errors at every check, so a script catching the error is stopped again.
+    prelude_buildengine.timed_out = false
+    prelude_buildengine.memory_exceeded = nil
+    watchdog = {
+        limit_instructions = limit_instructions,
+        limit_millis = limit_millis,
+        limit_memory = limit_memory,
+        instructions = 0,
+    }
+    sethook(watchdog_hook, "", WATCHDOG_INTERVAL)
+end
+
+local function stop_watchdog ()
+    watchdog = nil
+    sethook()
+end
+
+local function hooked (f)
+    -- The function f, setting the watchdog's hook in the coroutine it runs in first, as a hook is
+    -- only called for the coroutine it was set in. The hook does nothing while the watchdog is
+    -- stopped, so it is set even then, in case the coroutine is resumed once it has started.
+    if type(f) ~= "function" then
+        return f
+    end
+    return function (...)
+        sethook(watchdog_hook, "", WATCHDOG_INTERVAL)
+        return f(...)
+    end
+end
+
+-- Replaced so code run in a coroutine is limited by the watchdog too.
+function coroutine.create (f)
+    return coroutine_create(hooked(f))
+end
+
+function coroutine.wrap (f)
+    return coroutine_wrap(hooked(f))
+end
+
+-- The function the watchdog requests of script::Engine are taken from, set once by
+-- prelude_buildengine.set_watchdog_requests, so scripts can't start or stop the watchdog.
+local take_watchdog_request = nil
+
+function prelude_buildengine.set_watchdog_requests (take)
+    -- Sets the function requests are taken from, once, before any script has run, and removes it
+    -- from prelude_buildengine, so scripts can't take a request before it is run.
+    take_watchdog_request = take
+    prelude_buildengine.take_watchdog_request = nil
+    prelude_buildengine.set_watchdog_requests = nil
+end
+
+function prelude_buildengine.run_watchdog_request ()
+    -- Starts the watchdog with the limits script::Engine requested, or stops it. The limits are
+    -- given by script::Engine each time, and kept by the watchdog, so scripts can't change them
+    -- while they run. A script calling this can only run the request as it was made.
+    local kind, limit_instructions, limit_millis, limit_memory =
+        raw_load(take_watchdog_request(), "request", "t", {})()
+    if kind == "start" then
+        start_watchdog(limit_instructions, limit_millis, limit_memory)
+    elseif kind == "stop" then
+        stop_watchdog()
+    else
+        error("the watchdog wasn't requested to start or stop", 2)
+    end
+end
+
+-- The calls being made with prelude_buildengine.call_prelude_fn, by id. Each has a slot of it's
+-- own, so a call made while another is running, such as by a handler of an event, can't clobber
+-- the other's arguments or return value.
+prelude_buildengine.calls = {}
+local next_call_id = 1
+
+function prelude_buildengine.begin_call (fn_to_call, count, args)
+    -- Adds a call of the function named fn_to_call in the prelude_buildengine table, with the
+    -- first count values of args as it's arguments, returning the id of the call.
+    local id = next_call_id
+    next_call_id = next_call_id + 1
+    prelude_buildengine.calls[id] = {fn_to_call = fn_to_call, count = count, args = args}
+    return id
+end
+
+function prelude_buildengine.call_prelude_fn (id)
+    -- Makes the call with the id, added by prelude_buildengine.begin_call, placing every value
+    -- the function returns in the ret field of the call, packed by table.pack.
     -- This function is used to call functions with arguments in rust,
     -- since it isn't exposed in hlua.
-    if next(prelude_buildengine.args) ~= nil then
-        local ret = prelude_buildengine[prelude_buildengine.fn_to_call](unpack(prelude_buildengine.args))
-    else
-        local ret = prelude_buildengine[prelude_buildengine.fn_to_call]()
+    local call = prelude_buildengine.calls[id]
+    local ok, err = xpcall(function ()
+        call.ret = pack(prelude_buildengine[call.fn_to_call](unpack(call.args, 1, call.count)))
+    end, prelude_buildengine.record_traceback)
+    if not ok then
+        error(err, 0)
+    end
+end
+
+function prelude_buildengine.end_call (id)
+    -- Removes the call with the id, returning the values it's function returned, packed by
+    -- table.pack, or nil if it didn't return.
+    local call = prelude_buildengine.calls[id]
+    prelude_buildengine.calls[id] = nil
+    if call == nil then
+        return nil
+    end
+    return call.ret
+end
+
+function prelude_buildengine.call (fn_to_call, ...)
+    -- Calls the function named fn_to_call in the prelude_buildengine table, as
+    -- script::Engine::call_prelude_fn does, for lua code.
+    local id = prelude_buildengine.begin_call(fn_to_call, select("#", ...), {...})
+    local ok, err = pcall(prelude_buildengine.call_prelude_fn, id)
+    local ret = prelude_buildengine.end_call(id)
+    if not ok then
+        error(err, 0)
+    end
+    return unpack(ret, 1, ret.n)
+end
+
+-- The views made by prelude_buildengine.freeze, which the sandboxes' rawset refuses to set.
+local frozen = setmetatable({}, {__mode = "k"})
+frozen[module_base] = true
+
+function prelude_buildengine.freeze (fields, name)
+    -- A read-only view of the table fields, which changes to fields show through. Setting a field
+    -- of the view errors, naming it name, as does rawset under the sandboxes, and it's metatable
+    -- can't be replaced.
+    local view = setmetatable({}, {
+        __index = fields,
+        __newindex = function ()
+            error(name .. " is read-only", 2)
+        end,
+        __pairs = function ()
+            -- fields is never handed out, as the state of the iterator, so it can't be written to
+            -- through it.
+            local key = nil
+            return function ()
+                local value
+                key, value = next(fields, key)
+                return key, value
+            end
+        end,
+        __metatable = false,
+    })
+    frozen[view] = true
+    return view
+end
+
+function prelude_buildengine.protect ()
+    -- Moves prelude_buildengine out of the globals into the __index of their metatable, so it can
+    -- still be read, but setting it, even with rawset, errors. The metatable can't be replaced
+    -- either, and rawset can't set the views made by prelude_buildengine.freeze. Called by the
+    -- sandboxes, as scripts under SandboxLevel::Full are trusted with it.
+    rawset(globals, "prelude_buildengine", nil)
+    setmetatable(globals, {
+        __index = {prelude_buildengine = prelude_buildengine},
+        __newindex = function (tbl, key, value)
+            if key == "prelude_buildengine" then
+                error("prelude_buildengine can't be replaced", 2)
+            end
+            rawset(tbl, key, value)
+        end,
+        __metatable = false,
+    })
+    globals.rawset = function (tbl, key, value)
+        if tbl == globals and key == "prelude_buildengine" then
+            error("prelude_buildengine can't be replaced", 2)
+        end
+        if frozen[tbl] then
+            error("the table is read-only", 2)
+        end
+        return rawset(tbl, key, value)
     end
-    prelude_buildengine.ret = ret
 end
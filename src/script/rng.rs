@@ -0,0 +1,72 @@
+//! Contains the random number generator scripts use through `buildengine.random`.
+
+/// Taken as the state in place of 0, which xorshift never leaves.
+const ZERO_SEED_STATE: u64 = 0x2545F4914F6CDD1D;
+
+/// A xorshift* generator, so the same seed always gives the same numbers, unlike `math.random`.
+#[derive(Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Constructs a generator with the seed.
+    pub fn new(seed: u64) -> Rng {
+        let mut rng = Rng { state: 0 };
+        rng.seed(seed);
+        rng
+    }
+
+    /// Restarts the generator from the seed.
+    ///
+    /// The state is itself a seed, which carries on from where the generator was, so
+    /// `seed(state())` changes nothing.
+    pub fn seed(&mut self, seed: u64) {
+        self.state = if seed == 0 {
+            ZERO_SEED_STATE
+        } else {
+            seed
+        };
+    }
+
+    /// The state of the generator, to seed it with later.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    /// The next number, which may be any u64.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    /// A number from 0 up to, but not including, 1.
+    pub fn float(&mut self) -> f64 {
+        // The top 53 bits, as many as an f64 can hold exactly.
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A whole number from min to max, including both. Every number is as likely as the others.
+    ///
+    /// # Panics
+    /// If min is greater than max.
+    pub fn int(&mut self, min: i64, max: i64) -> i64 {
+        assert!(min <= max, "the range of Rng::int was empty");
+        // The number of values in the range, less one so the whole range of i64 still fits.
+        let span = max.wrapping_sub(min) as u64;
+        if span == u64::max_value() {
+            return self.next_u64() as i64;
+        }
+        let count = span + 1;
+        // Numbers past the last whole multiple of count would make the lower values more likely.
+        let limit = u64::max_value() - u64::max_value() % count;
+        loop {
+            let value = self.next_u64();
+            if value < limit {
+                return min.wrapping_add((value % count) as i64);
+            }
+        }
+    }
+}
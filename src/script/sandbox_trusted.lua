@@ -0,0 +1,6 @@
+-- Run after the prelude under SandboxLevel::Trusted, before any script.
+-- os and package.loaded.os are the same table, so require("os") can't bring these back.
+os.execute = nil
+io.popen = nil
+
+prelude_buildengine.protect()
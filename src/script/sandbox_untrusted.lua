@@ -0,0 +1,63 @@
+-- Run after the prelude under SandboxLevel::Untrusted, before any script.
+
+-- Read by the engine std, which leaves out what untrusted scripts can't have.
+prelude_buildengine.untrusted = true
+-- Only the engine can load and unload modules, which the engine std leaving out
+-- buildengine.load_module alone can't stop, as the scripts can reach prelude_buildengine.
+prelude_buildengine.lock_modules()
+
+-- Only the parts of os that can't touch the filesystem or other programs.
+os = {time = os.time, clock = os.clock}
+package.loaded.os = os
+io = nil
+package.loaded.io = nil
+dofile = nil
+loadfile = nil
+
+-- Precompiled chunks can crash the interpreter, so only text chunks may be loaded.
+local raw_load = load
+load = function (chunk, chunkname, mode, ...)
+    -- The environment is passed on as varargs, since giving load nil as one is not the same as
+    -- giving none.
+    return raw_load(chunk, chunkname, "t", ...)
+end
+if loadstring ~= nil then
+    loadstring = load
+end
+
+-- debug can reach the locals and upvalues of any function, such as raw_load above, remove the
+-- watchdog's hook and replace protected metatables, and string.dump makes the precompiled chunks
+-- load can no longer take. The prelude keeps what it needs of debug as locals.
+debug = nil
+package.loaded.debug = nil
+string.dump = nil
+
+-- Random numbers come from the generator behind buildengine.random, so they can be reproduced by
+-- seeding it the same way.
+math.random = function (m, n)
+    if m == nil then
+        return prelude_buildengine.random_float()
+    end
+    if n == nil then
+        m, n = 1, m
+    end
+    if m ~= m or n ~= n or m == math.huge or n == math.huge or m == -math.huge or
+       n == -math.huge then
+        error("bad argument to 'random' (interval is not finite)", 2)
+    end
+    if m > n then
+        error("bad argument to 'random' (interval is empty)", 2)
+    end
+    return prelude_buildengine.random_int(math.floor(m), math.floor(n))
+end
+math.randomseed = function (seed)
+    prelude_buildengine.random_seed(math.floor(seed))
+end
+
+-- Only the modules given to the engine can be required. require keeps it's own reference to
+-- package, so it still works once the global is gone.
+package.searchers = {prelude_buildengine.package_searcher}
+package.loaded.package = nil
+package = nil
+
+prelude_buildengine.protect()
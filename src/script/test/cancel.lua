@@ -0,0 +1,21 @@
+be = require("buildengine")
+calls = ""
+
+local function record (name, cancel)
+    return function ()
+        calls = calls .. name
+        if cancel then
+            return be.CANCEL
+        end
+    end
+end
+
+be.on("cancel_first", record("a", true))
+be.on("cancel_first", record("b"))
+
+be.on("cancel_middle", record("a"))
+be.on("cancel_middle", record("b", true))
+be.on("cancel_middle", record("c"))
+
+be.on("no_cancel", record("a"))
+be.on("no_cancel", record("b"))
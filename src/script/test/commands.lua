@@ -0,0 +1,20 @@
+local be = require("buildengine")
+
+be.commands.register("greet", "Greets whoever is named.", function (name)
+    return "hello " .. name
+end)
+
+be.commands.register("count", "Prints how many arguments it was given.", function (...)
+    return select("#", ...)
+end)
+
+be.commands.register("quiet", "Prints nothing.", function ()
+end)
+
+be.commands.register("fail", "Always fails.", function ()
+    error("failed on purpose")
+end)
+
+be.commands.register("shadowed", "Never runs, as rust registers it too.", function ()
+    return "from lua"
+end)
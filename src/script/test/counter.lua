@@ -0,0 +1,27 @@
+be = require("buildengine")
+count = 0
+ticks = 0
+
+function tick ()
+    ticks = ticks + 1
+end
+
+function start_ticking ()
+    ticker = be.every(1, tick)
+end
+
+be.on("count", function (by)
+    count = count + (by or 1)
+end)
+
+be.on("on_snapshot", function ()
+    return {count = count, ticker = ticker}
+end)
+
+be.on("on_restore", function (state)
+    count = state.count
+    if state.ticker ~= nil then
+        ticker = state.ticker
+        be.resume_timer(ticker, tick)
+    end
+end)
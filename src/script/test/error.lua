@@ -0,0 +1,10 @@
+be = require("buildengine")
+later_called = false
+be.subscribe("fails", function ()
+end)
+be.subscribe("fails", function ()
+    error("handler went wrong")
+end)
+be.subscribe("fails", function ()
+    later_called = true
+end)
@@ -0,0 +1,7 @@
+local failing = {}
+
+function failing.fail ()
+    error("failed on purpose")
+end
+
+return failing
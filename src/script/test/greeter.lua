@@ -0,0 +1,12 @@
+local be = require("buildengine")
+local greeter = {}
+
+function greeter.greeting ()
+    return "hello"
+end
+
+be.on("count", function ()
+    counted = (counted or 0) + 1
+end)
+
+return greeter
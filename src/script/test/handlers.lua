@@ -0,0 +1,30 @@
+be = require("buildengine")
+calls = ""
+
+local function record (name)
+    return function ()
+        calls = calls .. name
+    end
+end
+
+be.on("record", record("a"))
+local b_id = be.on("record", record("b"))
+be.on("record", record("c"))
+
+be.on("remove_b", function ()
+    removed = be.off("record", b_id)
+end)
+
+be.on("clear", function ()
+    be.clear_event("record")
+end)
+
+-- The first handler removes itself and the one after it while the event is being activated.
+local first_id, second_id
+first_id = be.on("remove_mid", function ()
+    calls = calls .. "1"
+    be.off("remove_mid", first_id)
+    be.off("remove_mid", second_id)
+end)
+second_id = be.on("remove_mid", record("2"))
+be.on("remove_mid", record("3"))
@@ -0,0 +1,28 @@
+local be = require("buildengine")
+
+be.on("read_info", function ()
+    local info = be.info
+    return info.version, info.protocol_version, info.is_server, info.is_client, info.tick_rate,
+           info.os, info.has("timers"), info.has("flying_cars")
+end)
+
+be.on("info_version_pairs", function ()
+    for key, value in pairs(be.info) do
+        if key == "version" then
+            return value
+        end
+    end
+end)
+
+be.on("write_info", function (how)
+    if how == "set" then
+        be.info.version = "0.0.0"
+    elseif how == "rawset" then
+        rawset(be.info, "version", "0.0.0")
+    elseif how == "metatable" then
+        setmetatable(be.info, nil)
+    elseif how == "pairs" then
+        local _, state = pairs(be.info)
+        state.version = "0.0.0"
+    end
+end)
@@ -0,0 +1,15 @@
+be = require("buildengine")
+
+-- Logged through buildengine.native.record_log instead, to check what would have been logged.
+for _,level in ipairs({"debug", "info", "warn", "error"}) do
+    prelude_buildengine["log_" .. level] = function (target, message)
+        be.native.record_log(level, target, message)
+    end
+end
+
+print("hello", 1, nil, true)
+print()
+be.log.warn("%d players", 3)
+be.log.error("plain %d")
+be.log.debug(42)
+require("chat").join()
@@ -0,0 +1,12 @@
+be = require("buildengine")
+
+be.on("allocate", function ()
+    local tables = {}
+    for i = 1, 100000000 do
+        tables[i] = {i}
+    end
+end)
+
+be.on("after", function ()
+    after_ran = true
+end)
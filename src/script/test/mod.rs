@@ -1,7 +1,22 @@
+use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::u64;
 
+use bincode::SizeLimit;
+use bincode::serde::{deserialize, serialize};
 use hlua::any::AnyLuaValue;
-use hlua::{LuaTable, function0};
+use hlua::{LuaError, LuaTable, function0};
 
 use super::*;
 use test_util;
@@ -9,12 +24,75 @@ use test_util;
 const EVENT: &'static str = include_str!("event.lua");
 const TEST: &'static str = include_str!("test.lua");
 const REQUIRE: &'static str = include_str!("require.lua");
+const NET_EVENT: &'static str = include_str!("net_event.lua");
+const REPR: &'static str = include_str!("repr.lua");
+const TICK: &'static str = include_str!("tick.lua");
+const PLAYERS: &'static str = include_str!("players.lua");
+const SHUTDOWN: &'static str = include_str!("shutdown.lua");
+const SANDBOX: &'static str = include_str!("sandbox.lua");
+const ERROR: &'static str = include_str!("error.lua");
+const TRACEBACK: &'static str = include_str!("traceback.lua");
+const FAILING: &'static str = include_str!("failing.lua");
+const HANDLERS: &'static str = include_str!("handlers.lua");
+const CANCEL: &'static str = include_str!("cancel.lua");
+const NATIVE: &'static str = include_str!("native.lua");
+const TIMEOUT: &'static str = include_str!("timeout.lua");
+const MEMORY: &'static str = include_str!("memory.lua");
+const RELOAD: &'static str = include_str!("reload.lua");
+const GREETER: &'static str = include_str!("greeter.lua");
+const GREETER_RELOADED: &'static str = include_str!("greeter_reloaded.lua");
+const MODULES: &'static str = include_str!("modules.lua");
+const NESTED: &'static str = include_str!("nested.lua");
+const RETURNS: &'static str = include_str!("returns.lua");
+const TIMERS: &'static str = include_str!("timers.lua");
+const QUEUE: &'static str = include_str!("queue.lua");
+const WILDCARD: &'static str = include_str!("wildcard.lua");
+const PROFILING: &'static str = include_str!("profiling.lua");
+const STORAGE: &'static str = include_str!("storage.lua");
+const COUNTER: &'static str = include_str!("counter.lua");
+const LOG: &'static str = include_str!("log.lua");
+const RANDOM: &'static str = include_str!("random.lua");
+const INFO: &'static str = include_str!("info.lua");
+const ON_ERROR: &'static str = include_str!("on_error.lua");
+const NET: &'static str = include_str!("net.lua");
+const COMMANDS: &'static str = include_str!("commands.lua");
+const TIME: &'static str = include_str!("time.lua");
+const PAUSE: &'static str = include_str!("pause.lua");
 
-/// Call Engine.new without any code.
+/// A nested value using every kind of LuaValueRepr.
+fn nested_repr() -> LuaValueRepr {
+    LuaValueRepr::Array(vec![LuaValueRepr::String("te\"st\n".to_owned()),
+                             LuaValueRepr::Array(vec![LuaValueRepr::Number(1.5),
+                                                      LuaValueRepr::Boolean(true)]),
+                             LuaValueRepr::Number(-3.0)])
+}
+
+/// Constructs an engine from scripts without an init script, allowing it to be missing.
+fn without_init(scripts: HashMap<String, String>) -> Engine<'static> {
+    Engine::new_with_policy(scripts, SandboxLevel::Full, InitPolicy::AllowMissing).unwrap()
+}
+
+/// The string in the global variable, panicking if it isn't one.
+fn global_string(engine: &mut Engine, name: &str) -> String {
+    match engine.get_global(name) {
+        Some(AnyLuaValue::LuaString(value)) => value,
+        other => panic!("expected {} to be a string, got {:?}", name, other),
+    }
+}
+
+/// The number in the global variable, panicking if it isn't one.
+fn global_number(engine: &mut Engine, name: &str) -> f64 {
+    match engine.get_global(name) {
+        Some(AnyLuaValue::LuaNumber(value)) => value,
+        other => panic!("expected {} to be a number, got {:?}", name, other),
+    }
+}
+
+/// Call Engine.new_with_policy without any code, allowing the init script to be missing.
 #[test]
 fn engine_new_no_code() {
     test_util::start_log_once();
-    Engine::new(HashMap::new()).unwrap();
+    without_init(HashMap::new());
 }
 
 /// Tests requiring a module.
@@ -34,18 +112,764 @@ fn lua_event() {
     let mut scripts: HashMap<String, String> = HashMap::new();
     scripts.insert("init".to_owned(), EVENT.to_owned());
     let mut engine = Engine::new(scripts).unwrap();
-    let _ = engine.exec_event("test".to_owned(), Vec::new()).expect("failed to exec event");
-    let test_val: AnyLuaValue = engine.interpreter.get("test_val").unwrap();
+    let _ = engine.exec_event("test", Vec::new()).expect("failed to exec event");
+    let test_val = engine.get_global("test_val").unwrap();
     assert_eq!(test_val, AnyLuaValue::LuaBoolean(true));
 }
 
+/// Tests that a handler erroring stops the event, and that the error says which handler it was and
+/// why.
+#[test]
+fn lua_event_handler_error() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), ERROR.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let err = engine.exec_event("fails", Vec::new()).unwrap_err();
+    match err {
+        ExecEventError::HandlerError { ref event, handler, ref message, .. } => {
+            assert_eq!(event, "fails");
+            assert_eq!(handler, 2);
+            assert!(message.contains("handler went wrong"), "message: {}", message);
+        }
+        ref err => panic!("expected a HandlerError, got {:?}", err),
+    }
+    assert!(err.to_string().contains("handler went wrong"));
+    let later_called = engine.get_global("later_called").unwrap();
+    assert_eq!(later_called, AnyLuaValue::LuaBoolean(false));
+
+    // A later event that succeeds isn't reported as the earlier handler's error.
+    engine.exec_event("no_handlers", Vec::new()).unwrap();
+}
+
+/// Tests that an erroring handler's traceback names the modules and lines it went through.
+#[test]
+fn lua_event_traceback() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), TRACEBACK.to_owned());
+    scripts.insert("failing".to_owned(), FAILING.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let err = engine.exec_event("fails_deep", Vec::new()).unwrap_err();
+    assert!(err.to_string().contains("[string \"failing\"]:4: failed on purpose"),
+            "error: {}",
+            err);
+    let traceback = err.traceback().expect("the error had no traceback").to_owned();
+    assert!(traceback.contains("[string \"failing\"]:4"), "traceback: {}", traceback);
+    assert!(traceback.contains("[string \"init\"]:4"), "traceback: {}", traceback);
+
+    // call_prelude_fn keeps the traceback of it's last call.
+    assert!(engine.call_prelude_fn("not_a_function", Vec::new()).is_err());
+    assert!(engine.last_traceback().is_some());
+    engine.call_prelude_fn("activate_event", vec![AnyLuaValue::LuaString("none".to_owned())])
+          .unwrap();
+    assert_eq!(engine.last_traceback(), None);
+}
+
+/// Tests that a syntax error in the init script is named after it.
+#[test]
+fn init_syntax_error() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), "this is not lua".to_owned());
+    match Engine::new(scripts) {
+        Err(LuaError::SyntaxError(ref message)) => {
+            assert!(message.contains("[string \"init\"]:1"), "message: {}", message)
+        }
+        other => panic!("expected a syntax error, got {:?}", other.map(|_engine| ())),
+    }
+}
+
+/// Tests that the init script must be there and not be empty, unless it is allowed to be missing.
+#[test]
+fn init_policy() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("test".to_owned(), TEST.to_owned());
+    scripts.insert("greeter".to_owned(), GREETER.to_owned());
+    match Engine::new_ordered(scripts_by_name(scripts.clone()), SandboxLevel::Full) {
+        Err(::InitError::ScriptEngineError(InitError::MissingInitScript(ref names))) => {
+            assert_eq!(*names, vec!["greeter", "test"])
+        }
+        other => panic!("expected MissingInitScript, got {:?}", other),
+    }
+    match Engine::new(scripts.clone()) {
+        Err(LuaError::ExecutionError(ref message)) => {
+            assert!(message.contains("MissingInitScript"), "unexpected message: {}", message);
+            assert!(message.contains("[greeter, test]"), "unexpected message: {}", message);
+        }
+        other => panic!("expected an execution error, got {:?}", other),
+    }
+    let mut engine = without_init(scripts.clone());
+    assert!(engine.has_module("greeter"));
+    assert!(engine.has_module("buildengine"));
+
+    scripts.insert("init".to_owned(), " \n\t".to_owned());
+    match Engine::new_ordered(scripts_by_name(scripts.clone()), SandboxLevel::Full) {
+        Err(::InitError::ScriptEngineError(InitError::EmptyInitScript)) => {}
+        other => panic!("expected EmptyInitScript, got {:?}", other),
+    }
+    without_init(scripts.clone());
+    scripts.insert("init".to_owned(), "x = 1".to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    assert_eq!(engine.get_global("x"), Some(AnyLuaValue::LuaNumber(1.0)));
+}
+
+/// Tests that scripts can't use the names reserved for the engine std, or replace it's source.
+#[test]
+fn reserved_module_names() {
+    test_util::start_log_once();
+    for name in &["buildengine", "buildengine.physics"] {
+        let mut scripts: HashMap<String, String> = HashMap::new();
+        scripts.insert("init".to_owned(), "x = 1".to_owned());
+        scripts.insert((*name).to_owned(), "return {on = function () end}".to_owned());
+        match Engine::new_ordered(scripts_by_name(scripts), SandboxLevel::Full) {
+            Err(::InitError::ScriptEngineError(InitError::ReservedModuleName(ref reserved))) => {
+                assert_eq!(reserved, name)
+            }
+            other => panic!("expected {} to be reserved, got {:?}", name, other),
+        }
+    }
+
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(),
+                   "extras = require(\"buildengine_extras\").value".to_owned());
+    scripts.insert("buildengine_extras".to_owned(), "return {value = 42}".to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    assert_eq!(engine.get_global("extras"), Some(AnyLuaValue::LuaNumber(42.0)));
+    assert!(engine.has_module("buildengine_extras"));
+    match engine.load_module("buildengine.physics", "return {}".to_owned()) {
+        Err(ReloadError::ReservedName(ref reserved)) => assert_eq!(reserved, "buildengine.physics"),
+        other => panic!("expected buildengine.physics to be reserved, got {:?}", other),
+    }
+
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(),
+                   "prelude_buildengine.modules.buildengine = \"return {}\"".to_owned());
+    match Engine::new_ordered(scripts_by_name(scripts), SandboxLevel::Full) {
+        Err(::InitError::ScriptEngineError(InitError::EngineStdReplaced)) => {}
+        other => panic!("expected EngineStdReplaced, got {:?}", other),
+    }
+}
+
+/// Executes the event and returns the `calls` global, then empties it.
+fn exec_recording(engine: &mut Engine, event: &str) -> String {
+    engine.exec_event(event, Vec::new()).unwrap();
+    let calls = global_string(engine, "calls");
+    engine.set_global("calls", AnyLuaValue::LuaString(String::new())).unwrap();
+    calls
+}
+
+/// Tests adding, removing and clearing the handlers of events.
+#[test]
+fn lua_event_handlers() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), HANDLERS.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    assert_eq!(engine.handler_count("record"), 3);
+    assert_eq!(exec_recording(&mut engine, "record"), "abc");
+
+    exec_recording(&mut engine, "remove_b");
+    let removed = engine.get_global("removed").unwrap();
+    assert_eq!(removed, AnyLuaValue::LuaBoolean(true));
+    assert_eq!(engine.handler_count("record"), 2);
+    assert_eq!(exec_recording(&mut engine, "record"), "ac");
+    // Already removed.
+    exec_recording(&mut engine, "remove_b");
+    let removed = engine.get_global("removed").unwrap();
+    assert_eq!(removed, AnyLuaValue::LuaBoolean(false));
+
+    exec_recording(&mut engine, "clear");
+    assert_eq!(engine.handler_count("record"), 0);
+    assert_eq!(exec_recording(&mut engine, "record"), "");
+    assert_eq!(engine.handler_count("not_an_event"), 0);
+}
+
+/// Tests that removing handlers while their event is being activated neither skips nor repeats
+/// the handlers left.
+#[test]
+fn lua_event_remove_during_dispatch() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), HANDLERS.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    assert_eq!(engine.handler_count("remove_mid"), 3);
+    assert_eq!(exec_recording(&mut engine, "remove_mid"), "13");
+    assert_eq!(engine.handler_count("remove_mid"), 1);
+    assert_eq!(exec_recording(&mut engine, "remove_mid"), "3");
+}
+
+/// Tests that a handler returning buildengine.CANCEL stops the handlers after it.
+#[test]
+fn lua_event_cancel() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), CANCEL.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let cases = [("cancel_first", EventOutcome::Cancelled { by_handler: 1 }, "a"),
+                 ("cancel_middle", EventOutcome::Cancelled { by_handler: 2 }, "ab"),
+                 ("no_cancel", EventOutcome::Completed(Vec::new()), "ab")];
+    for &(event, ref expected, expected_calls) in &cases {
+        let outcome = engine.exec_event(event, Vec::new()).unwrap();
+        assert_eq!(&outcome, expected, "event {}", event);
+        let calls = global_string(&mut engine, "calls");
+        assert_eq!(calls, expected_calls, "event {}", event);
+        engine.set_global("calls", AnyLuaValue::LuaString(String::new())).unwrap();
+    }
+}
+
+/// Tests calling rust functions registered before and after the init script runs.
+#[test]
+fn register_native_fn() {
+    test_util::start_log_once();
+    let tattle = test_util::Tattle::new();
+    let tattle_clone = tattle.clone();
+    // Checked outside of the function, as panicking inside of lua would abort.
+    let called_with = Arc::new(Mutex::new(Vec::new()));
+    let called_with_clone = called_with.clone();
+    let mut engine = Engine::new_empty(SandboxLevel::Full).unwrap();
+    engine.register_fn("record", move |args| {
+        *called_with_clone.lock().unwrap() = args;
+        tattle_clone.call();
+        Some(AnyLuaValue::LuaNumber(5.0))
+    }).unwrap();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), NATIVE.to_owned());
+    engine.run_init(scripts).unwrap();
+    tattle.assert_changed(|| {
+        engine.exec_event("call_native", Vec::new()).unwrap();
+    });
+    assert_eq!(*called_with.lock().unwrap(),
+               vec![AnyLuaValue::LuaNumber(1.0),
+                    AnyLuaValue::LuaNil,
+                    AnyLuaValue::LuaString("three".to_owned())]);
+    let result = engine.get_global("native_result").unwrap();
+    assert_eq!(result, AnyLuaValue::LuaNumber(5.0));
+
+    // The script kept the old function, but calls the new one.
+    let replaced = test_util::Tattle::new();
+    let replaced_clone = replaced.clone();
+    engine.register_fn("record", move |_args| {
+        replaced_clone.call();
+        None
+    }).unwrap();
+    replaced.assert_changed(|| {
+        engine.exec_event("call_native", Vec::new()).unwrap();
+    });
+    assert_eq!(tattle.get(), 1);
+    assert_eq!(engine.get_global("native_result"), None);
+}
+
+/// Tests that the number of arguments, and their indexes, come from lua, so made up ones are
+/// bounded by the values given rather than allocated for.
+#[test]
+fn native_fn_bounded_nils() {
+    test_util::start_log_once();
+    let called_with = Arc::new(Mutex::new(Vec::new()));
+    let called_with_clone = called_with.clone();
+    let mut engine = Engine::new_empty(SandboxLevel::Full).unwrap();
+    engine.register_fn("record", move |args| {
+        *called_with_clone.lock().unwrap() = args;
+        None
+    }).unwrap();
+    engine.eval("prelude_buildengine.native_raw.record(1e12, {[1e11] = true, \"a\"})").unwrap();
+    {
+        let called_with = called_with.lock().unwrap();
+        assert_eq!(called_with.len(), 2 + MAX_NATIVE_NILS);
+        assert_eq!(called_with[0], AnyLuaValue::LuaString("a".to_owned()));
+        assert!(called_with[1..].iter().all(|arg| *arg == AnyLuaValue::LuaNil));
+    }
+    engine.eval("prelude_buildengine.native_raw.record(0 / 0, {\"a\"})").unwrap();
+    assert_eq!(*called_with.lock().unwrap(), Vec::new());
+}
+
+/// Tests that tables rust can't read, as they contain themselves or are nested too deep, error
+/// when passed to a rust function instead of reaching it, and tables that are only shared don't.
+#[test]
+fn native_fn_unreadable_args() {
+    test_util::start_log_once();
+    let tattle = test_util::Tattle::new();
+    let tattle_clone = tattle.clone();
+    let mut engine = Engine::new_empty(SandboxLevel::Full).unwrap();
+    engine.register_fn("record", move |_args| {
+        tattle_clone.call();
+        None
+    }).unwrap();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), NATIVE.to_owned());
+    engine.run_init(scripts).unwrap();
+    engine.eval("cyclic = {} cyclic.self = {cyclic}
+                 deep = {} for i = 1, 1000 do deep = {deep} end
+                 shared = {} shared = {shared, shared, {shared}}")
+          .unwrap();
+    for unreadable in &["be.native.record(1, cyclic)",
+                        "be.native.record({[cyclic] = true})",
+                        "be.native.record(deep)",
+                        "prelude_buildengine.native_raw.record(1, {cyclic})",
+                        "prelude_buildengine.storage_set(\"key\", cyclic)",
+                        "prelude_buildengine.storage_set(\"key\", deep)",
+                        "prelude_buildengine.net_broadcast(\"event\", cyclic)"] {
+        match engine.eval(unreadable) {
+            Err(LuaError::ExecutionError(ref message)) => {
+                assert!(message.contains("contains itself") || message.contains("nested"),
+                        "unexpected error for {}: {}",
+                        unreadable,
+                        message)
+            }
+            other => panic!("expected {} to fail, got {:?}", unreadable, other),
+        }
+    }
+    assert_eq!(tattle.get(), 0);
+    engine.eval("be.native.record(shared) be.storage.set(\"key\", shared)").unwrap();
+    assert_eq!(tattle.get(), 1);
+}
+
+/// Tests that events running over the execution limit are aborted, and the next event still runs.
+#[test]
+fn exec_event_timeout() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), TIMEOUT.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let limits = [ExecutionLimit {
+                      instructions: None,
+                      millis: Some(100),
+                  },
+                  ExecutionLimit {
+                      instructions: Some(100_000),
+                      millis: None,
+                  }];
+    for limit in &limits {
+        engine.set_execution_limit(*limit);
+        for event in &["spin", "spin_caught", "spin_unlimited", "spin_stopped", "spin_coroutine"] {
+            match engine.exec_event(event, Vec::new()) {
+                Err(ExecEventError::Timeout { event: ref timed_out }) => {
+                    assert_eq!(timed_out, event)
+                }
+                other => panic!("expected a timeout for {}, got {:?}", event, other),
+            }
+            engine.set_global("after_ran", AnyLuaValue::LuaBoolean(false)).unwrap();
+            engine.exec_event("after", Vec::new()).unwrap();
+            let after_ran = engine.get_global("after_ran").unwrap();
+            assert_eq!(after_ran, AnyLuaValue::LuaBoolean(true));
+        }
+    }
+}
+
+/// Tests that an init script running over the execution limit is aborted.
+#[test]
+fn init_timeout() {
+    test_util::start_log_once();
+    let mut engine = Engine::new_empty(SandboxLevel::Full).unwrap();
+    engine.set_execution_limit(ExecutionLimit {
+        instructions: Some(100_000),
+        millis: None,
+    });
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), "while true do end".to_owned());
+    assert!(engine.run_init(scripts).is_err());
+}
+
+/// Tests that events using more memory than the memory limit are aborted, and the next event
+/// still runs.
+#[test]
+fn exec_event_memory_limit() {
+    test_util::start_log_once();
+    let limit = 4 * 1024 * 1024;
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), MEMORY.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let usage = engine.lua_memory_usage().unwrap();
+    assert!(usage > 0 && usage < limit, "usage: {}", usage);
+    engine.set_memory_limit(Some(limit));
+    match engine.exec_event("allocate", Vec::new()) {
+        Err(ExecEventError::MemoryLimitExceeded { ref event, usage }) => {
+            assert_eq!(event, "allocate");
+            assert!(usage > limit, "usage: {}", usage);
+        }
+        other => panic!("expected the memory limit to be exceeded, got {:?}", other),
+    }
+    engine.exec_event("after", Vec::new()).unwrap();
+    let after_ran = engine.get_global("after_ran").unwrap();
+    assert_eq!(after_ran, AnyLuaValue::LuaBoolean(true));
+}
+
+/// Executes "greet" and "count", returning the greeting and how many times "count" has run.
+fn greet(engine: &mut Engine) -> (String, f64) {
+    engine.exec_event("greet", Vec::new()).unwrap();
+    engine.exec_event("count", Vec::new()).unwrap();
+    let greeting = global_string(engine, "greeting");
+    let counted = match engine.get_module_global("greeter", "counted") {
+        Some(AnyLuaValue::LuaNumber(counted)) => counted,
+        other => panic!("expected greeter to have counted, got {:?}", other),
+    };
+    (greeting, counted)
+}
+
+/// Tests reloading a module, and that a new version that fails to load leaves the old one.
+#[test]
+fn reload_module() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), RELOAD.to_owned());
+    scripts.insert("greeter".to_owned(), GREETER.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    assert_eq!(greet(&mut engine), ("hello".to_owned(), 1.0));
+
+    engine.reload_module("greeter", GREETER_RELOADED.to_owned()).unwrap();
+    let reloaded = global_string(&mut engine, "reloaded");
+    assert_eq!(reloaded, "greeter");
+    // The old version's handler for "count" was removed, so it only counts once.
+    assert_eq!(engine.handler_count("count"), 1);
+    assert_eq!(greet(&mut engine), ("goodbye".to_owned(), 2.0));
+
+    match engine.reload_module("greeter", "this is not lua".to_owned()) {
+        Err(ReloadError::SyntaxError(_)) => {}
+        other => panic!("expected a syntax error, got {:?}", other),
+    }
+    let failing = "require(\"buildengine\").on(\"count\", function () end)
+                   error(\"failed to load\")";
+    match engine.reload_module("greeter", failing.to_owned()) {
+        Err(ReloadError::RuntimeError(ref message)) => {
+            assert!(message.contains("failed to load"), "message: {}", message)
+        }
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+    assert_eq!(engine.handler_count("count"), 1);
+    assert_eq!(greet(&mut engine), ("goodbye".to_owned(), 3.0));
+
+    match engine.reload_module("not_a_module", GREETER.to_owned()) {
+        Err(ReloadError::UnknownModule(ref name)) => assert_eq!(name, "not_a_module"),
+        other => panic!("expected an unknown module, got {:?}", other),
+    }
+}
+
+/// Tests loading modules after the engine is made, requiring one from another, and unloading
+/// them.
+#[test]
+fn load_and_unload_modules() {
+    test_util::start_log_once();
+    let mut engine = without_init(HashMap::new());
+    engine.load_module("helper", "return {value = function () return 42 end}".to_owned())
+          .unwrap();
+    let user = "local helper = require(\"helper\")
+                require(\"buildengine\").on(\"use\", function () used = helper.value() end)";
+    engine.load_module("user", user.to_owned()).unwrap();
+    engine.exec_event("use", Vec::new()).unwrap();
+    assert_eq!(engine.get_module_global("user", "used"),
+               Some(AnyLuaValue::LuaNumber(42.0)));
+
+    match engine.load_module("helper", "return {}".to_owned()) {
+        Err(ReloadError::DuplicateModule(ref name)) => assert_eq!(name, "helper"),
+        other => panic!("expected a duplicate module, got {:?}", other),
+    }
+    for name in &RESERVED_MODULES {
+        match engine.load_module(name, "return {}".to_owned()) {
+            Err(ReloadError::ReservedName(ref reserved)) => assert_eq!(reserved, name),
+            other => panic!("expected {} to be reserved, got {:?}", name, other),
+        }
+    }
+    match engine.unload_module("buildengine") {
+        Err(ReloadError::ReservedName(_)) => {}
+        other => panic!("expected buildengine to be reserved, got {:?}", other),
+    }
+
+    engine.unload_module("user").unwrap();
+    assert!(!engine.has_module("user"));
+    assert_eq!(engine.handler_count("use"), 0);
+    match engine.unload_module("user") {
+        Err(ReloadError::UnknownModule(_)) => {}
+        other => panic!("expected an unknown module, got {:?}", other),
+    }
+}
+
+/// Tests loading modules from lua, which untrusted scripts can't.
+#[test]
+fn load_modules_from_lua() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), MODULES.to_owned());
+    let mut engine = Engine::new_with_sandbox(scripts.clone(), SandboxLevel::Full).unwrap();
+    let value = global_number(&mut engine, "from_lua_value");
+    assert_eq!(value, 7.0);
+    let reserved_ok = engine.get_global("reserved_ok").unwrap();
+    assert_eq!(reserved_ok, AnyLuaValue::LuaBoolean(false));
+    let mut engine = Engine::new_with_sandbox(scripts, SandboxLevel::Untrusted).unwrap();
+    let can_load = engine.get_global("can_load_modules").unwrap();
+    assert_eq!(can_load, AnyLuaValue::LuaBoolean(false));
+    assert_eq!(engine.get_global("bypassed").unwrap(), AnyLuaValue::LuaBoolean(false));
+    assert!(!engine.has_module("evil"));
+    // The engine itself still can.
+    engine.load_module("helper", "return {value = 42}".to_owned()).unwrap();
+    assert_eq!(engine.eval("require(\"helper\").value").unwrap(), AnyLuaValue::LuaNumber(42.0));
+    engine.unload_module("helper").unwrap();
+    assert!(!engine.has_module("helper"));
+}
+
+/// Tests that modules requiring each other fail to load with the modules involved, rather than
+/// overflowing the stack.
+#[test]
+fn circular_require() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("a".to_owned(), "require(\"b\")\nreturn {}".to_owned());
+    scripts.insert("b".to_owned(), "require(\"a\")\nreturn {}".to_owned());
+    match Engine::new_with_policy(scripts, SandboxLevel::Full, InitPolicy::AllowMissing) {
+        Err(LuaError::ExecutionError(ref message)) => {
+            assert!(message.contains("circular require: a -> b -> a"),
+                    "unexpected message: {}",
+                    message)
+        }
+        other => panic!("expected an execution error, got {:?}", other),
+    }
+}
+
+/// Tests validating scripts, which reports every problem without running anything.
+#[test]
+fn validate_scripts() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), "ran = true".to_owned());
+    scripts.insert("valid".to_owned(), "--@depends buildengine\nreturn {}".to_owned());
+    scripts.insert("broken".to_owned(), "local x = 1\n\nlocal = 2\nreturn x".to_owned());
+    scripts.insert("needy".to_owned(), "--@depends valid, nowhere\nreturn {}".to_owned());
+    let diagnostics = validate(&scripts);
+    assert_eq!(diagnostics.len(), 2, "unexpected diagnostics: {:?}", diagnostics);
+    match diagnostics[0] {
+        ScriptDiagnostic::SyntaxError { ref script, line, ref message } => {
+            assert_eq!(script, "broken");
+            assert_eq!(line, Some(3));
+            assert!(message.contains("[string \"broken\"]:3:"), "unexpected message: {}", message);
+        }
+        ref other => panic!("expected a syntax error, got {:?}", other),
+    }
+    assert_eq!(diagnostics[1],
+               ScriptDiagnostic::MissingDependency {
+                   script: "needy".to_owned(),
+                   dependency: "nowhere".to_owned(),
+               });
+
+    scripts.remove("broken");
+    scripts.remove("needy");
+    assert_eq!(validate(&scripts), Vec::new());
+
+    // Precompiled chunks are never loaded, so they don't validate either.
+    scripts.insert("binary".to_owned(), "\x1bLua".to_owned());
+    let diagnostics = validate(&scripts);
+    assert_eq!(diagnostics.len(), 1, "unexpected diagnostics: {:?}", diagnostics);
+    match diagnostics[0] {
+        ScriptDiagnostic::SyntaxError { ref script, ref message, .. } => {
+            assert_eq!(script, "binary");
+            assert!(message.contains("binary chunk"), "unexpected message: {}", message);
+        }
+        ref other => panic!("expected a syntax error, got {:?}", other),
+    }
+}
+
+/// Tests evaluating lua code in a running engine.
+#[test]
+fn eval() {
+    test_util::start_log_once();
+    let mut engine = without_init(HashMap::new());
+    assert_eq!(engine.eval("1 + 1").unwrap(), AnyLuaValue::LuaNumber(2.0));
+    assert_eq!(engine.eval("x = 5").unwrap(), AnyLuaValue::LuaNil);
+    assert_eq!(any_lua_to_vec(engine.eval("return x, \"y\"").unwrap()),
+               vec![AnyLuaValue::LuaNumber(5.0), AnyLuaValue::LuaString("y".to_owned())]);
+    assert_eq!(engine.eval("prelude_buildengine.set_clock").unwrap(), AnyLuaValue::LuaNil);
+
+    match engine.eval("error(\"evaluated \" .. x)") {
+        Err(LuaError::ExecutionError(ref message)) => {
+            assert!(message.contains("[string \"eval\"]:1: evaluated 5"),
+                    "unexpected message: {}",
+                    message)
+        }
+        other => panic!("expected an execution error, got {:?}", other),
+    }
+    assert!(engine.last_traceback().is_some());
+    match engine.eval("x +") {
+        Err(LuaError::SyntaxError(_)) => {}
+        other => panic!("expected a syntax error, got {:?}", other),
+    }
+
+    // Handlers added in a module are removed along with it's own.
+    engine.load_module("owner", "return {}".to_owned()).unwrap();
+    engine.eval_in_module("owner", "require(\"buildengine\").on(\"owned\", function () end)")
+          .unwrap();
+    assert_eq!(engine.handler_count("owned"), 1);
+    engine.unload_module("owner").unwrap();
+    assert_eq!(engine.handler_count("owned"), 0);
+    assert!(engine.eval_in_module("owner", "1").is_err());
+}
+
+/// Tests that each module keeps the globals it sets apart from the others, sharing them only
+/// through buildengine.shared.
+#[test]
+fn module_environments() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("a".to_owned(),
+                   "x = \"a\"
+                    _G.upper = string.upper(x)
+                    require(\"buildengine\").shared.from_a = x
+                    return {x = function () return x end, y = function () return y end}"
+                       .to_owned());
+    scripts.insert("b".to_owned(),
+                   "--@depends a
+                    x = \"b\"
+                    return {x = function () return x end,
+                            from_a = function () return require(\"buildengine\").shared.from_a end}"
+                       .to_owned());
+    scripts.insert("init".to_owned(), "x = \"init\"\ny = \"init\"".to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let string = |value: &str| Some(AnyLuaValue::LuaString(value.to_owned()));
+    assert_eq!(engine.get_module_global("a", "x"), string("a"));
+    assert_eq!(engine.get_module_global("b", "x"), string("b"));
+    assert_eq!(engine.get_global("x"), string("init"));
+    assert_eq!(engine.eval("require(\"a\").x()").unwrap(), string("a").unwrap());
+    assert_eq!(engine.eval("require(\"b\").x()").unwrap(), string("b").unwrap());
+    assert_eq!(engine.get_module_global("a", "upper"), string("A"));
+    assert_eq!(engine.get_global("upper"), None);
+    // Globals a module hasn't set are read from the shared ones, but aren't it's own.
+    assert_eq!(engine.eval("require(\"a\").y()").unwrap(), string("init").unwrap());
+    assert_eq!(engine.get_module_global("a", "y"), None);
+    assert_eq!(engine.eval("require(\"b\").from_a()").unwrap(), string("a").unwrap());
+
+    engine.eval_in_module("a", "y = \"a\"").unwrap();
+    assert_eq!(engine.eval("require(\"a\").y()").unwrap(), string("a").unwrap());
+    assert_eq!(engine.get_global("y"), string("init"));
+    assert_eq!(engine.get_module_global("nowhere", "x"), None);
+
+    // A module can't get at the metatable of it's environment, to change what the others read.
+    assert_eq!(engine.eval_in_module("a", "getmetatable(_ENV)").unwrap(),
+               AnyLuaValue::LuaBoolean(false));
+    assert!(engine.eval_in_module("a", "setmetatable(_ENV, {__index = {y = \"a\"}})").is_err());
+    assert_eq!(engine.eval("require(\"b\").x()").unwrap(), string("b").unwrap());
+    assert_eq!(engine.eval_in_module("b", "y").unwrap(), string("init").unwrap());
+}
+
+/// Tests getting and setting globals, and values at paths through tables.
+#[test]
+fn globals_and_paths() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("test".to_owned(), TEST.to_owned());
+    let mut engine = without_init(scripts);
+    assert_eq!(engine.get_global("unset"), None);
+    engine.set_global("set", AnyLuaValue::LuaNumber(3.0)).unwrap();
+    assert_eq!(engine.eval("set").unwrap(), AnyLuaValue::LuaNumber(3.0));
+    engine.set_global("set", AnyLuaValue::LuaNil).unwrap();
+    assert_eq!(engine.get_global("set"), None);
+
+    assert_eq!(engine.get_path("prelude_buildengine.modules.test"),
+               Some(AnyLuaValue::LuaString(TEST.to_owned())));
+    assert_eq!(engine.get_path("prelude_buildengine.modules.missing"), None);
+    assert_eq!(engine.get_path("prelude_buildengine.modules.test.len"), None);
+    assert_eq!(engine.get_path("nowhere.at.all"), None);
+    assert_eq!(engine.get_path("prelude_buildengine..modules"), None);
+
+    let deep = "a.b.c.d.e";
+    assert_eq!(engine.set_path(deep, AnyLuaValue::LuaBoolean(true), false),
+               Err(PathError::MissingTable("a".to_owned())));
+    engine.set_path(deep, AnyLuaValue::LuaBoolean(true), true).unwrap();
+    assert_eq!(engine.get_path(deep), Some(AnyLuaValue::LuaBoolean(true)));
+    assert_eq!(engine.eval("a.b.c.d.e").unwrap(), AnyLuaValue::LuaBoolean(true));
+    engine.set_path("a.b.other", AnyLuaValue::LuaNumber(1.0), false).unwrap();
+    assert_eq!(engine.get_path("a.b.c.d.e"), Some(AnyLuaValue::LuaBoolean(true)));
+
+    assert_eq!(engine.set_path("a.b.other.x", AnyLuaValue::LuaNil, true),
+               Err(PathError::NotATable("a.b.other".to_owned())));
+    assert_eq!(engine.set_path("", AnyLuaValue::LuaNil, true),
+               Err(PathError::InvalidPath("".to_owned())));
+}
+
+/// A module depending on the given modules, appending it's name to the global loaded when loaded.
+fn recording_module(name: &str, depends: &str) -> (String, String) {
+    let source = format!("-- The {} module.\n--@depends {}\n\
+                          local shared = require(\"buildengine\").shared\n\
+                          shared.loaded = (shared.loaded or \"\") .. \"{} \"",
+                         name,
+                         depends,
+                         name);
+    (name.to_owned(), source)
+}
+
+/// Tests that modules are loaded before init, each after the modules it depends on.
+#[test]
+fn module_dependency_order() {
+    test_util::start_log_once();
+    let scripts = vec![recording_module("a", "b"),
+                       ("init".to_owned(),
+                        "loaded = require(\"buildengine\").shared.loaded .. \"init\"".to_owned()),
+                       recording_module("b", "c, buildengine"),
+                       recording_module("c", "")];
+    let names: Vec<String> = order_scripts(scripts.clone())
+                                 .unwrap()
+                                 .into_iter()
+                                 .map(|(name, _)| name)
+                                 .collect();
+    assert_eq!(names, vec!["c", "b", "a", "init"]);
+    let mut engine = Engine::new_ordered(scripts, SandboxLevel::Full).unwrap();
+    let loaded = global_string(&mut engine, "loaded");
+    assert_eq!(loaded, "c b a init");
+
+    // Without dependencies a HashMap of scripts is loaded by name.
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    for name in &["z", "x", "y"] {
+        let (name, source) = recording_module(name, "");
+        scripts.insert(name, source);
+    }
+    scripts.insert("init".to_owned(),
+                   "loaded = require(\"buildengine\").shared.loaded".to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let loaded = global_string(&mut engine, "loaded");
+    assert_eq!(loaded, "x y z ");
+}
+
+/// Tests that scripts depending on each other, or on a script that doesn't exist, fail to load.
+#[test]
+fn module_dependency_errors() {
+    test_util::start_log_once();
+    let cycle = vec![recording_module("a", "b"),
+                     recording_module("b", "c"),
+                     recording_module("c", "a"),
+                     recording_module("d", "")];
+    match Engine::new_ordered(cycle.clone(), SandboxLevel::Full) {
+        Err(::InitError::ScriptDependencyCycle(ref names)) => {
+            assert_eq!(*names, vec!["a", "b", "c", "a"])
+        }
+        other => panic!("expected a dependency cycle, got {:?}", other),
+    }
+    match Engine::new(cycle.into_iter().collect()) {
+        Err(LuaError::ExecutionError(ref message)) => assert!(message.contains("a -> b -> c -> a")),
+        other => panic!("expected an execution error, got {:?}", other),
+    }
+    // Init is left untill last, though it depends on nothing, and the cycle is still found.
+    let cycle = vec![recording_module("init", ""),
+                     recording_module("x", "y"),
+                     recording_module("y", "x")];
+    match order_scripts(cycle) {
+        Err(::InitError::ScriptDependencyCycle(ref names)) => {
+            assert_eq!(*names, vec!["x", "y", "x"])
+        }
+        other => panic!("expected a dependency cycle, got {:?}", other),
+    }
+    match order_scripts(vec![recording_module("a", "missing")]) {
+        Err(::InitError::MissingScriptDependency(ref script, ref dependency)) => {
+            assert_eq!(script, "a");
+            assert_eq!(dependency, "missing");
+        }
+        other => panic!("expected a missing dependency, got {:?}", other),
+    }
+}
+
 /// Tests calling a prelude function using Engine::call_prelude_fn.
 #[test]
 fn call_fn_no_args() {
     test_util::start_log_once();
     let tattle = test_util::Tattle::new();
     let tattle_clone = tattle.clone();
-    let mut engine = Engine::new(HashMap::new()).unwrap();
+    let mut engine = without_init(HashMap::new());
     let fun = function0(|| {
         tattle_clone.call();
     });
@@ -62,3 +886,2288 @@ fn call_fn_no_args() {
                 result);
     });
 }
+
+/// Tests that a prelude call made while another is running, by a handler that called a native
+/// function, leaves the outer call's return alone, and that calls don't leave their returns behind.
+#[test]
+fn call_fn_nested() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), NESTED.to_owned());
+    let mut engine = Engine::new_empty(SandboxLevel::Full).unwrap();
+    // The native function executes "inner" on the engine that called it, as a native with a
+    // handle to it's engine would. The engine isn't moved while the pointer is in use.
+    let engine_ptr: *mut Engine = &mut engine;
+    engine.register_fn("double", move |args| {
+        let doubled = match args.get(0) {
+            Some(&AnyLuaValue::LuaNumber(value)) => AnyLuaValue::LuaNumber(value * 2.0),
+            _ => return None,
+        };
+        let args = vec![AnyLuaValue::LuaString("inner".to_owned()), doubled];
+        let engine = unsafe { &mut *engine_ptr };
+        let returns = engine.call_prelude_fn_multi("activate_event", args).unwrap();
+        Some(AnyLuaValue::LuaArray(returns.into_iter()
+                                          .enumerate()
+                                          .map(|(i, value)| {
+                                              (AnyLuaValue::LuaNumber(i as f64 + 1.0), value)
+                                          })
+                                          .collect()))
+    }).unwrap();
+    engine.run_init(scripts).unwrap();
+    let outcome = engine.exec_event("outer", Vec::new()).unwrap();
+    assert_eq!(outcome.returns(),
+               vec![AnyLuaValue::LuaString("outer".to_owned()),
+                    AnyLuaValue::LuaString("inner".to_owned()),
+                    AnyLuaValue::LuaNumber(42.0)]);
+    assert_eq!(engine.get_path("prelude_buildengine.calls"),
+               Some(AnyLuaValue::LuaArray(Vec::new())));
+
+    assert!(engine.call_prelude_fn("memory_usage", Vec::new()).unwrap().is_some());
+    assert_eq!(engine.call_prelude_fn("load_modules", Vec::new()).unwrap(), None);
+}
+
+/// Tests that every value handlers return is passed on, nils included.
+#[test]
+fn event_multiple_returns() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), RETURNS.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    fn exec(engine: &mut Engine, event: &str, args: Vec<AnyLuaValue>) -> Vec<AnyLuaValue> {
+        engine.exec_event(event, args).unwrap().returns()
+    }
+    assert_eq!(exec(&mut engine, "none", Vec::new()), Vec::new());
+    assert_eq!(exec(&mut engine, "one", Vec::new()), vec![AnyLuaValue::LuaNumber(1.0)]);
+    assert_eq!(exec(&mut engine, "three", Vec::new()),
+               vec![AnyLuaValue::LuaNumber(1.0),
+                    AnyLuaValue::LuaString("two".to_owned()),
+                    AnyLuaValue::LuaBoolean(true)]);
+    assert_eq!(exec(&mut engine, "nils", Vec::new()),
+               vec![AnyLuaValue::LuaNil, AnyLuaValue::LuaNumber(2.0), AnyLuaValue::LuaNil]);
+    assert_eq!(exec(&mut engine,
+                    "chain",
+                    vec![AnyLuaValue::LuaNumber(1.0), AnyLuaValue::LuaNumber(2.0)]),
+               vec![AnyLuaValue::LuaNumber(2.0),
+                    AnyLuaValue::LuaNil,
+                    AnyLuaValue::LuaNumber(1.0),
+                    AnyLuaValue::LuaNil]);
+    assert_eq!(engine.get_global("chain_count"), Some(AnyLuaValue::LuaNumber(3.0)));
+
+    let three = vec![AnyLuaValue::LuaString("three".to_owned())];
+    assert_eq!(engine.call_prelude_fn_multi("activate_event", three.clone()).unwrap().len(), 3);
+    assert_eq!(engine.call_prelude_fn("activate_event", three).unwrap(),
+               Some(AnyLuaValue::LuaNumber(1.0)));
+}
+
+/// Tests that handlers are given exactly the arguments an event is executed or queued with, and
+/// not it's name.
+#[test]
+fn handlers_get_exact_args() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(),
+                   "local be = require(\"buildengine\")
+                    be.on(\"args\", function (...) return select(\"#\", ...), ... end)
+                    be.on(\"queued\", function (...) queued = {select(\"#\", ...), ...} end)"
+                       .to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let args = vec![AnyLuaValue::LuaNumber(1.0),
+                    AnyLuaValue::LuaString("two".to_owned()),
+                    AnyLuaValue::LuaNil];
+    let returns = engine.exec_event("args", args.clone()).unwrap().returns();
+    let mut expected = vec![AnyLuaValue::LuaNumber(3.0)];
+    expected.extend(args.iter().cloned());
+    assert_eq!(returns, expected);
+    assert_eq!(engine.exec_event("args", Vec::new()).unwrap().returns(),
+               vec![AnyLuaValue::LuaNumber(0.0)]);
+
+    engine.queue_event("queued", args).unwrap();
+    assert_eq!(engine.process_queued_events(QUEUED_EVENTS_PER_TICK).unwrap(), 1);
+    assert_eq!(engine.eval("queued[1]").unwrap(), AnyLuaValue::LuaNumber(3.0));
+    assert_eq!(engine.eval("queued[2]").unwrap(), AnyLuaValue::LuaNumber(1.0));
+    assert_eq!(engine.eval("queued[3]").unwrap(), AnyLuaValue::LuaString("two".to_owned()));
+}
+
+/// Tests converting the values events return to rust types, and the errors when they can't be.
+#[test]
+fn exec_event_as_types() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(),
+                   "local be = require(\"buildengine\")
+                    be.on(\"echo\", function (...) return ... end)
+                    be.on(\"cancel\", function () return be.CANCEL end)
+                    be.on(\"nested\", function () return {{\"a\", 1}, {\"b\", 2}} end)
+                    be.on(\"list\", function () return {true, false, \"no\"} end)"
+                       .to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let number = |val: f64| AnyLuaValue::LuaNumber(val);
+    let string = |val: &str| AnyLuaValue::LuaString(val.to_owned());
+
+    let ok: bool = engine.exec_event_as("echo", vec![AnyLuaValue::LuaBoolean(true)]).unwrap();
+    assert!(ok);
+    let int: i64 = engine.exec_event_as("echo", vec![number(-42.0)]).unwrap();
+    assert_eq!(int, -42);
+    let float: f64 = engine.exec_event_as("echo", vec![number(1.5)]).unwrap();
+    assert_eq!(float, 1.5);
+    let text: String = engine.exec_event_as("echo", vec![string("hi")]).unwrap();
+    assert_eq!(text, "hi");
+    let some: Option<String> = engine.exec_event_as("echo", vec![string("hi")]).unwrap();
+    assert_eq!(some, Some("hi".to_owned()));
+    let none: Option<String> = engine.exec_event_as("echo", Vec::new()).unwrap();
+    assert_eq!(none, None);
+    let cancelled: Option<bool> = engine.exec_event_as("cancel", Vec::new()).unwrap();
+    assert_eq!(cancelled, None);
+    let array = lua_array(vec![(1.0, 3.0), (2.0, 2.0), (3.0, 1.0)]);
+    let vec: Vec<i64> = engine.exec_event_as("echo", vec![array]).unwrap();
+    assert_eq!(vec, vec![3, 2, 1]);
+    let nested: Vec<(String, i64)> = engine.exec_event_as("nested", Vec::new()).unwrap();
+    assert_eq!(nested, vec![("a".to_owned(), 1), ("b".to_owned(), 2)]);
+    let one: (f64,) = engine.exec_event_as("echo", vec![number(1.0)]).unwrap();
+    assert_eq!(one, (1.0,));
+    let args = vec![string("a"), AnyLuaValue::LuaBoolean(false)];
+    let two: (String, bool) = engine.exec_event_as("echo", args).unwrap();
+    assert_eq!(two, ("a".to_owned(), false));
+    let three: (i64, Option<i64>, String) =
+        engine.exec_event_as("echo", vec![number(1.0), AnyLuaValue::LuaNil, string("c")]).unwrap();
+    assert_eq!(three, (1, None, "c".to_owned()));
+    // Values past the end of the tuple are ignored.
+    let four: (i64, i64, i64, i64) =
+        engine.exec_event_as("echo", (1..6).map(|i| number(i as f64)).collect()).unwrap();
+    assert_eq!(four, (1, 2, 3, 4));
+
+    let mismatch = |result: Result<(), ExecEventError>| {
+        match result {
+            Err(ExecEventError::ReturnTypeMismatch { ref event, ref mismatch }) => {
+                (event.clone(), mismatch.to_string())
+            }
+            other => panic!("expected a ReturnTypeMismatch, got {:?}", other),
+        }
+    };
+    let result = engine.exec_event_as::<bool>("echo", vec![string("yes")]).map(|_| ());
+    assert_eq!(mismatch(result),
+               ("echo".to_owned(), "return value 1: expected boolean, found string".to_owned()));
+    let result = engine.exec_event_as::<i64>("echo", vec![number(1.5)]).map(|_| ());
+    assert_eq!(mismatch(result).1, "return value 1: expected integer, found number 1.5");
+    let result = engine.exec_event_as::<String>("echo", Vec::new()).map(|_| ());
+    assert_eq!(mismatch(result).1, "return value 1: expected string, found nil");
+    let result = engine.exec_event_as::<Option<f64>>("echo", vec![string("1")]).map(|_| ());
+    assert_eq!(mismatch(result).1, "return value 1: expected number or nil, found string");
+    let result = engine.exec_event_as::<Vec<bool>>("list", Vec::new()).map(|_| ());
+    assert_eq!(mismatch(result).1, "return value 1, element 3: expected boolean, found string");
+    let result = engine.exec_event_as::<Vec<bool>>("echo", vec![number(1.0)]).map(|_| ());
+    assert_eq!(mismatch(result).1, "return value 1: expected array of boolean, found number");
+    let result = engine.exec_event_as::<(i64, String)>("echo", vec![number(1.0)]).map(|_| ());
+    assert_eq!(mismatch(result).1, "return value 2: expected string, found nil");
+    let result = engine.exec_event_as::<Vec<(String, bool)>>("nested", Vec::new()).map(|_| ());
+    assert_eq!(mismatch(result).1,
+               "return value 1, element 1, element 2: expected boolean, found number");
+}
+
+/// Tests reading buildengine.info from a handler, and that scripts can't change it.
+#[test]
+fn engine_info() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), INFO.to_owned());
+    let mut engine = Engine::new_with_sandbox(scripts.clone(), SandboxLevel::Untrusted).unwrap();
+    let string = |val: &str| AnyLuaValue::LuaString(val.to_owned());
+    let returns = engine.exec_event("read_info", Vec::new()).unwrap().returns();
+    assert_eq!(returns,
+               vec![string(::VERSION),
+                    AnyLuaValue::LuaNumber(::net::PROTOCOL_VERSION as f64),
+                    AnyLuaValue::LuaBoolean(false),
+                    AnyLuaValue::LuaBoolean(false),
+                    AnyLuaValue::LuaNil,
+                    string(env::consts::OS),
+                    AnyLuaValue::LuaBoolean(true),
+                    AnyLuaValue::LuaBoolean(false)]);
+
+    let mut info = EngineInfo::default();
+    info.is_client = true;
+    info.tick_rate = Some(20);
+    info.features.insert("flying_cars".to_owned());
+    engine.set_info(info.clone());
+    assert_eq!(engine.info(), info);
+    let returns = engine.exec_event("read_info", Vec::new()).unwrap().returns();
+    assert_eq!(returns[3..],
+               [AnyLuaValue::LuaBoolean(true),
+                AnyLuaValue::LuaNumber(20.0),
+                string(env::consts::OS),
+                AnyLuaValue::LuaBoolean(true),
+                AnyLuaValue::LuaBoolean(true)]);
+
+    let returns = engine.exec_event("info_version_pairs", Vec::new()).unwrap().returns();
+    assert_eq!(returns, vec![string(::VERSION)]);
+    for how in &["set", "rawset", "metatable", "pairs"] {
+        match engine.exec_event("write_info", vec![string(how)]) {
+            Err(ExecEventError::HandlerError { .. }) => {}
+            other => panic!("expected writing with {} to error, got {:?}", how, other),
+        }
+    }
+    let version = engine.eval("require(\"buildengine\").info.version").unwrap();
+    assert_eq!(version, string(::VERSION));
+    let mut engine = Engine::new(scripts).unwrap();
+    assert!(engine.exec_event("write_info", vec![string("set")]).is_err());
+    assert!(engine.exec_event("write_info", vec![string("metatable")]).is_err());
+}
+
+/// Tests that errors of handlers are reported to the handlers of on_error, and kept for
+/// recent_errors, but an error of on_error itself is only kept.
+#[test]
+fn on_error_event() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), ON_ERROR.to_owned());
+    scripts.insert("exploder".to_owned(),
+                   "require(\"buildengine\").on(\"explode\", function () error(\"boom\", 0) end)"
+                       .to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    assert!(engine.recent_errors().is_empty());
+    match engine.exec_event("explode", Vec::new()) {
+        Err(ExecEventError::HandlerError { ref module, ref message, .. }) => {
+            assert_eq!(module.as_ref().map(|module| &module[..]), Some("exploder"));
+            assert_eq!(message, "boom");
+        }
+        other => panic!("expected a HandlerError, got {:?}", other),
+    }
+    assert_eq!(engine.eval("#reports").unwrap(), AnyLuaValue::LuaNumber(1.0));
+    assert_eq!(engine.eval("reports[1]").unwrap(),
+               AnyLuaValue::LuaString("explode|exploder|boom|true".to_owned()));
+    let errors = engine.recent_errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].event, "explode");
+    assert_eq!(errors[0].module, Some("exploder".to_owned()));
+    assert_eq!(errors[0].message, "boom");
+    assert!(errors[0].traceback.is_some());
+
+    engine.set_global("fail_reporting", AnyLuaValue::LuaBoolean(true)).unwrap();
+    assert!(engine.exec_event("explode", Vec::new()).is_err());
+    // on_error ran for the handler, but wasn't executed again for it's own error.
+    assert_eq!(engine.eval("#reports").unwrap(), AnyLuaValue::LuaNumber(2.0));
+    let errors = engine.recent_errors();
+    assert_eq!(errors.len(), 3);
+    assert_eq!(errors[2].event, "on_error");
+    assert_eq!(errors[2].message, "on_error failed too");
+
+    for _ in 0..RECENT_ERRORS_KEPT {
+        assert!(engine.exec_event("explode", Vec::new()).is_err());
+    }
+    assert_eq!(engine.recent_errors().len(), RECENT_ERRORS_KEPT);
+    engine.clear_recent_errors();
+    assert!(engine.recent_errors().is_empty());
+}
+
+/// Tests timers started by scripts, advancing the time by hand.
+#[test]
+fn script_timers() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), TIMERS.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    assert_eq!(engine.timer_count(), 4);
+    let steps = [(500, "o"), (500, "oa"), (1000, "oar"), (1000, "oarb"), (1000, "oarbrc")];
+    for &(millis, fired) in &steps {
+        engine.advance_time(Duration::from_millis(millis)).unwrap();
+        assert_eq!(global_string(&mut engine, "fired"), fired, "after {}ms more", millis);
+    }
+    // Cancelling a timer that already fired does nothing.
+    engine.eval("be.cancel_timer(once)").unwrap();
+    assert_eq!(engine.timer_count(), 1);
+    engine.eval("be.cancel_timer(repeating)").unwrap();
+    assert_eq!(engine.timer_count(), 0);
+    engine.advance_time(Duration::from_secs(10)).unwrap();
+    assert_eq!(global_string(&mut engine, "fired"), "oarbrc");
+
+    engine.eval("be.after(0, function () error(\"timer went wrong\") end)").unwrap();
+    match engine.advance_time(Duration::from_secs(0)) {
+        Err(ExecEventError::LuaError { ref event, .. }) => assert_eq!(event, "timer 7"),
+        other => panic!("expected a lua error, got {:?}", other),
+    }
+
+    // Timers too far off, or not a number of seconds at all, are refused.
+    for seconds in &["1e300", "0/0", "1/0", "\"soon\""] {
+        let code = format!("be.after({}, function () end)", seconds);
+        assert!(engine.eval(&code).is_err(), "{} seconds", seconds);
+    }
+    // Even started past the checks, a timer that can never be due doesn't overflow the time.
+    engine.eval("prelude_buildengine.add_timer(1e300, true)").unwrap();
+    engine.advance_time(Duration::from_secs(1)).unwrap();
+    engine.advance_time(Duration::new(u64::MAX, 0)).unwrap();
+}
+
+/// Tests the game time and ticks scripts read with buildengine.time, and that snapshots carry them
+/// over, along with the timers kept against them.
+#[test]
+fn game_time() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), TIME.to_owned());
+    let mut engine = Engine::new(scripts.clone()).unwrap();
+    assert_eq!(engine.eval("time_state()").unwrap(),
+               AnyLuaValue::LuaString("0 0 0".to_owned()));
+    for _ in 0..3 {
+        engine.advance_tick(Duration::from_millis(500)).unwrap();
+    }
+    assert_eq!(engine.tick(), 3);
+    assert_eq!(engine.game_time(), Duration::from_millis(1500));
+    assert_eq!(engine.eval("time_state()").unwrap(),
+               AnyLuaValue::LuaString("1.5 3 0.5".to_owned()));
+    assert_eq!(global_number(&mut engine, "fired_at"), 1.0);
+    // Advancing the time alone doesn't count a tick.
+    engine.advance_time(Duration::from_millis(250)).unwrap();
+    assert_eq!(engine.eval("time_state()").unwrap(),
+               AnyLuaValue::LuaString("1.75 3 0.5".to_owned()));
+
+    engine.eval("start_timer()").unwrap();
+    let snapshot = engine.snapshot().unwrap();
+    assert_eq!(snapshot.game_time, 1.75);
+    assert_eq!(snapshot.tick, 3);
+    let mut restored = Engine::restore(scripts, snapshot, SandboxLevel::Full).unwrap();
+    assert_eq!(restored.game_time(), Duration::from_millis(1750));
+    assert_eq!(restored.eval("time_state()").unwrap(),
+               AnyLuaValue::LuaString("1.75 3 0".to_owned()));
+    // The restored timer was cancelled, as it wasn't given it's callback back.
+    restored.eval("start_timer()").unwrap();
+    restored.advance_tick(Duration::from_millis(1000)).unwrap();
+    assert_eq!(global_number(&mut restored, "fired_at"), 2.75);
+    assert_eq!(restored.eval("time_state()").unwrap(),
+               AnyLuaValue::LuaString("2.75 4 1".to_owned()));
+}
+
+/// Tests that queued events run in order, after the event queuing them, and that those over the
+/// budget stay queued.
+#[test]
+fn queued_events() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), QUEUE.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let args = vec![AnyLuaValue::LuaString("a".to_owned()), AnyLuaValue::LuaString("b".to_owned())];
+    engine.queue_event("first", args.clone()).unwrap();
+    assert_eq!(engine.queued_event_count(), 1);
+    assert_eq!(global_string(&mut engine, "ran"), "");
+    assert_eq!(engine.process_queued_events(10).unwrap(), 3);
+    assert_eq!(global_string(&mut engine, "ran"), "first(ab)/second(x)third");
+    assert_eq!(engine.queued_event_count(), 0);
+
+    engine.set_global("ran", AnyLuaValue::LuaString(String::new())).unwrap();
+    engine.queue_event("first", args).unwrap();
+    assert_eq!(engine.process_queued_events(2).unwrap(), 2);
+    assert_eq!(global_string(&mut engine, "ran"), "first(ab)/second(x)");
+    assert_eq!(engine.queued_event_count(), 1);
+    assert_eq!(engine.process_queued_events(10).unwrap(), 1);
+    assert_eq!(global_string(&mut engine, "ran"), "first(ab)/second(x)third");
+    assert_eq!(engine.process_queued_events(10).unwrap(), 0);
+
+    // An event name that isn't a string is refused, and an entry that isn't an event is dropped
+    // without holding up the ones after it.
+    match engine.eval("be.queue_event(5)") {
+        Err(LuaError::ExecutionError(ref message)) => {
+            assert!(message.contains("must be a string"), "unexpected message: {}", message)
+        }
+        other => panic!("expected queueing a number to fail, got {:?}", other),
+    }
+    engine.set_global("ran", AnyLuaValue::LuaString(String::new())).unwrap();
+    engine.eval("local queue = prelude_buildengine.queued_events
+                 queue.last = queue.last + 1
+                 queue[queue.last] = {5}
+                 be.queue_event(\"third\")")
+          .unwrap();
+    assert_eq!(engine.queued_event_count(), 2);
+    assert_eq!(engine.process_queued_events(10).unwrap(), 1);
+    assert_eq!(global_string(&mut engine, "ran"), "third");
+    assert_eq!(engine.queued_event_count(), 0);
+}
+
+/// Tests that handlers added to wildcards are called after the event's own, only for events that
+/// match them.
+#[test]
+fn wildcard_events() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), WILDCARD.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    engine.exec_event("player.move", vec![AnyLuaValue::LuaNumber(1.0)]).unwrap();
+    let calls = exec_recording(&mut engine, "player.jump");
+    assert_eq!(calls,
+               "exact(1)player(player.move)all(player.move)player(player.jump)all(player.jump)");
+    assert_eq!(exec_recording(&mut engine, "world.tick"), "all(world.tick)");
+    assert_eq!(exec_recording(&mut engine, "player"), "all(player)");
+    assert_eq!(exec_recording(&mut engine, "players.move"), "all(players.move)");
+
+    for name in &["", "*", "player.*"] {
+        match engine.exec_event(name, Vec::new()) {
+            Err(ExecEventError::InvalidEventName(ref invalid)) => assert_eq!(invalid, name),
+            other => panic!("expected {:?} to be invalid, got {:?}", name, other),
+        }
+    }
+
+    engine.eval("be.off(\"*\", everything)").unwrap();
+    assert_eq!(exec_recording(&mut engine, "world.tick"), "");
+    assert_eq!(exec_recording(&mut engine, "player.jump"), "player(player.jump)");
+}
+
+/// Tests recording the time taken by handlers, and logging slow ones.
+#[test]
+fn handler_profiling() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), PROFILING.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    engine.exec_event("tick", Vec::new()).unwrap();
+    assert!(engine.profiling_report().is_empty());
+
+    engine.set_profiling(true).unwrap();
+    engine.set_slow_handler_threshold(Some(Duration::from_millis(10))).unwrap();
+    for _ in 0..2 {
+        engine.exec_event("tick", Vec::new()).unwrap();
+    }
+    let report = engine.profiling_report();
+    assert_eq!(report.len(), 2);
+    let slow = &report[0];
+    assert_eq!(slow.module, "init");
+    assert_eq!(slow.event, "tick");
+    assert_eq!(slow.handler_id, global_number(&mut engine, "slow") as u64);
+    assert_eq!(slow.calls, 2);
+    assert!(slow.total >= Duration::from_millis(100));
+    assert!(slow.max >= Duration::from_millis(50) && slow.max <= slow.total);
+    let fast = &report[1];
+    assert_eq!(fast.handler_id, global_number(&mut engine, "fast") as u64);
+    assert_eq!(fast.calls, 2);
+    assert!(fast.total < slow.total);
+
+    engine.set_profiling(false).unwrap();
+    engine.set_slow_handler_threshold(None).unwrap();
+    engine.exec_event("tick", Vec::new()).unwrap();
+    assert_eq!(engine.profiling_report()[0].calls, 2);
+    engine.clear_profiling().unwrap();
+    assert!(engine.profiling_report().is_empty());
+}
+
+/// Tests storing values with buildengine.storage, and saving them to load into another engine.
+#[test]
+fn script_storage() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), STORAGE.to_owned());
+    let mut engine = Engine::new(scripts.clone()).unwrap();
+    engine.eval("store_world()").unwrap();
+    assert_eq!(engine.eval("check_world()").unwrap(), AnyLuaValue::LuaBoolean(true));
+    for bad in &["be.storage.set(\"bad\", {print})",
+                 "local t = {} t.t = t be.storage.set(\"bad\", t)",
+                 "be.storage.set(1, 2)"] {
+        match engine.eval(bad) {
+            Err(LuaError::ExecutionError(_)) => {}
+            other => panic!("expected storing with {:?} to fail, got {:?}", bad, other),
+        }
+    }
+    assert_eq!(engine.eval("be.storage.get(\"bad\")").unwrap(), AnyLuaValue::LuaNil);
+
+    let path = temp_dir("storage").join("storage.bin");
+    engine.save_storage(&path).unwrap();
+    let mut restarted = Engine::new(scripts).unwrap();
+    assert_eq!(restarted.eval("be.storage.get(\"world\")").unwrap(), AnyLuaValue::LuaNil);
+    restarted.load_storage(&path).unwrap();
+    assert_eq!(restarted.eval("check_world()").unwrap(), AnyLuaValue::LuaBoolean(true));
+
+    write_file(path.parent().unwrap(), "storage.bin", b"\xff");
+    match restarted.load_storage(&path) {
+        Err(ref err) if err.kind() == io::ErrorKind::InvalidData ||
+                        err.kind() == io::ErrorKind::UnexpectedEof => {}
+        other => panic!("expected a malformed file to fail to load, got {:?}", other),
+    }
+    assert_eq!(restarted.eval("check_world()").unwrap(), AnyLuaValue::LuaBoolean(true));
+}
+
+/// Tests taking a snapshot of an engine and restoring it, the scripts carrying on from where they
+/// were.
+#[test]
+fn snapshot_and_restore() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), COUNTER.to_owned());
+    let mut engine = Engine::new(scripts.clone()).unwrap();
+    for _ in 0..3 {
+        engine.exec_event("count", Vec::new()).unwrap();
+    }
+    engine.eval("start_ticking()").unwrap();
+    engine.eval("be.after(10, function () end)").unwrap();
+    engine.eval("be.storage.set(\"best\", 3)").unwrap();
+    engine.queue_event("count", vec![AnyLuaValue::LuaNumber(5.0)]).unwrap();
+    engine.advance_time(Duration::from_millis(500)).unwrap();
+    let snapshot = engine.snapshot().unwrap();
+    assert_eq!(snapshot.timers.len(), 2);
+    assert_eq!(snapshot.queued_events,
+               vec![("count".to_owned(), vec![LuaValueRepr::Number(5.0)])]);
+    let encoded = serialize(&snapshot, SizeLimit::Infinite).unwrap();
+    let snapshot: EngineSnapshot = deserialize(&encoded).unwrap();
+    assert_eq!(snapshot.rng_state, engine.rng_state());
+
+    // Restoring into an engine that was set up first keeps it's sandbox and natives.
+    let mut sandboxed = Engine::new_empty(SandboxLevel::Untrusted).unwrap();
+    sandboxed.register_fn("seven", |_| Some(AnyLuaValue::LuaNumber(7.0))).unwrap();
+    sandboxed.run_restore(scripts.clone(), snapshot.clone()).unwrap();
+    assert_eq!(global_number(&mut sandboxed, "count"), 3.0);
+    assert_eq!(sandboxed.eval("debug == nil").unwrap(), AnyLuaValue::LuaBoolean(true));
+    assert_eq!(sandboxed.eval("be.native.seven()").unwrap(), AnyLuaValue::LuaNumber(7.0));
+
+    let mut restored = Engine::restore(scripts, snapshot, SandboxLevel::Full).unwrap();
+    assert_eq!(restored.rng_state(), engine.rng_state());
+    assert_eq!(global_number(&mut restored, "count"), 3.0);
+    // The timer from be.after wasn't given it's callback back, so it was cancelled.
+    assert_eq!(restored.timer_count(), 1);
+    assert_eq!(restored.process_queued_events(QUEUED_EVENTS_PER_TICK).unwrap(), 1);
+    restored.exec_event("count", Vec::new()).unwrap();
+    assert_eq!(global_number(&mut restored, "count"), 9.0);
+    restored.advance_time(Duration::from_millis(500)).unwrap();
+    assert_eq!(global_number(&mut restored, "ticks"), 1.0);
+    assert_eq!(restored.eval("be.storage.get(\"best\")").unwrap(),
+               AnyLuaValue::LuaNumber(3.0));
+
+    restored.eval("be.on(\"on_snapshot\", function (state) state.f = print return state end)")
+            .unwrap();
+    match restored.snapshot() {
+        Err(SnapshotError::Unserializable(_)) => {}
+        other => panic!("expected a function in the state to fail, got {:?}", other),
+    }
+}
+
+/// Tests that print and buildengine.log go to the log, at the right level and with the module
+/// logging as the target.
+#[test]
+fn script_logging() {
+    test_util::start_log_once();
+    let mut engine = Engine::new_empty(SandboxLevel::Full).unwrap();
+    let tattle = test_util::Tattle::new();
+    let logged = Rc::new(RefCell::new(Vec::new()));
+    {
+        let tattle = tattle.clone();
+        let logged = logged.clone();
+        engine.register_fn("record_log", move |args| {
+            tattle.call();
+            let args: Vec<String> = args.into_iter()
+                                        .map(|arg| {
+                                            match arg {
+                                                AnyLuaValue::LuaString(arg) => arg,
+                                                other => format!("{:?}", other),
+                                            }
+                                        })
+                                        .collect();
+            logged.borrow_mut().push(args.join(" "));
+            None
+        }).unwrap();
+    }
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), LOG.to_owned());
+    scripts.insert("chat".to_owned(),
+                   "local be = require(\"buildengine\")\n\
+                    return {join = function () be.log.info(\"joined\") end}"
+                       .to_owned());
+    engine.run_init(scripts).unwrap();
+    assert_eq!(tattle.get(), 6);
+    assert_eq!(*logged.borrow(),
+               vec!["info lua hello\t1\tnil\ttrue",
+                    "info lua ",
+                    "warn lua::init 3 players",
+                    "error lua::init plain %d",
+                    "debug lua::init 42",
+                    "info lua::chat joined"]);
+
+    // Going to the real log works as well.
+    let mut engine = Engine::new_empty(SandboxLevel::Untrusted).unwrap();
+    engine.eval("print(\"to the log\")").unwrap();
+}
+
+/// Tests that buildengine.random, and math.random in the untrusted sandbox, give the same numbers
+/// for the same seed.
+#[test]
+fn seeded_random() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), RANDOM.to_owned());
+    let mut first = Engine::new(scripts.clone()).unwrap();
+    let mut second = Engine::new(scripts.clone()).unwrap();
+    first.set_rng_seed(42);
+    second.set_rng_seed(42);
+    let rolled = first.eval("roll()").unwrap();
+    assert_eq!(rolled, second.eval("roll()").unwrap());
+    assert_eq!(first.rng_state(), second.rng_state());
+
+    first.eval("be.random.seed(42)").unwrap();
+    assert_eq!(first.eval("roll()").unwrap(), rolled);
+    let state = first.rng_state();
+    let next = first.eval("roll()").unwrap();
+    assert!(next != rolled);
+    first.set_rng_seed(state);
+    assert_eq!(first.eval("roll()").unwrap(), next);
+
+    for bad in &["be.random.int(2, 1)", "be.random.int(1.5, 2)", "be.random.seed(\"42\")"] {
+        assert!(first.eval(bad).is_err(), "expected {} to fail", bad);
+    }
+
+    let mut untrusted = Engine::new_with_sandbox(scripts, SandboxLevel::Untrusted).unwrap();
+    untrusted.eval("math.randomseed(42)").unwrap();
+    first.set_rng_seed(42);
+    assert_eq!(untrusted.eval("math.random(1, 6), math.random(6), math.random()").unwrap(),
+               first.eval("be.random.int(1, 6), be.random.int(1, 6), be.random.float()")
+                    .unwrap());
+}
+
+/// Tests that empty or infinite ranges given to the generator error, rather than crashing, even
+/// when passed to it straight from prelude_buildengine.
+#[test]
+fn random_bad_range() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), RANDOM.to_owned());
+    let mut engine = Engine::new(scripts.clone()).unwrap();
+    for bad in &["prelude_buildengine.random_int(2, 1)",
+                 "prelude_buildengine.random_int(1, 0/0)",
+                 "prelude_buildengine.random_int(1, math.huge)",
+                 "be.random.int(1, math.huge)"] {
+        assert!(engine.eval(bad).is_err(), "expected {} to fail", bad);
+    }
+    let mut untrusted = Engine::new_with_sandbox(scripts, SandboxLevel::Untrusted).unwrap();
+    for bad in &["math.random(1, 0/0)", "math.random(0/0)", "math.random(-math.huge, 1)"] {
+        assert!(untrusted.eval(bad).is_err(), "expected {} to fail", bad);
+    }
+    assert!(untrusted.eval("math.random(1, 6)").is_ok());
+}
+
+/// Tests serializing a LuaValueRepr and converting it to and from AnyLuaValue.
+#[test]
+fn lua_value_repr_round_trip() {
+    test_util::start_log_once();
+    let repr = nested_repr();
+    let bytes = serialize(&repr, SizeLimit::Infinite).unwrap();
+    let read: LuaValueRepr = deserialize(&bytes).unwrap();
+    assert_eq!(read, repr);
+    let any: AnyLuaValue = read.try_into().unwrap();
+    assert_eq!(LuaValueRepr::from(any), repr);
+}
+
+/// Converts to AnyLuaValue and back, and through bincode, checking the value doesn't change.
+fn assert_repr_round_trip(repr: LuaValueRepr) {
+    let bytes = serialize(&repr, SizeLimit::Infinite).unwrap();
+    let read: LuaValueRepr = deserialize(&bytes).unwrap();
+    assert_eq!(read, repr);
+    let any: AnyLuaValue = read.try_into().unwrap();
+    assert_eq!(LuaValueRepr::from(any), repr);
+}
+
+/// Tests round tripping every kind of LuaValueRepr.
+#[test]
+fn lua_value_repr_kinds() {
+    test_util::start_log_once();
+    assert_repr_round_trip(LuaValueRepr::Nil);
+    assert_repr_round_trip(LuaValueRepr::Boolean(false));
+    assert_repr_round_trip(LuaValueRepr::Number(0.1));
+    assert_repr_round_trip(LuaValueRepr::Number(::std::f64::INFINITY));
+    assert_repr_round_trip(LuaValueRepr::String("".to_owned()));
+    assert_repr_round_trip(LuaValueRepr::Array(Vec::new()));
+    assert_repr_round_trip(LuaValueRepr::Table(vec![(LuaValueRepr::String("x".to_owned()),
+                                                     LuaValueRepr::Number(1.0)),
+                                                    (LuaValueRepr::Number(1.0),
+                                                     LuaValueRepr::Boolean(true)),
+                                                    (LuaValueRepr::Number(3.0),
+                                                     nested_repr())]));
+}
+
+/// Tests that a table with keys 1 to n in any order is an array, but one with gaps is not.
+#[test]
+fn lua_value_repr_arrays() {
+    test_util::start_log_once();
+    let shuffled = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(2.0),
+                                               AnyLuaValue::LuaString("b".to_owned())),
+                                              (AnyLuaValue::LuaNumber(1.0),
+                                               AnyLuaValue::LuaString("a".to_owned()))]);
+    assert_eq!(LuaValueRepr::from(shuffled),
+               LuaValueRepr::Array(vec![LuaValueRepr::String("a".to_owned()),
+                                        LuaValueRepr::String("b".to_owned())]));
+    let gap = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0), AnyLuaValue::LuaNil),
+                                         (AnyLuaValue::LuaNumber(3.0), AnyLuaValue::LuaNil)]);
+    match LuaValueRepr::from(gap) {
+        LuaValueRepr::Table(pairs) => assert_eq!(pairs.len(), 2),
+        other => panic!("expected a table, got {:?}", other),
+    }
+    let fraction = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.5), AnyLuaValue::LuaNil)]);
+    match LuaValueRepr::from(fraction) {
+        LuaValueRepr::Table(_) => {}
+        other => panic!("expected a table, got {:?}", other),
+    }
+}
+
+/// Shuffles the slice with a xorshift generator, so the same seed always gives the same order.
+fn shuffle<T>(items: &mut [T], mut seed: u64) {
+    for i in (1..items.len()).rev() {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        items.swap(i, (seed % (i as u64 + 1)) as usize);
+    }
+}
+
+/// Makes a lua array of the values with the given lua indexes.
+fn lua_array(pairs: Vec<(f64, f64)>) -> AnyLuaValue {
+    AnyLuaValue::LuaArray(pairs.into_iter()
+                               .map(|(index, value)| {
+                                   (AnyLuaValue::LuaNumber(index), AnyLuaValue::LuaNumber(value))
+                               })
+                               .collect())
+}
+
+/// Tests that arrays convert to the same vector whatever order lua gives their keys in.
+#[test]
+fn try_any_lua_to_vec_shuffled() {
+    test_util::start_log_once();
+    for len in 0..40 {
+        let expected: Vec<AnyLuaValue> = (0..len)
+                                             .map(|i| AnyLuaValue::LuaNumber(i as f64))
+                                             .collect();
+        for seed in 1..20 {
+            let mut pairs: Vec<(f64, f64)> = (0..len).map(|i| (i as f64 + 1.0, i as f64)).collect();
+            shuffle(&mut pairs, seed);
+            assert_eq!(try_any_lua_to_vec(lua_array(pairs)).unwrap(), expected);
+        }
+    }
+}
+
+/// Tests that the gaps in sparse arrays are filled with nil, whatever order the keys are in.
+#[test]
+fn try_any_lua_to_vec_sparse() {
+    test_util::start_log_once();
+    for len in 1..40 {
+        // Every odd index, ending with the highest so the length is known.
+        let mut pairs: Vec<(f64, f64)> = (0..len)
+                                             .filter(|i| i % 2 == 0 || *i == len - 1)
+                                             .map(|i| (i as f64 + 1.0, i as f64))
+                                             .collect();
+        let expected: Vec<AnyLuaValue> = (0..len)
+                                             .map(|i| {
+                                                 if i % 2 == 0 || i == len - 1 {
+                                                     AnyLuaValue::LuaNumber(i as f64)
+                                                 } else {
+                                                     AnyLuaValue::LuaNil
+                                                 }
+                                             })
+                                             .collect();
+        for seed in 1..20 {
+            shuffle(&mut pairs, seed);
+            assert_eq!(try_any_lua_to_vec(lua_array(pairs.clone())).unwrap(), expected);
+        }
+    }
+}
+
+/// Tests the errors converting values that aren't arrays.
+#[test]
+fn try_any_lua_to_vec_errors() {
+    test_util::start_log_once();
+    assert_eq!(try_any_lua_to_vec(AnyLuaValue::LuaNumber(1.0)),
+               Err(LuaConversionError::NotATable("number")));
+    assert_eq!(try_any_lua_to_vec(lua_array(vec![(0.0, 1.0), (1.0, 2.0)])),
+               Err(LuaConversionError::NotAnIndex(0.0)));
+    assert_eq!(try_any_lua_to_vec(lua_array(vec![(1.5, 1.0)])),
+               Err(LuaConversionError::NotAnIndex(1.5)));
+    assert_eq!(try_any_lua_to_vec(lua_array(vec![(1e9, 1.0)])),
+               Err(LuaConversionError::TooSparse {
+                   len: 1,
+                   highest: 1e9,
+               }));
+    let mixed = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0), AnyLuaValue::LuaNil),
+                                           (AnyLuaValue::LuaString("x".to_owned()),
+                                            AnyLuaValue::LuaNil)]);
+    assert_eq!(try_any_lua_to_vec(mixed), Err(LuaConversionError::BadKey("string")));
+}
+
+/// Tests converting maps to lua tables and back.
+#[test]
+fn any_lua_map_round_trip() {
+    test_util::start_log_once();
+    let mut map = HashMap::new();
+    map.insert("name".to_owned(), AnyLuaValue::LuaString("wrench".to_owned()));
+    map.insert("count".to_owned(), AnyLuaValue::LuaNumber(3.0));
+    assert_eq!(any_lua_to_map(map_to_any_lua(map.clone())).unwrap(), map);
+    assert_eq!(any_lua_to_map(lua_array(vec![(1.0, 1.0)])),
+               Err(LuaConversionError::BadKey("number")));
+    assert_eq!(any_lua_to_map(AnyLuaValue::LuaBoolean(true)),
+               Err(LuaConversionError::NotATable("boolean")));
+}
+
+/// Makes a table of LuaData from it's array and map parts.
+fn data_table(array: Vec<LuaData>, map: Vec<(&str, LuaData)>) -> LuaData {
+    LuaData::Table {
+        array: array,
+        map: map.into_iter().map(|(key, value)| (key.to_owned(), value)).collect(),
+    }
+}
+
+/// Tests converting nested tables with both array and map parts to LuaData and back.
+#[test]
+fn lua_data_round_trip() {
+    test_util::start_log_once();
+    let inventory = data_table(vec![LuaData::String("wrench".to_owned()),
+                                    LuaData::Nil,
+                                    LuaData::String("crowbar".to_owned())],
+                               vec![("size", LuaData::Number(4.0))]);
+    let data = data_table(vec![LuaData::Boolean(true), inventory],
+                          vec![("name", LuaData::String("assistant".to_owned())),
+                               ("stats",
+                                data_table(Vec::new(), vec![("health", LuaData::Number(100.0))]))]);
+    assert_eq!(any_lua_to_data(data_to_any_lua(data.clone())).unwrap(), data);
+
+    // Converting from lua, where the array and map parts are mixed in any order.
+    let mixed = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaString("x".to_owned()),
+                                            AnyLuaValue::LuaNumber(1.0)),
+                                           (AnyLuaValue::LuaNumber(2.0),
+                                            AnyLuaValue::LuaString("b".to_owned())),
+                                           (AnyLuaValue::LuaNumber(1.0),
+                                            AnyLuaValue::LuaString("a".to_owned()))]);
+    assert_eq!(any_lua_to_data(mixed).unwrap(),
+               data_table(vec![LuaData::String("a".to_owned()), LuaData::String("b".to_owned())],
+                          vec![("x", LuaData::Number(1.0))]));
+}
+
+/// Tests the errors converting values to LuaData.
+#[test]
+fn lua_data_errors() {
+    test_util::start_log_once();
+    let bool_key = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaBoolean(true),
+                                               AnyLuaValue::LuaNil)]);
+    assert_eq!(any_lua_to_data(bool_key), Err(LuaConversionError::BadKey("boolean")));
+    let function = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0),
+                                               AnyLuaValue::LuaOther)]);
+    assert_eq!(any_lua_to_data(function), Err(LuaConversionError::BadValue("other")));
+
+    let mut nested = AnyLuaValue::LuaNil;
+    for _ in 0..MAX_TABLE_DEPTH {
+        nested = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0), nested)]);
+    }
+    assert!(any_lua_to_data(nested.clone()).is_ok());
+    let too_deep = AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0), nested)]);
+    assert_eq!(any_lua_to_data(too_deep.clone()),
+               Err(LuaConversionError::TooDeep(MAX_TABLE_DEPTH)));
+    assert_eq!(any_lua_to_data_with_depth(too_deep, 3),
+               Err(LuaConversionError::TooDeep(3)));
+}
+
+/// Tests round tripping deeply nested tables.
+#[test]
+fn lua_value_repr_deep() {
+    test_util::start_log_once();
+    let mut array = LuaValueRepr::Nil;
+    let mut table = LuaValueRepr::Nil;
+    for i in 0..200 {
+        array = LuaValueRepr::Array(vec![array, LuaValueRepr::Number(i as f64)]);
+        table = LuaValueRepr::Table(vec![(LuaValueRepr::String(i.to_string()), table)]);
+    }
+    assert_repr_round_trip(array);
+    assert_repr_round_trip(table);
+}
+
+/// Tests the values that can't be converted back into lua.
+#[test]
+fn lua_value_repr_errors() {
+    test_util::start_log_once();
+    let nil_key = LuaValueRepr::Table(vec![(LuaValueRepr::Nil, LuaValueRepr::Nil)]);
+    let result: Result<AnyLuaValue, _> = nil_key.try_into();
+    assert_eq!(result, Err(LuaReprError::NilKey));
+    let nan_key = LuaValueRepr::Array(vec![LuaValueRepr::Table(vec![(
+        LuaValueRepr::Number(::std::f64::NAN), LuaValueRepr::Nil)])]);
+    let result: Result<AnyLuaValue, _> = nan_key.try_into();
+    assert_eq!(result, Err(LuaReprError::NanKey));
+    let result: Result<AnyLuaValue, _> = LuaValueRepr::Opaque.try_into();
+    assert_eq!(result, Err(LuaReprError::Opaque));
+    assert_eq!(LuaValueRepr::from(AnyLuaValue::LuaOther), LuaValueRepr::Opaque);
+    // NaN is fine anywhere other than a key.
+    let nan = LuaValueRepr::Number(::std::f64::NAN);
+    match nan.try_into() {
+        Ok(AnyLuaValue::LuaNumber(num)) => assert!(num.is_nan()),
+        other => panic!("expected NaN, got {:?}", other),
+    }
+}
+
+/// Tests passing a mixed table to lua through a LuaValueRepr, comparing it to the same table.
+#[test]
+fn lua_value_repr_to_lua() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), REPR.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let deep = LuaValueRepr::Table(vec![(LuaValueRepr::String("deep".to_owned()),
+                                         LuaValueRepr::String("er".to_owned()))]);
+    let nested = LuaValueRepr::Array(vec![LuaValueRepr::Array(Vec::new()),
+                                          LuaValueRepr::Array(vec![LuaValueRepr::Number(1.5),
+                                                                   deep])]);
+    let repr = LuaValueRepr::Table(vec![(LuaValueRepr::Number(1.0), LuaValueRepr::Number(1.0)),
+                                        (LuaValueRepr::Number(2.0), LuaValueRepr::Number(2.0)),
+                                        (LuaValueRepr::Number(3.0),
+                                         LuaValueRepr::String("three".to_owned())),
+                                        (LuaValueRepr::String("x".to_owned()),
+                                         LuaValueRepr::String("y".to_owned())),
+                                        (LuaValueRepr::Number(10.0), LuaValueRepr::Boolean(false)),
+                                        (LuaValueRepr::String("nested".to_owned()), nested)]);
+    let bytes = serialize(&repr, SizeLimit::Infinite).unwrap();
+    let read: LuaValueRepr = deserialize(&bytes).unwrap();
+    engine.exec_event("compare", vec![read.try_into().unwrap()]).unwrap();
+    let same = engine.get_global("same").unwrap();
+    assert_eq!(same, AnyLuaValue::LuaBoolean(true));
+}
+
+/// Tests passing a nested table to an event handler.
+#[test]
+fn nested_event_args() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), NET_EVENT.to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let bytes = serialize(&nested_repr(), SizeLimit::Infinite).unwrap();
+    let read: LuaValueRepr = deserialize(&bytes).unwrap();
+    let args = vec![AnyLuaValue::LuaNumber(7.0), read.try_into().unwrap()];
+    engine.exec_event("net_test", args).unwrap();
+    let from = engine.get_global("got_from").unwrap();
+    assert_eq!(from, AnyLuaValue::LuaNumber(7.0));
+    let first = engine.get_global("got_first").unwrap();
+    assert_eq!(first, AnyLuaValue::LuaString("te\"st\n".to_owned()));
+    let inner = engine.get_global("got_inner").unwrap();
+    assert_eq!(inner, AnyLuaValue::LuaBoolean(true));
+}
+
+/// A client engine connected to a new listening controller, which is returned along with it.
+fn connected_client() -> (::Engine<'static>, ::net::Controller) {
+    let mut server = ::net::Controller::new_empty();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    server.add_listener(listener).unwrap();
+    (::Engine::new_client(addr).unwrap(), server)
+}
+
+/// Tests that the overrides on an engine and a controller config take precedence over the global
+/// should_crash.
+#[test]
+fn engine_should_crash_override() {
+    test_util::start_log_once();
+    let (mut engine, _server) = connected_client();
+    assert_eq!(engine.should_crash, None);
+    assert_eq!(engine.should_crash(), ::should_crash());
+    engine.should_crash = Some(!::should_crash());
+    assert_eq!(engine.should_crash(), !::should_crash());
+    let mut config = ::net::ControllerConfig::default();
+    assert_eq!(config.should_crash(), ::should_crash());
+    config.should_crash = Some(!::should_crash());
+    assert_eq!(config.should_crash(), !::should_crash());
+}
+
+/// Tests that an Error packet crashes a client that should crash.
+#[test]
+#[should_panic(expected = "the server on connection 3 sent an error: ServerFull")]
+fn client_error_packet_crashes() {
+    test_util::start_log_once();
+    let (mut engine, _server) = connected_client();
+    engine.should_crash = Some(true);
+    let packet = ::net::NetworkPacket::Error(::net::NetworkError::ServerFull);
+    let _ = engine.handle_packet(::net::ConnectionId(3), packet);
+}
+
+/// Tests that an Error packet is returned on a client that should not crash.
+#[test]
+fn client_error_packet_recoverable() {
+    test_util::start_log_once();
+    let (mut engine, _server) = connected_client();
+    engine.should_crash = Some(false);
+    let packet = ::net::NetworkPacket::Error(::net::NetworkError::ServerFull);
+    match engine.handle_packet(::net::ConnectionId(3), packet) {
+        Err(::HandlePacketError::Peer(id, err)) => {
+            assert_eq!(id, ::net::ConnectionId(3));
+            assert_eq!(err, ::net::NetworkError::ServerFull);
+        }
+        other => panic!("expected a Peer error, got {:?}", other),
+    }
+    let packet = ::net::NetworkPacket::Error(::net::NetworkError::ShouldCrashBothTrue);
+    engine.handle_packet(::net::ConnectionId(3), packet).unwrap();
+}
+
+/// Tests that the reason the server closed the connection with is returned on a client.
+#[test]
+fn client_disconnect_packet_returns_reason() {
+    test_util::start_log_once();
+    let (mut engine, _server) = connected_client();
+    let packet = ::net::NetworkPacket::Disconnect { reason: "server restarting".to_owned() };
+    match engine.handle_packet(::net::ConnectionId(2), packet) {
+        Err(::HandlePacketError::Disconnected(id, reason)) => {
+            assert_eq!(id, ::net::ConnectionId(2));
+            assert_eq!(reason, "server restarting");
+        }
+        other => panic!("expected a Disconnected error, got {:?}", other),
+    }
+}
+
+/// Tests that an Event packet runs the event with the id of the connection it came from.
+#[test]
+fn handle_event_packet() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), NET_EVENT.to_owned());
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts).unwrap();
+    let packet = ::net::NetworkPacket::Event {
+        name: "net_test".to_owned(),
+        args: vec![nested_repr()],
+    };
+    engine.handle_packet(::net::ConnectionId(3), packet).unwrap();
+    let script_engine = engine.script_engine.as_mut().unwrap();
+    let from = script_engine.get_global("got_from").unwrap();
+    assert_eq!(from, AnyLuaValue::LuaNumber(3.0));
+    let inner = script_engine.get_global("got_inner").unwrap();
+    assert_eq!(inner, AnyLuaValue::LuaBoolean(true));
+}
+
+/// The address a server engine is listening on.
+fn server_addr(engine: &::Engine) -> SocketAddr {
+    let addrs = engine.net.listener_addrs();
+    assert_eq!(addrs.len(), 1);
+    addrs[0].1
+}
+
+/// Tests that a server engine accepts sockets on the address it was created with.
+#[test]
+fn server_engine_accepts() {
+    test_util::start_log_once();
+    let engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), HashMap::new()).unwrap();
+    let _stream = TcpStream::connect(server_addr(&engine)).unwrap();
+    let started = Instant::now();
+    while engine.net.raw.connections.read().unwrap().is_empty() {
+        assert!(started.elapsed() < Duration::from_millis(test_util::TEST_SLEEP_TIME_MILLIS * 4),
+                "the server engine did not accept the socket in time");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Tests that a server engine can't be created on an address that is in use.
+#[test]
+fn server_engine_bind_fails() {
+    test_util::start_log_once();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    match ::Engine::new_server(&listener.local_addr().unwrap(), HashMap::new()) {
+        Err(::InitError::IoError(_)) => {}
+        other => panic!("expected an IoError, got {:?}", other),
+    }
+}
+
+/// Tests that events sent to a server engine are executed by `handle_incoming`.
+#[test]
+fn server_engine_handles_events() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), NET_EVENT.to_owned());
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts).unwrap();
+    let mut client = ::net::Controller::new_empty();
+    let id = client.connect(server_addr(&engine)).unwrap();
+    let packet = ::net::NetworkPacket::Event {
+        name: "net_test".to_owned(),
+        args: vec![nested_repr()],
+    };
+    client.send_to(id, packet).unwrap();
+    let started = Instant::now();
+    loop {
+        engine.handle_incoming();
+        let script_engine = engine.script_engine.as_mut().unwrap();
+        let inner = script_engine.get_global("got_inner");
+        if inner == Some(AnyLuaValue::LuaBoolean(true)) {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_millis(test_util::TEST_SLEEP_TIME_MILLIS * 4),
+                "the server engine did not execute the event in time");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// A network for `buildengine.net` recording what is sent through it, with one peer, 1.
+struct RecordingNetwork(Rc<RefCell<Vec<(u64, String, Vec<LuaValueRepr>)>>>);
+
+impl ScriptNetwork for RecordingNetwork {
+    fn send(&self, to: u64, event_name: String, args: Vec<LuaValueRepr>) -> Result<(), String> {
+        if to != 1 {
+            return Err(format!("no connection {}", to));
+        }
+        self.0.borrow_mut().push((to, event_name, args));
+        Ok(())
+    }
+
+    fn broadcast(&self, event_name: String, args: Vec<LuaValueRepr>) -> usize {
+        self.0.borrow_mut().push((1, event_name, args));
+        1
+    }
+
+    fn kick(&self, to: u64, reason: &str) -> Result<(), String> {
+        let args = vec![LuaValueRepr::String(reason.to_owned())];
+        self.0.borrow_mut().push((to, "kicked".to_owned(), args));
+        Ok(())
+    }
+
+    fn peer_count(&self) -> usize {
+        1
+    }
+
+    fn peer_addr(&self, to: u64) -> Option<String> {
+        if to == 1 {
+            Some("127.0.0.1:4000".to_owned())
+        } else {
+            None
+        }
+    }
+
+    fn stats(&self, to: u64) -> Option<PeerStats> {
+        if to != 1 {
+            return None;
+        }
+        Some(PeerStats {
+            rtt_ms: None,
+            bytes_sent: 10,
+            bytes_received: 20,
+            connected_seconds: 1.5,
+        })
+    }
+}
+
+/// Tests that buildengine.net raises lua errors without a network, or for arguments that can't be
+/// sent, and goes through the network it is given.
+#[test]
+fn script_net_functions() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), "be = require(\"buildengine\")".to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    assert!(!engine.has_network());
+    for call in &["be.net.send(1, \"x\", {})", "be.net.broadcast(\"x\")", "be.net.kick(1)",
+                  "be.net.peer_count()", "be.net.peer_addr(1)", "be.net.stats(1)"] {
+        let code = format!("select(2, pcall(function () {} end))", call);
+        match engine.eval(&code).unwrap() {
+            AnyLuaValue::LuaString(ref message) => {
+                assert!(message.contains("buildengine.net is not available"),
+                        "unexpected message from {}: {}",
+                        call,
+                        message)
+            }
+            other => panic!("expected {} to error, got {:?}", call, other),
+        }
+    }
+
+    let sent = Rc::new(RefCell::new(Vec::new()));
+    engine.set_network(Some(Box::new(RecordingNetwork(sent.clone()))));
+    assert!(engine.has_network());
+    engine.eval("be.net.send(1, \"hello\", {\"a\", {b = 2}})").unwrap();
+    assert_eq!(engine.eval("be.net.broadcast(\"all\", {true})").unwrap(),
+               AnyLuaValue::LuaNumber(1.0));
+    engine.eval("be.net.kick(1, \"bye\")").unwrap();
+    assert_eq!(engine.eval("be.net.peer_count()").unwrap(), AnyLuaValue::LuaNumber(1.0));
+    assert_eq!(engine.eval("be.net.peer_addr(1)").unwrap(),
+               AnyLuaValue::LuaString("127.0.0.1:4000".to_owned()));
+    assert_eq!(engine.eval("be.net.peer_addr(2)").unwrap(), AnyLuaValue::LuaNil);
+    assert_eq!(engine.eval("be.net.stats(1).bytes_received").unwrap(),
+               AnyLuaValue::LuaNumber(20.0));
+    assert_eq!(engine.eval("be.net.stats(1).rtt_ms").unwrap(), AnyLuaValue::LuaNil);
+    assert_eq!(engine.eval("be.net.stats(2)").unwrap(), AnyLuaValue::LuaNil);
+    let b = vec![(LuaValueRepr::String("b".to_owned()), LuaValueRepr::Number(2.0))];
+    assert_eq!(*sent.borrow(),
+               vec![(1,
+                     "hello".to_owned(),
+                     vec![LuaValueRepr::String("a".to_owned()), LuaValueRepr::Table(b)]),
+                    (1, "all".to_owned(), vec![LuaValueRepr::Boolean(true)]),
+                    (1, "kicked".to_owned(), vec![LuaValueRepr::String("bye".to_owned())])]);
+
+    sent.borrow_mut().clear();
+    for &(call, expected) in &[("be.net.send(1, \"x\", {1, {player = {callback = print}}})",
+                                "args[2].player.callback"),
+                               ("be.net.broadcast(\"x\", {coroutine.create(print)})", "args[1]"),
+                               ("be.net.send(2, \"x\", {})", "no connection 2"),
+                               ("local t = {} t[1] = t be.net.send(1, \"x\", t)",
+                                "contains itself"),
+                               ("be.net.send(1.5, \"x\", {})", "connection_id")] {
+        let code = format!("select(2, pcall(function () {} end))", call);
+        match engine.eval(&code).unwrap() {
+            AnyLuaValue::LuaString(ref message) => {
+                assert!(message.contains(expected), "unexpected message from {}: {}", call, message)
+            }
+            other => panic!("expected {} to error, got {:?}", call, other),
+        }
+    }
+    // The native behind it can be called with anything, without crashing the engine.
+    match engine.eval("select(2, load(prelude_buildengine.net_send(1, 5))())").unwrap() {
+        AnyLuaValue::LuaString(ref message) => {
+            assert!(message.contains("bad argument event to net.send"),
+                    "unexpected message: {}",
+                    message)
+        }
+        other => panic!("expected net_send to fail, got {:?}", other),
+    }
+    assert!(sent.borrow().is_empty());
+}
+
+/// Tests that a handler of a server engine can reply to an event with `buildengine.net.send`.
+#[test]
+fn script_net_reply() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), NET.to_owned());
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts).unwrap();
+    let mut client = ::net::Controller::new_empty();
+    let id = client.connect(server_addr(&engine)).unwrap();
+    let packet = ::net::NetworkPacket::Event {
+        name: "ping".to_owned(),
+        args: vec![LuaValueRepr::Number(7.0)],
+    };
+    client.send_to(id, packet).unwrap();
+    let started = Instant::now();
+    loop {
+        engine.handle_incoming();
+        match client.try_recv_packet() {
+            Some((_, ::net::NetworkPacket::Event { name, args })) => {
+                assert_eq!(name, "pong");
+                assert_eq!(args, vec![LuaValueRepr::Number(7.0), LuaValueRepr::Number(1.0)]);
+                break;
+            }
+            Some(_) => {}
+            None => thread::sleep(Duration::from_millis(10)),
+        }
+        assert!(started.elapsed() < Duration::from_millis(test_util::TEST_SLEEP_TIME_MILLIS * 4),
+                "the client did not receive the reply in time");
+    }
+    let script_engine = engine.script_engine.as_mut().unwrap();
+    match script_engine.get_global("pinger_addr") {
+        Some(AnyLuaValue::LuaString(ref addr)) => {
+            assert!(addr.starts_with("127.0.0.1:"), "bad address {}", addr)
+        }
+        other => panic!("expected the address of the client, got {:?}", other),
+    }
+}
+
+/// Tests that scripts see the traffic on connections with `buildengine.net.stats`.
+#[test]
+fn script_net_stats() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), NET.to_owned());
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts).unwrap();
+    let (_first, second) = engine.net.add_loopback_pair().unwrap();
+    let packet = ::net::NetworkPacket::Event {
+        name: "check_stats".to_owned(),
+        args: vec![LuaValueRepr::Number(second.0 as f64)],
+    };
+    engine.net.send_to(second, packet).unwrap();
+    handle_until_global(&mut engine, "stats_checked", 1.0);
+    let script_engine = engine.script_engine.as_mut().unwrap();
+    for global in &["bytes_received", "sender_bytes_sent"] {
+        match script_engine.get_global(global) {
+            Some(AnyLuaValue::LuaNumber(bytes)) => assert!(bytes > 0.0, "{} is 0", global),
+            other => panic!("expected {} to be a number, got {:?}", global, other),
+        }
+    }
+    match script_engine.get_global("connected_seconds") {
+        Some(AnyLuaValue::LuaNumber(seconds)) => assert!(seconds >= 0.0),
+        other => panic!("expected connected_seconds to be a number, got {:?}", other),
+    }
+    assert_eq!(script_engine.get_global("unknown_is_nil"),
+               Some(AnyLuaValue::LuaBoolean(true)));
+}
+
+/// Tests that command lines are split at whitespace, with quotes and backslashes keeping it.
+#[test]
+fn split_command_words() {
+    test_util::start_log_once();
+    assert_eq!(split_command("kick 3 \"too much lag\"").unwrap(),
+               vec!["kick", "3", "too much lag"]);
+    assert_eq!(split_command("  a\\ b\\\\c  'd \\e' \"f\\\"g\" \"\" ''").unwrap(),
+               vec!["a b\\c", "d \\e", "f\"g", "", ""]);
+    assert_eq!(split_command("say \"two \"words").unwrap(), vec!["say", "two words"]);
+    assert_eq!(split_command(" \t ").unwrap(), Vec::<String>::new());
+    for line in &["say \"unclosed", "say 'unclosed", "say trailing\\"] {
+        match split_command(line) {
+            Err(CommandError::Syntax(_)) => {}
+            other => panic!("expected {} to be a syntax error, got {:?}", line, other),
+        }
+    }
+}
+
+/// Tests that commands registered by scripts and in rust run, rust ones first, along with the
+/// built in ones, and that unknown and failing commands give their errors.
+#[test]
+fn run_commands() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("commands".to_owned(), COMMANDS.to_owned());
+    scripts.insert("init".to_owned(), "require(\"commands\")".to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    engine.register_command("add", "Adds the numbers.", |args| {
+        let mut sum = 0;
+        for arg in args {
+            sum += try!(arg.parse::<i64>().map_err(|_| format!("{} isn't a number", arg)));
+        }
+        Ok(sum.to_string())
+    });
+    engine.register_command("shadowed", "Runs instead of the lua one.", |_| {
+        Ok("from rust".to_owned())
+    });
+
+    assert_eq!(engine.run_command("greet \"big world\"").unwrap(), "hello big world");
+    assert_eq!(engine.run_command("count a 'b c' \"\"").unwrap(), "3");
+    assert_eq!(engine.run_command("quiet").unwrap(), "");
+    assert_eq!(engine.run_command("add 1 2 3").unwrap(), "6");
+    assert_eq!(engine.run_command("shadowed").unwrap(), "from rust");
+    assert_eq!(engine.run_command("   "), Err(CommandError::Empty));
+    assert_eq!(engine.run_command("nope 1"),
+               Err(CommandError::UnknownCommand("nope".to_owned())));
+    match engine.run_command("fail") {
+        Err(CommandError::HandlerError { ref command, ref message }) => {
+            assert_eq!(command, "fail");
+            assert!(message.contains("failed on purpose"), "unexpected message: {}", message);
+        }
+        other => panic!("expected fail to fail, got {:?}", other),
+    }
+    match engine.run_command("add 1 x") {
+        Err(CommandError::HandlerError { ref message, .. }) => {
+            assert_eq!(message, "x isn't a number")
+        }
+        other => panic!("expected add to fail, got {:?}", other),
+    }
+
+    let help = engine.run_command("help").unwrap();
+    for line in &["add - Adds the numbers.",
+                  "greet - Greets whoever is named.",
+                  "help - Lists every command, or describes the one named.",
+                  "shadowed - Runs instead of the lua one."] {
+        assert!(help.lines().any(|help_line| help_line == *line),
+                "{} is missing from the help: {}",
+                line,
+                help);
+    }
+    assert_eq!(engine.run_command("help greet").unwrap(),
+               "greet - Greets whoever is named.");
+    assert_eq!(engine.run_command("help nope"),
+               Err(CommandError::UnknownCommand("nope".to_owned())));
+    assert_eq!(engine.run_command("scripts").unwrap(), "buildengine\ncommands");
+
+    assert_eq!(engine.run_command("lua return 1 + 1"),
+               Err(CommandError::NotTrusted("lua".to_owned())));
+    engine.set_commands_trusted(true);
+    assert_eq!(engine.run_command("lua return \"a  b\", 1 + 1").unwrap(),
+               "{[(1)] = \"a  b\", [(2)] = (2), }");
+    assert_eq!(engine.run_command("lua return 1 + 1").unwrap(), "2");
+    assert_eq!(engine.run_command("lua x = 1").unwrap(), "nil");
+    match engine.run_command("lua error(\"oops\")") {
+        Err(CommandError::HandlerError { ref command, .. }) => assert_eq!(command, "lua"),
+        other => panic!("expected the lua command to fail, got {:?}", other),
+    }
+}
+
+/// Tests that every tick executes on_tick with it's number and the time since the last tick.
+#[test]
+fn tick_executes_on_tick() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), TICK.to_owned());
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts).unwrap();
+    for _ in 0..3 {
+        engine.tick().unwrap();
+    }
+    assert_eq!(engine.ticks(), 3);
+    {
+        let script_engine = engine.script_engine.as_mut().unwrap();
+        let ticks = script_engine.get_global("ticks").unwrap();
+        assert_eq!(ticks, AnyLuaValue::LuaNumber(3.0));
+        let last_tick = script_engine.get_global("last_tick").unwrap();
+        assert_eq!(last_tick, AnyLuaValue::LuaNumber(2.0));
+        match script_engine.get_global("last_delta").unwrap() {
+            AnyLuaValue::LuaNumber(delta) => assert!(delta >= 0.0 && delta < 1.0),
+            other => panic!("expected a number, got {:?}", other),
+        }
+        // "on_tick" is given the tick the script engine counts, so it carries on from one
+        // advanced or restored behind the engine's back.
+        script_engine.advance_tick(Duration::from_millis(0)).unwrap();
+    }
+    assert_eq!(engine.ticks(), 4);
+    engine.tick().unwrap();
+    let script_engine = engine.script_engine.as_mut().unwrap();
+    assert_eq!(script_engine.get_global("last_tick").unwrap(), AnyLuaValue::LuaNumber(4.0));
+    assert_eq!(script_engine.eval("be.time.tick()").unwrap(), AnyLuaValue::LuaNumber(5.0));
+}
+
+/// Tests that a tick on an engine without the engine std, or without scripts at all, succeeds.
+#[test]
+fn tick_without_engine_std() {
+    test_util::start_log_once();
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), HashMap::new()).unwrap();
+    engine.tick().unwrap();
+    let (mut client, _server) = connected_client();
+    client.tick().unwrap();
+}
+
+/// Tests that run keeps to the tick rate, and stops when asked from another thread.
+#[test]
+fn run_stops_on_request() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), TICK.to_owned());
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts).unwrap();
+    engine.config.tick_rate = 100;
+    let stop = engine.stop_handle();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(105));
+        stop.request_stop();
+    });
+    let started = Instant::now();
+    engine.run().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    // The tick rate is a target, so a loaded machine may run fewer ticks, but never more.
+    let ran = engine.ticks();
+    assert!(ran >= 2 && ran <= 12, "ran {} ticks", ran);
+    {
+        let script_engine = engine.script_engine.as_mut().unwrap();
+        let ticks = script_engine.get_global("ticks").unwrap();
+        assert_eq!(ticks, AnyLuaValue::LuaNumber(ran as f64));
+    }
+    engine.request_stop();
+    engine.run().unwrap();
+}
+
+/// Runs the engine for about the time, stopping it from another thread.
+fn run_for(engine: &mut ::Engine, millis: u64) {
+    let stop = engine.stop_handle();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(millis));
+        stop.request_stop();
+    });
+    engine.run().unwrap();
+}
+
+/// Tests that pausing the engine stops "on_tick" and the game time untill it is resumed, with
+/// "on_pause" and "on_resume" executed between.
+#[test]
+fn pause_and_resume() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), PAUSE.to_owned());
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts).unwrap();
+    engine.config.tick_rate = 100;
+    run_for(&mut engine, 55);
+    assert!(engine.ticks() > 0);
+
+    // Paused by the console, from the tick after the one running the command.
+    engine.inject_console_line("pause");
+    run_for(&mut engine, 55);
+    assert!(engine.is_paused());
+    let paused_at = engine.ticks();
+    let game_time = engine.script_engine.as_ref().unwrap().game_time();
+    run_for(&mut engine, 55);
+    assert_eq!(engine.ticks(), paused_at);
+    {
+        let script_engine = engine.script_engine.as_mut().unwrap();
+        assert_eq!(script_engine.game_time(), game_time);
+        assert_eq!(script_engine.get_global("ticks").unwrap(),
+                   AnyLuaValue::LuaNumber(paused_at as f64));
+        assert_eq!(script_engine.get_global("transitions").unwrap(),
+                   AnyLuaValue::LuaString("p".to_owned()));
+    }
+
+    engine.pause_handle().resume();
+    assert!(!engine.is_paused());
+    run_for(&mut engine, 55);
+    assert!(engine.ticks() > paused_at);
+    let ticks = engine.ticks();
+    let script_engine = engine.script_engine.as_mut().unwrap();
+    assert!(script_engine.game_time() > game_time);
+    assert_eq!(script_engine.get_global("ticks").unwrap(),
+               AnyLuaValue::LuaNumber(ticks as f64));
+    assert_eq!(script_engine.get_global("transitions").unwrap(),
+               AnyLuaValue::LuaString("pr".to_owned()));
+}
+
+/// Asserts which libraries and functions the sandbox script found under the sandbox level.
+fn assert_sandboxed(engine: &mut Engine, sandbox: SandboxLevel) {
+    let trusted = sandbox != SandboxLevel::Untrusted;
+    let expected = [("ran", true),
+                    ("has_os_execute", sandbox == SandboxLevel::Full),
+                    ("has_os_time", true),
+                    ("has_io", trusted),
+                    ("has_io_popen", sandbox == SandboxLevel::Full),
+                    ("has_package", trusted),
+                    ("has_dofile", trusted),
+                    ("has_loadfile", trusted),
+                    ("has_string", true),
+                    ("loads_binary", trusted),
+                    ("has_debug", trusted),
+                    ("has_string_dump", trusted),
+                    ("has_engine_std", true)];
+    for &(global, expected) in &expected {
+        let value = engine.get_global(global).unwrap();
+        assert_eq!(value,
+                   AnyLuaValue::LuaBoolean(expected),
+                   "{} under {:?}",
+                   global,
+                   sandbox);
+    }
+}
+
+/// Tests what each sandbox level removes.
+#[test]
+fn sandbox_levels() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), SANDBOX.to_owned());
+    for &sandbox in &[SandboxLevel::Full, SandboxLevel::Trusted, SandboxLevel::Untrusted] {
+        let mut engine = Engine::new_with_sandbox(scripts.clone(), sandbox).unwrap();
+        assert_sandboxed(&mut engine, sandbox);
+    }
+    let mut engine = Engine::new_with_sandbox(scripts.clone(), SandboxLevel::Untrusted).unwrap();
+    let result = engine.interpreter.execute::<()>("os.execute(\"true\")");
+    assert!(result.is_err());
+    // Only the scripts given to the engine can be required, not ones on the filesystem.
+    let result = engine.interpreter.execute::<()>("require(\"src.script.test.test\")");
+    assert!(result.is_err());
+    let mut engine = Engine::new_with_sandbox(scripts, SandboxLevel::Full).unwrap();
+    engine.interpreter.execute::<()>("require(\"src.script.test.test\")").unwrap();
+}
+
+/// Asserts the result is `ExecEventError::PreludeMissing`.
+fn assert_prelude_missing<T: ::std::fmt::Debug>(result: Result<T, ExecEventError>) {
+    match result {
+        Err(ExecEventError::PreludeMissing) => {}
+        other => panic!("expected PreludeMissing, got {:?}", other),
+    }
+}
+
+/// Tests that a script removing the prelude_buildengine table makes events fail rather than
+/// panic, and that sandboxed scripts can't remove or replace it.
+#[test]
+fn prelude_removed() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), "prelude_buildengine = nil".to_owned());
+    let mut engine = Engine::new(scripts.clone()).unwrap();
+    match engine.exec_event("on_tick", Vec::new()) {
+        Err(ExecEventError::PreludeMissing) => {}
+        other => panic!("expected PreludeMissing, got {:?}", other),
+    }
+    match engine.queue_event("on_tick", Vec::new()) {
+        Err(ExecEventError::PreludeMissing) => {}
+        other => panic!("expected PreludeMissing, got {:?}", other),
+    }
+    match engine.process_queued_events(QUEUED_EVENTS_PER_TICK) {
+        Err(ExecEventError::PreludeMissing) => {}
+        other => panic!("expected PreludeMissing, got {:?}", other),
+    }
+    assert!(engine.take_outgoing().is_empty());
+    assert!(!engine.has_module("buildengine"));
+    assert_eq!(engine.handler_count("on_tick"), 0);
+    assert!(engine.eval("1 + 1").is_err());
+    match engine.snapshot() {
+        Err(SnapshotError::Event(ExecEventError::PreludeMissing)) => {}
+        other => panic!("expected PreludeMissing, got {:?}", other),
+    }
+    assert_prelude_missing(engine.register_fn("noop", |_| None));
+    assert_prelude_missing(engine.set_profiling(true));
+    assert_prelude_missing(engine.lua_memory_usage());
+    assert_eq!(engine.set_global("x", AnyLuaValue::LuaNumber(1.0)),
+               Err(PathError::PreludeMissing));
+    assert_eq!(engine.get_module_global("init", "x"), None);
+
+    for &sandbox in &[SandboxLevel::Trusted, SandboxLevel::Untrusted] {
+        assert!(Engine::new_with_sandbox(scripts.clone(), sandbox).is_err());
+    }
+    scripts.insert("init".to_owned(), "rawset(_G, \"prelude_buildengine\", {})".to_owned());
+    assert!(Engine::new_with_sandbox(scripts.clone(), SandboxLevel::Untrusted).is_err());
+    scripts.insert("init".to_owned(), "x = 1".to_owned());
+    let mut engine = Engine::new_with_sandbox(scripts, SandboxLevel::Untrusted).unwrap();
+    assert!(engine.eval("setmetatable(_G, nil)").is_err());
+    assert_eq!(engine.get_global("x"), Some(AnyLuaValue::LuaNumber(1.0)));
+    engine.exec_event("on_tick", Vec::new()).unwrap();
+}
+
+/// Tests that a client only gets a script engine when given scripts, which are sandboxed.
+#[test]
+fn client_scripts_sandboxed() {
+    test_util::start_log_once();
+    let (client, server) = connected_client();
+    assert!(client.script_engine.is_none());
+    let addr = server.listener_addrs()[0].1;
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), SANDBOX.to_owned());
+    let mut client = ::Engine::new_client_with_scripts(addr, scripts).unwrap();
+    assert_sandboxed(client.script_engine.as_mut().unwrap(), SandboxLevel::Untrusted);
+    assert!(client.scripts.is_empty());
+}
+
+/// A new, empty directory in the temporary directory, which should be removed once done with.
+fn temp_dir(name: &str) -> PathBuf {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    let dir = env::temp_dir().join(format!("buildengine5-{}-{}", name, nanos));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes the file in the directory, creating any directories it is in.
+fn write_file(dir: &Path, path: &str, contents: &[u8]) {
+    let path = dir.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    File::create(path).unwrap().write_all(contents).unwrap();
+}
+
+/// Tests loading nested scripts from a directory, and running them.
+#[test]
+fn load_scripts_nested() {
+    test_util::start_log_once();
+    let dir = temp_dir("nested");
+    write_file(&dir, "init.lua", b"x = require(\"foo.bar\")");
+    write_file(&dir, "foo/bar.lua", b"return require(\"foo.baz.qux\") + 1");
+    write_file(&dir, "foo/baz/qux.lua", b"return 4");
+    write_file(&dir, "foo/readme.txt", b"not a script");
+    let scripts = load_scripts_from_dir(&dir).unwrap();
+    let mut names: Vec<&String> = scripts.keys().collect();
+    names.sort();
+    assert_eq!(names, vec!["foo.bar", "foo.baz.qux", "init"]);
+    assert_eq!(scripts["foo.baz.qux"], "return 4");
+    let config = ::EngineConfig::server(::net::ip("127.0.0.1:0")).script_dir(&dir).build();
+    let mut engine = ::Engine::with_config(config).unwrap();
+    let x = engine.script_engine.as_mut().unwrap().get_global("x").unwrap();
+    assert_eq!(x, AnyLuaValue::LuaNumber(5.0));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Tests that links to directories are skipped, so a link back up the tree doesn't recurse
+/// forever.
+#[cfg(unix)]
+#[test]
+fn load_scripts_skips_linked_dirs() {
+    use std::os::unix::fs::symlink;
+    test_util::start_log_once();
+    let dir = temp_dir("linked");
+    write_file(&dir, "init.lua", b"x = 1");
+    write_file(&dir, "foo/bar.lua", b"return 1");
+    symlink(&dir, dir.join("foo/up")).unwrap();
+    symlink(dir.join("foo"), dir.join("alias")).unwrap();
+    symlink(dir.join("init.lua"), dir.join("foo/linked.lua")).unwrap();
+    let scripts = load_scripts_from_dir(&dir).unwrap();
+    let mut names: Vec<&String> = scripts.keys().collect();
+    names.sort();
+    assert_eq!(names, vec!["foo.bar", "foo.linked", "init"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Tests that a script that is not UTF-8 is reported with it's path.
+#[test]
+fn load_scripts_not_utf8() {
+    test_util::start_log_once();
+    let dir = temp_dir("not-utf8");
+    write_file(&dir, "init.lua", b"x = 1");
+    write_file(&dir, "foo/bad.lua", b"x = \"\xff\xfe\"");
+    match load_scripts_from_dir(&dir) {
+        Err(ScriptLoadError::NotUtf8(path)) => assert_eq!(path, dir.join("foo/bad.lua")),
+        other => panic!("expected NotUtf8, got {:?}", other),
+    }
+    let config = ::EngineConfig::server(::net::ip("127.0.0.1:0")).script_dir(&dir).build();
+    match ::Engine::with_config(config) {
+        Err(::InitError::LoadError(ScriptLoadError::NotUtf8(_))) => {}
+        other => panic!("expected a LoadError, got {:?}", other),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Tests that scripts with the same module name apart from case are reported.
+#[test]
+fn load_scripts_duplicate() {
+    test_util::start_log_once();
+    let dir = temp_dir("duplicate");
+    write_file(&dir, "foo/bar.lua", b"return 1");
+    write_file(&dir, "Foo.Bar.lua", b"return 2");
+    match load_scripts_from_dir(&dir) {
+        Err(ScriptLoadError::DuplicateModule(name, path, other)) => {
+            assert_eq!(name.to_lowercase(), "foo.bar");
+            let mut paths = vec![path, other];
+            paths.sort();
+            assert_eq!(paths, vec![dir.join("Foo.Bar.lua"), dir.join("foo/bar.lua")]);
+        }
+        other => panic!("expected DuplicateModule, got {:?}", other),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Tests that a missing directory is reported with it's path.
+#[test]
+fn load_scripts_missing_dir() {
+    test_util::start_log_once();
+    let dir = temp_dir("missing").join("nothing here");
+    match load_scripts_from_dir(&dir) {
+        Err(ScriptLoadError::Io(path, _)) => assert_eq!(path, dir),
+        other => panic!("expected Io, got {:?}", other),
+    }
+    fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}
+
+/// The number of times the "ping" event's handlers counted, after executing it.
+fn ping(engine: &mut Engine) -> f64 {
+    engine.exec_event("ping", Vec::new()).unwrap();
+    match engine.get_module_global("game.pinger", "pinged") {
+        Some(AnyLuaValue::LuaNumber(pinged)) => pinged,
+        other => panic!("expected game.pinger to have pinged, got {:?}", other),
+    }
+}
+
+/// Tests that a watcher loads added scripts, reloads changed ones and unloads removed ones.
+#[test]
+fn script_watcher_reloads() {
+    test_util::start_log_once();
+    let dir = temp_dir("watcher");
+    write_file(&dir, "init.lua", b"be = require(\"buildengine\")");
+    let mut engine = Engine::new(load_scripts_from_dir(&dir).unwrap()).unwrap();
+    let mut watcher = ScriptWatcher::new(&dir);
+    watcher.set_interval(Duration::from_millis(0));
+    watcher.set_debounce(Duration::from_millis(0));
+    let pinger = b"pinged = 0
+                   require(\"buildengine\").on(\"ping\", function () pinged = pinged + 1 end)";
+    write_file(&dir, "game/pinger.lua", pinger);
+
+    // Nothing happens while it is stopped.
+    watcher.poll(&mut engine);
+    assert!(!engine.has_module("game.pinger"));
+    watcher.start();
+    watcher.poll(&mut engine);
+    assert!(engine.has_module("game.pinger"));
+    assert_eq!(ping(&mut engine), 1.0);
+
+    // A different length, so the change is seen even if the modification time is not. The module
+    // keeps it's environment, so it carries on from where it was.
+    let pinger = b"require(\"buildengine\").on(\"ping\", function () pinged = pinged + 10 end)";
+    write_file(&dir, "game/pinger.lua", pinger);
+    watcher.poll(&mut engine);
+    assert_eq!(engine.handler_count("ping"), 1);
+    assert_eq!(ping(&mut engine), 11.0);
+
+    fs::remove_file(dir.join("game/pinger.lua")).unwrap();
+    watcher.poll(&mut engine);
+    assert!(!engine.has_module("game.pinger"));
+    assert_eq!(engine.handler_count("ping"), 0);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Tests that a watcher waits for a changed script to settle before loading it.
+#[test]
+fn script_watcher_debounces() {
+    test_util::start_log_once();
+    let dir = temp_dir("watcher-debounce");
+    let mut engine = without_init(HashMap::new());
+    let mut watcher = ScriptWatcher::new(&dir);
+    watcher.set_interval(Duration::from_millis(0));
+    watcher.set_debounce(Duration::from_millis(200));
+    watcher.start();
+    write_file(&dir, "pinger.lua", b"return {}");
+    watcher.poll(&mut engine);
+    assert!(!engine.has_module("pinger"));
+    thread::sleep(Duration::from_millis(300));
+    watcher.poll(&mut engine);
+    assert!(engine.has_module("pinger"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Tests the length of a tick at various tick rates.
+#[test]
+fn engine_config_tick_length() {
+    let mut config = ::EngineConfig::server(::net::ip("127.0.0.1:0")).build();
+    assert_eq!(config.tick_length(), Duration::from_millis(50));
+    config.tick_rate = 1;
+    assert_eq!(config.tick_length(), Duration::from_secs(1));
+    config.tick_rate = 3;
+    assert_eq!(config.tick_length(), Duration::new(0, 333333333));
+}
+
+/// Tests the defaults of an EngineConfig, and what the builder sets.
+#[test]
+fn engine_config_builder() {
+    let addr = ::net::ip("127.0.0.1:0");
+    let config = ::EngineConfig::server(addr).build();
+    assert_eq!(config.role, ::EngineRole::Server(addr));
+    assert!(config.scripts.is_empty());
+    assert_eq!(config.tick_rate, ::DEFAULT_TICK_RATE);
+    assert_eq!(config.net.max_clients, ::net::MAX_CONNECTED_CLIENTS);
+    assert_eq!(config.net.password, None);
+    assert_eq!(config.should_crash, None);
+    assert_eq!(config.net.should_crash, None);
+    assert_eq!(config.sandbox, SandboxLevel::Full);
+    config.validate().unwrap();
+    assert_eq!(::EngineConfig::client(addr).build().sandbox, SandboxLevel::Untrusted);
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), "x = 1".to_owned());
+    let config = ::EngineConfig::client(addr)
+                     .scripts(scripts.clone())
+                     .tick_rate(30)
+                     .max_clients(64)
+                     .password("hunter2")
+                     .should_crash(false)
+                     .build();
+    assert_eq!(config.role, ::EngineRole::Client(addr));
+    assert_eq!(config.scripts, scripts);
+    assert_eq!(config.tick_rate, 30);
+    assert_eq!(config.net.max_clients, 64);
+    assert_eq!(config.net.password, Some("hunter2".to_owned()));
+    assert_eq!(config.should_crash, Some(false));
+    assert_eq!(config.net.should_crash, Some(false));
+    config.validate().unwrap();
+}
+
+/// Tests that a server engine is not created from a config with a tick rate of 0.
+#[test]
+fn engine_config_zero_tick_rate() {
+    test_util::start_log_once();
+    let config = ::EngineConfig::server(::net::ip("127.0.0.1:0")).tick_rate(0).build();
+    match ::Engine::with_config(config) {
+        Err(::InitError::ZeroTickRate) => {}
+        other => panic!("expected ZeroTickRate, got {:?}", other),
+    }
+}
+
+/// Tests that a server engine is not created from scripts without an init.
+#[test]
+fn engine_config_missing_init() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("test".to_owned(), TEST.to_owned());
+    match ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts) {
+        Err(::InitError::MissingInitScript) => {}
+        other => panic!("expected MissingInitScript, got {:?}", other),
+    }
+}
+
+/// Tests that a server engine created from a config uses it's settings.
+#[test]
+fn engine_with_config() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), TICK.to_owned());
+    let config = ::EngineConfig::server(::net::ip("127.0.0.1:0"))
+                     .scripts(scripts.clone())
+                     .tick_rate(30)
+                     .max_clients(64)
+                     .should_crash(false)
+                     .build();
+    let engine = ::Engine::with_config(config).unwrap();
+    assert_eq!(engine.scripts, scripts);
+    assert_eq!(engine.should_crash, Some(false));
+    assert_eq!(engine.config.tick_rate, 30);
+    assert_eq!(engine.net.raw.config.read().unwrap().max_clients, 64);
+    let info = engine.script_engine.as_ref().unwrap().info();
+    assert!(info.is_server && !info.is_client);
+    assert_eq!(info.tick_rate, Some(30));
+}
+
+/// Handles the packets received by the engine untill the global is the number.
+fn handle_until_global(engine: &mut ::Engine, global: &str, expected: f64) {
+    let started = Instant::now();
+    loop {
+        engine.handle_incoming();
+        let value: Option<AnyLuaValue> = engine.script_engine
+                                               .as_mut()
+                                               .unwrap()
+                                               .interpreter
+                                               .get(global);
+        if value == Some(AnyLuaValue::LuaNumber(expected)) {
+            return;
+        }
+        assert!(started.elapsed() < Duration::from_millis(test_util::TEST_SLEEP_TIME_MILLIS * 4),
+                "{} did not become {} in time, it is {:?}",
+                global,
+                expected,
+                value);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Tests that players connecting and disconnecting execute events, and that scripts can send
+/// events to them.
+#[test]
+fn player_lifecycle_events() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), PLAYERS.to_owned());
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts).unwrap();
+    let mut client = ::net::Controller::new_empty();
+    client.connect(server_addr(&engine)).unwrap();
+    handle_until_global(&mut engine, "connects", 1.0);
+    let players: Option<AnyLuaValue> = engine.script_engine
+                                             .as_mut()
+                                             .unwrap()
+                                             .interpreter
+                                             .get("players");
+    let id = match players {
+        Some(AnyLuaValue::LuaArray(pairs)) => {
+            assert_eq!(pairs.len(), 1);
+            match pairs[0] {
+                (AnyLuaValue::LuaNumber(id), AnyLuaValue::LuaString(ref addr)) => {
+                    assert!(addr.starts_with("127.0.0.1:"), "bad address {}", addr);
+                    id
+                }
+                ref other => panic!("expected an id and an address, got {:?}", other),
+            }
+        }
+        other => panic!("expected the players table, got {:?}", other),
+    };
+    let started = Instant::now();
+    loop {
+        match client.try_recv_packet() {
+            Some((_, ::net::NetworkPacket::Event { name, args })) => {
+                assert_eq!(name, "welcome");
+                assert_eq!(args,
+                           vec![LuaValueRepr::String("hello".to_owned()),
+                                LuaValueRepr::Number(id)]);
+                break;
+            }
+            Some(_) => {}
+            None => thread::sleep(Duration::from_millis(10)),
+        }
+        assert!(started.elapsed() < Duration::from_millis(test_util::TEST_SLEEP_TIME_MILLIS * 4),
+                "the client did not receive the welcome event in time");
+    }
+    client.shutdown();
+    handle_until_global(&mut engine, "disconnects", 1.0);
+    let script_engine = engine.script_engine.as_mut().unwrap();
+    let reason = script_engine.get_global("last_reason").unwrap();
+    assert_eq!(reason,
+               AnyLuaValue::LuaString(format!("Disconnected: {}", ::net::SHUTDOWN_REASON)));
+    let players = script_engine.get_global("players").unwrap();
+    assert_eq!(players, AnyLuaValue::LuaArray(Vec::new()));
+    // The player was still known to the handlers of on_player_disconnect, and removed after.
+    assert_eq!(script_engine.get_global("left_known").unwrap(), AnyLuaValue::LuaBoolean(true));
+    assert_eq!(script_engine.eval("be.players.count()").unwrap(), AnyLuaValue::LuaNumber(0.0));
+}
+
+/// Tests that player names are unique ignoring case, validated, and made up when none is given.
+#[test]
+fn player_registry_names() {
+    test_util::start_log_once();
+    let mut registry = ::player::PlayerRegistry::new();
+    let alice = registry.add("Alice", ::net::ConnectionId(10)).unwrap();
+    assert_eq!(registry.add("aLiCe", ::net::ConnectionId(11)),
+               Err(::net::NetworkError::NameTaken));
+    let too_long: String = (0..::player::MAX_NAME_LEN + 1).map(|_| "x").collect();
+    for name in &["has space", "ünïcode", "a.b", &too_long[..]] {
+        assert_eq!(registry.add(name, ::net::ConnectionId(11)),
+                   Err(::net::NetworkError::InvalidName));
+    }
+    let made_up = registry.add("", ::net::ConnectionId(11)).unwrap();
+    assert_eq!(registry.get(made_up).unwrap().name, format!("player{}", made_up.0));
+    assert_eq!(registry.by_name("ALICE").map(|player| player.id), Some(alice));
+    assert_eq!(registry.by_connection(::net::ConnectionId(11)).map(|player| player.id),
+               Some(made_up));
+    assert_eq!(registry.list().iter().map(|player| player.id).collect::<Vec<_>>(),
+               vec![alice, made_up]);
+
+    let removed = registry.remove_connection(::net::ConnectionId(10)).unwrap();
+    assert_eq!(removed.name, "Alice");
+    assert_eq!(registry.remove_connection(::net::ConnectionId(10)), None);
+    assert_eq!(registry.len(), 1);
+    let again = registry.add("alice", ::net::ConnectionId(12)).unwrap();
+    assert!(again > made_up, "ids must not be reused");
+}
+
+/// Connects a controller asking for the name to the server.
+fn connect_named(engine: &::Engine, display_name: &str) -> ::net::Controller {
+    let config = ::net::ControllerConfig {
+        display_name: display_name.to_owned(),
+        ..Default::default()
+    };
+    let mut client = ::net::Controller::new_with_config(config);
+    client.connect(server_addr(engine)).unwrap();
+    client
+}
+
+/// Tests that a server refuses a second player with a name that is taken, and that scripts list
+/// the players that joined with `buildengine.players`.
+#[test]
+fn duplicate_player_names() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), PLAYERS.to_owned());
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts).unwrap();
+    let _alice = connect_named(&engine, "alice");
+    handle_until_global(&mut engine, "connects", 1.0);
+
+    let impostor = connect_named(&engine, "ALICE");
+    let started = Instant::now();
+    loop {
+        engine.handle_incoming();
+        match impostor.try_recv_packet() {
+            Some((_, ::net::NetworkPacket::Error(err))) => {
+                assert_eq!(err, ::net::NetworkError::NameTaken);
+                break;
+            }
+            Some(_) => {}
+            None => thread::sleep(Duration::from_millis(10)),
+        }
+        assert!(started.elapsed() < Duration::from_millis(test_util::TEST_SLEEP_TIME_MILLIS * 4),
+                "the impostor was not refused in time");
+    }
+
+    let _nameless = connect_named(&engine, "");
+    handle_until_global(&mut engine, "connects", 2.0);
+    assert_eq!(engine.players().len(), 2);
+    let alice = engine.players().by_name("alice").unwrap().clone();
+    let script_engine = engine.script_engine.as_mut().unwrap();
+    let listed = script_engine.eval("local list = be.players.list()
+                                     return #list, list[1].name, list[2].name,
+                                            be.players.count(), be.players.get(\"Alice\").id,
+                                            be.players.get(list[1].id).connection,
+                                            be.players.get(\"nobody\") == nil")
+                              .unwrap();
+    assert_eq!(listed,
+               AnyLuaValue::LuaArray(vec![(AnyLuaValue::LuaNumber(1.0),
+                                           AnyLuaValue::LuaNumber(2.0)),
+                                          (AnyLuaValue::LuaNumber(2.0),
+                                           AnyLuaValue::LuaString("alice".to_owned())),
+                                          (AnyLuaValue::LuaNumber(3.0),
+                                           AnyLuaValue::LuaString("player2".to_owned())),
+                                          (AnyLuaValue::LuaNumber(4.0),
+                                           AnyLuaValue::LuaNumber(2.0)),
+                                          (AnyLuaValue::LuaNumber(5.0),
+                                           AnyLuaValue::LuaNumber(alice.id.0 as f64)),
+                                          (AnyLuaValue::LuaNumber(6.0),
+                                           AnyLuaValue::LuaNumber(alice.connection.0 as f64)),
+                                          (AnyLuaValue::LuaNumber(7.0),
+                                           AnyLuaValue::LuaBoolean(true))]));
+}
+
+/// Tests that malformed events queued by scripts are skipped.
+#[test]
+fn take_outgoing_skips_malformed() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(),
+                   "be = require(\"buildengine\")
+                    be.send_to(\"nope\", \"bad\")
+                    be.send_to(1.5, \"bad\")
+                    be.send_to(2, \"good\")"
+                       .to_owned());
+    let mut engine = Engine::new(scripts).unwrap();
+    let outgoing = engine.take_outgoing();
+    assert_eq!(outgoing,
+               vec![OutgoingEvent {
+                        to: 2,
+                        name: "good".to_owned(),
+                        args: Vec::new(),
+                    }]);
+    assert!(engine.take_outgoing().is_empty());
+}
+
+/// Tests that shutting a server engine down disconnects it's peers, executes on_shutdown, and
+/// releases it's address, twice in a row.
+#[test]
+fn engine_shutdown_releases_address() {
+    test_util::start_log_once();
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), SHUTDOWN.to_owned());
+    for _ in 0..2 {
+        let tattle = test_util::Tattle::new();
+        let tattle_clone = tattle.clone();
+        let mut engine = ::Engine::new_server(&addr, scripts.clone()).unwrap();
+        engine.script_engine
+              .as_mut()
+              .unwrap()
+              .interpreter
+              .set("record_shutdown", function0(move || tattle_clone.call()));
+        let mut client = ::net::Controller::new_empty();
+        client.connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(test_util::TEST_SLEEP_TIME_MILLIS));
+        engine.shutdown().unwrap();
+        assert_eq!(tattle.get(), 1);
+        thread::sleep(Duration::from_millis(test_util::TEST_SLEEP_TIME_MILLIS));
+        let mut disconnected = false;
+        while let Some((_, packet)) = client.try_recv_packet() {
+            if let ::net::NetworkPacket::Disconnect { reason } = packet {
+                assert_eq!(reason, ::net::SHUTDOWN_REASON);
+                disconnected = true;
+            }
+        }
+        assert!(disconnected);
+        client.shutdown();
+    }
+}
+
+/// Tests that dropping an engine without shutting it down still releases it's address.
+#[test]
+fn engine_drop_releases_address() {
+    test_util::start_log_once();
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    drop(::Engine::new_server(&addr, HashMap::new()).unwrap());
+    ::Engine::new_server(&addr, HashMap::new()).unwrap().shutdown().unwrap();
+}
+
+/// Tests that lines sent to the console are run as commands by the next tick, and that the
+/// thread reading stdin doesn't hold up shutting down.
+#[test]
+fn console_lines_run_on_tick() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(),
+                   "local be = require(\"buildengine\")
+                    be.commands.register(\"set\", \"Sets value.\", function (v) value = v end)"
+                       .to_owned());
+    let mut engine = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts).unwrap();
+    engine.inject_console_line("set \"a b\"");
+    engine.inject_console_line("   ");
+    engine.inject_console_line("unknown");
+    assert_eq!(engine.script_engine.as_mut().unwrap().get_global("value"), None);
+    engine.tick().unwrap();
+    assert_eq!(engine.script_engine.as_mut().unwrap().get_global("value"),
+               Some(AnyLuaValue::LuaString("a b".to_owned())));
+    assert_eq!(engine.handle_console(), 0);
+    engine.spawn_console().unwrap();
+    engine.spawn_console().unwrap();
+    engine.shutdown().unwrap();
+}
+
+/// Feeds every packet received by each controller to it's engine, untill the client has synced
+/// it's scripts. Returns how many ScriptBody packets the client received.
+fn pump_script_sync(server: &mut ::Engine, client: &mut ::Engine) -> usize {
+    let mut bodies = 0;
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(test_util::TEST_SLEEP_TIME_MILLIS * 4) {
+        while let Some((id, packet)) = server.controller.as_ref().unwrap().try_recv_packet() {
+            server.handle_packet(id, packet).unwrap();
+        }
+        while let Some((id, packet)) = client.controller.as_ref().unwrap().try_recv_packet() {
+            if let ::net::NetworkPacket::ScriptBody { .. } = packet {
+                bodies += 1;
+            }
+            client.handle_packet(id, packet).unwrap();
+        }
+        if client.synced_scripts().is_some() {
+            return bodies;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the client did not sync it's scripts in time");
+}
+
+/// Tests that a client receives the scripts of a server, and skips them when reconnecting.
+#[test]
+fn scripts_synced_to_client() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), "x = 1".to_owned());
+    // Large enough to be split across several packets under the small max_packet_size, and valid
+    // lua, as every module is loaded.
+    let large: String = (0..100).map(|_| "-- ünïcode\n").collect();
+    scripts.insert("large".to_owned(), large);
+    let config = ::net::ControllerConfig { max_packet_size: 256, ..Default::default() };
+    let mut server_controller = ::net::Controller::new_with_config(config);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    server_controller.add_listener(listener).unwrap();
+    let mut server = ::Engine::new_server(&::net::ip("127.0.0.1:0"), scripts.clone()).unwrap();
+    server.controller = Some(server_controller);
+    let mut client_controller = ::net::Controller::new_empty();
+    let mut client = ::Engine::new_client(addr).unwrap();
+    client.controller = Some(client_controller.clone());
+    client_controller.connect(addr).unwrap();
+    assert!(pump_script_sync(&mut server, &mut client) > 2);
+    assert_eq!(client.synced_scripts(), Some(&scripts));
+    client_controller.connect(addr).unwrap();
+    client.pending_scripts = None;
+    assert_eq!(pump_script_sync(&mut server, &mut client), 0);
+    assert_eq!(client.synced_scripts(), Some(&scripts));
+
+    // A script larger than MAX_SCRIPT_SIZE is dropped as it arrives, rather than kept whole.
+    let mut pending = HashMap::new();
+    pending.insert("huge".to_owned(), (0, String::new()));
+    client.pending_scripts = Some(pending);
+    let piece: String = (0..::MAX_SCRIPT_SIZE / 2 + 1).map(|_| "x").collect();
+    let body = ::net::NetworkPacket::ScriptBody {
+        name: "huge".to_owned(),
+        source: piece,
+        last: false,
+    };
+    client.handle_packet(::net::ConnectionId(1), body.clone()).unwrap();
+    match client.handle_packet(::net::ConnectionId(1), body) {
+        Err(::HandlePacketError::ScriptTooLarge(_, ref name)) => assert_eq!(name, "huge"),
+        other => panic!("expected the script to be too large, got {:?}", other),
+    }
+    assert_eq!(client.pending_scripts.as_ref().unwrap()["huge"].1, "");
+}
+
+/// Sends a remote console packet from the controller to the server, once it's connection is
+/// ready, and returns the RconResponse it gets back, handling what the server receives meanwhile.
+/// None if no response came in time.
+fn rcon_request(server: &mut ::Engine,
+                client: &::net::Controller,
+                id: ::net::ConnectionId,
+                packet: ::net::NetworkPacket)
+                -> Option<String> {
+    let started = Instant::now();
+    let timeout = Duration::from_millis(test_util::TEST_SLEEP_TIME_MILLIS * 4);
+    let mut packet = Some(packet);
+    while started.elapsed() < timeout {
+        server.handle_incoming();
+        if let Some(unsent) = packet.take() {
+            match client.send_to(id, unsent.clone()) {
+                Ok(()) => {}
+                Err(::net::SendError::NotReady(_)) => packet = Some(unsent),
+                Err(err) => panic!("failed to send {:?}: {}", unsent, err),
+            }
+        }
+        while let Some((_, received)) = client.try_recv_packet() {
+            if let ::net::NetworkPacket::RconResponse { output } = received {
+                return Some(output);
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    None
+}
+
+/// Tests that commands sent over the remote console are only run once the connection
+/// authenticated, and that connections failing too often are dropped.
+#[test]
+fn rcon_commands() {
+    test_util::start_log_once();
+    let mut scripts: HashMap<String, String> = HashMap::new();
+    scripts.insert("init".to_owned(), "x = 1".to_owned());
+    let config = ::EngineConfig::server(::net::ip("127.0.0.1:0"))
+                     .scripts(scripts)
+                     .rcon_password("secret")
+                     .build();
+    let mut server = ::Engine::with_config(config).unwrap();
+    let addr = server.net.listener_addrs()[0].1;
+    let mut client = ::net::Controller::new_empty();
+    let id = client.connect(addr).unwrap();
+    let help = || ::net::NetworkPacket::RconCommand { line: "help".to_owned() };
+    let auth = |password: &str| ::net::NetworkPacket::RconAuth { password: password.to_owned() };
+
+    assert_eq!(rcon_request(&mut server, &client, id, help()).unwrap(),
+               "Not authenticated.");
+    assert_eq!(rcon_request(&mut server, &client, id, auth("wrong")).unwrap(),
+               "Wrong password.");
+    assert_eq!(rcon_request(&mut server, &client, id, auth("secret")).unwrap(),
+               "Authenticated.");
+    let output = rcon_request(&mut server, &client, id, help()).unwrap();
+    assert!(output.contains("help - "), "unexpected help: {}", output);
+
+    let other = client.connect(addr).unwrap();
+    for _ in 0..::net::MAX_RCON_FAILURES - 1 {
+        assert_eq!(rcon_request(&mut server, &client, other, auth("wrong")).unwrap(),
+                   "Wrong password.");
+    }
+    assert_eq!(rcon_request(&mut server, &client, other, auth("wrong")), None);
+    assert_eq!(server.net.connection_count(), 1);
+    assert_eq!(rcon_request(&mut server, &client, id, help()).map(|output| output.is_empty()),
+               Some(false));
+}
+
+/// Tests that sources are split without splitting characters, and joined back together.
+#[test]
+fn split_source_keeps_characters() {
+    test_util::start_log_once();
+    let source: String = (0..10).map(|_| "aü€😀b").collect();
+    for max_len in 0..12 {
+        let pieces = ::split_source(&source, max_len);
+        assert!(pieces.iter().all(|piece| piece.len() <= cmp::max(max_len, 4)));
+        assert_eq!(pieces.concat(), source);
+    }
+    assert_eq!(::split_source("", 10), vec![""]);
+}
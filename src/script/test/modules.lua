@@ -0,0 +1,13 @@
+be = require("buildengine")
+can_load_modules = be.load_module ~= nil
+if can_load_modules then
+    be.load_module("from_lua", "return {value = 7}")
+    from_lua_value = require("from_lua").value
+    reserved_ok = pcall(be.load_module, "buildengine", "return {}")
+else
+    -- Nor can they get around it through prelude_buildengine.
+    bypassed = pcall(prelude_buildengine.reload_module, "evil", "return {}") or
+               pcall(prelude_buildengine.unload_module, "init") or
+               pcall(prelude_buildengine.run_module_request) or
+               prelude_buildengine.take_module_request ~= nil
+end
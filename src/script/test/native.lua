@@ -0,0 +1,5 @@
+be = require("buildengine")
+local record = be.native.record
+be.on("call_native", function ()
+    native_result = record(1, nil, "three")
+end)
@@ -0,0 +1,12 @@
+be = require("buildengine")
+
+be.on("inner", function (value)
+    return "inner", value
+end)
+
+-- Raises "inner" from inside the call of "outer", as the native function executes it from rust
+-- with the value it doubled, returning what it returned.
+be.on("outer", function ()
+    local inner = be.native.double(21)
+    return "outer", inner[1], inner[2]
+end)
@@ -0,0 +1,13 @@
+be = require("buildengine")
+be.subscribe("ping", function (from, value)
+    be.net.send(from, "pong", {value, be.net.peer_count()})
+    pinger_addr = be.net.peer_addr(from)
+end)
+be.subscribe("check_stats", function (from, sender)
+    local received, sent = be.net.stats(from), be.net.stats(sender)
+    bytes_received = received.bytes_received
+    sender_bytes_sent = sent.bytes_sent
+    connected_seconds = received.connected_seconds
+    unknown_is_nil = be.net.stats(123456) == nil
+    stats_checked = 1
+end)
@@ -0,0 +1,6 @@
+be = require("buildengine")
+be.subscribe("net_test", function (from, value)
+    got_from = from
+    got_first = value[1]
+    got_inner = value[2][2]
+end)
@@ -0,0 +1,13 @@
+local be = require("buildengine")
+
+-- Each error reported, as "event|module|message|if it has a traceback".
+reports = {}
+fail_reporting = false
+
+be.on("on_error", function (err)
+    table.insert(reports, table.concat({err.event, tostring(err.module), err.message,
+                                        tostring(err.traceback ~= nil)}, "|"))
+    if fail_reporting then
+        error("on_error failed too", 0)
+    end
+end)
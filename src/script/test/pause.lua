@@ -0,0 +1,6 @@
+be = require("buildengine")
+ticks = 0
+transitions = ""
+be.subscribe("on_tick", function () ticks = ticks + 1 end)
+be.subscribe("on_pause", function () transitions = transitions .. "p" end)
+be.subscribe("on_resume", function () transitions = transitions .. "r" end)
@@ -0,0 +1,15 @@
+be = require("buildengine")
+players = {}
+connects = 0
+disconnects = 0
+be.subscribe("on_player_connect", function (id, addr)
+    players[id] = addr
+    connects = connects + 1
+    be.send_to(be.players.get(id).connection, "welcome", {"hello", id})
+end)
+be.subscribe("on_player_disconnect", function (id, reason)
+    players[id] = nil
+    left_known = be.players.get(id) ~= nil
+    disconnects = disconnects + 1
+    last_reason = reason
+end)
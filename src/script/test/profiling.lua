@@ -0,0 +1,13 @@
+be = require("buildengine")
+
+local function busy (seconds)
+    local started = os.clock()
+    while os.clock() - started < seconds do
+    end
+end
+
+slow = be.on("tick", function ()
+    busy(0.05)
+end)
+fast = be.on("tick", function ()
+end)
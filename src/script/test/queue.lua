@@ -0,0 +1,12 @@
+be = require("buildengine")
+ran = ""
+
+be.on("first", function (a, b)
+    ran = ran .. "first(" .. a .. b .. ")"
+    be.queue_event("second", "x")
+    be.queue_event("third")
+    -- The queued events haven't run yet.
+    ran = ran .. "/"
+end)
+be.on("second", function (x) ran = ran .. "second(" .. x .. ")" end)
+be.on("third", function () ran = ran .. "third" end)
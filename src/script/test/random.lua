@@ -0,0 +1,19 @@
+be = require("buildengine")
+
+function roll ()
+    local rolled = {}
+    for i = 1, 20 do
+        local x = be.random.int(1, 6)
+        assert(x >= 1 and x <= 6)
+        table.insert(rolled, x)
+    end
+    for i = 1, 5 do
+        local x = be.random.float()
+        assert(x >= 0 and x < 1)
+        table.insert(rolled, x)
+    end
+    for _,x in ipairs(be.random.shuffle({1, 2, 3, 4, 5, 6, 7, 8})) do
+        table.insert(rolled, x)
+    end
+    return rolled
+end
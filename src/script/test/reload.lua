@@ -0,0 +1,10 @@
+be = require("buildengine")
+require("greeter")
+
+be.on("greet", function ()
+    greeting = require("greeter").greeting()
+end)
+
+be.on("on_module_reloaded", function (name)
+    reloaded = name
+end)
@@ -0,0 +1,24 @@
+be = require("buildengine")
+original = {1, 2, "three", x = "y", [10] = false, nested = {{}, {1.5, {deep = "er"}}}}
+same = false
+
+local function deep_equal (a, b)
+    if type(a) ~= "table" or type(b) ~= "table" then
+        return a == b
+    end
+    for key, value in pairs(a) do
+        if not deep_equal(value, b[key]) then
+            return false
+        end
+    end
+    for key, _ in pairs(b) do
+        if a[key] == nil then
+            return false
+        end
+    end
+    return true
+end
+
+be.subscribe("compare", function (copy)
+    same = deep_equal(original, copy)
+end)
@@ -0,0 +1,16 @@
+be = require("buildengine")
+
+be.on("none", function () end)
+be.on("one", function () return 1 end)
+be.on("three", function () return 1, "two", true end)
+be.on("nils", function () return nil, 2, nil end)
+
+-- Each handler is given every value the one before returned.
+be.on("chain", function (a, b)
+    return a, nil, b
+end)
+be.on("chain", function (...)
+    chain_count = select("#", ...)
+    local a, b, c = ...
+    return c, b, a, nil
+end)
@@ -0,0 +1,15 @@
+ran = true
+has_os_execute = os ~= nil and os.execute ~= nil
+has_os_time = os ~= nil and os.time ~= nil
+has_io = io ~= nil
+has_io_popen = io ~= nil and io.popen ~= nil
+has_package = package ~= nil
+has_dofile = dofile ~= nil
+has_loadfile = loadfile ~= nil
+has_string = string ~= nil
+has_debug = debug ~= nil
+has_string_dump = string.dump ~= nil
+-- A precompiled chunk, which load only takes outside of the untrusted sandbox. Without
+-- string.dump, the header of one is enough to tell.
+loads_binary = load(has_string_dump and string.dump(function () end) or "\27Lua") ~= nil
+has_engine_std = require("buildengine").on ~= nil
@@ -0,0 +1,4 @@
+be = require("buildengine")
+be.subscribe("on_shutdown", function ()
+    record_shutdown()
+end)
@@ -0,0 +1,18 @@
+be = require("buildengine")
+
+function store_world ()
+    be.storage.set("world", {
+        name = "station",
+        size = {x = 10, y = 20},
+        tiles = {"floor", "wall", {"door", open = false}},
+    })
+    be.storage.set("gone", 1)
+    be.storage.delete("gone")
+end
+
+function check_world ()
+    local world = be.storage.get("world")
+    return world.name == "station" and world.size.x == 10 and world.size.y == 20 and
+           world.tiles[2] == "wall" and world.tiles[3][1] == "door" and
+           world.tiles[3].open == false and be.storage.get("gone") == nil
+end
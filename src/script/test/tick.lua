@@ -0,0 +1,7 @@
+be = require("buildengine")
+ticks = 0
+be.subscribe("on_tick", function (tick, delta)
+    ticks = ticks + 1
+    last_tick = tick
+    last_delta = delta
+end)
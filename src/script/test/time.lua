@@ -0,0 +1,11 @@
+be = require("buildengine")
+
+be.after(1, function () fired_at = be.time.now() end)
+
+function time_state ()
+    return string.format("%g %d %g", be.time.now(), be.time.tick(), be.time.delta())
+end
+
+function start_timer ()
+    be.after(1, function () fired_at = be.time.now() end)
+end
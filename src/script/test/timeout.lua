@@ -0,0 +1,49 @@
+be = require("buildengine")
+
+be.on("spin", function ()
+    while true do
+    end
+end)
+
+-- Catching the error the watchdog raises doesn't stop it.
+be.on("spin_caught", function ()
+    pcall(function ()
+        while true do
+        end
+    end)
+    while true do
+    end
+end)
+
+-- Neither does clearing the limits, or replacing the clock, from the script being limited.
+be.on("spin_unlimited", function ()
+    prelude_buildengine.limit_instructions = nil
+    prelude_buildengine.limit_millis = nil
+    prelude_buildengine.elapsed_millis = function () return 0 end
+    while true do
+    end
+end)
+
+-- Nor trying to stop the watchdog, which only script::Engine can.
+be.on("spin_stopped", function ()
+    pcall(prelude_buildengine.stop_watchdog)
+    pcall(prelude_buildengine.run_watchdog_request)
+    while true do
+    end
+end)
+
+-- Nor spinning in a coroutine.
+be.on("spin_coroutine", function ()
+    coroutine.resume(coroutine.create(function ()
+        while true do
+        end
+    end))
+    coroutine.wrap(function ()
+        while true do
+        end
+    end)()
+end)
+
+be.on("after", function ()
+    after_ran = true
+end)
@@ -0,0 +1,12 @@
+be = require("buildengine")
+fired = ""
+
+once = be.after(0.5, function () fired = fired .. "o" end)
+be.after(1, function () fired = fired .. "a" end)
+local cancelled = be.after(1, function () fired = fired .. "x" end)
+be.cancel_timer(cancelled)
+repeating = be.every(2, function () fired = fired .. "r" end)
+be.after(3, function ()
+    fired = fired .. "b"
+    be.after(1, function () fired = fired .. "c" end)
+end)
@@ -0,0 +1,5 @@
+be = require("buildengine")
+failing = require("failing")
+be.subscribe("fails_deep", function ()
+    failing.fail()
+end)
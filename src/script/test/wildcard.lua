@@ -0,0 +1,12 @@
+be = require("buildengine")
+calls = ""
+
+be.on("player.move", function (x)
+    calls = calls .. "exact(" .. x .. ")"
+end)
+be.on("player.*", function (event_name)
+    calls = calls .. "player(" .. event_name .. ")"
+end)
+everything = be.on("*", function (event_name)
+    calls = calls .. "all(" .. event_name .. ")"
+end)
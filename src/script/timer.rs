@@ -0,0 +1,153 @@
+//! Contains the schedule of the timers scripts start with `buildengine.after` and
+//! `buildengine.every`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use std::u64;
+
+/// The timers scripts have started, and the time they are kept against.
+///
+/// The time only moves when `advance` is called, so scripts are timed by ticks of the engine
+/// rather than the clock, and tests can move it as they like. The callbacks of the timers are kept
+/// by lua, by the id of their timer.
+#[derive(Debug)]
+pub struct Timers {
+    /// The time advanced so far.
+    now: Duration,
+    next_id: u64,
+    /// The time each timer is due, with it's id, soonest first.
+    schedule: BTreeSet<(Duration, u64)>,
+    /// Every timer that hasn't fired or been cancelled, by id.
+    timers: HashMap<u64, Timer>,
+}
+
+/// A timer that hasn't fired or been cancelled.
+#[derive(Debug)]
+struct Timer {
+    due: Duration,
+    /// How often the timer repeats, or None if it only fires once.
+    interval: Option<Duration>,
+}
+
+impl Timers {
+    /// Constructs a schedule with no timers, at time 0.
+    pub fn new() -> Timers {
+        Timers {
+            now: Duration::from_secs(0),
+            next_id: 1,
+            schedule: BTreeSet::new(),
+            timers: HashMap::new(),
+        }
+    }
+
+    /// Adds a timer due after the delay, repeating every delay after that if repeat is true.
+    /// Returns the id of the timer, which is never reused.
+    pub fn add(&mut self, delay: Duration, repeat: bool) -> u64 {
+        let id = self.next_id;
+        self.insert(id, delay, if repeat { Some(delay) } else { None });
+        id
+    }
+
+    /// Adds a timer with the id, due after the delay and repeating every interval after that, such
+    /// as to restore one from a snapshot. Ids given by `add` from then on are greater than it.
+    ///
+    /// A timer that already has the id is replaced.
+    pub fn insert(&mut self, id: u64, delay: Duration, interval: Option<Duration>) {
+        self.cancel(id);
+        if id >= self.next_id {
+            self.next_id = id + 1;
+        }
+        let due = later(self.now, delay);
+        self.schedule.insert((due, id));
+        self.timers.insert(id,
+                           Timer {
+                               due: due,
+                               interval: interval,
+                           });
+    }
+
+    /// Removes the timer, returning false if it already fired or was cancelled.
+    pub fn cancel(&mut self, id: u64) -> bool {
+        match self.timers.remove(&id) {
+            Some(timer) => {
+                self.schedule.remove(&(timer.due, id));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// If the timer repeats, or None if it already fired or was cancelled.
+    pub fn repeats(&self, id: u64) -> Option<bool> {
+        self.timers.get(&id).map(|timer| timer.interval.is_some())
+    }
+
+    /// Every timer that hasn't fired or been cancelled, soonest first, as it's id, the time untill
+    /// it is due, and how often it repeats.
+    pub fn snapshot(&self) -> Vec<(u64, Duration, Option<Duration>)> {
+        self.schedule
+            .iter()
+            .map(|&(due, id)| {
+                let left = due.checked_sub(self.now).unwrap_or(Duration::from_secs(0));
+                (id, left, self.timers[&id].interval)
+            })
+            .collect()
+    }
+
+    /// The time advanced so far.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Sets the time, such as to carry it over from a snapshot. Timers already added keep the time
+    /// they are due, so it should be set before adding any.
+    pub fn set_now(&mut self, now: Duration) {
+        self.now = now;
+    }
+
+    /// The number of timers that haven't fired or been cancelled.
+    pub fn count(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Moves the time forward, returning the ids of the timers that are due, soonest first.
+    ///
+    /// Each timer is returned at most once per call. Timers that only fire once are removed, and
+    /// repeating timers are due again an interval after they were due, or after now if they
+    /// missed more than one interval, so a timer that fell behind doesn't fire over and over to
+    /// catch up. Timers added after this returns are due no sooner than the next call.
+    pub fn advance(&mut self, dt: Duration) -> Vec<u64> {
+        self.now = later(self.now, dt);
+        let mut due = Vec::new();
+        while let Some(&(time, id)) = self.schedule.iter().next() {
+            if time > self.now {
+                break;
+            }
+            self.schedule.remove(&(time, id));
+            due.push(id);
+        }
+        for &id in &due {
+            let repeat = self.timers[&id].interval;
+            match repeat {
+                Some(interval) => {
+                    let mut next = later(self.timers[&id].due, interval);
+                    if next <= self.now {
+                        next = later(self.now, interval);
+                    }
+                    self.timers.get_mut(&id).unwrap().due = next;
+                    self.schedule.insert((next, id));
+                }
+                None => {
+                    self.timers.remove(&id);
+                }
+            }
+        }
+        due
+    }
+}
+
+/// The time the delay after the time, or the latest time a Duration can hold if that would
+/// overflow, so a timer due that late never fires rather than panicking.
+fn later(time: Duration, delay: Duration) -> Duration {
+    time.checked_add(delay).unwrap_or(Duration::new(u64::MAX, 999_999_999))
+}
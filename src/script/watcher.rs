@@ -0,0 +1,253 @@
+//! Contains a watcher reloading scripts from a directory as they are changed.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{Engine, module_name};
+
+/// The default time between polls of the directory, in milliseconds.
+pub const DEFAULT_POLL_MILLIS: u64 = 500;
+
+/// The default time a file must go unchanged before it is loaded, in milliseconds.
+pub const DEFAULT_DEBOUNCE_MILLIS: u64 = 200;
+
+/// Polls a directory of scripts, as loaded by `load_scripts_from_dir`, and loads each script that
+/// is changed, added, or removed into an engine, with `Engine::reload_module`,
+/// `Engine::load_module` and `Engine::unload_module`.
+///
+/// Nothing happens untill `start` is called, so it can be left off outside of development.
+///
+/// A file is only loaded once it has gone unchanged for the debounce time, as editors often write
+/// a file more than once when saving it, and a file that can't be read, such as one still being
+/// written, is tried again at the next poll. The init script can't be reloaded, so changes to it
+/// are only logged.
+#[derive(Debug)]
+pub struct ScriptWatcher {
+    dir: PathBuf,
+    interval: Duration,
+    debounce: Duration,
+    running: bool,
+    last_poll: Option<Instant>,
+    /// Every .lua file in the directory at the last poll, by path.
+    files: HashMap<PathBuf, WatchedFile>,
+}
+
+/// What is known of a file in the watched directory.
+#[derive(Debug)]
+struct WatchedFile {
+    /// The modification time and length of the file, which change whenever it is written.
+    stamp: (SystemTime, u64),
+    /// When the stamp last changed.
+    changed_at: Instant,
+    /// If the file changed since it was last loaded.
+    pending: bool,
+    /// When the file was first found missing, if it has been since it was last loaded.
+    missing_since: Option<Instant>,
+}
+
+impl ScriptWatcher {
+    /// Constructs a stopped watcher for the directory, polling every DEFAULT_POLL_MILLIS with a
+    /// debounce of DEFAULT_DEBOUNCE_MILLIS.
+    ///
+    /// The scripts in the directory now are taken to be loaded already.
+    pub fn new(dir: &Path) -> ScriptWatcher {
+        let mut watcher = ScriptWatcher {
+            dir: dir.to_owned(),
+            interval: Duration::from_millis(DEFAULT_POLL_MILLIS),
+            debounce: Duration::from_millis(DEFAULT_DEBOUNCE_MILLIS),
+            running: false,
+            last_poll: None,
+            files: HashMap::new(),
+        };
+        let now = Instant::now();
+        for (path, stamp) in watcher.scan() {
+            watcher.files.insert(path,
+                                 WatchedFile {
+                                     stamp: stamp,
+                                     changed_at: now,
+                                     pending: false,
+                                     missing_since: None,
+                                 });
+        }
+        watcher
+    }
+
+    /// Sets the time between polls of the directory.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Sets how long a file must go unchanged before it is loaded.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+    }
+
+    /// Makes `poll` watch the directory.
+    pub fn start(&mut self) {
+        info!("Watching {} for changed scripts.", self.dir.display());
+        self.running = true;
+    }
+
+    /// Makes `poll` do nothing untill `start` is called again.
+    pub fn stop(&mut self) {
+        info!("No longer watching {} for changed scripts.", self.dir.display());
+        self.running = false;
+    }
+
+    /// If the watcher has been started.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Polls the directory if the watcher is running and the interval has passed since the last
+    /// poll, loading each script that changed into the engine.
+    ///
+    /// Meant to be called often, such as every tick. Each action, and each script that failed to
+    /// load, is logged.
+    pub fn poll(&mut self, engine: &mut Engine) {
+        if !self.running {
+            return;
+        }
+        if let Some(last_poll) = self.last_poll {
+            if last_poll.elapsed() < self.interval {
+                return;
+            }
+        }
+        self.last_poll = Some(Instant::now());
+        let found = self.scan();
+        let now = Instant::now();
+        for (path, stamp) in &found {
+            let file = self.files.entry(path.clone()).or_insert(WatchedFile {
+                stamp: *stamp,
+                changed_at: now,
+                pending: true,
+                missing_since: None,
+            });
+            file.missing_since = None;
+            if file.stamp != *stamp {
+                file.stamp = *stamp;
+                file.changed_at = now;
+                file.pending = true;
+            }
+        }
+        let paths: Vec<PathBuf> = self.files.keys().cloned().collect();
+        for path in paths {
+            if found.contains_key(&path) {
+                self.load_if_settled(&path, engine);
+            } else {
+                self.unload_if_settled(&path, engine);
+            }
+        }
+    }
+
+    /// Loads the file if it changed and has gone unchanged for the debounce time since.
+    fn load_if_settled(&mut self, path: &Path, engine: &mut Engine) {
+        {
+            let file = &self.files[path];
+            if !file.pending || file.changed_at.elapsed() < self.debounce {
+                return;
+            }
+        }
+        let name = match module_name(&self.dir, path) {
+            Ok(name) => name,
+            Err(err) => {
+                warn!("Not loading a changed script: {}", err);
+                self.files.get_mut(path).unwrap().pending = false;
+                return;
+            }
+        };
+        let mut source = String::new();
+        if let Err(err) = File::open(path).and_then(|mut file| file.read_to_string(&mut source)) {
+            // Likely still being written, so it stays pending to be tried again.
+            debug!("Failed to read changed script {}, retrying: {}", path.display(), err);
+            return;
+        }
+        self.files.get_mut(path).unwrap().pending = false;
+        if name == "init" {
+            warn!("The init script {} changed, restart to run it.", path.display());
+            return;
+        }
+        let reloading = engine.has_module(&name);
+        let result = if reloading {
+            engine.reload_module(&name, source)
+        } else {
+            engine.load_module(&name, source)
+        };
+        match result {
+            Ok(()) if reloading => info!("Reloaded module {} from {}.", name, path.display()),
+            Ok(()) => info!("Loaded module {} from {}.", name, path.display()),
+            Err(err) => warn!("Failed to load module {} from {}: {}", name, path.display(), err),
+        }
+    }
+
+    /// Unloads the file's module if it has been missing for the debounce time.
+    fn unload_if_settled(&mut self, path: &Path, engine: &mut Engine) {
+        {
+            let file = self.files.get_mut(path).unwrap();
+            let missing_since = file.missing_since.unwrap_or(Instant::now());
+            file.missing_since = Some(missing_since);
+            if missing_since.elapsed() < self.debounce {
+                return;
+            }
+        }
+        self.files.remove(path);
+        let name = match module_name(&self.dir, path) {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+        if name == "init" {
+            warn!("The init script {} was removed.", path.display());
+            return;
+        }
+        match engine.unload_module(&name) {
+            Ok(()) => info!("Unloaded module {}, as {} was removed.", name, path.display()),
+            Err(err) => warn!("Failed to unload module {}: {}", name, err),
+        }
+    }
+
+    /// The modification time and length of every .lua file in the directory and the directories
+    /// in it, by path. Files that can't be read are left out.
+    fn scan(&self) -> HashMap<PathBuf, (SystemTime, u64)> {
+        let mut found = HashMap::new();
+        scan_dir(&self.dir, &mut found);
+        found
+    }
+}
+
+/// Adds the stamp of each .lua file in dir and the directories in it to found.
+fn scan_dir(dir: &Path, found: &mut HashMap<PathBuf, (SystemTime, u64)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("Failed to read script directory {}: {}", dir.display(), err);
+            return;
+        }
+    };
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue,
+        };
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            // Skipped like `load_scripts_from_dir` does, so a link back up the tree isn't followed
+            // forever.
+            let is_link = fs::symlink_metadata(&path)
+                              .map(|link| link.file_type().is_symlink())
+                              .unwrap_or(true);
+            if !is_link {
+                scan_dir(&path, found);
+            }
+        } else if path.extension().and_then(|ext| ext.to_str()) == Some("lua") {
+            if let Ok(modified) = metadata.modified() {
+                found.insert(path, (modified, metadata.len()));
+            }
+        }
+    }
+}
@@ -3,6 +3,7 @@
 //! While these structs and functions are made avalable in release mode, it is not encouraged to use them.
 //! You should instead request the functionality to be better exposed in official APIs.
 
+use std::net::{TcpListener, TcpStream};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -68,3 +69,14 @@ impl Tattle {
 pub fn start_log_once() {
     let _ = env_logger::init();
 }
+
+/// Opens a pair of connected TcpStreams over the loopback interface.
+///
+/// The first stream is the accepted side, and the second is the side that connected.
+pub fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind loopback listener");
+    let addr = listener.local_addr().unwrap();
+    let connected = TcpStream::connect(addr).expect("failed to connect to loopback listener");
+    let (accepted, _) = listener.accept().expect("failed to accept loopback connection");
+    (accepted, connected)
+}